@@ -0,0 +1,80 @@
+//! Compares reading a fast, bursty response token by token against coalescing the tokens of each
+//! burst, as with `OpenAICompatible::with_coalesced_tokens`.
+//!
+//! Run with `cargo bench --bench coalesce`.
+
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use lmql::llms::openai::compatible::OpenAICompatible;
+use lmql::transport::{Connect, Connection, Transport};
+use lmql::{Message, PromptOptions, LLM};
+
+const TOKENS: usize = 20_000;
+const ROUNDS: u32 = 20;
+
+/// Serves the same body to every request over an in-memory connection, in one frame, as a server
+/// generating faster than the network can deliver would.
+struct BurstTransport {
+    body: hyper::body::Bytes,
+}
+
+impl Transport for BurstTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let body = self.body.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |_request| {
+                let response = hyper::Response::new(http_body_util::Full::new(body.clone()));
+                async move { Ok::<_, std::convert::Infallible>(response) }
+            });
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+                .await
+                .ok();
+        });
+        Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+    }
+}
+
+/// Reads a whole response, returning how long it took and how many chunks it was read as.
+async fn read(llm: &OpenAICompatible) -> (Duration, usize) {
+    let start = Instant::now();
+    let chunks = llm
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .count()
+        .await;
+    (start.elapsed(), chunks)
+}
+
+#[tokio::main]
+async fn main() {
+    let event = r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"token "},"finish_reason":null}]}"#;
+    let body = format!("{event}\n\n").repeat(TOKENS);
+    let transport = || BurstTransport {
+        body: body.clone().into(),
+    };
+    let llm = OpenAICompatible::new("https://api.groq.com/openai/v1", "llama-3.1-8b-instant")
+        .with_transport(transport());
+    let coalesced = OpenAICompatible::new("https://api.groq.com/openai/v1", "llama-3.1-8b-instant")
+        .with_coalesced_tokens()
+        .with_transport(transport());
+
+    for (name, llm) in [("per token", &llm), ("coalesced", &coalesced)] {
+        // The first read warms up the connection machinery.
+        read(llm).await;
+        let mut total = Duration::ZERO;
+        let mut chunks = 0;
+        for _ in 0..ROUNDS {
+            let (elapsed, count) = read(llm).await;
+            total += elapsed;
+            chunks = count;
+        }
+        println!(
+            "{name:>10}: {:?} per response of {TOKENS} tokens, read as {chunks} chunks",
+            total / ROUNDS
+        );
+    }
+}
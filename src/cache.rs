@@ -0,0 +1,236 @@
+//! Reusing complete responses to identical requests, to save on cost and latency when the same
+//! prompts are sent repeatedly, e.g. while developing against a fixed set of inputs.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+
+use crate::{Chunk, Message, PromptError, PromptOptions, TokenError, LLM};
+
+type Responses = Arc<Mutex<HashMap<String, Vec<Chunk>>>>;
+
+/// Wraps an [`LLM`] so that responses are remembered and replayed for any later request which is
+/// the same once sent. Clones share the same cache.
+///
+/// Requests are compared by their provider, their model and their whole
+/// [request body](LLM::build_request_body), so changing anything which is sent, such as the model,
+/// a message, the temperature, the seed or a tool, misses the cache, while options which are not
+/// sent, such as timeouts, do not. The order of tools is ignored, as it doesn't change what the
+/// model may call.
+///
+/// Only responses which complete without error are remembered, and they are replayed all at once.
+///
+/// ```no_run
+/// use lmql::cache::Cached;
+///
+/// let llm = Cached::new(lmql::llms::openai::Gpt::new_from_env(
+///     lmql::llms::openai::GptModel::Gpt4oMini,
+/// ));
+/// ```
+pub struct Cached<L> {
+    llm: Arc<L>,
+    responses: Responses,
+}
+
+impl<L> Clone for Cached<L> {
+    fn clone(&self) -> Self {
+        Self {
+            llm: self.llm.clone(),
+            responses: self.responses.clone(),
+        }
+    }
+}
+
+impl<L: LLM> Cached<L> {
+    pub fn new(llm: L) -> Self {
+        Self {
+            llm: Arc::new(llm),
+            responses: Responses::default(),
+        }
+    }
+
+    /// The wrapped LLM.
+    pub fn inner(&self) -> &L {
+        &self.llm
+    }
+
+    /// Forgets every remembered response.
+    pub fn clear(&self) {
+        self.responses.lock().unwrap().clear();
+    }
+
+    /// As with [`LLM::prompt`], but the request is sent even if its response is remembered, and
+    /// the new response replaces it once complete.
+    pub fn force_refresh(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<CachedStream<L>, PromptError> {
+        let key = self.key(messages, options)?;
+        self.send(key, messages, options)
+    }
+
+    /// Identifies the request by everything which is sent. The model is included as some
+    /// providers send it in the url rather than the body.
+    fn key(&self, messages: &[Message], options: &PromptOptions) -> Result<String, PromptError> {
+        let body = self.llm.build_request_body(messages, options)?;
+        let body = canonicalize(serde_json::from_str(&body)?);
+        Ok(format!(
+            "{}\n{}\n{body}",
+            self.llm.provider(),
+            options.resolve_model_name(&*self.llm)
+        ))
+    }
+
+    fn send(
+        &self,
+        key: String,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<CachedStream<L>, PromptError> {
+        let stream = self.llm.prompt(messages, options)?;
+        Ok(CachedStream {
+            state: State::Recording {
+                stream: Box::pin(stream),
+                key,
+                chunks: vec![],
+                responses: self.responses.clone(),
+            },
+        })
+    }
+}
+
+/// Sorts the keys of every object, and the tools of every tool list, so that requests which only
+/// differ in their order are the same.
+fn canonicalize(value: serde_json::Value) -> serde_json::Value {
+    let mut value = crate::sort_keys(value);
+    sort_tools(&mut value);
+    value
+}
+
+fn sort_tools(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                sort_tools(value);
+                // Gemini nests its tools' declarations in a tool of their own.
+                if key == "tools" || key == "functionDeclarations" {
+                    if let serde_json::Value::Array(tools) = value {
+                        tools.sort_by_cached_key(|tool| tool.to_string());
+                    }
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(sort_tools),
+        _ => {}
+    }
+}
+
+impl<L> LLM for Cached<L>
+where
+    L: LLM + Send + Sync + 'static,
+{
+    type TokenStream = CachedStream<L>;
+
+    fn prompt(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<CachedStream<L>, PromptError> {
+        let key = self.key(messages, options)?;
+        if let Some(chunks) = self.responses.lock().unwrap().get(&key) {
+            tracing::debug!("replaying a cached response");
+            return Ok(CachedStream {
+                state: State::Replaying(chunks.iter().cloned().collect()),
+            });
+        }
+        self.send(key, messages, options)
+    }
+
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        self.llm.build_request_body(messages, options)
+    }
+
+    fn provider(&self) -> &'static str {
+        self.llm.provider()
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.llm.model_name()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.llm.supports_tools()
+    }
+}
+
+enum State<L: LLM> {
+    Replaying(VecDeque<Chunk>),
+    Recording {
+        stream: Pin<Box<L::TokenStream>>,
+        key: String,
+        chunks: Vec<Chunk>,
+        responses: Responses,
+    },
+    /// Passing on the rest of a response which can't be remembered.
+    Streaming(Pin<Box<L::TokenStream>>),
+}
+
+/// The stream returned by [`Cached`], either replaying a remembered response or remembering the
+/// response as it arrives.
+pub struct CachedStream<L: LLM> {
+    state: State<L>,
+}
+
+impl<L: LLM> futures::Stream for CachedStream<L> {
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let item = match &mut this.state {
+            State::Replaying(chunks) => return Poll::Ready(chunks.pop_front().map(Ok)),
+            State::Streaming(stream) => return stream.as_mut().poll_next(cx),
+            State::Recording { stream, .. } => match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(item) => item,
+            },
+        };
+
+        match item {
+            Some(Ok(chunk)) => {
+                if let State::Recording { chunks, .. } = &mut this.state {
+                    chunks.push(chunk.clone());
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Some(Err(error)) => {
+                let state = std::mem::replace(&mut this.state, State::Replaying(VecDeque::new()));
+                if let State::Recording { stream, .. } = state {
+                    this.state = State::Streaming(stream);
+                }
+                Poll::Ready(Some(Err(error)))
+            }
+            None => {
+                let state = std::mem::replace(&mut this.state, State::Replaying(VecDeque::new()));
+                if let State::Recording {
+                    key,
+                    chunks,
+                    responses,
+                    ..
+                } = state
+                {
+                    responses.lock().unwrap().insert(key, chunks);
+                }
+                Poll::Ready(None)
+            }
+        }
+    }
+}
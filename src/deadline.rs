@@ -0,0 +1,59 @@
+//! Bounding the total time a response may take, however steadily it arrives.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::{Chunk, TokenError};
+
+/// The stream returned by [`crate::TokenStreamExt::deadline`].
+pub(crate) struct Deadline<S> {
+    stream: Option<Pin<Box<S>>>,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+    duration: Duration,
+}
+
+impl<S> Deadline<S> {
+    pub(crate) fn new(stream: S, duration: Duration) -> Self {
+        Self {
+            stream: Some(Box::pin(stream)),
+            sleep: Box::pin(tokio::time::sleep(duration)),
+            duration,
+        }
+    }
+}
+
+impl<S> futures::Stream for Deadline<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(stream) = this.stream.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        // Chunks which have already arrived are still returned once the deadline has passed.
+        if let Poll::Ready(item) = stream.as_mut().poll_next(cx) {
+            if item.is_none() {
+                this.stream = None;
+            }
+            return Poll::Ready(item);
+        }
+
+        match this.sleep.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => {
+                // Dropping the stream cancels the response.
+                this.stream = None;
+                Poll::Ready(Some(Err(TokenError::Timeout(this.duration))))
+            }
+        }
+    }
+}
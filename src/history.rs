@@ -0,0 +1,110 @@
+//! Helpers for keeping a conversation within a model's context window.
+
+use crate::Message;
+
+/// Roughly how many characters make up a token, for English text with common tokenizers.
+pub(crate) const CHARS_PER_TOKEN: usize = 4;
+/// The tokens used to mark the start and role of each message.
+const TOKENS_PER_MESSAGE: usize = 4;
+/// The most tokens a single image is counted as by the supported providers.
+const TOKENS_PER_IMAGE: usize = 1600;
+
+/// Estimates the number of tokens the messages will use once sent. This is a heuristic based on
+/// the length of the text, and does not depend on the model, so should be given some headroom.
+pub fn estimate_tokens(messages: &[Message]) -> usize {
+    messages.iter().map(estimate_message_tokens).sum()
+}
+
+/// Estimates the number of tokens of the text, as [`estimate_tokens`] does for each part of a
+/// message.
+pub(crate) fn estimate_text_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+fn estimate_message_tokens(message: &Message) -> usize {
+    TOKENS_PER_MESSAGE
+        + match message {
+            Message::User(content) | Message::Assistant(content) => estimate_text_tokens(content),
+            Message::UserParts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    crate::ContentPart::Text(text) => estimate_text_tokens(text),
+                    crate::ContentPart::Image(_) => TOKENS_PER_IMAGE,
+                })
+                .sum(),
+            Message::Thinking {
+                thinking,
+                signature,
+            } => estimate_text_tokens(thinking) + estimate_text_tokens(signature),
+            Message::ToolRequest {
+                id,
+                name,
+                arguments,
+            } => {
+                estimate_text_tokens(id)
+                    + estimate_text_tokens(name)
+                    + estimate_text_tokens(&arguments.serialized)
+            }
+            Message::ToolResponse {
+                content,
+                id,
+                images,
+            } => {
+                estimate_text_tokens(content)
+                    + estimate_text_tokens(id)
+                    + images.len() * TOKENS_PER_IMAGE
+            }
+        }
+}
+
+/// Drops the oldest turns of the conversation until its [estimated](estimate_tokens) size fits
+/// within `budget` tokens, returning the number of messages removed.
+///
+/// The conversation is only ever cut before a [`Message::User`], so it still starts with the user
+/// and tool requests are never separated from their responses. If the latest turn alone is over
+/// budget then it is kept regardless. The system prompt is given separately, in
+/// [`crate::PromptOptions`], so it is always kept but is not counted towards the budget.
+pub fn truncate_to_budget(messages: &mut Vec<Message>, budget: usize) -> usize {
+    // The size of the conversation from each message onwards.
+    let mut remaining = 0;
+    let mut cut = None;
+    for (i, message) in messages.iter().enumerate().rev() {
+        remaining += estimate_message_tokens(message);
+        if !matches!(message, Message::User(_) | Message::UserParts(_)) {
+            continue;
+        }
+        if remaining > budget && cut.is_some() {
+            break;
+        }
+        cut = Some(i);
+    }
+
+    let cut = cut.unwrap_or(0);
+    messages.drain(..cut);
+    cut
+}
+
+/// Splits text into pieces which are each [estimated](estimate_tokens) to fit within `budget`
+/// tokens, for text too long to send at once. Pieces end at the last paragraph break within the
+/// budget, or failing that the last line break, sentence or space, so that they read on their own.
+pub fn split_to_budget(text: &str, budget: usize) -> Vec<String> {
+    let max_chars = budget.saturating_mul(CHARS_PER_TOKEN).max(1);
+    let mut pieces = vec![];
+    let mut rest = text;
+    while let Some((window, _)) = rest.char_indices().nth(max_chars) {
+        let head = &rest[..window];
+        let end = ["\n\n", "\n", ". ", " "]
+            .iter()
+            .find_map(|separator| {
+                head.rfind(separator)
+                    .map(|start| start + separator.len())
+                    .filter(|end| !head[..*end].trim().is_empty())
+            })
+            .unwrap_or(window);
+        pieces.push(rest[..end].trim().to_owned());
+        rest = &rest[end..];
+    }
+    pieces.push(rest.trim().to_owned());
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
@@ -0,0 +1,89 @@
+//! Generating images from a text prompt, for providers which support it.
+
+/// An image model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, serde::Serialize)]
+pub enum ImageModel {
+    #[serde(rename = "dall-e-2")]
+    DallE2,
+    #[default]
+    #[serde(rename = "dall-e-3")]
+    DallE3,
+    #[serde(rename = "gpt-image-1")]
+    GptImage1,
+}
+
+/// How generated images are returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageResponseFormat {
+    /// A link to the image, which expires after a while.
+    #[default]
+    Url,
+    /// The image data itself.
+    Base64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOptions {
+    pub model: ImageModel,
+    /// The number of images to generate.
+    pub n: usize,
+    /// The size of the images, e.g. `1024x1024`. If `None`, the model's default is used.
+    pub size: Option<String>,
+    /// The quality of the images, e.g. `hd` for DALL·E 3 or `high` for GPT Image. If `None`, the
+    /// model's default is used.
+    pub quality: Option<String>,
+    /// Ignored by models which only return [`ImageResponseFormat::Base64`], such as GPT Image.
+    pub response_format: ImageResponseFormat,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            model: ImageModel::default(),
+            n: 1,
+            size: None,
+            quality: None,
+            response_format: ImageResponseFormat::default(),
+        }
+    }
+}
+
+impl ImageOptions {
+    pub fn set_model(&mut self, model: ImageModel) -> &mut Self {
+        self.model = model;
+        self
+    }
+    pub fn set_n(&mut self, n: usize) -> &mut Self {
+        self.n = n;
+        self
+    }
+    pub fn set_size(&mut self, size: String) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+    pub fn set_quality(&mut self, quality: String) -> &mut Self {
+        self.quality = Some(quality);
+        self
+    }
+    pub fn set_response_format(&mut self, response_format: ImageResponseFormat) -> &mut Self {
+        self.response_format = response_format;
+        self
+    }
+}
+
+/// A generated image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageData {
+    Url(String),
+    Base64(crate::Image),
+}
+
+/// A provider which can generate images.
+pub trait ImageGeneration {
+    /// Generates [`ImageOptions::n`] images from the prompt.
+    fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> impl std::future::Future<Output = Result<Vec<ImageData>, crate::EndpointError>> + Send;
+}
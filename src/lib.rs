@@ -1,9 +1,34 @@
 #![doc = include_str!("../README.md")]
 
+pub mod cache;
+mod deadline;
+pub mod history;
+pub mod images;
 pub mod llms;
+pub mod logging;
+mod markdown;
+pub mod rate_limit;
+mod read;
+mod rechunk;
+pub mod rerank;
+pub mod retry;
+mod sentence;
+pub mod signing;
+mod span;
+mod split;
 mod sse;
+pub mod store;
+pub mod summarize;
+pub mod transport;
 
 pub const DEFAULT_MAX_TOKENS: usize = 4096;
+/// A [`PromptOptions::max_tokens`] of as many tokens as the model can output. Providers which
+/// don't know the model's limit leave it to the provider's default.
+pub const MODEL_MAX_TOKENS: usize = usize::MAX;
+#[deprecated(
+    since = "0.3.0",
+    note = "`PromptOptions::temperature` is now optional, and left to the model's default when unset"
+)]
 pub const DEFAULT_TEMPERATURE: f32 = 1.0;
 
 //pub use lmql_macros::*;
@@ -32,12 +57,89 @@ pub const DEFAULT_TEMPERATURE: f32 = 1.0;
     }};
 }*/
 
+/// Any error that can occur between building a prompt and reading its response.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to send prompt")]
+    Prompt(#[from] PromptError),
+    #[error("failed to read response")]
+    Token(#[from] TokenError),
+    #[error("the response did not contain a structured value")]
+    MissingStructuredOutput,
+    #[error("the structured value in the response did not match the requested type")]
+    MalformedStructuredOutput {
+        raw_json: String,
+        #[source]
+        error: serde_json::Error,
+    },
+    /// The summaries of [`summarize::map_reduce`] couldn't be shortened to fit in a prompt.
+    #[error("the summaries could not be made to fit within {budget} tokens")]
+    SummaryOverBudget { budget: usize },
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PromptError {
     #[error("failed to build request to model")]
     RequestError(#[from] hyper::http::Error),
     #[error("failed to transcode prompt or response")]
     TranscodingError(#[from] serde_json::Error),
+    #[error("{provider} cannot use the model override {model_override:?}")]
+    IncompatibleModelOverride {
+        provider: &'static str,
+        model_override: ModelOverride,
+    },
+    #[error("{provider} accepts at most {limit} stopping sequences, but {count} were given")]
+    TooManyStoppingSequences {
+        provider: &'static str,
+        limit: usize,
+        count: usize,
+    },
+    #[error("the extra body field `{0}` is already set by the request")]
+    ConflictingExtraBodyField(String),
+    #[error("more than one tool is named `{0}`")]
+    DuplicateToolName(String),
+    /// A tool was given the name of the tool which [`LLMExt::prompt_structured`] responds with.
+    #[error("the tool name `{0}` is reserved for structured responses")]
+    ReservedToolName(String),
+    #[error(
+        "{provider} cannot use a prompt template; prompt templates are only supported by the \
+         Responses API"
+    )]
+    UnsupportedPromptTemplate { provider: &'static str },
+    #[error(
+        "{provider} needs a reasoning budget of at least {min} tokens and less than the \
+         {max_tokens} max tokens, but {budget} was given"
+    )]
+    InvalidReasoningBudget {
+        provider: &'static str,
+        budget: usize,
+        min: usize,
+        max_tokens: usize,
+    },
+    /// The model doesn't implement [`LLM::build_request_body`].
+    #[error("{provider} cannot build request bodies without sending them")]
+    UnsupportedRequestBody { provider: &'static str },
+}
+
+/// Serializes a request body, adding the fields of [`PromptOptions::extra_body`] to the top level.
+fn serialize_request_body(
+    body: &impl serde::Serialize,
+    extra_body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<String, PromptError> {
+    if extra_body.is_empty() {
+        return Ok(serde_json::to_string(body)?);
+    }
+
+    let mut body = serde_json::to_value(body)?;
+    if let Some(fields) = body.as_object_mut() {
+        for (key, value) in extra_body {
+            if fields.contains_key(key) {
+                return Err(PromptError::ConflictingExtraBodyField(key.clone()));
+            }
+            fields.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(serde_json::to_string(&body)?)
 }
 
 pub struct ToolParameter<'a> {
@@ -50,6 +152,20 @@ pub struct ToolParameter<'a> {
 #[derive(Debug, Clone, PartialEq)]
 pub struct ToolParameters {
     inner: schemars::schema::Schema,
+    strict: bool,
+}
+
+// Schemas only hold values which JSON can represent, so never NaN.
+impl Eq for ToolParameters {}
+
+impl std::hash::Hash for ToolParameters {
+    /// Hashes the schema as JSON with its keys sorted, as equal schemas may list their keys in a
+    /// different order where maps keep the order they were built in.
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        let schema = serde_json::to_value(&self.inner).expect("schemas are always serializable");
+        sort_keys(schema).to_string().hash(state);
+        self.strict.hash(state);
+    }
 }
 
 impl ToolParameters {
@@ -57,12 +173,150 @@ impl ToolParameters {
         let mut generator = schemars::gen::SchemaGenerator::default();
         Self {
             inner: <S as schemars::JsonSchema>::json_schema(&mut generator),
+            strict: false,
+        }
+    }
+
+    /// Asks providers which support it, such as OpenAI, to hold the model's arguments to the
+    /// schema exactly. The schema is sent with every property required and no others allowed,
+    /// and properties which weren't required may be `null` instead.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Whether the model's arguments are held to the schema, as set by [`Self::strict`].
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// The schema to send to a provider which supports strict schemas.
+    pub(crate) fn resolve_strict_schema(&self) -> std::borrow::Cow<'_, schemars::schema::Schema> {
+        if !self.strict {
+            return std::borrow::Cow::Borrowed(&self.inner);
+        }
+        let mut schema =
+            serde_json::to_value(&self.inner).expect("schemas are always serializable");
+        make_strict(&mut schema);
+        std::borrow::Cow::Owned(
+            serde_json::from_value(schema).expect("strict schemas are still schemas"),
+        )
+    }
+
+    /// As with [`Self::new`], but generates the schema with the given settings, for providers which
+    /// want a particular dialect. For example, setting
+    /// [`inline_subschemas`](schemars::gen::SchemaSettings::inline_subschemas) avoids `$ref`s
+    /// entirely, and unsetting [`meta_schema`](schemars::gen::SchemaSettings::meta_schema) leaves out
+    /// `$schema`. Any definitions are kept at the settings' `definitions_path`.
+    pub fn new_with_settings<S: schemars::JsonSchema>(
+        settings: schemars::gen::SchemaSettings,
+    ) -> Self {
+        let definitions_path = settings.definitions_path.clone();
+        let root = settings.into_generator().into_root_schema_for::<S>();
+
+        let mut schema = root.schema;
+        if let Some(meta_schema) = root.meta_schema {
+            schema
+                .extensions
+                .insert("$schema".to_owned(), serde_json::Value::String(meta_schema));
+        }
+        if !root.definitions.is_empty() {
+            // Nested paths, e.g. `#/components/schemas/`, become nested objects.
+            let mut keys = definitions_path
+                .trim_start_matches("#/")
+                .trim_end_matches('/')
+                .rsplit('/');
+            let mut key = keys.next().unwrap_or("definitions");
+            let mut definitions = serde_json::Value::Object(
+                root.definitions
+                    .into_iter()
+                    .map(|(name, definition)| {
+                        let definition = serde_json::to_value(definition)
+                            .expect("schemas are always serializable");
+                        (name, definition)
+                    })
+                    .collect(),
+            );
+            for parent in keys {
+                definitions = serde_json::json!({ key: definitions });
+                key = parent;
+            }
+            schema.extensions.insert(key.to_owned(), definitions);
+        }
+
+        Self {
+            inner: schemars::schema::Schema::Object(schema),
+            strict: false,
+        }
+    }
+}
+
+/// Requires every property of every object in the schema and allows no others, making those
+/// which weren't required nullable.
+fn make_strict(schema: &mut serde_json::Value) {
+    let serde_json::Value::Object(schema) = schema else {
+        return;
+    };
+
+    let required = match schema.get("required") {
+        Some(serde_json::Value::Array(required)) => required.clone(),
+        _ => vec![],
+    };
+    if let Some(serde_json::Value::Object(properties)) = schema.get_mut("properties") {
+        for (name, property) in properties.iter_mut() {
+            make_strict(property);
+            if !required.contains(&serde_json::Value::String(name.clone())) {
+                make_nullable(property);
+            }
+        }
+        let names = properties.keys().cloned().map(serde_json::Value::String);
+        let names = names.collect();
+        schema.insert("required".to_owned(), serde_json::Value::Array(names));
+        schema.insert(
+            "additionalProperties".to_owned(),
+            serde_json::Value::Bool(false),
+        );
+    }
+
+    for key in ["items", "anyOf", "oneOf", "allOf"] {
+        match schema.get_mut(key) {
+            Some(serde_json::Value::Array(subschemas)) => {
+                subschemas.iter_mut().for_each(make_strict)
+            }
+            Some(subschema) => make_strict(subschema),
+            None => {}
+        }
+    }
+    for key in ["definitions", "$defs"] {
+        if let Some(serde_json::Value::Object(definitions)) = schema.get_mut(key) {
+            definitions.values_mut().for_each(make_strict);
+        }
+    }
+}
+
+/// Allows `null` as well as whatever the schema allows.
+fn make_nullable(schema: &mut serde_json::Value) {
+    let null = serde_json::Value::String("null".to_owned());
+    match schema.get_mut("type") {
+        Some(serde_json::Value::Array(types)) => {
+            if !types.contains(&null) {
+                types.push(null);
+            }
+        }
+        Some(ty @ serde_json::Value::String(_)) => {
+            *ty = serde_json::Value::Array(vec![ty.take(), null])
+        }
+        _ => *schema = serde_json::json!({ "anyOf": [schema.take(), { "type": "null" }] }),
+    }
+    if let Some(serde_json::Value::Array(values)) = schema.get_mut("enum") {
+        if !values.contains(&serde_json::Value::Null) {
+            values.push(serde_json::Value::Null);
         }
     }
 }
 
 /// A tool accessible to an LLM.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Tool {
     pub name: String,
     pub description: String,
@@ -74,51 +328,327 @@ pub struct Tool {
 /// For non-open-ai models, this corresponds to the maximum number of tokens to use for reasoning.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ReasoningEffort {
+    /// As little reasoning as the model allows. Models which always reason, such as OpenAI's
+    /// o-series, reason at their lowest effort.
+    Off,
     Low,
     Medium,
     High,
+    /// Reason for at most this many tokens. Providers which only take an effort level use the
+    /// nearest level. Claude needs a budget of at least 1024 tokens, and less than
+    /// [`PromptOptions::max_tokens`].
+    Budget(usize),
 }
 
 impl ReasoningEffort {
-    fn max_tokens(&self) -> usize {
+    /// The most tokens to reason for, or `None` if reasoning should be turned off.
+    fn max_tokens(&self) -> Option<usize> {
+        match self {
+            Self::Off => None,
+            Self::Low => Some(1024),
+            Self::Medium => Some(2048),
+            Self::High => Some(4096),
+            Self::Budget(budget) => Some(*budget),
+        }
+    }
+
+    /// The effort level, for providers which cannot be given a budget or turn reasoning off.
+    fn level(&self) -> ReasoningLevel {
+        match self {
+            Self::Off | Self::Low => ReasoningLevel::Low,
+            Self::Medium => ReasoningLevel::Medium,
+            Self::High => ReasoningLevel::High,
+            Self::Budget(budget) if *budget <= 1024 => ReasoningLevel::Low,
+            Self::Budget(budget) if *budget <= 2048 => ReasoningLevel::Medium,
+            Self::Budget(_) => ReasoningLevel::High,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReasoningLevel {
+    Low,
+    Medium,
+    High,
+}
+
+/// How detailed a summary of the model's reasoning should be, for providers which only return a
+/// summary rather than the reasoning itself.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReasoningSummary {
+    /// The most detailed summary the model supports.
+    Auto,
+    Concise,
+    Detailed,
+}
+
+/// Which of the given tools the model may call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must call a tool, but may choose which. OpenAI calls this `required`. Claude
+    /// does not allow this while reasoning.
+    Any,
+    /// The model must call the named tool. Claude does not allow this while reasoning.
+    Tool(String),
+    /// The model must not call any tool, although it is still told about them.
+    None,
+}
+
+/// A format to constrain the response to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// Any JSON object. OpenAI also requires that the messages mention JSON.
+    JsonObject,
+}
+
+/// The spoken form of a response, for models which can produce audio.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioOutput {
+    /// The voice to speak with, such as `alloy`.
+    pub voice: String,
+    pub format: AudioFormat,
+}
+
+/// The encoding of audio produced by a model.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum AudioFormat {
+    /// Raw 16-bit little-endian mono samples at 24kHz. The only format which can be streamed.
+    #[default]
+    Pcm16,
+    Wav,
+    Mp3,
+    Flac,
+    Opus,
+}
+
+/// A prompt stored with the provider, such as one managed in OpenAI's dashboard, to prompt with by
+/// id rather than by sending its text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTemplate {
+    pub id: String,
+    /// The version of the prompt to use. If `None`, the provider's current version is used.
+    pub version: Option<String>,
+    /// The values to substitute for the prompt's variables, by name.
+    pub variables: std::collections::BTreeMap<String, String>,
+}
+
+impl PromptTemplate {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            version: None,
+            variables: std::collections::BTreeMap::new(),
+        }
+    }
+
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn with_variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.variables.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// A model to use for a single prompt, in place of the model the provider was created with.
+/// The override must be of the kind that the provider uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelOverride {
+    /// For [`llms::anthropic::Claude`].
+    Claude(llms::anthropic::ClaudeModel),
+    /// For [`llms::openai::Gpt`].
+    Gpt(llms::openai::GptModel),
+    /// For providers which name their models, such as [`llms::openrouter::OpenRouter`].
+    Named(String),
+}
+
+/// What to do when more stopping sequences are given than a provider accepts.
+///
+/// Only OpenAI's chat completions, Hugging Face's TGI and Gemini have a known limit. Anthropic
+/// documents none, OpenRouter leaves it to whichever provider serves the model, and Replicate
+/// gives its models every sequence as a single string, so all of them are sent to those.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum StoppingSequenceOverflow {
+    /// Fail with [`PromptError::TooManyStoppingSequences`] before sending the request.
+    #[default]
+    Error,
+    /// Send only as many stopping sequences as the provider accepts, logging a warning.
+    Truncate,
+}
+
+impl StoppingSequenceOverflow {
+    /// Applies the provider's limit on the number of stopping sequences.
+    fn limit<'a>(
+        self,
+        stopping_sequences: &'a [String],
+        provider: &'static str,
+        limit: usize,
+    ) -> Result<&'a [String], PromptError> {
+        if stopping_sequences.len() <= limit {
+            return Ok(stopping_sequences);
+        }
+
         match self {
-            Self::Low => 1024,
-            Self::Medium => 2048,
-            Self::High => 4096,
+            Self::Error => Err(PromptError::TooManyStoppingSequences {
+                provider,
+                limit,
+                count: stopping_sequences.len(),
+            }),
+            Self::Truncate => {
+                tracing::warn!(
+                    "{provider} accepts at most {limit} stopping sequences, ignoring {:?}",
+                    &stopping_sequences[limit..]
+                );
+                Ok(&stopping_sequences[..limit])
+            }
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct PromptOptions {
+    /// The most tokens to output, which is lowered to the model's limit where it is known, as some
+    /// providers reject more. Use [`MODEL_MAX_TOKENS`] for the model's limit.
     pub max_tokens: usize,
-    pub temperature: f32,
+    /// If `None`, none is sent, so the provider uses its default for the model. Reasoning models
+    /// only accept their default, so a temperature is never sent to them.
+    pub temperature: Option<f32>,
+    /// A blank system prompt is left out, as if it were `None`, as some providers reject one.
     pub system_prompt: Option<String>,
     pub stopping_sequences: Vec<String>,
+    /// Each tool must have a different name, or the prompt fails with
+    /// [`PromptError::DuplicateToolName`].
     pub tools: Vec<Tool>,
+    /// How much the model should reason. If `None`, the provider's default is used, which for
+    /// models where reasoning is optional is not to reason.
     pub reasoning: Option<ReasoningEffort>,
+    pub model_override: Option<ModelOverride>,
+    /// Whether to reason without returning the thinking to the caller. Where the provider supports
+    /// it the thinking is never sent, otherwise [`Chunk::Thinking`] chunks are dropped on arrival.
+    pub exclude_reasoning: bool,
+    /// The longest the connection may go without receiving any data (including pings) before it
+    /// is considered dead. If `None`, the connection is never timed out once the response begins.
+    pub keepalive_timeout: Option<std::time::Duration>,
+    /// Sent as the `Idempotency-Key` header, so that a retried request is not processed twice.
+    pub idempotency_key: Option<String>,
+    /// What to do with stopping sequences beyond the provider's limit, where it has one.
+    pub stopping_sequence_overflow: StoppingSequenceOverflow,
+    /// Text the response is expected to be similar to, which some providers can use
+    /// to generate the response faster. Only supported by OpenAI and OpenRouter.
+    pub prediction: Option<String>,
+    /// Additional fields to add to the top level of the request body, for provider parameters which
+    /// are not otherwise supported. Fields which the request already sets cause a
+    /// [`PromptError::ConflictingExtraBodyField`] rather than being overwritten.
+    pub extra_body: serde_json::Map<String, serde_json::Value>,
+    /// Whether the model may call several tools in one response. If `None`, the provider's default
+    /// is used, which for all supported providers is to allow it. Only sent when tools are given.
+    pub parallel_tool_calls: Option<bool>,
+    /// Whether and which tools the model must call. If `None`, the provider's default is used,
+    /// which for all supported providers is [`ToolChoice::Auto`]. Only sent when tools are given.
+    pub tool_choice: Option<ToolChoice>,
+    /// The detail of the reasoning summary to request from providers which return a summary of the
+    /// reasoning rather than the reasoning itself. If `None`, the provider chooses the detail. Only
+    /// supported by [`llms::openai::responses::GptResponses`], as chat completions return no reasoning.
+    pub reasoning_summary: Option<ReasoningSummary>,
+    /// Asks the model to also speak its response, which is returned as [`Chunk::Audio`] alongside a
+    /// transcript as [`Chunk::Token`]. Only supported by OpenAI audio models.
+    pub audio_output: Option<AudioOutput>,
+    /// An id to record on the prompt's `tracing` span, and so on every event logged for the
+    /// prompt, for correlating them with e.g. the request which caused the prompt.
+    pub correlation_id: Option<String>,
+    /// Whether OpenAI should store the response, for later retrieval or building evals. If
+    /// `None`, the provider's default is used. Only supported by OpenAI.
+    pub store: Option<bool>,
+    /// Continues the conversation of a stored response, given by
+    /// [`llms::openai::responses::GptResponsesTokenStream::response_id`], so that only the new
+    /// messages need be sent. Only supported by [`llms::openai::responses::GptResponses`].
+    pub previous_response_id: Option<String>,
+    /// A prompt stored with the provider to prompt with, before any messages, which may then be
+    /// empty. Only supported by [`llms::openai::responses::GptResponses`]; other providers
+    /// return [`PromptError::UnsupportedPromptTemplate`].
+    pub prompt_template: Option<PromptTemplate>,
+    /// Constrains the response to a format. Supported by OpenAI and compatible providers, and by
+    /// Gemini. Elsewhere it is ignored.
+    pub response_format: Option<ResponseFormat>,
+    /// Asks the provider to sample deterministically, so that repeating a prompt with the same
+    /// seed gives the same response, as far as the provider can manage. Supported by OpenAI chat
+    /// completions and compatible providers, and by Gemini. Elsewhere it is ignored.
+    pub seed: Option<u64>,
 }
 
+/// The options set by [`PromptOptions::set_global_defaults`], if any.
+static GLOBAL_DEFAULTS: std::sync::OnceLock<PromptOptions> = std::sync::OnceLock::new();
+
 impl Default for PromptOptions {
+    /// The options set by [`PromptOptions::set_global_defaults`], or otherwise
+    /// [`DEFAULT_MAX_TOKENS`] and nothing else set.
     fn default() -> Self {
+        GLOBAL_DEFAULTS
+            .get()
+            .cloned()
+            .unwrap_or_else(Self::builtin_defaults)
+    }
+}
+
+impl PromptOptions {
+    fn builtin_defaults() -> Self {
         Self {
             max_tokens: DEFAULT_MAX_TOKENS,
-            temperature: DEFAULT_TEMPERATURE,
+            temperature: None,
             system_prompt: None,
             stopping_sequences: vec![],
             tools: vec![],
             reasoning: None,
+            model_override: None,
+            exclude_reasoning: false,
+            keepalive_timeout: None,
+            idempotency_key: None,
+            stopping_sequence_overflow: StoppingSequenceOverflow::Error,
+            prediction: None,
+            extra_body: serde_json::Map::new(),
+            parallel_tool_calls: None,
+            tool_choice: None,
+            reasoning_summary: None,
+            audio_output: None,
+            correlation_id: None,
+            store: None,
+            previous_response_id: None,
+            prompt_template: None,
+            response_format: None,
+            seed: None,
         }
     }
-}
 
-impl PromptOptions {
+    /// Sets the options returned by [`PromptOptions::default`] for the rest of the process, such
+    /// as a lower `max_tokens` to control costs across every call site.
+    ///
+    /// The defaults can only be set once, so this should be called at startup, before any options
+    /// are made; options made before are unchanged. If the defaults have already been set, the
+    /// given options are returned.
+    ///
+    /// ```
+    /// use lmql::PromptOptions;
+    ///
+    /// let mut defaults = PromptOptions::default();
+    /// defaults.set_max_tokens(1024);
+    /// PromptOptions::set_global_defaults(defaults).unwrap();
+    ///
+    /// assert_eq!(PromptOptions::default().max_tokens, 1024);
+    /// ```
+    pub fn set_global_defaults(defaults: PromptOptions) -> Result<(), Box<PromptOptions>> {
+        GLOBAL_DEFAULTS.set(defaults).map_err(Box::new)
+    }
+
     pub fn set_max_tokens(&mut self, max_tokens: usize) -> &mut Self {
         self.max_tokens = max_tokens;
         self
     }
     pub fn set_temperature(&mut self, temperature: f32) -> &mut Self {
-        self.temperature = temperature;
+        self.temperature = Some(temperature);
         self
     }
     pub fn set_system_prompt(&mut self, system_prompt: String) -> &mut Self {
@@ -129,11 +659,85 @@ impl PromptOptions {
         self.stopping_sequences = stopping_sequences;
         self
     }
+    pub fn set_model_override(&mut self, model_override: ModelOverride) -> &mut Self {
+        self.model_override = Some(model_override);
+        self
+    }
+    pub fn set_exclude_reasoning(&mut self, exclude_reasoning: bool) -> &mut Self {
+        self.exclude_reasoning = exclude_reasoning;
+        self
+    }
+    pub fn set_keepalive_timeout(&mut self, keepalive_timeout: std::time::Duration) -> &mut Self {
+        self.keepalive_timeout = Some(keepalive_timeout);
+        self
+    }
+    pub fn set_idempotency_key(&mut self, idempotency_key: String) -> &mut Self {
+        self.idempotency_key = Some(idempotency_key);
+        self
+    }
+    pub fn set_stopping_sequence_overflow(
+        &mut self,
+        stopping_sequence_overflow: StoppingSequenceOverflow,
+    ) -> &mut Self {
+        self.stopping_sequence_overflow = stopping_sequence_overflow;
+        self
+    }
+    pub fn set_prediction(&mut self, prediction: String) -> &mut Self {
+        self.prediction = Some(prediction);
+        self
+    }
+    pub fn set_extra_body(
+        &mut self,
+        extra_body: serde_json::Map<String, serde_json::Value>,
+    ) -> &mut Self {
+        self.extra_body = extra_body;
+        self
+    }
+    pub fn set_parallel_tool_calls(&mut self, parallel_tool_calls: bool) -> &mut Self {
+        self.parallel_tool_calls = Some(parallel_tool_calls);
+        self
+    }
+    pub fn set_tool_choice(&mut self, tool_choice: ToolChoice) -> &mut Self {
+        self.tool_choice = Some(tool_choice);
+        self
+    }
+    pub fn set_reasoning_summary(&mut self, reasoning_summary: ReasoningSummary) -> &mut Self {
+        self.reasoning_summary = Some(reasoning_summary);
+        self
+    }
+    pub fn set_audio_output(&mut self, audio_output: AudioOutput) -> &mut Self {
+        self.audio_output = Some(audio_output);
+        self
+    }
+    pub fn set_correlation_id(&mut self, correlation_id: String) -> &mut Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+    pub fn set_store(&mut self, store: bool) -> &mut Self {
+        self.store = Some(store);
+        self
+    }
+    pub fn set_previous_response_id(&mut self, previous_response_id: String) -> &mut Self {
+        self.previous_response_id = Some(previous_response_id);
+        self
+    }
+    pub fn set_prompt_template(&mut self, prompt_template: PromptTemplate) -> &mut Self {
+        self.prompt_template = Some(prompt_template);
+        self
+    }
+    pub fn set_response_format(&mut self, response_format: ResponseFormat) -> &mut Self {
+        self.response_format = Some(response_format);
+        self
+    }
+    pub fn set_seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = Some(seed);
+        self
+    }
 
     pub fn max_tokens(&self) -> usize {
         self.max_tokens
     }
-    pub fn temperature(&self) -> f32 {
+    pub fn temperature(&self) -> Option<f32> {
         self.temperature
     }
     pub fn system_prompt(&self) -> Option<&str> {
@@ -142,9 +746,356 @@ impl PromptOptions {
     pub fn stopping_sequences(&self) -> &[String] {
         &self.stopping_sequences[..]
     }
+    pub fn model_override(&self) -> Option<&ModelOverride> {
+        self.model_override.as_ref()
+    }
+    pub fn exclude_reasoning(&self) -> bool {
+        self.exclude_reasoning
+    }
+    pub fn keepalive_timeout(&self) -> Option<std::time::Duration> {
+        self.keepalive_timeout
+    }
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+    pub fn stopping_sequence_overflow(&self) -> StoppingSequenceOverflow {
+        self.stopping_sequence_overflow
+    }
+    pub fn prediction(&self) -> Option<&str> {
+        self.prediction.as_deref()
+    }
+    pub fn extra_body(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.extra_body
+    }
+    pub fn parallel_tool_calls(&self) -> Option<bool> {
+        self.parallel_tool_calls
+    }
+    pub fn tool_choice(&self) -> Option<&ToolChoice> {
+        self.tool_choice.as_ref()
+    }
+    pub fn reasoning_summary(&self) -> Option<ReasoningSummary> {
+        self.reasoning_summary
+    }
+    pub fn audio_output(&self) -> Option<&AudioOutput> {
+        self.audio_output.as_ref()
+    }
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+    pub fn store(&self) -> Option<bool> {
+        self.store
+    }
+    pub fn previous_response_id(&self) -> Option<&str> {
+        self.previous_response_id.as_deref()
+    }
+    pub fn prompt_template(&self) -> Option<&PromptTemplate> {
+        self.prompt_template.as_ref()
+    }
+    pub fn response_format(&self) -> Option<ResponseFormat> {
+        self.response_format
+    }
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+}
+
+impl PromptOptions {
+    /// The `max_tokens` to send for a model which outputs at most `limit` tokens, if known, or
+    /// `None` to leave it to the provider.
+    pub(crate) fn resolve_max_tokens(&self, limit: Option<usize>) -> Option<usize> {
+        match limit {
+            Some(limit) => Some(self.max_tokens.min(limit)),
+            None if self.max_tokens == MODEL_MAX_TOKENS => None,
+            None => Some(self.max_tokens),
+        }
+    }
+
+    /// The system prompt to send, leaving out a blank one.
+    pub(crate) fn resolve_system_prompt(&self) -> Option<&str> {
+        self.system_prompt
+            .as_deref()
+            .filter(|system_prompt| !system_prompt.trim().is_empty())
+    }
+
+    /// The name of the model the prompt is sent to, which is the override if there is one.
+    pub(crate) fn resolve_model_name(&self, llm: &(impl LLM + ?Sized)) -> String {
+        match &self.model_override {
+            None => llm.model_name().into_owned(),
+            Some(ModelOverride::Claude(model)) => llms::model_name(model),
+            Some(ModelOverride::Gpt(model)) => llms::model_name(model),
+            Some(ModelOverride::Named(model)) => model.clone(),
+        }
+    }
+
+    /// The tools to send to a provider, which rejects tools sharing a name.
+    pub(crate) fn resolve_tools(&self) -> Result<&[Tool], PromptError> {
+        let mut names = std::collections::HashSet::new();
+        for tool in &self.tools {
+            if !names.insert(tool.name.as_str()) {
+                return Err(PromptError::DuplicateToolName(tool.name.clone()));
+            }
+        }
+        Ok(&self.tools)
+    }
+
+    /// The stopping sequences to send to a provider which accepts at most `limit` of them, if it
+    /// has a limit, applying [`Self::stopping_sequence_overflow`] to any beyond it.
+    pub(crate) fn resolve_stopping_sequences(
+        &self,
+        provider: &'static str,
+        limit: Option<usize>,
+    ) -> Result<&[String], PromptError> {
+        match limit {
+            Some(limit) => {
+                self.stopping_sequence_overflow
+                    .limit(&self.stopping_sequences, provider, limit)
+            }
+            None => Ok(&self.stopping_sequences),
+        }
+    }
+}
+
+/// A partial set of [`PromptOptions`], to layer on top of some base options with
+/// [`PromptOptions::with_overrides`]. Only the fields which are `Some` are overridden, so an
+/// override cannot unset an optional field of the base.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PromptOptionsOverrides {
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub system_prompt: Option<String>,
+    pub stopping_sequences: Option<Vec<String>>,
+    pub tools: Option<Vec<Tool>>,
+    pub reasoning: Option<ReasoningEffort>,
+    pub model_override: Option<ModelOverride>,
+    pub exclude_reasoning: Option<bool>,
+    pub keepalive_timeout: Option<std::time::Duration>,
+    pub idempotency_key: Option<String>,
+    pub stopping_sequence_overflow: Option<StoppingSequenceOverflow>,
+    pub prediction: Option<String>,
+    pub parallel_tool_calls: Option<bool>,
+    pub tool_choice: Option<ToolChoice>,
+    pub reasoning_summary: Option<ReasoningSummary>,
+    pub audio_output: Option<AudioOutput>,
+    pub correlation_id: Option<String>,
+    pub store: Option<bool>,
+    pub previous_response_id: Option<String>,
+    pub prompt_template: Option<PromptTemplate>,
+    pub response_format: Option<ResponseFormat>,
+    pub seed: Option<u64>,
+    /// Added to the base's extra body fields, replacing any with the same name.
+    pub extra_body: serde_json::Map<String, serde_json::Value>,
+}
+
+impl PromptOptions {
+    /// Combines these options with the given overrides, which take precedence where set.
+    pub fn with_overrides(mut self, overrides: PromptOptionsOverrides) -> Self {
+        let PromptOptionsOverrides {
+            max_tokens,
+            temperature,
+            system_prompt,
+            stopping_sequences,
+            tools,
+            reasoning,
+            model_override,
+            exclude_reasoning,
+            keepalive_timeout,
+            idempotency_key,
+            stopping_sequence_overflow,
+            prediction,
+            parallel_tool_calls,
+            tool_choice,
+            reasoning_summary,
+            audio_output,
+            correlation_id,
+            store,
+            previous_response_id,
+            prompt_template,
+            response_format,
+            seed,
+            extra_body,
+        } = overrides;
+
+        if let Some(max_tokens) = max_tokens {
+            self.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = temperature {
+            self.temperature = Some(temperature);
+        }
+        if let Some(system_prompt) = system_prompt {
+            self.system_prompt = Some(system_prompt);
+        }
+        if let Some(stopping_sequences) = stopping_sequences {
+            self.stopping_sequences = stopping_sequences;
+        }
+        if let Some(tools) = tools {
+            self.tools = tools;
+        }
+        if let Some(reasoning) = reasoning {
+            self.reasoning = Some(reasoning);
+        }
+        if let Some(model_override) = model_override {
+            self.model_override = Some(model_override);
+        }
+        if let Some(exclude_reasoning) = exclude_reasoning {
+            self.exclude_reasoning = exclude_reasoning;
+        }
+        if let Some(keepalive_timeout) = keepalive_timeout {
+            self.keepalive_timeout = Some(keepalive_timeout);
+        }
+        if let Some(idempotency_key) = idempotency_key {
+            self.idempotency_key = Some(idempotency_key);
+        }
+        if let Some(stopping_sequence_overflow) = stopping_sequence_overflow {
+            self.stopping_sequence_overflow = stopping_sequence_overflow;
+        }
+        if let Some(prediction) = prediction {
+            self.prediction = Some(prediction);
+        }
+        if let Some(parallel_tool_calls) = parallel_tool_calls {
+            self.parallel_tool_calls = Some(parallel_tool_calls);
+        }
+        if let Some(tool_choice) = tool_choice {
+            self.tool_choice = Some(tool_choice);
+        }
+        if let Some(reasoning_summary) = reasoning_summary {
+            self.reasoning_summary = Some(reasoning_summary);
+        }
+        if let Some(audio_output) = audio_output {
+            self.audio_output = Some(audio_output);
+        }
+        if let Some(correlation_id) = correlation_id {
+            self.correlation_id = Some(correlation_id);
+        }
+        if let Some(store) = store {
+            self.store = Some(store);
+        }
+        if let Some(previous_response_id) = previous_response_id {
+            self.previous_response_id = Some(previous_response_id);
+        }
+        if let Some(prompt_template) = prompt_template {
+            self.prompt_template = Some(prompt_template);
+        }
+        if let Some(response_format) = response_format {
+            self.response_format = Some(response_format);
+        }
+        if let Some(seed) = seed {
+            self.seed = Some(seed);
+        }
+        self.extra_body.extend(extra_body);
+
+        self
+    }
+}
+
+/// The status and headers of a successful response from a provider, useful for debugging.
+#[derive(Debug, Clone)]
+pub struct ResponseMetadata {
+    pub status: hyper::StatusCode,
+    pub headers: hyper::HeaderMap,
+}
+
+impl ResponseMetadata {
+    /// The provider's identifier for the request, as sent in the `x-request-id` (OpenAI, OpenRouter)
+    /// or `request-id` (Anthropic) header. Include this when filing support tickets.
+    pub fn request_id(&self) -> Option<&str> {
+        self.headers
+            .get("x-request-id")
+            .or_else(|| self.headers.get("request-id"))
+            .and_then(|value| value.to_str().ok())
+    }
+}
+
+/// What determines whether a response can be reproduced, as returned by
+/// [`llms::openai::OpenAITokenStream::reproducibility`]. Two responses to the same prompt with the
+/// same seed are only expected to match if these are equal; a changed fingerprint means the
+/// provider changed the backend serving the model.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Reproducibility {
+    /// The [`PromptOptions::seed`] the prompt was sent with. OpenAI does not echo it back.
+    pub seed: Option<u64>,
+    /// The provider's identifier for the configuration of the backend which served the response,
+    /// once the response has started, if the provider gives one.
+    pub system_fingerprint: Option<String>,
+}
+
+/// A record of a prompt being sent, as returned by [`LLMExt::prompt_with_start`], for logging each
+/// request the same way whichever provider it goes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptStart {
+    pub provider: &'static str,
+    /// The model the prompt is sent to, taking into account any [`PromptOptions::model_override`].
+    pub model: String,
+    pub correlation_id: Option<String>,
+    pub started_at: std::time::SystemTime,
+}
+
+/// An event as sent by the server, before it is interpreted into chunks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawEvent {
+    /// The type of the event, which is empty if the server does not name its events.
+    pub event: String,
+    /// The data of the event. Servers which send plain text give it as a JSON string.
+    pub value: serde_json::Value,
+}
+
+/// An image to give to a model, as base64-encoded data.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Image {
+    /// The MIME type of the image, e.g. `image/png`.
+    pub media_type: String,
+    /// The base64-encoded image data.
+    pub data: String,
+}
+
+impl Image {
+    /// Encodes the raw bytes of an image, e.g. `image/png`.
+    pub fn from_bytes(bytes: impl AsRef<[u8]>, media_type: impl Into<String>) -> Self {
+        use base64::Engine;
+        Self {
+            media_type: media_type.into(),
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+
+    /// Reads and encodes an image file. The media type is detected from the file's contents,
+    /// falling back to its extension, and the file is rejected if neither is a known image type.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let media_type = image_media_type(&bytes)
+            .or_else(|| {
+                let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+                Some(match extension.as_str() {
+                    "png" => "image/png",
+                    "jpg" | "jpeg" => "image/jpeg",
+                    "gif" => "image/gif",
+                    "webp" => "image/webp",
+                    _ => return None,
+                })
+            })
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} is not a png, jpeg, gif or webp image", path.display()),
+                )
+            })?;
+        Ok(Self::from_bytes(bytes, media_type))
+    }
+}
+
+/// The media type of an image, from the signature at the start of its data.
+fn image_media_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
 }
 
 /// Some `serde_json::Value` that has been serialized to a string.
+#[derive(Clone)]
 pub struct SerializedJson {
     raw: serde_json::Value,
     serialized: String,
@@ -160,9 +1111,51 @@ impl SerializedJson {
     }
 }
 
+impl std::fmt::Debug for SerializedJson {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.serialized)
+    }
+}
+
+/// Compares by the serialized JSON, so values which serialize identically are equal.
+impl PartialEq for SerializedJson {
+    fn eq(&self, other: &Self) -> bool {
+        self.serialized == other.serialized
+    }
+}
+
+impl Eq for SerializedJson {}
+
+impl serde::Serialize for SerializedJson {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SerializedJson {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Self::try_new(value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A message of a conversation. Messages can be serialized, e.g. to persist a conversation with a
+/// [`store::ConversationStore`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Message {
     User(String),
+    /// A user message of text and images, in order, as built by [`UserMessage`]. Providers which
+    /// only take text, such as Replicate's models, send only the text.
+    UserParts(Vec<ContentPart>),
     Assistant(String),
+    /// The assistant's thinking, signed by the provider so that it can be sent back, as is
+    /// required to continue a response which called tools. Only supported by Anthropic; ignored
+    /// elsewhere.
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
     ToolRequest {
         id: String,
         name: String,
@@ -171,20 +1164,544 @@ pub enum Message {
     ToolResponse {
         content: String,
         id: String,
+        /// Images produced by the tool. Only supported by Anthropic; ignored elsewhere.
+        images: Vec<Image>,
     },
 }
 
-/// Some hook into an LLM, which can be used to generate text.
-pub trait LLM {
-    type TokenStream: futures::Stream<Item = Result<Chunk, TokenError>> + Send;
+/// A part of a [`Message::UserParts`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentPart {
+    Text(String),
+    Image(Image),
+}
+
+impl ContentPart {
+    pub(crate) fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            Self::Image(_) => None,
+        }
+    }
+}
+
+/// The text of the parts, joined by blank lines, for providers which only take text.
+pub(crate) fn joined_text(parts: &[ContentPart]) -> String {
+    let text = parts.iter().filter_map(ContentPart::as_text);
+    text.collect::<Vec<_>>().join("\n\n")
+}
+
+/// Builds a user message from text and images, in the order they are added.
+///
+/// ```
+/// use lmql::{Image, Message, UserMessage};
+///
+/// let chart = Image::from_bytes(b"...", "image/png");
+/// let message = UserMessage::new()
+///     .text("Describe these")
+///     .image(chart.clone())
+///     .image(chart)
+///     .build();
+/// assert!(matches!(message, Message::UserParts(parts) if parts.len() == 3));
+///
+/// // Without images, the message is plain text.
+/// let message = UserMessage::new().text("Hello").build();
+/// assert_eq!(message, Message::User("Hello".to_owned()));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UserMessage {
+    parts: Vec<ContentPart>,
+}
+
+impl UserMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.parts.push(ContentPart::Text(text.into()));
+        self
+    }
+
+    pub fn image(mut self, image: Image) -> Self {
+        self.parts.push(ContentPart::Image(image));
+        self
+    }
+
+    /// Produces a [`Message::UserParts`], or a [`Message::User`] if there are no images, with the
+    /// text joined by blank lines.
+    pub fn build(self) -> Message {
+        if self.parts.iter().all(|part| part.as_text().is_some()) {
+            return Message::User(joined_text(&self.parts));
+        }
+        Message::UserParts(self.parts)
+    }
+}
+
+impl Message {
+    /// Converts the chunks of a response into the messages which represent it in a conversation,
+    /// as the inverse of how providers render them. Text is joined into one [`Message::Assistant`],
+    /// and each complete tool call becomes a [`Message::ToolRequest`]. Incomplete tool calls and
+    /// other chunks are dropped. Chunks may be as streamed or as collated by
+    /// [`TokenStreamExt::all_tokens`].
+    ///
+    /// Thinking followed by its [`Chunk::ThinkingSignature`] becomes a [`Message::Thinking`].
+    /// Unsigned thinking has nowhere to go, so if `keep_thinking` is set it is kept in the
+    /// assistant text between `<thinking>` tags, and otherwise it is dropped.
+    pub fn from_chunks(chunks: impl IntoIterator<Item = Chunk>, keep_thinking: bool) -> Vec<Self> {
+        let mut collated = vec![];
+        for chunk in chunks {
+            collate(&mut collated, chunk);
+        }
+
+        let mut messages = vec![];
+        let mut collated = collated.into_iter().peekable();
+        while let Some(chunk) = collated.next() {
+            let message = match chunk {
+                Chunk::Thinking(thinking) => {
+                    match collated.next_if(|chunk| matches!(chunk, Chunk::ThinkingSignature(_))) {
+                        Some(Chunk::ThinkingSignature(signature)) => Self::Thinking {
+                            thinking,
+                            signature,
+                        },
+                        _ if keep_thinking => {
+                            Self::Assistant(format!("<thinking>{thinking}</thinking>"))
+                        }
+                        _ => continue,
+                    }
+                }
+                chunk => match chunk.try_into_message() {
+                    Some(message) => message,
+                    None => continue,
+                },
+            };
+            match (messages.last_mut(), message) {
+                (Some(Self::Assistant(lhs)), Self::Assistant(rhs)) => lhs.push_str(&rhs),
+                (_, message) => messages.push(message),
+            }
+        }
+        messages
+    }
+}
+
+/// Some hook into an LLM, which can be used to generate text.
+pub trait LLM {
+    type TokenStream: futures::Stream<Item = Result<Chunk, TokenError>> + Send;
 
     /// Generates a response to the given prompt. The prompt is a list of strings, where each
     /// is either the user or the assistant, starting with the user and alternating.
+    ///
+    /// The request is built before this returns, so the stream borrows neither `messages` nor
+    /// `options`, and may outlive both.
     fn prompt(
         &self,
         messages: &[Message],
         options: &PromptOptions,
     ) -> Result<Self::TokenStream, PromptError>;
+
+    /// Serializes the JSON body that [`Self::prompt`] would send for the given prompt, without
+    /// sending anything. Useful for debugging, and for testing how options are encoded.
+    ///
+    /// By default, fails with [`PromptError::UnsupportedRequestBody`], as do prompts to a
+    /// [`cache::Cached`] wrapping a model which doesn't implement this.
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        let _ = (messages, options);
+        Err(PromptError::UnsupportedRequestBody {
+            provider: self.provider(),
+        })
+    }
+
+    /// The name of the provider serving the model, e.g. `"Anthropic"`. By default, the name of
+    /// the implementing type.
+    fn provider(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// The name of the model as sent to its provider, e.g. `"gpt-4o"`. Does not take into account
+    /// any [`PromptOptions::model_override`]. By default, `"unknown"`.
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "unknown".into()
+    }
+
+    /// Whether the model can be given tools to call. Tools given to a model which can't call them
+    /// are ignored. Does not take into account any [`PromptOptions::model_override`].
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    /// As with [`Self::prompt`], but with the type of the stream erased, so that code choosing
+    /// between providers can return the response of whichever it picked.
+    ///
+    /// ```no_run
+    /// use futures::stream::BoxStream;
+    /// use lmql::{Chunk, Message, PromptError, PromptOptions, TokenError, LLM};
+    ///
+    /// fn prompt(
+    ///     local: bool,
+    ///     chat: &[Message],
+    /// ) -> Result<BoxStream<'static, Result<Chunk, TokenError>>, PromptError> {
+    ///     let options = PromptOptions::default();
+    ///     if local {
+    ///         lmql::llms::echo::Echo::new().prompt_boxed(chat, &options)
+    ///     } else {
+    ///         lmql::llms::anthropic::Claude::new_from_env(
+    ///             lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+    ///         )
+    ///         .prompt_boxed(chat, &options)
+    ///     }
+    /// }
+    /// ```
+    fn prompt_boxed(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<futures::stream::BoxStream<'static, Result<Chunk, TokenError>>, PromptError>
+    where
+        Self::TokenStream: 'static,
+    {
+        Ok(Box::pin(self.prompt(messages, options)?))
+    }
+}
+
+/// The name of the tool which [`LLMExt::prompt_structured`] offers the model to respond with.
+const STRUCTURED_TOOL_NAME: &str = "respond";
+
+/// A value parsed from a response by [`LLMExt::prompt_structured`], along with the text it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Structured<T> {
+    pub value: T,
+    /// The JSON the value was parsed from, exactly as the model produced it.
+    pub raw_json: String,
+    /// Any text the model produced before the value, such as an explanation of its answer.
+    pub preface: Option<String>,
+}
+
+/// Convenience methods available on every [`LLM`].
+pub trait LLMExt: LLM {
+    /// As with [`LLM::prompt`], but also returns a record of the prompt being sent, available
+    /// before any of the response.
+    ///
+    /// The provider's request id isn't known until the response headers arrive, which is before
+    /// the first chunk. From then on it is given by [`ResponseMetadata::request_id`], from the
+    /// `response_metadata` of token streams which have one, such as
+    /// [`llms::openai::OpenAITokenStream::response_metadata`].
+    fn prompt_with_start(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<(PromptStart, Self::TokenStream), PromptError>;
+
+    /// Sends a single user message with the default options, returning the text of the response.
+    /// Any thinking or tool calls in the response are discarded.
+    fn prompt_str(
+        &self,
+        text: impl Into<String>,
+    ) -> impl std::future::Future<Output = Result<String, Error>> + Send;
+
+    /// Takes one turn of a conversation, by adding the user's message to the history and prompting
+    /// the model with it. Returns the text of the response, along with the history grown by the
+    /// user's message and the response's messages, as given by [`Message::from_chunks`], ready
+    /// for the next turn. Any thinking in the response is discarded.
+    fn chat(
+        &self,
+        history: Vec<Message>,
+        user_input: impl Into<String>,
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<(String, Vec<Message>), Error>> + Send;
+
+    /// As with [`Self::chat`], but the history is loaded from the store by the conversation's id,
+    /// and the grown history is saved back to it once the response is complete. A conversation
+    /// which has never been saved starts empty.
+    fn chat_with_store<C: store::ConversationStore + Sync>(
+        &self,
+        store: &C,
+        id: &str,
+        user_input: impl Into<String>,
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<String, store::ChatError<C::Error>>> + Send
+    where
+        Self: Sync;
+
+    /// Prompts the model, and while the response stops with [`FinishReason::MaxTokens`] re-prompts
+    /// with the text so far as a partial assistant message, for at most `max_rounds` prompts in
+    /// total. Returns the concatenated text of every round.
+    fn continue_until_complete(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+        max_rounds: usize,
+    ) -> impl std::future::Future<Output = Result<String, Error>> + Send
+    where
+        Self: Sync;
+
+    /// Prompts the model for a value of type `S`, with the most reliable mechanism the model
+    /// supports, in order:
+    ///
+    /// 1. A tool named `respond` which takes the value as its parameters, which the model is made
+    ///    to call with [`ToolChoice::Tool`]. Claude doesn't allow forcing a tool while reasoning,
+    ///    so if [`PromptOptions::reasoning`] is set, or a [`PromptOptions::tool_choice`] is given,
+    ///    the tool is only offered. None of the given tools may be named `respond`. The tool's
+    ///    parameters are [strict](ToolParameters::strict), so OpenAI models use strict function
+    ///    calling, while other providers are given the schema as it is.
+    /// 2. For models which don't [support tools](LLM::supports_tools),
+    ///    [`ResponseFormat::JsonObject`] along with the value's schema in the system prompt.
+    ///
+    /// If the model answers in text instead of calling the tool, the first JSON value in the text
+    /// which deserializes as `S` is used. Text before the value is kept as the preface.
+    fn prompt_structured<S: schemars::JsonSchema + serde::de::DeserializeOwned>(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<Structured<S>, Error>> + Send;
+}
+impl<T: LLM> LLMExt for T {
+    fn prompt_with_start(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<(PromptStart, Self::TokenStream), PromptError> {
+        let start = PromptStart {
+            provider: self.provider(),
+            model: options.resolve_model_name(self),
+            correlation_id: options.correlation_id.clone(),
+            started_at: std::time::SystemTime::now(),
+        };
+        Ok((start, self.prompt(messages, options)?))
+    }
+
+    fn prompt_str(
+        &self,
+        text: impl Into<String>,
+    ) -> impl std::future::Future<Output = Result<String, Error>> + Send {
+        let stream = self.prompt(&[Message::User(text.into())], &PromptOptions::default());
+
+        async move {
+            let chunks = stream?.all_tokens().await?;
+
+            Ok(chunks
+                .into_iter()
+                .filter_map(|chunk| match chunk {
+                    Chunk::Token(text) => Some(text),
+                    _ => None,
+                })
+                .collect())
+        }
+    }
+
+    fn chat(
+        &self,
+        mut history: Vec<Message>,
+        user_input: impl Into<String>,
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<(String, Vec<Message>), Error>> + Send {
+        history.push(Message::User(user_input.into()));
+        let stream = self.prompt(&history, options);
+
+        async move {
+            let chunks = stream?.all_tokens().await?;
+
+            let text = chunks
+                .iter()
+                .filter_map(|chunk| match chunk {
+                    Chunk::Token(text) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect();
+            history.extend(Message::from_chunks(chunks, false));
+            Ok((text, history))
+        }
+    }
+
+    fn chat_with_store<C: store::ConversationStore + Sync>(
+        &self,
+        store: &C,
+        id: &str,
+        user_input: impl Into<String>,
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<String, store::ChatError<C::Error>>> + Send
+    where
+        Self: Sync,
+    {
+        let user_input = user_input.into();
+
+        async move {
+            let history = store
+                .load(id)
+                .await
+                .map_err(store::ChatError::Store)?
+                .unwrap_or_default();
+            let (text, history) = self.chat(history, user_input, options).await?;
+            store
+                .save(id, &history)
+                .await
+                .map_err(store::ChatError::Store)?;
+            Ok(text)
+        }
+    }
+
+    async fn continue_until_complete(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+        max_rounds: usize,
+    ) -> Result<String, Error>
+    where
+        Self: Sync,
+    {
+        let mut chat = messages.to_vec();
+        let mut text = String::new();
+
+        for round in 0..max_rounds {
+            let chunks = self.prompt(&chat, options)?.all_tokens().await?;
+
+            let mut truncated = false;
+            for chunk in chunks {
+                match chunk {
+                    Chunk::Token(token) => text.push_str(&token),
+                    Chunk::Finish(FinishReason::MaxTokens) => truncated = true,
+                    _ => {}
+                }
+            }
+            if !truncated {
+                break;
+            }
+
+            // The partial response replaces the previous round's, rather than being collated with it.
+            let partial = Message::Assistant(text.clone());
+            if round == 0 {
+                chat.push(partial);
+            } else if let Some(last) = chat.last_mut() {
+                *last = partial;
+            }
+        }
+
+        Ok(text)
+    }
+
+    fn prompt_structured<S: schemars::JsonSchema + serde::de::DeserializeOwned>(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> impl std::future::Future<Output = Result<Structured<S>, Error>> + Send {
+        let stream = prompt_for_structured::<S, _>(self, messages, options);
+
+        async move {
+            let chunks = stream?.all_tokens().await?;
+
+            let mut text = String::new();
+            let mut raw_json = None;
+            for chunk in chunks {
+                match chunk {
+                    Chunk::Token(token) => text.push_str(&token),
+                    Chunk::ToolCall(tool_call)
+                        if tool_call.name.as_deref() == Some(STRUCTURED_TOOL_NAME) =>
+                    {
+                        raw_json = Some(tool_call.arguments);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            let (raw_json, value) = match raw_json {
+                Some(raw_json) => match serde_json::from_str(&raw_json) {
+                    Ok(value) => (raw_json, value),
+                    Err(error) => return Err(Error::MalformedStructuredOutput { raw_json, error }),
+                },
+                None => {
+                    let (start, end, value) = find_json_value::<S>(&text)?;
+                    let raw_json = text[start..end].to_owned();
+                    text.truncate(start);
+                    // A value in a code block leaves the opening fence at the end of the preface.
+                    let trimmed = text.trim_end();
+                    if let Some(preface) = trimmed
+                        .strip_suffix("```json")
+                        .or_else(|| trimmed.strip_suffix("```"))
+                    {
+                        text.truncate(preface.len());
+                    }
+                    (raw_json, value)
+                }
+            };
+            let preface = text.trim();
+
+            Ok(Structured {
+                value,
+                preface: (!preface.is_empty()).then(|| preface.to_owned()),
+                raw_json,
+            })
+        }
+    }
+}
+
+/// Finds the first JSON value in the text which deserializes as `S`, trying each `{` and `[` in
+/// turn, as brackets in the prose before the value may not start one. Returns the value with the
+/// byte range it was parsed from.
+///
+/// If no value deserializes, the error is that of the first JSON value found, if any.
+fn find_json_value<S: serde::de::DeserializeOwned>(text: &str) -> Result<(usize, usize, S), Error> {
+    let mut first_error = None;
+    for (start, _) in text.match_indices(['{', '[']) {
+        let mut values = serde_json::Deserializer::from_str(&text[start..]).into_iter::<S>();
+        let error = match values.next() {
+            Some(Ok(value)) => return Ok((start, start + values.byte_offset(), value)),
+            Some(Err(error)) => error,
+            None => continue,
+        };
+        if first_error.is_some() {
+            continue;
+        }
+        // Only a well-formed value of the wrong shape is worth reporting, rather than prose.
+        let mut values =
+            serde_json::Deserializer::from_str(&text[start..]).into_iter::<serde::de::IgnoredAny>();
+        if let Some(Ok(_)) = values.next() {
+            let raw_json = text[start..start + values.byte_offset()].to_owned();
+            first_error = Some(Error::MalformedStructuredOutput { raw_json, error });
+        }
+    }
+    Err(first_error.unwrap_or(Error::MissingStructuredOutput))
+}
+
+/// Prompts the model for a value of type `S`, as described by [`LLMExt::prompt_structured`].
+fn prompt_for_structured<S: schemars::JsonSchema, L: LLM>(
+    llm: &L,
+    messages: &[Message],
+    options: &PromptOptions,
+) -> Result<L::TokenStream, Error> {
+    let mut options = options.clone();
+    let parameters = ToolParameters::new::<S>();
+    if llm.supports_tools() {
+        if options
+            .tools
+            .iter()
+            .any(|tool| tool.name == STRUCTURED_TOOL_NAME)
+        {
+            return Err(PromptError::ReservedToolName(STRUCTURED_TOOL_NAME.to_owned()).into());
+        }
+        if options.tool_choice.is_none() && options.reasoning.is_none() {
+            options.tool_choice = Some(ToolChoice::Tool(STRUCTURED_TOOL_NAME.to_owned()));
+        }
+        options.tools.push(Tool {
+            name: STRUCTURED_TOOL_NAME.to_owned(),
+            description: "Responds with the requested value.".to_owned(),
+            parameters: parameters.strict(),
+        });
+    } else {
+        let schema = serde_json::to_string(&parameters.inner).map_err(PromptError::from)?;
+        let instruction =
+            format!("Respond with only a JSON object matching this JSON schema:\n{schema}");
+        options.system_prompt = Some(match options.resolve_system_prompt() {
+            Some(system_prompt) => format!("{system_prompt}\n\n{instruction}"),
+            None => instruction,
+        });
+        options.response_format = Some(ResponseFormat::JsonObject);
+    }
+    Ok(llm.prompt(messages, &options)?)
 }
 
 mod sealed {
@@ -198,64 +1715,526 @@ mod sealed {
 pub trait TokenStreamExt: sealed::TokenStreamExtSealed {
     /// Converts the stream of tokens into a single set of tokens future, collapsing adjacent like tokens.
     /// This is useful for when you don't want to filter the tokens as they arrive.
+    ///
+    /// If the stream fails, the chunks received so far are logged as a warning and discarded. Use
+    /// [`Self::all_tokens_lossy`] to keep them.
     fn all_tokens(self)
         -> impl std::future::Future<Output = Result<Vec<Chunk>, TokenError>> + Send;
+
+    /// As with [`Self::all_tokens`], but if the stream fails then the chunks collected before the
+    /// failure are returned alongside the error, so that partial output can be salvaged.
+    fn all_tokens_lossy(
+        self,
+    ) -> impl std::future::Future<Output = Result<Vec<Chunk>, PartialResponse>> + Send;
+
+    /// Collapses the stream with [`Self::all_tokens`] and returns only the completed tool calls, as
+    /// `(name, arguments)` pairs. Tool calls without a name or with unparseable arguments are dropped.
+    fn tool_calls(
+        self,
+    ) -> impl std::future::Future<Output = Result<Vec<(String, serde_json::Value)>, TokenError>> + Send;
+
+    /// Sends every chunk of the stream to a broadcast channel of the given capacity, so that several
+    /// consumers can each receive the whole response. The stream is only read while the returned
+    /// future is driven, so further receivers should be made with
+    /// [`tokio::sync::broadcast::Receiver::resubscribe`] before driving it.
+    ///
+    /// An error is broadcast to every receiver and ends the stream. The stream is also cancelled
+    /// if every receiver is dropped.
+    ///
+    /// The channel holds at most `capacity` chunks which some receiver hasn't yet read, and the
+    /// stream is never held back for a slow receiver. Once a receiver falls further behind, the
+    /// oldest chunks are lost to it, and its next
+    /// [`recv`](tokio::sync::broadcast::Receiver::recv) returns
+    /// [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged) with the number lost
+    /// before carrying on from the oldest chunk still held. A receiver which needs the whole
+    /// response should treat that as a failure, or the capacity should be at least the number of
+    /// chunks expected.
+    fn into_broadcast(
+        self,
+        capacity: usize,
+    ) -> (
+        impl std::future::Future<Output = ()> + Send,
+        tokio::sync::broadcast::Receiver<Result<Chunk, std::sync::Arc<TokenError>>>,
+    );
+
+    /// Buffers the text of the stream and yields it a whole sentence at a time, as a single
+    /// [`Chunk::Token`] per sentence, which is useful for e.g. text-to-speech. Any text left over
+    /// when the stream ends is yielded as a final, possibly incomplete, sentence.
+    ///
+    /// Other chunks are passed through in order if `keep_other_chunks` is set, and dropped otherwise.
+    /// Errors are always passed through. Either flushes the text buffered before it.
+    fn by_sentence(
+        self,
+        keep_other_chunks: bool,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send;
+
+    /// Pairs each chunk with whether it is inside a fenced code block, so that code can be rendered
+    /// differently as it streams. Text is split where code blocks open and close, with each fence
+    /// line reported as part of its block. The start of each line is held back until it is known
+    /// whether it is a fence, as a fence may arrive over several chunks.
+    fn with_markdown_state(
+        self,
+    ) -> impl futures::Stream<Item = Result<(Chunk, MarkdownState), TokenError>> + Send;
+
+    /// Splits the stream into its [`Chunk::Thinking`] text and its [`Chunk::Token`] text, as
+    /// `(thinking, answer)`, for showing the reasoning apart from the answer. Other chunks are
+    /// dropped.
+    ///
+    /// Each stream can be read independently, with whatever arrives for the other buffered until it
+    /// is read. An error ends both streams, and is returned by the answer stream. The response is
+    /// cancelled once both streams are dropped.
+    fn split_thinking(
+        self,
+    ) -> (
+        impl futures::Stream<Item = Result<String, TokenError>> + Send,
+        impl futures::Stream<Item = Result<String, TokenError>> + Send,
+    );
+
+    /// Ends the stream with [`TokenError::Timeout`] once `duration` has passed since this was
+    /// called, cancelling the rest of the response. Unlike
+    /// [`PromptOptions::keepalive_timeout`], which bounds the wait between chunks, this bounds the
+    /// whole response, however steadily it arrives.
+    fn deadline(
+        self,
+        duration: std::time::Duration,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send;
+
+    /// Splits text into pieces of at most `max_chars_per_chunk` characters, returned at least
+    /// `min_delay` apart, so that text rendered as it arrives looks like steady typing even when
+    /// the provider sends it in bursts. Other chunks are passed through as they are, in order.
+    fn rechunk(
+        self,
+        max_chars_per_chunk: usize,
+        min_delay: std::time::Duration,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send;
+
+    /// Reads the text of the stream as UTF-8 bytes as it arrives, for libraries which consume an
+    /// [`AsyncRead`](tokio::io::AsyncRead). Other chunks are discarded, and an error ends the
+    /// text with an [`std::io::Error`] wrapping the [`TokenError`].
+    fn into_async_read(self) -> impl tokio::io::AsyncRead + Send;
+
+    /// Folds each chunk into an accumulator with `f` until it returns [`ControlFlow::Break`], then
+    /// drops the stream, cancelling the rest of the response. This allows stopping on conditions
+    /// which stopping sequences can't express, such as a JSON value being complete.
+    ///
+    /// Returns the value `f` broke with, or the accumulator as [`ControlFlow::Continue`] if the
+    /// stream ended first.
+    ///
+    /// [`ControlFlow::Break`]: std::ops::ControlFlow::Break
+    /// [`ControlFlow::Continue`]: std::ops::ControlFlow::Continue
+    fn try_fold_until<S, B, F>(
+        self,
+        init: S,
+        f: F,
+    ) -> impl std::future::Future<Output = Result<std::ops::ControlFlow<B, S>, TokenError>> + Send
+    where
+        S: Send,
+        B: Send,
+        F: FnMut(S, Chunk) -> std::ops::ControlFlow<B, S> + Send;
+
+    /// Sends each chunk to the sink as it arrives, such as a websocket encoder, flushing after
+    /// each so that the text isn't held back. The sink isn't closed, so pass `&mut sink` to keep
+    /// using it afterwards.
+    ///
+    /// Stops at the first error from either the stream or the sink.
+    fn forward_to<K>(
+        self,
+        sink: K,
+    ) -> impl std::future::Future<Output = Result<(), ForwardError<K::Error>>> + Send
+    where
+        K: futures::Sink<Chunk> + Send,
+        K::Error: Send;
+
+    /// Reads the text of the stream until `n` characters have arrived, then drops the stream,
+    /// cancelling the rest of the response. Returns at most the first `n` characters, or all of
+    /// the text if the stream ends first. Other chunks are discarded.
+    fn take_chars(
+        self,
+        n: usize,
+    ) -> impl std::future::Future<Output = Result<String, TokenError>> + Send;
+
+    /// As with [`Self::take_chars`], but stops after `n` text chunks. Providers usually send a
+    /// token per chunk, so this approximates a limit on tokens.
+    fn take_tokens(
+        self,
+        n: usize,
+    ) -> impl std::future::Future<Output = Result<String, TokenError>> + Send;
+
+    /// Waits for the next chunk of the stream, so that reading a stream as it arrives doesn't need
+    /// [`futures::StreamExt`]. Returns `None` once the stream has ended.
+    fn next_chunk(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<Chunk, TokenError>>> + Send
+    where
+        Self: Unpin;
+
+    /// As with [`Self::next_chunk`], but skips any chunks which aren't text, returning only the
+    /// text of each [`Chunk::Token`].
+    fn next_token(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<String, TokenError>>> + Send
+    where
+        Self: Unpin;
 }
 impl<T> TokenStreamExt for T
 where
     T: sealed::TokenStreamExtSealed + futures::Stream<Item = Result<Chunk, TokenError>> + Send,
 {
     async fn all_tokens(self) -> Result<Vec<Chunk>, TokenError> {
+        self.all_tokens_lossy().await.map_err(|partial_response| {
+            // Otherwise a late failure looks the same as one before anything arrived.
+            if !partial_response.partial.is_empty() {
+                tracing::warn!(
+                    "token stream failed after {} chunks, discarding them: {}",
+                    partial_response.partial.len(),
+                    partial_response.error
+                );
+            }
+            partial_response.error
+        })
+    }
+
+    async fn all_tokens_lossy(self) -> Result<Vec<Chunk>, PartialResponse> {
         use futures::StreamExt;
         let mut stream = Box::pin(self);
 
         let mut acc = vec![];
 
         while let Some(token) = stream.next().await {
-            tracing::debug!("received token in all_tokens: {:?}", token);
-            if let Some(last_acc) = acc.last_mut() {
-                match (last_acc, token?) {
-                    (Chunk::Token(lhs), Chunk::Token(rhs)) => lhs.push_str(&rhs),
-                    (Chunk::Thinking(lhs), Chunk::Thinking(rhs)) => lhs.push_str(&rhs),
-                    (Chunk::ToolCall(lhs), Chunk::ToolCall(rhs))
-                        if lhs.id.as_ref().is_none_or(|lhs_id| {
-                            rhs.id.as_ref().is_none_or(|rhs_id| lhs_id == rhs_id)
-                        }) =>
-                    {
-                        lhs.id = lhs.id.take().or(rhs.id);
-                        lhs.name = lhs.name.take().or(rhs.name);
-                        lhs.arguments.push_str(&rhs.arguments);
-                    }
-                    (_, token) => acc.push(token),
+            if logging::log_bodies() {
+                tracing::debug!(
+                    "received token in all_tokens: {}",
+                    logging::body(&format!("{token:?}"))
+                );
+            }
+            let token = match token {
+                Ok(token) => token,
+                Err(error) => {
+                    return Err(PartialResponse {
+                        partial: acc,
+                        error,
+                    })
                 }
-            } else {
-                acc.push(token?);
             };
+            collate(&mut acc, token);
         }
 
         Ok(acc)
     }
+
+    fn into_broadcast(
+        self,
+        capacity: usize,
+    ) -> (
+        impl std::future::Future<Output = ()> + Send,
+        tokio::sync::broadcast::Receiver<Result<Chunk, std::sync::Arc<TokenError>>>,
+    ) {
+        let (tx, rx) = tokio::sync::broadcast::channel(capacity);
+
+        let drive = async move {
+            use futures::StreamExt;
+            let mut stream = Box::pin(self);
+
+            while let Some(chunk) = stream.next().await {
+                let failed = chunk.is_err();
+                if tx.send(chunk.map_err(std::sync::Arc::new)).is_err() {
+                    tracing::debug!("every broadcast receiver was dropped, cancelling the stream");
+                    return;
+                }
+                if failed {
+                    return;
+                }
+            }
+        };
+
+        (drive, rx)
+    }
+
+    async fn tool_calls(self) -> Result<Vec<(String, serde_json::Value)>, TokenError> {
+        let chunks = self.all_tokens().await?;
+
+        Ok(chunks
+            .into_iter()
+            .filter_map(|chunk| {
+                let Chunk::ToolCall(tool_call) = chunk else {
+                    return None;
+                };
+                let name = tool_call.name?;
+                let Ok(arguments) = serde_json::from_str(&tool_call.arguments) else {
+                    tracing::warn!(
+                        "dropping tool call `{name}` with malformed arguments: `{}`",
+                        tool_call.arguments
+                    );
+                    return None;
+                };
+                Some((name, arguments))
+            })
+            .collect())
+    }
+
+    fn by_sentence(
+        self,
+        keep_other_chunks: bool,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send {
+        sentence::BySentence::new(self, keep_other_chunks)
+    }
+
+    fn with_markdown_state(
+        self,
+    ) -> impl futures::Stream<Item = Result<(Chunk, MarkdownState), TokenError>> + Send {
+        markdown::WithMarkdownState::new(self)
+    }
+
+    fn split_thinking(
+        self,
+    ) -> (
+        impl futures::Stream<Item = Result<String, TokenError>> + Send,
+        impl futures::Stream<Item = Result<String, TokenError>> + Send,
+    ) {
+        split::split(self)
+    }
+
+    fn deadline(
+        self,
+        duration: std::time::Duration,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send {
+        deadline::Deadline::new(self, duration)
+    }
+
+    fn rechunk(
+        self,
+        max_chars_per_chunk: usize,
+        min_delay: std::time::Duration,
+    ) -> impl futures::Stream<Item = Result<Chunk, TokenError>> + Send {
+        rechunk::Rechunk::new(self, max_chars_per_chunk, min_delay)
+    }
+
+    fn into_async_read(self) -> impl tokio::io::AsyncRead + Send {
+        read::TextReader::new(self)
+    }
+
+    async fn try_fold_until<S, B, F>(
+        self,
+        init: S,
+        mut f: F,
+    ) -> Result<std::ops::ControlFlow<B, S>, TokenError>
+    where
+        S: Send,
+        B: Send,
+        F: FnMut(S, Chunk) -> std::ops::ControlFlow<B, S> + Send,
+    {
+        use futures::StreamExt;
+        let mut stream = Box::pin(self);
+
+        let mut acc = init;
+        while let Some(chunk) = stream.next().await {
+            match f(acc, chunk?) {
+                std::ops::ControlFlow::Continue(next) => acc = next,
+                std::ops::ControlFlow::Break(result) => {
+                    return Ok(std::ops::ControlFlow::Break(result))
+                }
+            }
+        }
+        Ok(std::ops::ControlFlow::Continue(acc))
+    }
+
+    async fn forward_to<K>(self, sink: K) -> Result<(), ForwardError<K::Error>>
+    where
+        K: futures::Sink<Chunk> + Send,
+        K::Error: Send,
+    {
+        use futures::{SinkExt, StreamExt};
+        let mut stream = Box::pin(self);
+        let mut sink = Box::pin(sink);
+
+        while let Some(chunk) = stream.next().await {
+            sink.send(chunk.map_err(ForwardError::Stream)?)
+                .await
+                .map_err(ForwardError::Sink)?;
+        }
+
+        Ok(())
+    }
+
+    async fn take_chars(self, n: usize) -> Result<String, TokenError> {
+        use futures::StreamExt;
+        let mut stream = Box::pin(self);
+
+        let mut text = String::new();
+        let mut count = 0;
+        while count < n {
+            let Some(chunk) = stream.next().await else {
+                break;
+            };
+            let Chunk::Token(token) = chunk? else {
+                continue;
+            };
+            for c in token.chars().take(n - count) {
+                text.push(c);
+                count += 1;
+            }
+        }
+
+        Ok(text)
+    }
+
+    async fn take_tokens(self, n: usize) -> Result<String, TokenError> {
+        use futures::StreamExt;
+        let mut stream = Box::pin(self);
+
+        let mut text = String::new();
+        let mut count = 0;
+        while count < n {
+            let Some(chunk) = stream.next().await else {
+                break;
+            };
+            if let Chunk::Token(token) = chunk? {
+                text.push_str(&token);
+                count += 1;
+            }
+        }
+
+        Ok(text)
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Chunk, TokenError>>
+    where
+        Self: Unpin,
+    {
+        futures::StreamExt::next(self).await
+    }
+
+    async fn next_token(&mut self) -> Option<Result<String, TokenError>>
+    where
+        Self: Unpin,
+    {
+        loop {
+            match self.next_chunk().await? {
+                Ok(Chunk::Token(token)) => return Some(Ok(token)),
+                Ok(_) => continue,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ToolCallChunk {
+    /// The position of the tool call within the response, where given by the provider. Chunks of
+    /// parallel tool calls may be interleaved, and are matched up by this index.
+    pub index: Option<usize>,
     pub id: Option<String>,
     pub name: Option<String>,
     pub arguments: String,
 }
 
+impl ToolCallChunk {
+    /// A chunk of a tool call which is not tied to a position in the response.
+    pub fn new(id: Option<String>, name: Option<String>, arguments: impl Into<String>) -> Self {
+        Self {
+            index: None,
+            id,
+            name,
+            arguments: arguments.into(),
+        }
+    }
+
+    /// Sugar for [`Self::new`], for a tool call which is known in full.
+    pub fn complete(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        arguments: impl Into<String>,
+    ) -> Self {
+        Self::new(Some(id.into()), Some(name.into()), arguments)
+    }
+
+    /// Whether this chunk describes a whole tool call, i.e. it has an id and name, and its
+    /// arguments are valid JSON.
+    pub fn is_complete(&self) -> bool {
+        self.id.is_some()
+            && self.name.is_some()
+            && serde_json::from_str::<serde::de::IgnoredAny>(&self.arguments).is_ok()
+    }
+}
+
+/// Why a model stopped generating. More reasons may be added, so matches need a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FinishReason {
+    /// The model finished its response naturally.
+    EndTurn,
+    /// The model produced one of the stopping sequences, which is given if the provider says
+    /// which.
+    StopSequence(Option<String>),
+    /// The response reached the maximum number of tokens, and is probably incomplete.
+    MaxTokens,
+    /// The model stopped to wait for the result of a tool call.
+    ToolUse,
+    /// The response was cut short by the provider's content filter.
+    ContentFilter,
+    /// A reason not known to this library, as given by the provider.
+    Other(String),
+}
+
+impl FinishReason {
+    /// Interprets an Anthropic `stop_reason`, along with the `stop_sequence` it stopped at, if any.
+    fn from_anthropic(reason: &str, stop_sequence: Option<&str>) -> Self {
+        match reason {
+            "end_turn" => Self::EndTurn,
+            "stop_sequence" => Self::StopSequence(stop_sequence.map(str::to_owned)),
+            "max_tokens" => Self::MaxTokens,
+            "tool_use" => Self::ToolUse,
+            "refusal" => Self::ContentFilter,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+
+    /// Interprets an OpenAI-style `finish_reason`.
+    fn from_openai(reason: &str) -> Self {
+        match reason {
+            "stop" => Self::EndTurn,
+            "length" => Self::MaxTokens,
+            "tool_calls" | "function_call" => Self::ToolUse,
+            "content_filter" => Self::ContentFilter,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+/// The number of tokens used by a prompt, where reported by the provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub input_tokens: Option<usize>,
+    pub output_tokens: Option<usize>,
+}
+
+/// A piece of a response. More kinds of chunk may be added, so matches need a wildcard arm.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Chunk {
     Token(String),
     Thinking(String),
+    /// The signature of the thinking before it, which must be sent back with the thinking in a
+    /// [`Message::Thinking`].
+    ThinkingSignature(String),
     ToolCall(ToolCallChunk),
+    /// Part of the spoken response, in the format requested by [`PromptOptions::audio_output`].
+    Audio(Vec<u8>),
+    /// The final chunk of a response, if the provider gives a reason for stopping.
+    Finish(FinishReason),
+    /// The tokens used by the prompt and response, sent at the end of the response.
+    Usage(Usage),
 }
 
 impl Chunk {
     pub fn try_into_message(self) -> Option<Message> {
         match self {
             Chunk::Token(content) => Some(Message::Assistant(content)),
-            Chunk::Thinking(_) => None,
+            Chunk::Thinking(_)
+            | Chunk::ThinkingSignature(_)
+            | Chunk::Audio(_)
+            | Chunk::Finish(_)
+            | Chunk::Usage(_) => None,
             Chunk::ToolCall(tool_call_chunk) => Some(Message::ToolRequest {
                 id: tool_call_chunk.id?,
                 name: tool_call_chunk.name?,
@@ -268,10 +2247,81 @@ impl Chunk {
     }
 }
 
+/// Adds a chunk to those received so far, joining it to the chunk it continues, if any.
+fn collate(acc: &mut Vec<Chunk>, token: Chunk) {
+    if let Chunk::ToolCall(rhs) = token {
+        // Parallel tool calls may be interleaved, so indexed chunks are matched to their call
+        // wherever it is. Unindexed chunks can only continue the call immediately before them.
+        let lhs = match rhs.index {
+            Some(index) => acc.iter_mut().rev().find_map(|chunk| match chunk {
+                Chunk::ToolCall(lhs) if lhs.index == Some(index) => Some(lhs),
+                _ => None,
+            }),
+            None => match acc.last_mut() {
+                Some(Chunk::ToolCall(lhs)) if lhs.index.is_none() => Some(lhs),
+                _ => None,
+            },
+        };
+        match lhs {
+            Some(lhs) if rhs.id.is_none() || rhs.id == lhs.id => {
+                lhs.name = lhs.name.take().or(rhs.name);
+                lhs.arguments.push_str(&rhs.arguments);
+            }
+            _ => acc.push(Chunk::ToolCall(rhs)),
+        }
+        return;
+    }
+    // The transcript of spoken responses arrives between the audio, which is one recording.
+    if let Chunk::Audio(rhs) = token {
+        match acc.iter_mut().rev().find_map(|chunk| match chunk {
+            Chunk::Audio(lhs) => Some(lhs),
+            _ => None,
+        }) {
+            Some(lhs) => lhs.extend(rhs),
+            None => acc.push(Chunk::Audio(rhs)),
+        }
+        return;
+    }
+
+    match (acc.last_mut(), token) {
+        (Some(Chunk::Token(lhs)), Chunk::Token(rhs)) => lhs.push_str(&rhs),
+        (Some(Chunk::Thinking(lhs)), Chunk::Thinking(rhs)) => lhs.push_str(&rhs),
+        (Some(Chunk::ThinkingSignature(lhs)), Chunk::ThinkingSignature(rhs)) => lhs.push_str(&rhs),
+        (_, token) => acc.push(token),
+    }
+}
+
+/// The chunks received before a token stream failed, along with the failure.
+#[derive(Debug, thiserror::Error)]
+#[error("the token stream failed after {} chunks", partial.len())]
+pub struct PartialResponse {
+    pub partial: Vec<Chunk>,
+    #[source]
+    pub error: TokenError,
+}
+
+/// The error returned by [`TokenStreamExt::forward_to`].
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError<E> {
+    #[error("the token stream failed")]
+    Stream(#[source] TokenError),
+    #[error("the sink failed")]
+    Sink(#[source] E),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum TokenError {
     #[error("the connection was lost")]
     ConnectionLost(#[from] sse::Error),
+    #[error("the server reported an error: {0}")]
+    ServerError(String),
+    /// The server reported an error partway through the response which may pass if the prompt
+    /// is tried again, such as an internal error or a rate limit.
+    #[error("the server reported a transient error: {0}")]
+    TransientServerError(String),
+    /// The response took longer than the [`TokenStreamExt::deadline`].
+    #[error("the response did not finish within {0:?}")]
+    Timeout(std::time::Duration),
     #[error("the server responded with an unknown event type `{0}`")]
     UnknownEventType(String),
     #[error("the server responded with unexpected data: {message}")]
@@ -279,12 +2329,56 @@ pub enum TokenError {
         message: &'static str,
         value: serde_json::Value,
     },
+    /// A prompt which was held back, e.g. by [`rate_limit::RateLimited`], failed once sent.
+    #[error("failed to send the delayed prompt")]
+    DeferredPrompt(#[source] PromptError),
 }
 
+/// An error from an endpoint which answers with a single response rather than a stream, such as
+/// [`rerank::Reranker::rerank`] or [`images::ImageGeneration::generate_image`].
+#[derive(Debug, thiserror::Error)]
+pub enum EndpointError {
+    #[error("failed to build request to the API")]
+    RequestError(#[from] hyper::http::Error),
+    #[error("failed to communicate with the API")]
+    ConnectionError(#[from] sse::Error),
+    #[error("failed to transcode request or response")]
+    TranscodingError(#[from] serde_json::Error),
+    #[error("the server responded with unexpected data: {message}")]
+    MalformedResponse {
+        message: &'static str,
+        value: serde_json::Value,
+    },
+}
+
+pub use markdown::MarkdownState;
+pub use schemars;
 pub use schemars::JsonSchema;
 pub use serde;
 pub use serde_json;
 pub use sse::Error as SseError;
+pub use sse::ProviderErrorKind;
+
+/// Sorts the keys of every object, so that values which only differ in the order of their keys
+/// serialize the same.
+pub(crate) fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(fields) => {
+            let mut fields = fields.into_iter().collect::<Vec<_>>();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(sort_keys).collect())
+        }
+        value => value,
+    }
+}
 
 trait JsonExt {
     fn take_str(&mut self) -> Option<String>;
@@ -1,5 +1,19 @@
 //! The supported LLMs.
 
 pub mod anthropic;
+pub mod cohere;
+pub mod echo;
+pub mod huggingface;
 pub mod openai;
 pub mod openrouter;
+pub mod replicate;
+#[cfg(feature = "vertex")]
+pub mod vertex;
+
+/// The name a model is sent to its provider with.
+pub(crate) fn model_name(model: &impl serde::Serialize) -> String {
+    match serde_json::to_value(model) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
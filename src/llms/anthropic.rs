@@ -1,12 +1,36 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    sync::{Arc, OnceLock},
+};
 
 use hyper::{Method, Request, Version};
 
-use crate::{sse::SseClient, JsonExt};
+use crate::{
+    sse::{SseClient, SseTask},
+    transport::Transport,
+    JsonExt,
+};
+
+const COUNT_TOKENS_URL: &str = "https://api.anthropic.com/v1/messages/count_tokens";
+const INTERLEAVED_THINKING_BETA: &str = "interleaved-thinking-2025-05-14";
+/// The fewest tokens Claude can be given to think with.
+const MIN_THINKING_BUDGET: usize = 1024;
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum ClaudeModel {
+    #[serde(rename = "claude-opus-4-1-20250805")]
+    Claude_Opus_4_1_20250805,
+    #[serde(rename = "claude-opus-4-20250514")]
+    Claude_Opus_4_20250514,
+    #[serde(rename = "claude-opus-4-0")]
+    Claude_Opus_4_0,
+
+    #[serde(rename = "claude-sonnet-4-20250514")]
+    Claude_Sonnet_4_20250514,
+    #[serde(rename = "claude-sonnet-4-0")]
+    Claude_Sonnet_4_0,
+
     #[serde(rename = "claude-3-7-sonnet-20250219")]
     Claude_3_7_Sonnet_20250219,
     #[serde(rename = "claude-3-7-sonnet-latest")]
@@ -36,9 +60,51 @@ pub enum ClaudeModel {
     Claude_3_Haiku_20240307,
 }
 
+impl ClaudeModel {
+    /// Whether the model can be given tools to call, which every Claude 3 and 4 model can.
+    pub fn supports_tools(&self) -> bool {
+        true
+    }
+
+    /// Whether the model can think between its tool calls, as enabled by
+    /// [`Claude::with_interleaved_thinking`], which only Claude 4 models can.
+    pub fn supports_interleaved_thinking(&self) -> bool {
+        matches!(
+            self,
+            Self::Claude_Opus_4_1_20250805
+                | Self::Claude_Opus_4_20250514
+                | Self::Claude_Opus_4_0
+                | Self::Claude_Sonnet_4_20250514
+                | Self::Claude_Sonnet_4_0
+        )
+    }
+
+    /// The most tokens the model can output in one response.
+    pub fn max_output_tokens(&self) -> usize {
+        match self {
+            Self::Claude_Opus_4_1_20250805
+            | Self::Claude_Opus_4_20250514
+            | Self::Claude_Opus_4_0 => 32_000,
+            Self::Claude_Sonnet_4_20250514 | Self::Claude_Sonnet_4_0 => 64_000,
+            Self::Claude_3_7_Sonnet_20250219 | Self::Claude_3_7_Sonnet_latest => 64_000,
+            Self::Claude_3_5_Sonnet_20241022
+            | Self::Claude_3_5_Sonnet_20240620
+            | Self::Claude_3_5_Sonnet_Latest
+            | Self::Claude_3_5_Haiku_20241022
+            | Self::Claude_3_5_Haiku_Latest => 8192,
+            Self::Claude_3_Opus_20240229
+            | Self::Claude_3_Opus_Latest
+            | Self::Claude_3_Sonnet_20240229
+            | Self::Claude_3_Haiku_20240307 => 4096,
+        }
+    }
+}
+
 pub struct Claude {
     model: ClaudeModel,
     api_key: String,
+    interleaved_thinking: bool,
+    transport: Arc<dyn Transport>,
 }
 
 impl Claude {
@@ -52,258 +118,688 @@ impl Claude {
     }
 
     pub fn new(model: ClaudeModel, api_key: String) -> Self {
-        Self { model, api_key }
+        Self {
+            model,
+            api_key,
+            interleaved_thinking: false,
+            transport: crate::transport::default_transport(),
+        }
     }
-}
 
-impl crate::LLM for Claude {
-    type TokenStream = ClaudeTokenStream;
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
 
-    fn prompt(
+    /// Lets the model think between its tool calls within a response, rather than only before
+    /// them, with Anthropic's interleaved thinking beta. Only has an effect when reasoning, and
+    /// only for [models which support it](ClaudeModel::supports_interleaved_thinking).
+    ///
+    /// Each stretch of thinking arrives as its own [`crate::Chunk::Thinking`], separated by the
+    /// tool calls between them.
+    pub fn with_interleaved_thinking(mut self) -> Self {
+        self.interleaved_thinking = true;
+        self
+    }
+
+    /// Counts the input tokens that the prompt would use, including the system prompt and tools,
+    /// with Anthropic's token counting endpoint. Unlike [`crate::history::estimate_tokens`], the
+    /// count is exact.
+    pub async fn count_tokens(
         &self,
         chat: &[crate::Message],
         options: &crate::PromptOptions,
-    ) -> Result<ClaudeTokenStream, crate::PromptError> {
-        let crate::PromptOptions {
-            max_tokens,
-            temperature,
-            system_prompt,
-            stopping_sequences,
-            tools,
-            reasoning,
-        } = options;
-
-        fn is_one(v: &f32) -> bool {
-            *v == 1.0
+    ) -> Result<usize, crate::Error> {
+        let body = crate::LLM::build_request_body(self, chat, options)?;
+        let mut body =
+            serde_json::from_str::<serde_json::Value>(&body).map_err(crate::PromptError::from)?;
+        if let Some(fields) = body.as_object_mut() {
+            // The endpoint rejects the fields which only affect generation.
+            for field in ["stream", "max_tokens", "temperature", "stop_sequences"] {
+                fields.remove(field);
+            }
         }
 
-        #[derive(Debug, serde::Serialize)]
-        struct ClaudeThinking {
-            r#type: &'static str,
-            budget_tokens: usize,
-        }
+        let request = Request::builder()
+            .uri(COUNT_TOKENS_URL)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(body.to_string())
+            .map_err(crate::PromptError::from)?;
+        tracing::debug!(
+            "Claude token count request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
 
-        #[derive(Debug, serde::Serialize)]
-        struct ClaudeTool<'a> {
-            name: &'a str,
-            description: &'a str,
-            input_schema: &'a schemars::schema::Schema,
+        let response = crate::sse::fetch(&*self.transport, request)
+            .await
+            .map_err(crate::TokenError::from)?;
+        let response = serde_json::from_slice::<serde_json::Value>(&response)
+            .map_err(crate::PromptError::from)?;
+        match response
+            .get("input_tokens")
+            .and_then(serde_json::Value::as_u64)
+        {
+            Some(input_tokens) => Ok(input_tokens as usize),
+            None => Err(crate::TokenError::MalformedResponse {
+                message: "expected token count response to have input tokens",
+                value: response,
+            }
+            .into()),
         }
+    }
+}
 
-        #[derive(Debug, serde::Serialize)]
-        struct ClaudeMessageContent<'a> {
-            r#type: &'static str,
-
-            // For type: text
-            #[serde(skip_serializing_if = "str::is_empty")]
-            text: Cow<'a, str>,
-
-            // For type: tool_use
-            #[serde(skip_serializing_if = "Option::is_none")]
-            id: Option<&'a str>,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            name: Option<&'a str>,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            input: Option<&'a serde_json::Value>,
-
-            // For type: tool_result
-            #[serde(skip_serializing_if = "Option::is_none")]
-            tool_use_id: Option<&'a str>,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            content: Option<&'a str>,
-        }
+/// Where a Messages API request is sent, which decides how the model and API version are given.
+pub(crate) enum ClaudeEndpoint<'a> {
+    /// Anthropic's own API, which takes the model in the body and the version as a header.
+    Anthropic(ClaudeModel),
+    /// Google's Vertex AI, which takes the model, named as by Vertex AI, in the url and the
+    /// version in the body.
+    #[cfg_attr(not(feature = "vertex"), allow(dead_code))]
+    Vertex(&'a str),
+}
 
-        impl Default for ClaudeMessageContent<'_> {
-            fn default() -> Self {
-                Self {
-                    r#type: "",
-                    text: Cow::Borrowed(""),
-                    id: None,
-                    name: None,
-                    input: None,
-                    tool_use_id: None,
-                    content: None,
-                }
+/// The most tokens a model named as by Vertex AI, such as `claude-3-5-sonnet-v2@20241022`, can
+/// output in one response, if the model is known.
+#[cfg_attr(not(feature = "vertex"), allow(dead_code))]
+fn vertex_max_output_tokens(model: &str) -> Option<usize> {
+    let (name, _version) = model.split_once('@').unwrap_or((model, ""));
+    match name {
+        "claude-opus-4-1" | "claude-opus-4" => Some(32_000),
+        "claude-sonnet-4" | "claude-3-7-sonnet" => Some(64_000),
+        "claude-3-5-sonnet-v2" | "claude-3-5-sonnet" | "claude-3-5-haiku" => Some(8192),
+        "claude-3-opus" | "claude-3-sonnet" | "claude-3-haiku" => Some(4096),
+        _ => None,
+    }
+}
+
+/// Serializes a Messages API request body for the given conversation.
+pub(crate) fn request_body(
+    chat: &[crate::Message],
+    options: &crate::PromptOptions,
+    endpoint: ClaudeEndpoint<'_>,
+) -> Result<String, crate::PromptError> {
+    let crate::PromptOptions {
+        // Resolved against the model's limit.
+        max_tokens: _,
+        temperature,
+        system_prompt: _,
+        stopping_sequences: _,
+        tools: _,
+        reasoning,
+        // Resolved into the endpoint by the caller.
+        model_override: _,
+        exclude_reasoning: _,
+        keepalive_timeout: _,
+        idempotency_key: _,
+        stopping_sequence_overflow: _,
+        prediction: _,
+        extra_body,
+        parallel_tool_calls,
+        tool_choice,
+        reasoning_summary: _,
+        audio_output: _,
+        correlation_id: _,
+        store: _,
+        previous_response_id: _,
+        prompt_template,
+        response_format,
+        seed,
+    } = options;
+
+    if prompt_template.is_some() {
+        return Err(crate::PromptError::UnsupportedPromptTemplate {
+            provider: "Anthropic",
+        });
+    }
+    if response_format.is_some() {
+        tracing::warn!("Claude does not support response formats, ignoring it");
+    }
+    if seed.is_some() {
+        tracing::warn!("Claude does not support seeds, ignoring it");
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeThinking {
+        r#type: &'static str,
+        budget_tokens: usize,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeToolChoice<'a> {
+        r#type: &'static str,
+        // For type: tool
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        disable_parallel_tool_use: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeTool<'a> {
+        name: &'a str,
+        description: &'a str,
+        input_schema: &'a schemars::schema::Schema,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeMessageContent<'a> {
+        r#type: &'static str,
+
+        // For type: text
+        #[serde(skip_serializing_if = "str::is_empty")]
+        text: Cow<'a, str>,
+
+        // For type: thinking
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thinking: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signature: Option<&'a str>,
+
+        // For type: tool_use
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        input: Option<&'a serde_json::Value>,
+
+        // For type: tool_result
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_use_id: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<ClaudeToolResultContent<'a>>,
+
+        // For type: image
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<ClaudeImageSource<'a>>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(untagged)]
+    enum ClaudeToolResultContent<'a> {
+        Text(&'a str),
+        Parts(Vec<ClaudeMessageContent<'a>>),
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeImageSource<'a> {
+        r#type: &'static str,
+        media_type: &'a str,
+        data: &'a str,
+    }
+
+    impl Default for ClaudeMessageContent<'_> {
+        fn default() -> Self {
+            Self {
+                r#type: "",
+                text: Cow::Borrowed(""),
+                thinking: None,
+                signature: None,
+                id: None,
+                name: None,
+                input: None,
+                tool_use_id: None,
+                content: None,
+                source: None,
             }
         }
+    }
 
-        #[derive(Debug, serde::Serialize)]
-        struct ClaudeMessage<'a> {
-            role: &'a str,
-            content: Vec<ClaudeMessageContent<'a>>,
-        }
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeMessage<'a> {
+        role: &'a str,
+        content: Vec<ClaudeMessageContent<'a>>,
+    }
 
-        #[derive(Debug, serde::Serialize)]
-        struct ClaudeRequest<'a> {
-            model: ClaudeModel,
-            max_tokens: usize,
-            #[serde(skip_serializing_if = "is_one")]
-            temperature: f32,
-            #[serde(skip_serializing_if = "std::ops::Not::not")]
-            stream: bool,
-            #[serde(skip_serializing_if = "<[String]>::is_empty")]
-            stop_sequences: &'a [String],
-            #[serde(skip_serializing_if = "Option::is_none")]
-            system: Option<&'a str>,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            thinking: Option<ClaudeThinking>,
-            #[serde(skip_serializing_if = "Vec::is_empty")]
-            tools: Vec<ClaudeTool<'a>>,
-            messages: Vec<ClaudeMessage<'a>>,
+    #[derive(Debug, serde::Serialize)]
+    struct ClaudeRequest<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        model: Option<ClaudeModel>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        anthropic_version: Option<&'static str>,
+        max_tokens: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        stream: bool,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        stop_sequences: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thinking: Option<ClaudeThinking>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tools: Vec<ClaudeTool<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_choice: Option<ClaudeToolChoice<'a>>,
+        messages: Vec<ClaudeMessage<'a>>,
+    }
+
+    let mut messages: Vec<ClaudeMessage> = vec![];
+    fn maybe_append_text<'a>(
+        messages: &mut Vec<ClaudeMessage<'a>>,
+        content: &'a str,
+        role: &'a str,
+    ) -> Option<ClaudeMessage<'a>> {
+        if content.is_empty() {
+            return None;
         }
 
-        let mut messages: Vec<ClaudeMessage> = vec![];
-        fn maybe_append_text<'a>(
-            messages: &mut Vec<ClaudeMessage<'a>>,
-            content: &'a str,
-            role: &'a str,
-        ) -> Option<ClaudeMessage<'a>> {
-            if content.is_empty() {
+        let content_part = ClaudeMessageContent {
+            r#type: "text",
+            text: Cow::Borrowed(content),
+            ..ClaudeMessageContent::default()
+        };
+
+        // Try collate
+        if let Some(last) = messages.last_mut() {
+            if last.role == role {
+                if let Some(last_content) = last.content.last_mut() {
+                    if last_content.r#type == "text" {
+                        let text = last_content.text.to_mut();
+                        text.push_str("\n\n");
+                        text.push_str(content);
+                        return None;
+                    }
+                }
+
+                last.content.push(content_part);
+
                 return None;
             }
+        }
 
-            let content_part = ClaudeMessageContent {
-                r#type: "text",
-                text: Cow::Borrowed(content),
-                ..ClaudeMessageContent::default()
-            };
+        Some(ClaudeMessage {
+            role,
+            content: vec![content_part],
+        })
+    }
+
+    fn image_content(image: &crate::Image) -> ClaudeMessageContent<'_> {
+        ClaudeMessageContent {
+            r#type: "image",
+            source: Some(ClaudeImageSource {
+                r#type: "base64",
+                media_type: &image.media_type,
+                data: &image.data,
+            }),
+            ..ClaudeMessageContent::default()
+        }
+    }
 
-            // Try collate
-            if let Some(last) = messages.last_mut() {
-                if last.role == role {
-                    if let Some(last_content) = last.content.last_mut() {
-                        if last_content.r#type == "text" {
-                            last_content.text =
-                                Cow::Owned(format!("{}\n\n{}", last_content.text, content));
-                            return None;
+    for message in chat {
+        let new_message = match message {
+            crate::Message::User(content) => {
+                let Some(message) = maybe_append_text(&mut messages, content, "user") else {
+                    continue;
+                };
+                message
+            }
+            crate::Message::UserParts(parts) => {
+                for part in parts {
+                    match part {
+                        crate::ContentPart::Text(text) => {
+                            if let Some(message) = maybe_append_text(&mut messages, text, "user") {
+                                messages.push(message);
+                            }
                         }
+                        crate::ContentPart::Image(image) => match messages.last_mut() {
+                            // Try collate
+                            Some(last) if last.role == "user" => {
+                                last.content.push(image_content(image))
+                            }
+                            _ => messages.push(ClaudeMessage {
+                                role: "user",
+                                content: vec![image_content(image)],
+                            }),
+                        },
                     }
-
-                    last.content.push(content_part);
-
-                    return None;
                 }
+                continue;
             }
+            crate::Message::Assistant(content) => {
+                let Some(message) = maybe_append_text(&mut messages, content, "assistant") else {
+                    continue;
+                };
+                message
+            }
+            crate::Message::Thinking {
+                thinking,
+                signature,
+            } => {
+                let content = ClaudeMessageContent {
+                    r#type: "thinking",
+                    thinking: Some(thinking),
+                    signature: Some(signature),
+                    ..ClaudeMessageContent::default()
+                };
 
-            Some(ClaudeMessage {
-                role,
-                content: vec![content_part],
-            })
-        }
-
-        for message in chat {
-            let new_message = match message {
-                crate::Message::User(content) => {
-                    let Some(message) = maybe_append_text(&mut messages, content, "user") else {
+                // Try collate
+                if let Some(last) = messages.last_mut() {
+                    if last.role == "assistant" {
+                        last.content.push(content);
                         continue;
-                    };
-                    message
+                    }
                 }
-                crate::Message::Assistant(content) => {
-                    let Some(message) = maybe_append_text(&mut messages, content, "assistant")
-                    else {
-                        continue;
-                    };
-                    message
+
+                ClaudeMessage {
+                    role: "assistant",
+                    content: vec![content],
                 }
-                crate::Message::ToolRequest {
-                    id,
-                    name,
-                    arguments,
-                } => {
-                    let content = ClaudeMessageContent {
-                        r#type: "tool_use",
-                        id: Some(id),
-                        name: Some(name),
-                        input: Some(&arguments.raw),
-                        ..ClaudeMessageContent::default()
-                    };
+            }
+            crate::Message::ToolRequest {
+                id,
+                name,
+                arguments,
+            } => {
+                let content = ClaudeMessageContent {
+                    r#type: "tool_use",
+                    id: Some(id),
+                    name: Some(name),
+                    input: Some(&arguments.raw),
+                    ..ClaudeMessageContent::default()
+                };
 
-                    // Try collate
-                    if let Some(last) = messages.last_mut() {
-                        if last.role == "assistant" {
-                            last.content.push(content);
-                            continue;
-                        }
+                // Try collate
+                if let Some(last) = messages.last_mut() {
+                    if last.role == "assistant" {
+                        last.content.push(content);
+                        continue;
                     }
+                }
 
-                    ClaudeMessage {
-                        role: "assistant",
-                        content: vec![content],
-                    }
+                ClaudeMessage {
+                    role: "assistant",
+                    content: vec![content],
                 }
-                crate::Message::ToolResponse { content, id } => {
-                    let content = ClaudeMessageContent {
-                        r#type: "tool_result",
-                        tool_use_id: Some(id),
-                        content: Some(content),
+            }
+            crate::Message::ToolResponse {
+                content,
+                id,
+                images,
+            } => {
+                let content = if images.is_empty() {
+                    ClaudeToolResultContent::Text(content)
+                } else {
+                    let text = (!content.is_empty()).then(|| ClaudeMessageContent {
+                        r#type: "text",
+                        text: Cow::Borrowed(content),
                         ..ClaudeMessageContent::default()
-                    };
-                    // Try collate
-                    if let Some(last) = messages.last_mut() {
-                        if last.role == "user" {
-                            last.content.push(content);
-                            continue;
-                        }
-                    }
-                    ClaudeMessage {
-                        role: "user",
-                        content: vec![content],
+                    });
+                    let images = images.iter().map(image_content);
+                    ClaudeToolResultContent::Parts(text.into_iter().chain(images).collect())
+                };
+                let content = ClaudeMessageContent {
+                    r#type: "tool_result",
+                    tool_use_id: Some(id),
+                    content: Some(content),
+                    ..ClaudeMessageContent::default()
+                };
+                // Try collate
+                if let Some(last) = messages.last_mut() {
+                    if last.role == "user" {
+                        last.content.push(content);
+                        continue;
                     }
                 }
-            };
-            messages.push(new_message);
+                ClaudeMessage {
+                    role: "user",
+                    content: vec![content],
+                }
+            }
+        };
+        messages.push(new_message);
+    }
+
+    let tools: Vec<_> = options
+        .resolve_tools()?
+        .iter()
+        .map(|tool| ClaudeTool {
+            name: &tool.name,
+            description: &tool.description,
+            input_schema: &tool.parameters.inner,
+        })
+        .collect();
+
+    // The Messages API requires a limit, which isn't known for new models named by Vertex AI.
+    let limit = match &endpoint {
+        ClaudeEndpoint::Anthropic(model) => Some(model.max_output_tokens()),
+        ClaudeEndpoint::Vertex(model) => vertex_max_output_tokens(model),
+    };
+    let max_tokens = options
+        .resolve_max_tokens(limit)
+        .unwrap_or(crate::DEFAULT_MAX_TOKENS);
+    let (model, anthropic_version) = match endpoint {
+        ClaudeEndpoint::Anthropic(model) => (Some(model), None),
+        ClaudeEndpoint::Vertex(_) => (None, Some("vertex-2023-10-16")),
+    };
+
+    // Claude only thinks when asked to, so turning reasoning off is the same as not asking.
+    let thinking_budget = reasoning.and_then(|effort| effort.max_tokens());
+    if let Some(budget) = thinking_budget {
+        if budget < MIN_THINKING_BUDGET || budget >= max_tokens {
+            return Err(crate::PromptError::InvalidReasoningBudget {
+                provider: "Anthropic",
+                budget,
+                min: MIN_THINKING_BUDGET,
+                max_tokens,
+            });
         }
+    }
 
-        let tools = tools
-            .iter()
-            .map(|tool| ClaudeTool {
-                name: &tool.name,
-                description: &tool.description,
-                input_schema: &tool.parameters.inner,
+    let tool_choice =
+        if tools.is_empty() || (tool_choice.is_none() && parallel_tool_calls.is_none()) {
+            None
+        } else {
+            let (r#type, name) = match tool_choice {
+                None | Some(crate::ToolChoice::Auto) => ("auto", None),
+                Some(crate::ToolChoice::Any) => ("any", None),
+                Some(crate::ToolChoice::Tool(name)) => ("tool", Some(name.as_str())),
+                Some(crate::ToolChoice::None) => ("none", None),
+            };
+            Some(ClaudeToolChoice {
+                r#type,
+                name,
+                // Claude rejects the flag when no tool may be called.
+                disable_parallel_tool_use: parallel_tool_calls
+                    .filter(|_| r#type != "none")
+                    .map(|parallel_tool_calls| !parallel_tool_calls),
             })
-            .collect();
-
-        let body = ClaudeRequest {
-            model: self.model,
-            max_tokens: *max_tokens,
-            temperature: if reasoning.is_none() {
-                *temperature
-            } else {
-                1.0
-            },
-            stop_sequences: stopping_sequences.as_slice(),
-            system: system_prompt.as_deref(),
-            stream: true,
-            thinking: reasoning.map(|level| ClaudeThinking {
-                r#type: "enabled",
-                budget_tokens: level.max_tokens(),
-            }),
-            tools,
-            messages,
         };
-        let body = serde_json::to_string(&body)?;
-        tracing::debug!("Claude request body: {}", body);
 
-        let request = Request::builder()
+    let body = ClaudeRequest {
+        model,
+        anthropic_version,
+        max_tokens,
+        // Thinking requires the default temperature.
+        temperature: temperature.filter(|_| thinking_budget.is_none()),
+        // Anthropic documents no limit on the number of stopping sequences.
+        stop_sequences: options.resolve_stopping_sequences("Anthropic", None)?,
+        system: options.resolve_system_prompt(),
+        stream: true,
+        thinking: thinking_budget.map(|budget_tokens| ClaudeThinking {
+            r#type: "enabled",
+            budget_tokens,
+        }),
+        tool_choice,
+        tools,
+        messages,
+    };
+    crate::serialize_request_body(&body, extra_body)
+}
+
+impl crate::LLM for Claude {
+    type TokenStream = ClaudeTokenStream;
+
+    fn provider(&self) -> &'static str {
+        "Anthropic"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        crate::llms::model_name(&self.model).into()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        let model = match &options.model_override {
+            None => self.model,
+            Some(crate::ModelOverride::Claude(model)) => *model,
+            Some(model_override) => {
+                return Err(crate::PromptError::IncompatibleModelOverride {
+                    provider: "Anthropic",
+                    model_override: model_override.clone(),
+                })
+            }
+        };
+        request_body(chat, options, ClaudeEndpoint::Anthropic(model))
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<ClaudeTokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("Claude request body: {}", crate::logging::body(&body));
+
+        let model = match &options.model_override {
+            Some(crate::ModelOverride::Claude(model)) => *model,
+            _ => self.model,
+        };
+        let interleaved_thinking = self.interleaved_thinking && {
+            if !model.supports_interleaved_thinking() {
+                tracing::warn!(
+                    "Claude model {} does not support interleaved thinking, ignoring it",
+                    crate::llms::model_name(&model)
+                );
+            }
+            model.supports_interleaved_thinking()
+        };
+
+        let mut request = Request::builder()
             .uri("https://api.anthropic.com/v1/messages")
             .header("x-api-key", &self.api_key)
             .header("anthropic-version", "2023-06-01")
             .header("content-type", "application/json")
             .version(Version::HTTP_2)
-            .method(Method::POST)
-            .body(body)?;
-        tracing::debug!("Claude request: {:#?}", request);
-        let sse = SseClient::spawn(request);
+            .method(Method::POST);
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        if interleaved_thinking {
+            request = request.header("anthropic-beta", INTERLEAVED_THINKING_BETA);
+        }
+        let request = request.body(body)?;
+        tracing::debug!(
+            "Claude request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let sse = SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout);
 
-        Ok(ClaudeTokenStream {
-            stream: Some(Box::pin(sse)),
-        })
+        Ok(ClaudeTokenStream::new(sse, options.exclude_reasoning, span))
     }
 }
 
 pub struct ClaudeTokenStream {
     stream: Option<std::pin::Pin<Box<SseClient>>>,
+    task: Option<SseTask>,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    /// Claude always streams its thinking, so excluded thinking is dropped here instead.
+    exclude_reasoning: bool,
+    /// Reported at the start of the message, but only returned with the output tokens at the end.
+    input_tokens: Option<usize>,
+    /// A chunk to return before reading any more events.
+    pending: Option<crate::Chunk>,
+    /// The indices of the content blocks which have started but not yet stopped, in the order
+    /// they started.
+    open_blocks: Vec<usize>,
+    span: crate::span::PromptSpan,
+}
+
+impl ClaudeTokenStream {
+    pub(crate) fn new(
+        mut stream: SseClient,
+        exclude_reasoning: bool,
+        span: crate::span::PromptSpan,
+    ) -> Self {
+        Self {
+            exclude_reasoning,
+            input_tokens: None,
+            pending: None,
+            open_blocks: vec![],
+            metadata: stream.metadata(),
+            task: stream.take_task(),
+            stream: Some(Box::pin(stream)),
+            span,
+        }
+    }
+
+    /// The status and headers of the response, or `None` if the server has not yet responded successfully.
+    pub fn response_metadata(&self) -> Option<&crate::ResponseMetadata> {
+        self.metadata.get()
+    }
+
+    /// A copy of each event read by the stream from now on, as sent by the server, for inspecting
+    /// fields which aren't otherwise supported. Replaces any previous receiver.
+    pub fn raw_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<crate::RawEvent> {
+        crate::sse::SseClient::raw_events(self.stream.as_deref_mut())
+    }
+
+    /// Stops the response and waits for the task which read it to finish, returning any error
+    /// the task hit that the stream has not already returned. The connection is closed in the
+    /// background, and may still be closing when this returns.
+    pub async fn shutdown(mut self) -> Result<(), crate::TokenError> {
+        crate::sse::SseTask::shutdown_stream(self.task.take(), self.stream.take()).await
+    }
+
+    /// Tracks a content block event, returning the index of the block it belongs to. Events
+    /// without an index belong to the most recently started block that is still open.
+    fn block_index(&mut self, event: &str, value: &serde_json::Value) -> Option<usize> {
+        let index = value
+            .get("index")
+            .and_then(serde_json::Value::as_u64)
+            .map(|index| index as usize);
+        let open = index.map(|index| self.open_blocks.contains(&index));
+
+        match (event, index) {
+            ("content_block_start", Some(index)) => {
+                if open == Some(true) {
+                    tracing::warn!("content block {index} started again before it stopped");
+                } else {
+                    self.open_blocks.push(index);
+                }
+            }
+            ("content_block_stop", Some(index)) => {
+                self.open_blocks.retain(|open| *open != index);
+            }
+            (_, Some(index)) => {
+                if open == Some(false) {
+                    tracing::warn!("content block {index} received {event} while not started");
+                }
+            }
+            (_, None) => {
+                tracing::warn!("{event} is missing its content block index");
+                let index = self.open_blocks.last().copied();
+                if event == "content_block_stop" {
+                    self.open_blocks.pop();
+                }
+                return index;
+            }
+        }
+
+        index
+    }
 }
 
 impl futures::Stream for ClaudeTokenStream {
@@ -313,12 +809,28 @@ impl futures::Stream for ClaudeTokenStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        let _entered = self.span.span().clone().entered();
+        let poll = self.as_mut().poll_chunk(cx);
+        self.span.record(&poll);
+        poll
+    }
+}
+
+impl ClaudeTokenStream {
+    fn poll_chunk(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<crate::Chunk, crate::TokenError>>> {
         loop {
+            if let Some(chunk) = self.pending.take() {
+                return std::task::Poll::Ready(Some(Ok(chunk)));
+            }
+
             let Some(stream) = self.stream.as_mut() else {
                 return std::task::Poll::Ready(None);
             };
 
-            let message = stream.as_mut().poll_next(cx);
+            let message = futures::Stream::poll_next(stream.as_mut(), cx);
 
             let message = match message {
                 std::task::Poll::Ready(None) => {
@@ -341,8 +853,15 @@ impl futures::Stream for ClaudeTokenStream {
 
             match message.event.as_str() {
                 "ping" => {}
-                "message_start" => { /* pass */ }
+                "message_start" => {
+                    self.input_tokens = message
+                        .value
+                        .pointer("/message/usage/input_tokens")
+                        .and_then(serde_json::Value::as_u64)
+                        .map(|tokens| tokens as usize);
+                }
                 "content_block_start" => {
+                    let index = self.block_index(&message.event, &message.value);
                     let Some(content) = message.value.as_object_mut() else {
                         tracing::error!("content block start should be an object - {message:?}");
                         continue;
@@ -358,13 +877,22 @@ impl futures::Stream for ClaudeTokenStream {
                         continue;
                     };
 
-                    let Some(token) = process_content_block(content) else {
+                    let Some(token) = process_content_block(content, index) else {
                         continue;
                     };
+                    if self.exclude_reasoning
+                        && matches!(
+                            token,
+                            crate::Chunk::Thinking(_) | crate::Chunk::ThinkingSignature(_)
+                        )
+                    {
+                        continue;
+                    }
 
                     return std::task::Poll::Ready(Some(Ok(token)));
                 }
                 "content_block_delta" => {
+                    let index = self.block_index(&message.event, &message.value);
                     let Some(content) = message.value.as_object_mut() else {
                         tracing::error!("content block delta should be an object - {message:?}");
                         continue;
@@ -378,13 +906,51 @@ impl futures::Stream for ClaudeTokenStream {
                         continue;
                     };
 
-                    let Some(token) = process_content_block(content) else {
+                    let Some(token) = process_content_block(content, index) else {
                         continue;
                     };
+                    if self.exclude_reasoning
+                        && matches!(
+                            token,
+                            crate::Chunk::Thinking(_) | crate::Chunk::ThinkingSignature(_)
+                        )
+                    {
+                        continue;
+                    }
 
                     return std::task::Poll::Ready(Some(Ok(token)));
                 }
-                "content_block_stop" | "message_delta" => { /* pass */ }
+                "content_block_stop" => {
+                    self.block_index(&message.event, &message.value);
+                }
+                "message_delta" => {
+                    let usage = crate::Usage {
+                        input_tokens: self.input_tokens,
+                        output_tokens: message
+                            .value
+                            .pointer("/usage/output_tokens")
+                            .and_then(serde_json::Value::as_u64)
+                            .map(|tokens| tokens as usize),
+                    };
+
+                    let Some(reason) = message
+                        .value
+                        .pointer("/delta/stop_reason")
+                        .and_then(serde_json::Value::as_str)
+                    else {
+                        return std::task::Poll::Ready(Some(Ok(crate::Chunk::Usage(usage))));
+                    };
+
+                    let stop_sequence = message
+                        .value
+                        .pointer("/delta/stop_sequence")
+                        .and_then(serde_json::Value::as_str);
+
+                    self.pending = Some(crate::Chunk::Usage(usage));
+                    return std::task::Poll::Ready(Some(Ok(crate::Chunk::Finish(
+                        crate::FinishReason::from_anthropic(reason, stop_sequence),
+                    ))));
+                }
                 "message_stop" => {
                     self.stream = None;
                     return std::task::Poll::Ready(None);
@@ -400,8 +966,9 @@ impl futures::Stream for ClaudeTokenStream {
 
 fn process_content_block(
     content: &mut serde_json::Map<String, serde_json::Value>,
+    index: Option<usize>,
 ) -> Option<crate::Chunk> {
-    let Some(&serde_json::Value::String(ref ty)) = content.get("type") else {
+    let Some(serde_json::Value::String(ty)) = content.get("type") else {
         tracing::error!("expected content block to have type - {content:?}");
         return None;
     };
@@ -436,21 +1003,24 @@ fn process_content_block(
             let id = content.get_mut("id").and_then(|id| id.take_str());
             let name = content.get_mut("name").and_then(|id| id.take_str());
 
-            // Check we weren't given an input block
-            if let Some(serde_json::Value::Object(input)) = content.get("input") {
-                if !input.is_empty() {
-                    tracing::error!("expected content tool_use input to be empty - {content:?}");
+            // When streamed the input is empty and arrives in `input_json_delta`s, but it may also
+            // be given in full here.
+            let arguments = match content.get("input") {
+                Some(serde_json::Value::Object(input)) if input.is_empty() => String::new(),
+                Some(input @ serde_json::Value::Object(_)) => input.to_string(),
+                _ => {
+                    tracing::error!(
+                        "expected content tool_use block to have object input - {content:?}"
+                    );
+                    String::new()
                 }
-            } else {
-                tracing::error!(
-                    "expected content tool_use block to have empty input - {content:?}"
-                );
             };
 
             Some(crate::Chunk::ToolCall(crate::ToolCallChunk {
+                index,
                 id,
                 name,
-                arguments: String::new(),
+                arguments,
             }))
         }
         "input_json_delta" => {
@@ -470,12 +1040,24 @@ fn process_content_block(
             };
 
             Some(crate::Chunk::ToolCall(crate::ToolCallChunk {
+                index,
                 id: None,
                 name: None,
                 arguments: json,
             }))
         }
-        "signature_delta" | "redacted_thinking" => None,
+        "signature_delta" => {
+            let Some(signature) = content
+                .get_mut("signature")
+                .and_then(|text| text.take_str())
+            else {
+                tracing::error!("expected signature delta to have signature - {content:?}");
+                return None;
+            };
+
+            Some(crate::Chunk::ThinkingSignature(signature))
+        }
+        "redacted_thinking" => None,
         _ => {
             tracing::error!("unknown content block type: {ty} - {content:?}");
             None
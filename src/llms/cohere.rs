@@ -0,0 +1,102 @@
+//! Support for Cohere's [rerank](https://docs.cohere.com/reference/rerank) endpoint.
+
+use std::{fmt::Display, sync::Arc};
+
+use hyper::{Method, Request, Version};
+
+use crate::rerank::Reranker;
+use crate::transport::Transport;
+use crate::EndpointError;
+
+const RERANK_URL: &str = "https://api.cohere.com/v2/rerank";
+const DEFAULT_RERANK_MODEL: &str = "rerank-v3.5";
+
+/// A client for Cohere, which currently only supports reranking.
+pub struct Cohere {
+    rerank_model: String,
+    bearer_header: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl Cohere {
+    /// Sugar for [`Self::new`], but uses the `CO_API_KEY` environment variable for the API key.
+    pub fn new_from_env() -> Self {
+        Self::new(std::env::var("CO_API_KEY").expect("CO_API_KEY environment variable not set"))
+    }
+
+    pub fn new(api_key: impl Display) -> Self {
+        Self {
+            rerank_model: DEFAULT_RERANK_MODEL.to_owned(),
+            bearer_header: format!("Bearer {api_key}"),
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// The model to rerank with, rather than `rerank-v3.5`.
+    pub fn with_rerank_model(mut self, model: impl Into<String>) -> Self {
+        self.rerank_model = model.into();
+        self
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+}
+
+impl Reranker for Cohere {
+    async fn rerank(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<usize>,
+    ) -> Result<Vec<(usize, f32)>, EndpointError> {
+        #[derive(Debug, serde::Serialize)]
+        struct CohereRerankRequest<'a> {
+            model: &'a str,
+            query: &'a str,
+            documents: &'a [String],
+            #[serde(skip_serializing_if = "Option::is_none")]
+            top_n: Option<usize>,
+        }
+
+        #[derive(Debug, serde::Deserialize)]
+        struct CohereRerankResult {
+            index: usize,
+            relevance_score: f32,
+        }
+
+        let body = CohereRerankRequest {
+            model: &self.rerank_model,
+            query,
+            documents,
+            top_n,
+        };
+        let request = Request::builder()
+            .uri(RERANK_URL)
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(serde_json::to_string(&body)?)?;
+        tracing::debug!(
+            "Cohere rerank request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let response = crate::sse::fetch(&*self.transport, request).await?;
+        let mut response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        let Some(results) = response.get_mut("results").map(serde_json::Value::take) else {
+            return Err(EndpointError::MalformedResponse {
+                message: "expected rerank response to have results",
+                value: response,
+            });
+        };
+        Ok(serde_json::from_value::<Vec<CohereRerankResult>>(results)?
+            .into_iter()
+            .map(|result| (result.index, result.relevance_score))
+            .collect())
+    }
+}
@@ -0,0 +1,206 @@
+//! A stand-in model which streams back the last user message, for wiring up interfaces and load
+//! testing the streaming path without a provider.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::history::CHARS_PER_TOKEN;
+
+type Transform = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// A model which responds with the last user message, optionally transformed, streamed a few
+/// characters at a time. Nothing is sent anywhere.
+///
+/// Stopping sequences end the response where they first appear, and
+/// [`crate::PromptOptions::max_tokens`] is approximated as four characters a token.
+///
+/// ```no_run
+/// use lmql::llms::echo::Echo;
+///
+/// // Reads back each message reversed, as a fast typist would.
+/// let llm = Echo::new()
+///     .reversed()
+///     .with_chunk_size(3)
+///     .with_delay(std::time::Duration::from_millis(20));
+/// ```
+#[derive(Clone)]
+pub struct Echo {
+    chunk_size: usize,
+    delay: Duration,
+    transform: Option<Transform>,
+}
+
+impl Default for Echo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Echo {
+    /// Echoes each message unchanged, a token's worth of characters per chunk, without delay.
+    pub fn new() -> Self {
+        Self {
+            chunk_size: CHARS_PER_TOKEN,
+            delay: Duration::ZERO,
+            transform: None,
+        }
+    }
+
+    /// The number of characters to send in each chunk.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// How long to wait before sending each chunk.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Responds with the message as changed by the function, rather than the message itself.
+    pub fn with_transform(
+        mut self,
+        transform: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Sugar for [`Self::with_transform`], responding with the message reversed.
+    pub fn reversed(self) -> Self {
+        self.with_transform(|text| text.chars().rev().collect())
+    }
+
+    /// The full text of the response to the chat, before any limits are applied.
+    fn response(&self, chat: &[crate::Message]) -> String {
+        let message = chat
+            .iter()
+            .rev()
+            .find_map(|message| match message {
+                crate::Message::User(content) => Some(Cow::Borrowed(content.as_str())),
+                crate::Message::UserParts(parts) => Some(Cow::Owned(crate::joined_text(parts))),
+                _ => None,
+            })
+            .unwrap_or_default();
+        match &self.transform {
+            Some(transform) => transform(&message),
+            None => message.into_owned(),
+        }
+    }
+}
+
+impl crate::LLM for Echo {
+    type TokenStream = EchoTokenStream;
+
+    fn provider(&self) -> &'static str {
+        "Echo"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "echo".into()
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        #[derive(Debug, serde::Serialize)]
+        struct EchoRequest {
+            model: &'static str,
+            response: String,
+        }
+
+        let body = EchoRequest {
+            model: "echo",
+            response: self.response(chat),
+        };
+        crate::serialize_request_body(&body, &options.extra_body)
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<EchoTokenStream, crate::PromptError> {
+        self.build_request_body(chat, options)?;
+        let mut text = self.response(chat);
+
+        let mut finish_reason = crate::FinishReason::EndTurn;
+        if let Some((end, sequence)) = options
+            .stopping_sequences
+            .iter()
+            .filter(|sequence| !sequence.is_empty())
+            .filter_map(|sequence| Some((text.find(sequence.as_str())?, sequence)))
+            .min_by_key(|(end, _)| *end)
+        {
+            text.truncate(end);
+            finish_reason = crate::FinishReason::StopSequence(Some(sequence.clone()));
+        }
+        let max_chars = options.max_tokens.saturating_mul(CHARS_PER_TOKEN);
+        if let Some((end, _)) = text.char_indices().nth(max_chars) {
+            text.truncate(end);
+            finish_reason = crate::FinishReason::MaxTokens;
+        }
+
+        let mut chunks = VecDeque::new();
+        let chars = text.chars().collect::<Vec<_>>();
+        for chunk in chars.chunks(self.chunk_size) {
+            chunks.push_back(crate::Chunk::Token(chunk.iter().collect()));
+        }
+        chunks.push_back(crate::Chunk::Finish(finish_reason));
+        chunks.push_back(crate::Chunk::Usage(crate::Usage {
+            input_tokens: Some(crate::history::estimate_tokens(chat)),
+            output_tokens: Some(chars.len().div_ceil(CHARS_PER_TOKEN)),
+        }));
+
+        Ok(EchoTokenStream {
+            chunks,
+            delay: self.delay,
+            sleep: None,
+        })
+    }
+}
+
+/// The response of an [`Echo`] model.
+pub struct EchoTokenStream {
+    chunks: VecDeque<crate::Chunk>,
+    delay: Duration,
+    /// The wait before the next chunk, once it has started.
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl futures::Stream for EchoTokenStream {
+    type Item = Result<crate::Chunk, crate::TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        use std::future::Future;
+
+        let this = &mut *self;
+        // Only the text is delayed, as if it were being generated.
+        if matches!(this.chunks.front(), Some(crate::Chunk::Token(_))) && !this.delay.is_zero() {
+            let delay = this.delay;
+            let sleep = this
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.sleep = None;
+        }
+
+        Poll::Ready(this.chunks.pop_front().map(Ok))
+    }
+}
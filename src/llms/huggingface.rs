@@ -0,0 +1,149 @@
+//! Models hosted by Hugging Face, through the OpenAI-compatible chat completions route of
+//! [text-generation-inference](https://huggingface.co/docs/text-generation-inference) (TGI).
+
+use std::{fmt::Display, sync::Arc};
+
+use hyper::{Method, Request, Version};
+
+use crate::{sse::SseClient, transport::Transport};
+
+/// The serverless Inference API, which routes to whichever provider serves the model.
+const SERVERLESS_BASE_URL: &str = "https://router.huggingface.co/v1";
+
+/// TGI rejects more stopping sequences than this, unless the server is started with a higher
+/// `--max-stop-sequences`.
+const MAX_STOPPING_SEQUENCES: usize = 4;
+
+/// A model served by Hugging Face, either through the serverless Inference API or a dedicated
+/// Inference Endpoint.
+///
+/// A dedicated endpoint is used by setting its url, with `/v1` appended, as the base url. TGI
+/// serves a single model, which it answers to as `tgi`:
+///
+/// ```no_run
+/// use lmql::llms::huggingface::HuggingFace;
+///
+/// let llm = HuggingFace::new_from_env("tgi")
+///     .with_base_url("https://abc123.us-east-1.aws.endpoints.huggingface.cloud/v1");
+/// ```
+pub struct HuggingFace {
+    base_url: String,
+    model: String,
+    bearer_header: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl HuggingFace {
+    /// Sugar for [`Self::new`], but uses the `HF_TOKEN` environment variable for the access token.
+    pub fn new_from_env(model: impl Into<String>) -> Self {
+        Self::new(
+            model,
+            std::env::var("HF_TOKEN").expect("HF_TOKEN environment variable not set"),
+        )
+    }
+
+    /// A model on the serverless Inference API, e.g. `meta-llama/Llama-3.1-8B-Instruct`.
+    pub fn new(model: impl Into<String>, token: impl Display) -> Self {
+        Self {
+            base_url: SERVERLESS_BASE_URL.to_owned(),
+            model: model.into(),
+            bearer_header: format!("Bearer {token}"),
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// Sends requests to the given url, which `/chat/completions` is relative to, rather than to
+    /// the serverless Inference API. This is how a dedicated Inference Endpoint is prompted.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// The model to prompt, taking into account any override.
+    fn model<'a>(
+        &'a self,
+        options: &'a crate::PromptOptions,
+    ) -> Result<&'a str, crate::PromptError> {
+        match &options.model_override {
+            None => Ok(&self.model),
+            Some(crate::ModelOverride::Named(model)) => Ok(model),
+            Some(model_override) => Err(crate::PromptError::IncompatibleModelOverride {
+                provider: "HuggingFace",
+                model_override: model_override.clone(),
+            }),
+        }
+    }
+}
+
+impl crate::LLM for HuggingFace {
+    type TokenStream = super::openai::OpenAITokenStream;
+
+    fn provider(&self) -> &'static str {
+        "HuggingFace"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.model.clone().into()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        super::openai::chat_completion_body(
+            chat,
+            options,
+            super::openai::ChatCompletionTarget {
+                provider: "HuggingFace",
+                model: self.model(options)?,
+                system_role: "system",
+                supports_temperature: true,
+                supports_tools: true,
+                max_stopping_sequences: Some(MAX_STOPPING_SEQUENCES),
+                max_output_tokens: None,
+                supports_store: false,
+                supports_stream_options: false,
+            },
+            true,
+        )
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<super::openai::OpenAITokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("HuggingFace request body: {}", crate::logging::body(&body));
+
+        let mut request = Request::builder()
+            .uri(format!(
+                "{}/chat/completions",
+                self.base_url.trim_end_matches('/')
+            ))
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST);
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        let request = request.body(body)?;
+        tracing::debug!(
+            "HuggingFace request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let sse = SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout);
+
+        Ok(super::openai::OpenAITokenStream::new(sse, span).with_seed(options.seed))
+    }
+}
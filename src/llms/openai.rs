@@ -1,8 +1,25 @@
-use std::{borrow::Cow, collections::VecDeque};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, OnceLock},
+};
 
 use hyper::{Method, Request, Version};
 
-use crate::{sse::SseClient, JsonExt};
+use crate::{
+    sse::{SseClient, SseTask},
+    transport::Transport,
+    JsonExt,
+};
+
+pub mod batch;
+pub mod compatible;
+pub mod images;
+pub mod moderation;
+pub mod responses;
+
+/// The most stopping sequences the chat completions API accepts.
+const MAX_STOPPING_SEQUENCES: usize = 4;
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
@@ -17,6 +34,10 @@ pub enum GptModel {
     Gpt4oMini_2024_07_18,
     #[serde(rename = "gpt-4o-mini")]
     Gpt4oMini,
+    #[serde(rename = "gpt-4o-audio-preview")]
+    Gpt4oAudioPreview,
+    #[serde(rename = "gpt-4o-mini-audio-preview")]
+    Gpt4oMiniAudioPreview,
 
     #[serde(rename = "gpt-4.5-preview-2025-02-27")]
     Gpt4_5_preview_2025_02_27,
@@ -49,7 +70,9 @@ impl GptModel {
             | Self::Gpt4o_2024_08_06
             | Self::ChatGpt4oLatest
             | Self::Gpt4oMini_2024_07_18
-            | Self::Gpt4oMini => "system",
+            | Self::Gpt4oMini
+            | Self::Gpt4oAudioPreview
+            | Self::Gpt4oMiniAudioPreview => "system",
             Self::o1
             | Self::o1_2024_12_17
             | Self::o1Mini
@@ -69,6 +92,8 @@ impl GptModel {
             | Self::ChatGpt4oLatest
             | Self::Gpt4oMini_2024_07_18
             | Self::Gpt4oMini
+            | Self::Gpt4oAudioPreview
+            | Self::Gpt4oMiniAudioPreview
             | Self::Gpt4_5_preview_2025_02_27 => true,
             Self::o1
             | Self::o1_2024_12_17
@@ -80,11 +105,51 @@ impl GptModel {
             | Self::o1Preview_2024_09_12 => false,
         }
     }
+
+    /// Whether the model can be given tools to call.
+    pub fn supports_tools(&self) -> bool {
+        match self {
+            Self::Gpt4o
+            | Self::Gpt4o_2024_08_06
+            | Self::ChatGpt4oLatest
+            | Self::Gpt4oMini_2024_07_18
+            | Self::Gpt4oMini
+            | Self::Gpt4oAudioPreview
+            | Self::Gpt4oMiniAudioPreview
+            | Self::Gpt4_5_preview_2025_02_27
+            | Self::o1
+            | Self::o1_2024_12_17
+            | Self::o3Mini
+            | Self::o3Mini_2025_01_31 => true,
+            Self::o1Mini
+            | Self::o1Mini_2024_09_12
+            | Self::o1Preview
+            | Self::o1Preview_2024_09_12 => false,
+        }
+    }
+
+    /// The most tokens the model can output in one response, including any reasoning.
+    pub fn max_output_tokens(&self) -> usize {
+        match self {
+            Self::Gpt4o
+            | Self::Gpt4o_2024_08_06
+            | Self::ChatGpt4oLatest
+            | Self::Gpt4oMini_2024_07_18
+            | Self::Gpt4oMini
+            | Self::Gpt4oAudioPreview
+            | Self::Gpt4oMiniAudioPreview
+            | Self::Gpt4_5_preview_2025_02_27 => 16_384,
+            Self::o1Preview | Self::o1Preview_2024_09_12 => 32_768,
+            Self::o1Mini | Self::o1Mini_2024_09_12 => 65_536,
+            Self::o1 | Self::o1_2024_12_17 | Self::o3Mini | Self::o3Mini_2025_01_31 => 100_000,
+        }
+    }
 }
 
 pub struct Gpt {
     model: GptModel,
     bearer_header: String,
+    transport: Arc<dyn Transport>,
 }
 
 impl Gpt {
@@ -100,253 +165,682 @@ impl Gpt {
         Self {
             model,
             bearer_header: format!("Bearer {api_key}"),
+            transport: crate::transport::default_transport(),
         }
     }
-}
 
-impl crate::LLM for Gpt {
-    type TokenStream = OpenAITokenStream;
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+}
 
-    fn prompt(
+impl Gpt {
+    /// Serializes a chat completion request body for the given conversation, which may or may not
+    /// request a streamed response.
+    pub(crate) fn request_body(
         &self,
         chat: &[crate::Message],
         options: &crate::PromptOptions,
-    ) -> Result<OpenAITokenStream, crate::PromptError> {
-        let crate::PromptOptions {
-            max_tokens,
-            temperature,
-            system_prompt,
-            stopping_sequences,
-            tools,
-            reasoning,
-        } = options;
-
-        #[derive(Debug, serde::Serialize)]
-        enum OpenAIReasoningEffort {
-            #[serde(rename = "low")]
-            Low,
-            #[serde(rename = "medium")]
-            Medium,
-            #[serde(rename = "high")]
-            High,
-        }
+        stream: bool,
+    ) -> Result<String, crate::PromptError> {
+        let model = match &options.model_override {
+            None => self.model,
+            Some(crate::ModelOverride::Gpt(model)) => *model,
+            Some(model_override) => {
+                return Err(crate::PromptError::IncompatibleModelOverride {
+                    provider: "OpenAI",
+                    model_override: model_override.clone(),
+                })
+            }
+        };
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAIFunctionDescription<'a> {
-            name: &'a str,
-            description: &'a str,
-            parameters: &'a schemars::schema::Schema,
-        }
+        chat_completion_body(
+            chat,
+            options,
+            ChatCompletionTarget {
+                provider: "OpenAI",
+                model,
+                system_role: model.system_name(),
+                supports_temperature: model.supports_temperature(),
+                supports_tools: model.supports_tools(),
+                max_stopping_sequences: Some(MAX_STOPPING_SEQUENCES),
+                max_output_tokens: Some(model.max_output_tokens()),
+                supports_store: true,
+                supports_stream_options: true,
+            },
+            stream,
+        )
+    }
+}
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAITool<'a> {
-            r#type: &'a str,
-            function: OpenAIFunctionDescription<'a>,
-        }
+/// The parts of a chat completion request which differ between OpenAI-compatible endpoints.
+pub(crate) struct ChatCompletionTarget<M> {
+    pub(crate) provider: &'static str,
+    pub(crate) model: M,
+    /// The role to give the system prompt.
+    pub(crate) system_role: &'static str,
+    pub(crate) supports_temperature: bool,
+    /// If not, any tools are left out of the request rather than being rejected by the server.
+    pub(crate) supports_tools: bool,
+    pub(crate) max_stopping_sequences: Option<usize>,
+    /// The most tokens the model can output, if known.
+    pub(crate) max_output_tokens: Option<usize>,
+    /// If not, [`crate::PromptOptions::store`] is ignored.
+    pub(crate) supports_store: bool,
+    /// Whether streamed responses can be asked to end with their usage. If not, the usage isn't
+    /// asked for, as some servers reject `stream_options`.
+    pub(crate) supports_stream_options: bool,
+}
+
+/// The `tool_choice` of a chat completion request, which OpenRouter shares.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+pub(crate) enum OpenAIToolChoice<'a> {
+    Mode(&'static str),
+    Function {
+        r#type: &'static str,
+        function: OpenAIToolChoiceFunction<'a>,
+    },
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct OpenAIToolChoiceFunction<'a> {
+    name: &'a str,
+}
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAIToolCallFunction<'a> {
-            name: &'a str,
-            arguments: &'a str,
+impl<'a> OpenAIToolChoice<'a> {
+    pub(crate) fn new(tool_choice: &'a crate::ToolChoice) -> Self {
+        match tool_choice {
+            crate::ToolChoice::Auto => Self::Mode("auto"),
+            crate::ToolChoice::Any => Self::Mode("required"),
+            crate::ToolChoice::Tool(name) => Self::Function {
+                r#type: "function",
+                function: OpenAIToolChoiceFunction { name },
+            },
+            crate::ToolChoice::None => Self::Mode("none"),
         }
+    }
+}
+
+/// The content of a chat completion message, which OpenRouter shares. Text alone is sent as a
+/// string, and anything with images as a list of parts.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+pub(crate) enum OpenAIContent<'a> {
+    Text(Cow<'a, str>),
+    Parts(Vec<OpenAIContentPart<'a>>),
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum OpenAIContentPart<'a> {
+    Text { text: Cow<'a, str> },
+    ImageUrl { image_url: OpenAIImageUrl },
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct OpenAIImageUrl {
+    /// The image as a `data:` URL.
+    url: String,
+}
+
+impl Default for OpenAIContent<'_> {
+    fn default() -> Self {
+        Self::Text(Cow::Borrowed(""))
+    }
+}
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAIToolCall<'a> {
-            id: &'a str,
-            r#type: &'a str,
-            function: OpenAIToolCallFunction<'a>,
+impl<'a> OpenAIContent<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Parts(parts) => parts.is_empty(),
         }
+    }
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAIMessage<'a> {
-            role: &'a str,
-            #[serde(skip_serializing_if = "str::is_empty")]
-            content: Cow<'a, str>,
-            #[serde(skip_serializing_if = "str::is_empty")]
-            tool_call_id: &'a str,
-            #[serde(skip_serializing_if = "Vec::is_empty")]
-            tool_calls: Vec<OpenAIToolCall<'a>>,
+    /// Adds text to the end of the content, after a blank line if it continues earlier text.
+    pub(crate) fn push_text(&mut self, text: &'a str) {
+        match self {
+            Self::Text(content) if content.is_empty() => *content = Cow::Borrowed(text),
+            Self::Text(content) => {
+                let content = content.to_mut();
+                content.push_str("\n\n");
+                content.push_str(text);
+            }
+            Self::Parts(parts) => parts.push(OpenAIContentPart::Text {
+                text: Cow::Borrowed(text),
+            }),
         }
+    }
 
-        impl Default for OpenAIMessage<'_> {
-            fn default() -> Self {
-                Self {
-                    role: "",
-                    content: Cow::Borrowed(""),
-                    tool_call_id: "",
-                    tool_calls: vec![],
+    /// Adds the text and images of a user message to the end of the content, in order.
+    pub(crate) fn push_parts(&mut self, parts: &'a [crate::ContentPart]) {
+        for part in parts {
+            match part {
+                crate::ContentPart::Text(text) if text.is_empty() => {}
+                crate::ContentPart::Text(text) => self.push_text(text),
+                crate::ContentPart::Image(image) => {
+                    self.as_parts().push(OpenAIContentPart::ImageUrl {
+                        image_url: OpenAIImageUrl {
+                            url: format!("data:{};base64,{}", image.media_type, image.data),
+                        },
+                    })
                 }
             }
         }
+    }
 
-        #[derive(Debug, serde::Serialize)]
-        struct OpenAIRequest<'a> {
-            model: GptModel,
-            max_completion_tokens: usize,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            temperature: Option<f32>,
-            stream: bool,
-            #[serde(skip_serializing_if = "<[String]>::is_empty")]
-            stop: &'a [String],
-            #[serde(skip_serializing_if = "Option::is_none")]
-            reasoning_effort: Option<OpenAIReasoningEffort>,
-            #[serde(skip_serializing_if = "Vec::is_empty")]
-            tools: Vec<OpenAITool<'a>>,
-            messages: Vec<OpenAIMessage<'a>>,
+    /// Converts text content to a list of parts, so that images can be added.
+    fn as_parts(&mut self) -> &mut Vec<OpenAIContentPart<'a>> {
+        if let Self::Text(text) = self {
+            let text = std::mem::take(text);
+            *self = Self::Parts(match text.is_empty() {
+                true => vec![],
+                false => vec![OpenAIContentPart::Text { text }],
+            });
+        }
+        match self {
+            Self::Parts(parts) => parts,
+            Self::Text(_) => unreachable!("text content was just converted to parts"),
         }
+    }
+}
 
-        let tools = tools
-            .iter()
-            .map(|tool| OpenAITool {
-                r#type: "function",
-                function: OpenAIFunctionDescription {
-                    name: &tool.name,
-                    description: &tool.description,
-                    parameters: &tool.parameters.inner,
-                },
-            })
-            .collect();
-
-        let mut messages = vec![];
-
-        if let Some(system_prompt) = system_prompt {
-            messages.push(OpenAIMessage {
-                role: &self.model.system_name(),
-                content: Cow::Borrowed(system_prompt),
-                ..OpenAIMessage::default()
-            });
+/// Serializes a chat completion request body for the given conversation and endpoint.
+pub(crate) fn chat_completion_body<M: serde::Serialize>(
+    chat: &[crate::Message],
+    options: &crate::PromptOptions,
+    target: ChatCompletionTarget<M>,
+    stream: bool,
+) -> Result<String, crate::PromptError> {
+    let crate::PromptOptions {
+        // Resolved against the target's limit.
+        max_tokens: _,
+        temperature,
+        system_prompt: _,
+        // Resolved against the target's limit.
+        stopping_sequences: _,
+        stopping_sequence_overflow: _,
+        tools: _,
+        reasoning,
+        // Resolved into the target by the caller.
+        model_override: _,
+        exclude_reasoning: _,
+        keepalive_timeout: _,
+        idempotency_key: _,
+        prediction,
+        extra_body,
+        parallel_tool_calls,
+        tool_choice,
+        reasoning_summary: _,
+        audio_output,
+        correlation_id: _,
+        store,
+        previous_response_id: _,
+        prompt_template,
+        response_format,
+        seed,
+    } = options;
+
+    if prompt_template.is_some() {
+        return Err(crate::PromptError::UnsupportedPromptTemplate {
+            provider: target.provider,
+        });
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    enum OpenAIReasoningEffort {
+        #[serde(rename = "low")]
+        Low,
+        #[serde(rename = "medium")]
+        Medium,
+        #[serde(rename = "high")]
+        High,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIFunctionDescription<'a> {
+        name: &'a str,
+        description: &'a str,
+        parameters: Cow<'a, schemars::schema::Schema>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        strict: bool,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAITool<'a> {
+        r#type: &'a str,
+        function: OpenAIFunctionDescription<'a>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIToolCallFunction<'a> {
+        name: &'a str,
+        arguments: &'a str,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIToolCall<'a> {
+        id: &'a str,
+        r#type: &'a str,
+        function: OpenAIToolCallFunction<'a>,
+    }
+
+    #[derive(Debug, Default, serde::Serialize)]
+    struct OpenAIMessage<'a> {
+        role: &'a str,
+        #[serde(skip_serializing_if = "OpenAIContent::is_empty")]
+        content: OpenAIContent<'a>,
+        #[serde(skip_serializing_if = "str::is_empty")]
+        tool_call_id: &'a str,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<OpenAIToolCall<'a>>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIPrediction<'a> {
+        r#type: &'a str,
+        content: &'a str,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    enum OpenAIAudioFormat {
+        #[serde(rename = "pcm16")]
+        Pcm16,
+        #[serde(rename = "wav")]
+        Wav,
+        #[serde(rename = "mp3")]
+        Mp3,
+        #[serde(rename = "flac")]
+        Flac,
+        #[serde(rename = "opus")]
+        Opus,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIAudio<'a> {
+        voice: &'a str,
+        format: OpenAIAudioFormat,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIResponseFormat {
+        r#type: &'static str,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIStreamOptions {
+        include_usage: bool,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct OpenAIRequest<'a, M> {
+        model: M,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_completion_tokens: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        stream: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream_options: Option<OpenAIStreamOptions>,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        stop: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reasoning_effort: Option<OpenAIReasoningEffort>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tools: Vec<OpenAITool<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        parallel_tool_calls: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_choice: Option<OpenAIToolChoice<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prediction: Option<OpenAIPrediction<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response_format: Option<OpenAIResponseFormat>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        modalities: Option<[&'a str; 2]>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        audio: Option<OpenAIAudio<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        store: Option<bool>,
+        messages: Vec<OpenAIMessage<'a>>,
+    }
+
+    let tools = options.resolve_tools()?;
+    let tools = if !tools.is_empty() && !target.supports_tools {
+        tracing::warn!(
+            "{} model {} does not support tools, ignoring them",
+            target.provider,
+            crate::llms::model_name(&target.model)
+        );
+        &[]
+    } else {
+        tools
+    };
+    let tools: Vec<_> = tools
+        .iter()
+        .map(|tool| OpenAITool {
+            r#type: "function",
+            function: OpenAIFunctionDescription {
+                name: &tool.name,
+                description: &tool.description,
+                parameters: tool.parameters.resolve_strict_schema(),
+                strict: tool.parameters.is_strict(),
+            },
+        })
+        .collect();
+
+    let mut messages = vec![];
+
+    if let Some(system_prompt) = options.resolve_system_prompt() {
+        messages.push(OpenAIMessage {
+            role: target.system_role,
+            content: OpenAIContent::Text(Cow::Borrowed(system_prompt)),
+            ..OpenAIMessage::default()
+        });
+    }
+
+    fn maybe_append_text<'a>(
+        messages: &mut Vec<OpenAIMessage<'a>>,
+        content: &'a str,
+        role: &'a str,
+    ) -> Option<OpenAIMessage<'a>> {
+        if content.is_empty() {
+            return None;
         }
 
-        fn maybe_append_text<'a>(
-            messages: &mut Vec<OpenAIMessage<'a>>,
-            content: &'a str,
-            role: &'a str,
-        ) -> Option<OpenAIMessage<'a>> {
-            if content.is_empty() {
+        // Try collate
+        if let Some(last) = messages.last_mut() {
+            if last.role == role {
+                last.content.push_text(content);
+
                 return None;
             }
+        }
 
-            // Try collate
-            if let Some(last) = messages.last_mut() {
-                if last.role == role {
-                    if !last.content.is_empty() {
-                        last.content = Cow::Owned(format!("{}\n\n{}", last.content, content));
-                    } else {
-                        last.content = Cow::Borrowed(content);
-                    }
+        Some(OpenAIMessage {
+            role,
+            content: OpenAIContent::Text(Cow::Borrowed(content)),
+            ..OpenAIMessage::default()
+        })
+    }
 
-                    return None;
-                }
+    fn add_message<'a>(messages: &mut Vec<OpenAIMessage<'a>>, message: &'a crate::Message) {
+        let new_message = match message {
+            crate::Message::User(content) => {
+                let Some(message) = maybe_append_text(messages, content, "user") else {
+                    return;
+                };
+                message
             }
+            crate::Message::UserParts(parts) => {
+                // Try collate
+                if let Some(last) = messages.last_mut() {
+                    if last.role == "user" {
+                        last.content.push_parts(parts);
 
-            Some(OpenAIMessage {
-                role,
-                content: Cow::Borrowed(content),
-                ..OpenAIMessage::default()
-            })
-        }
-
-        fn add_message<'a>(messages: &mut Vec<OpenAIMessage<'a>>, message: &'a crate::Message) {
-            let new_message = match message {
-                crate::Message::User(content) => {
-                    let Some(message) = maybe_append_text(messages, content, "user") else {
                         return;
-                    };
-                    message
+                    }
                 }
-                crate::Message::Assistant(content) => {
-                    let Some(message) = maybe_append_text(messages, content, "assistant") else {
-                        return;
-                    };
-                    message
+
+                let mut content = OpenAIContent::default();
+                content.push_parts(parts);
+                if content.is_empty() {
+                    return;
+                }
+                OpenAIMessage {
+                    role: "user",
+                    content,
+                    ..OpenAIMessage::default()
                 }
-                crate::Message::ToolRequest {
+            }
+            crate::Message::Assistant(content) => {
+                let Some(message) = maybe_append_text(messages, content, "assistant") else {
+                    return;
+                };
+                message
+            }
+            crate::Message::Thinking { .. } => {
+                tracing::warn!("OpenAI does not support thinking messages, ignoring them");
+                return;
+            }
+            crate::Message::ToolRequest {
+                id,
+                name,
+                arguments,
+            } => {
+                let tool_request = OpenAIToolCall {
                     id,
-                    name,
-                    arguments,
-                } => {
-                    let tool_request = OpenAIToolCall {
-                        id: &id,
-                        r#type: "function",
-                        function: OpenAIToolCallFunction {
-                            name,
-                            arguments: &arguments.serialized,
-                        },
-                    };
-
-                    // Try collate
-                    if let Some(last) = messages.last_mut() {
-                        if last.role == "assistant" {
-                            last.tool_calls.push(tool_request);
+                    r#type: "function",
+                    function: OpenAIToolCallFunction {
+                        name,
+                        arguments: &arguments.serialized,
+                    },
+                };
+
+                // Try collate
+                if let Some(last) = messages.last_mut() {
+                    if last.role == "assistant" {
+                        last.tool_calls.push(tool_request);
 
-                            return;
-                        }
+                        return;
                     }
+                }
 
-                    OpenAIMessage {
-                        role: "assistant",
-                        tool_calls: vec![tool_request],
-                        ..OpenAIMessage::default()
-                    }
+                OpenAIMessage {
+                    role: "assistant",
+                    tool_calls: vec![tool_request],
+                    ..OpenAIMessage::default()
                 }
-                crate::Message::ToolResponse { content, id } => OpenAIMessage {
+            }
+            crate::Message::ToolResponse {
+                content,
+                id,
+                images,
+            } => {
+                if !images.is_empty() {
+                    tracing::warn!("tool responses cannot include images, ignoring them");
+                }
+                OpenAIMessage {
                     role: "tool",
-                    content: Cow::Borrowed(content),
-                    tool_call_id: &id,
+                    content: OpenAIContent::Text(Cow::Borrowed(content)),
+                    tool_call_id: id,
                     ..OpenAIMessage::default()
-                },
-            };
+                }
+            }
+        };
 
-            messages.push(new_message);
-        }
+        messages.push(new_message);
+    }
 
-        for message in chat.iter() {
-            add_message(&mut messages, message);
-        }
+    for message in chat.iter() {
+        add_message(&mut messages, message);
+    }
 
-        let body = OpenAIRequest {
-            model: self.model,
-            max_completion_tokens: *max_tokens,
-            temperature: self.model.supports_temperature().then_some(*temperature),
-            stop: stopping_sequences.as_slice(),
-            stream: true,
-            reasoning_effort: reasoning.map(|effort| match effort {
-                crate::ReasoningEffort::Low => OpenAIReasoningEffort::Low,
-                crate::ReasoningEffort::Medium => OpenAIReasoningEffort::Medium,
-                crate::ReasoningEffort::High => OpenAIReasoningEffort::High,
-            }),
-            tools,
-            messages,
-        };
-        let body = serde_json::to_string(&body)?;
-        tracing::debug!("OpenAI request body: {}", body);
+    let stop =
+        options.resolve_stopping_sequences(target.provider, target.max_stopping_sequences)?;
+
+    let body = OpenAIRequest {
+        model: target.model,
+        max_completion_tokens: options.resolve_max_tokens(target.max_output_tokens),
+        temperature: temperature.filter(|_| target.supports_temperature),
+        stop,
+        stream,
+        // The usage is then sent in a final event of its own.
+        stream_options: (stream && target.supports_stream_options).then_some(OpenAIStreamOptions {
+            include_usage: true,
+        }),
+        reasoning_effort: reasoning.map(|effort| match effort.level() {
+            crate::ReasoningLevel::Low => OpenAIReasoningEffort::Low,
+            crate::ReasoningLevel::Medium => OpenAIReasoningEffort::Medium,
+            crate::ReasoningLevel::High => OpenAIReasoningEffort::High,
+        }),
+        parallel_tool_calls: parallel_tool_calls.filter(|_| !tools.is_empty()),
+        tool_choice: tool_choice
+            .as_ref()
+            .filter(|_| !tools.is_empty())
+            .map(OpenAIToolChoice::new),
+        tools,
+        prediction: prediction.as_deref().map(|content| OpenAIPrediction {
+            r#type: "content",
+            content,
+        }),
+        response_format: response_format.map(|response_format| match response_format {
+            crate::ResponseFormat::JsonObject => OpenAIResponseFormat {
+                r#type: "json_object",
+            },
+        }),
+        seed: *seed,
+        modalities: audio_output.as_ref().map(|_| ["text", "audio"]),
+        audio: audio_output.as_ref().map(|audio_output| OpenAIAudio {
+            voice: &audio_output.voice,
+            format: match audio_output.format {
+                crate::AudioFormat::Pcm16 => OpenAIAudioFormat::Pcm16,
+                crate::AudioFormat::Wav => OpenAIAudioFormat::Wav,
+                crate::AudioFormat::Mp3 => OpenAIAudioFormat::Mp3,
+                crate::AudioFormat::Flac => OpenAIAudioFormat::Flac,
+                crate::AudioFormat::Opus => OpenAIAudioFormat::Opus,
+            },
+        }),
+        store: store.filter(|_| target.supports_store),
+        messages,
+    };
+    crate::serialize_request_body(&body, extra_body)
+}
+
+impl crate::LLM for Gpt {
+    type TokenStream = OpenAITokenStream;
+
+    fn provider(&self) -> &'static str {
+        "OpenAI"
+    }
 
-        let request = Request::builder()
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        crate::llms::model_name(&self.model).into()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.model.supports_tools()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        self.request_body(chat, options, true)
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<OpenAITokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("OpenAI request body: {}", crate::logging::body(&body));
+
+        let mut request = Request::builder()
             .uri("https://api.openai.com/v1/chat/completions")
             .header("Authorization", &self.bearer_header)
             .header("content-type", "application/json")
             .version(Version::HTTP_2)
-            .method(Method::POST)
-            .body(body)?;
-        tracing::debug!("OpenAI request: {:#?}", request);
-        let sse = SseClient::spawn(request);
-
-        Ok(OpenAITokenStream::new(sse))
+            .method(Method::POST);
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        let request = request.body(body)?;
+        tracing::debug!(
+            "OpenAI request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let sse = SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout);
+
+        Ok(OpenAITokenStream::new(sse, span).with_seed(options.seed))
     }
 }
 
 pub struct OpenAITokenStream {
     stream: Option<std::pin::Pin<Box<SseClient>>>,
+    task: Option<SseTask>,
     outstanding: VecDeque<crate::Chunk>,
+    /// An error which arrived after the outstanding chunks, returned once they have been.
+    pending_error: Option<crate::TokenError>,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    /// Whether the text of events which have already arrived is joined into one chunk.
+    coalesce_tokens: bool,
+    /// The id of the response, given with each event.
+    id: Option<String>,
+    /// The seed the prompt was sent with.
+    seed: Option<u64>,
+    system_fingerprint: Option<String>,
+    span: crate::span::PromptSpan,
 }
 
 impl OpenAITokenStream {
-    pub(crate) fn new(stream: SseClient) -> Self {
+    pub(crate) fn new(mut stream: SseClient, span: crate::span::PromptSpan) -> Self {
         Self {
+            metadata: stream.metadata(),
+            task: stream.take_task(),
             stream: Some(Box::pin(stream)),
             outstanding: VecDeque::new(),
+            pending_error: None,
+            coalesce_tokens: false,
+            id: None,
+            seed: None,
+            system_fingerprint: None,
+            span,
         }
     }
+
+    pub(crate) fn with_coalesced_tokens(mut self, coalesce_tokens: bool) -> Self {
+        self.coalesce_tokens = coalesce_tokens;
+        self
+    }
+
+    pub(crate) fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The status and headers of the response, or `None` if the server has not yet responded successfully.
+    pub fn response_metadata(&self) -> Option<&crate::ResponseMetadata> {
+        self.metadata.get()
+    }
+
+    /// The id the server gave the response, or `None` if no event has arrived yet. For
+    /// OpenRouter, this is the generation id to give to
+    /// [`crate::llms::openrouter::OpenRouter::generation_stats`].
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// The seed the prompt was sent with, and the fingerprint of the backend which served it, once
+    /// the response has started, for checking that repeated prompts were served alike.
+    pub fn reproducibility(&self) -> crate::Reproducibility {
+        crate::Reproducibility {
+            seed: self.seed,
+            system_fingerprint: self.system_fingerprint.clone(),
+        }
+    }
+
+    /// A copy of each event read by the stream from now on, as sent by the server, for inspecting
+    /// fields which aren't otherwise supported. Replaces any previous receiver.
+    pub fn raw_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<crate::RawEvent> {
+        crate::sse::SseClient::raw_events(self.stream.as_deref_mut())
+    }
+
+    /// Stops the response and waits for the task which read it to finish, returning any error
+    /// the task hit that the stream has not already returned. The connection is closed in the
+    /// background, and may still be closing when this returns.
+    pub async fn shutdown(mut self) -> Result<(), crate::TokenError> {
+        crate::sse::SseTask::shutdown_stream(self.task.take(), self.stream.take()).await
+    }
 }
 
 impl futures::Stream for OpenAITokenStream {
@@ -356,27 +850,60 @@ impl futures::Stream for OpenAITokenStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        let _entered = self.span.span().clone().entered();
+        let poll = self.as_mut().poll_chunk(cx);
+        self.span.record(&poll);
+        poll
+    }
+}
+
+impl OpenAITokenStream {
+    fn poll_chunk(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<crate::Chunk, crate::TokenError>>> {
         let Self {
             stream,
             outstanding,
+            pending_error,
+            coalesce_tokens,
+            id,
+            system_fingerprint,
+            ..
         } = &mut *self;
 
-        let Some(sse_client) = stream.as_mut() else {
-            return std::task::Poll::Ready(None);
-        };
-
         loop {
-            // Return any outstanding chunks
-            if let Some(chunk) = outstanding.pop_front() {
-                return std::task::Poll::Ready(Some(Ok(chunk)));
+            // Text which is followed by more events that have already arrived waits to be joined
+            // with their text, so that a burst of tokens becomes a single chunk.
+            let more_text_ready = *coalesce_tokens
+                && pending_error.is_none()
+                && stream
+                    .as_ref()
+                    .is_some_and(|sse_client| sse_client.has_buffered())
+                && outstanding
+                    .iter()
+                    .all(|chunk| matches!(chunk, crate::Chunk::Token(_)));
+
+            // Return any outstanding chunks, and then any error which followed them
+            if !more_text_ready {
+                if let Some(chunk) = outstanding.pop_front() {
+                    return std::task::Poll::Ready(Some(Ok(chunk)));
+                }
+                if let Some(error) = pending_error.take() {
+                    return std::task::Poll::Ready(Some(Err(error)));
+                }
             }
 
-            let message = sse_client.as_mut().poll_next(cx);
+            let Some(sse_client) = stream.as_mut() else {
+                return std::task::Poll::Ready(None);
+            };
+
+            let message = futures::Stream::poll_next(sse_client.as_mut(), cx);
 
             let message = match message {
                 std::task::Poll::Ready(None) => {
-                    self.stream = None;
-                    return std::task::Poll::Ready(None);
+                    *stream = None;
+                    continue;
                 }
                 std::task::Poll::Ready(Some(message)) => message,
                 std::task::Poll::Pending => return std::task::Poll::Pending,
@@ -384,10 +911,9 @@ impl futures::Stream for OpenAITokenStream {
 
             let mut message = match message {
                 Err(error) => {
-                    self.stream = None;
-                    return std::task::Poll::Ready(Some(Err(crate::TokenError::ConnectionLost(
-                        error,
-                    ))));
+                    *stream = None;
+                    *pending_error = Some(crate::TokenError::ConnectionLost(error));
+                    continue;
                 }
                 Ok(message) => message,
             };
@@ -395,30 +921,42 @@ impl futures::Stream for OpenAITokenStream {
             match message.event.as_str() {
                 "ping" => {}
                 "" => {
-                    let mut new_messages = match gather_messages(message.value.take()) {
+                    if id.is_none() {
+                        *id = message.value.pointer_mut("/id").and_then(JsonExt::take_str);
+                    }
+                    if let Some(fingerprint) = message
+                        .value
+                        .pointer_mut("/system_fingerprint")
+                        .and_then(JsonExt::take_str)
+                    {
+                        *system_fingerprint = Some(fingerprint);
+                    }
+                    let new_messages = match gather_messages(message.value.take()) {
                         Ok(new_messages) => new_messages,
                         Err(error) => {
-                            self.stream = None;
-                            return std::task::Poll::Ready(Some(Err(error)));
+                            *stream = None;
+                            *pending_error = Some(error);
+                            continue;
                         }
                     };
 
-                    if new_messages.len() > 1 {
-                        outstanding.extend(new_messages.drain(1..));
+                    if new_messages.is_empty() {
+                        tracing::warn!(
+                            "received empty message from endpoint: `{:?}`",
+                            message.value
+                        );
                     }
-                    if let Some(message) = new_messages.into_iter().next() {
-                        return std::task::Poll::Ready(Some(Ok(message)));
+                    for chunk in new_messages {
+                        match (*coalesce_tokens, outstanding.back_mut(), chunk) {
+                            (true, Some(crate::Chunk::Token(text)), crate::Chunk::Token(next)) => {
+                                text.push_str(&next)
+                            }
+                            (_, _, chunk) => outstanding.push_back(chunk),
+                        }
                     }
-
-                    tracing::warn!(
-                        "received empty message from endpoint: `{:?}`",
-                        message.value
-                    );
                 }
                 other => {
-                    return std::task::Poll::Ready(Some(Err(crate::TokenError::UnknownEventType(
-                        other.to_owned(),
-                    ))))
+                    *pending_error = Some(crate::TokenError::UnknownEventType(other.to_owned()));
                 }
             }
         }
@@ -442,6 +980,23 @@ fn gather_messages(mut value: serde_json::Value) -> Result<Vec<crate::Chunk>, cr
 
     match object.as_str() {
         "chat.completion.chunk" => {
+            // Sent as `null` by every event but the last, which has no choices.
+            let usage = match content.get("usage") {
+                Some(serde_json::Value::Object(usage)) => {
+                    let tokens = |key| {
+                        usage
+                            .get(key)
+                            .and_then(serde_json::Value::as_u64)
+                            .map(|tokens| tokens as usize)
+                    };
+                    Some(crate::Usage {
+                        input_tokens: tokens("prompt_tokens"),
+                        output_tokens: tokens("completion_tokens"),
+                    })
+                }
+                _ => None,
+            };
+
             let Some(serde_json::Value::Array(choices)) = content.get_mut("choices") else {
                 return Err(crate::TokenError::MalformedResponse {
                     message: "expected OpenAI chat completion chunk to have choices",
@@ -449,6 +1004,10 @@ fn gather_messages(mut value: serde_json::Value) -> Result<Vec<crate::Chunk>, cr
                 });
             };
 
+            if let (true, Some(usage)) = (choices.is_empty(), usage) {
+                return Ok(vec![crate::Chunk::Usage(usage)]);
+            }
+
             if choices.len() != 1 {
                 return Err(crate::TokenError::MalformedResponse {
                     message: "expected OpenAI chat completion chunk to have exactly one choice",
@@ -463,6 +1022,13 @@ fn gather_messages(mut value: serde_json::Value) -> Result<Vec<crate::Chunk>, cr
                 });
             };
 
+            let finish_reason = match choice.get("finish_reason") {
+                Some(serde_json::Value::String(reason)) => {
+                    Some(crate::FinishReason::from_openai(reason))
+                }
+                _ => None,
+            };
+
             let Some(serde_json::Value::Object(delta)) = choice.get_mut("delta") else {
                 return Err(crate::TokenError::MalformedResponse {
                     message: "expected OpenAI chat completion chunk to have delta",
@@ -470,38 +1036,129 @@ fn gather_messages(mut value: serde_json::Value) -> Result<Vec<crate::Chunk>, cr
                 });
             };
 
-            if delta.is_empty() {
-                return Ok(vec![]);
-            }
+            // Fields are often sent as `null` rather than left out, e.g. `content` alongside tool
+            // calls, and the role carries nothing, so a delta of only these is empty.
+            delta.retain(|key, value| !value.is_null() && key != "role");
 
-            if let Some(serde_json::Value::String(text)) = delta.remove("content") {
-                return Ok(if text.is_empty() {
+            let mut chunks = if delta.is_empty() {
+                vec![]
+            } else if let Some(serde_json::Value::String(text)) = delta.remove("content") {
+                if text.is_empty() {
                     vec![]
                 } else {
                     vec![crate::Chunk::Token(text)]
-                });
-            };
-
-            if let Some(serde_json::Value::Array(tool_calls)) = delta.get_mut("tool_calls") {
-                return tool_calls
-                    .into_iter()
+                }
+            } else if let Some(serde_json::Value::Object(audio)) = delta.get_mut("audio") {
+                parse_audio(audio)
+                    .map_err(|message| crate::TokenError::MalformedResponse { message, value })?
+            } else if let Some(serde_json::Value::Array(tool_calls)) = delta.get_mut("tool_calls") {
+                tool_calls
+                    .iter_mut()
                     .map(|tool_call| parse_tool_call(tool_call).map(crate::Chunk::ToolCall))
                     .collect::<Result<Vec<_>, _>>()
-                    .map_err(|message| crate::TokenError::MalformedResponse { message, value });
+                    .map_err(|message| crate::TokenError::MalformedResponse { message, value })?
+            } else if finish_reason.is_some() {
+                vec![]
+            } else {
+                return Err(crate::TokenError::MalformedResponse {
+                    message: "expected OpenAI chat completion chunk delta to have known key",
+                    value,
+                });
             };
 
-            return Err(crate::TokenError::MalformedResponse {
-                message: "expected OpenAI chat completion chunk delta to have known key",
-                value,
-            });
+            chunks.extend(finish_reason.map(crate::Chunk::Finish));
+            chunks.extend(usage.map(crate::Chunk::Usage));
+            Ok(chunks)
         }
-        _ => {
-            return Err(crate::TokenError::MalformedResponse {
-                message: "unexpected OpenAI object",
-                value,
-            })
+        _ => Err(crate::TokenError::MalformedResponse {
+            message: "unexpected OpenAI object",
+            value,
+        }),
+    }
+}
+
+/// Splits a complete, non-streamed chat completion into the chunks it would have been streamed as,
+/// so that it can be read by an [`OpenAITokenStream`]. Anything unrecognised is passed on as is,
+/// to be reported as malformed.
+pub(crate) fn completion_into_events(mut value: serde_json::Value) -> Vec<serde_json::Value> {
+    let Some(serde_json::Value::Object(mut choice)) =
+        value.pointer_mut("/choices/0").map(serde_json::Value::take)
+    else {
+        return vec![value];
+    };
+    let Some(serde_json::Value::Object(mut message)) = choice.remove("message") else {
+        return vec![value];
+    };
+
+    let event = |delta: serde_json::Value, finish_reason: serde_json::Value| {
+        serde_json::json!({
+            "object": "chat.completion.chunk",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+
+    let mut events = vec![];
+    if let Some(serde_json::Value::String(content)) = message.remove("content") {
+        if !content.is_empty() {
+            events.push(event(
+                serde_json::json!({ "content": content }),
+                serde_json::Value::Null,
+            ));
         }
     }
+    if let Some(serde_json::Value::Array(mut tool_calls)) = message.remove("tool_calls") {
+        // Streamed tool calls are told apart by their index, which complete ones don't have.
+        for (index, tool_call) in tool_calls.iter_mut().enumerate() {
+            if let Some(tool_call) = tool_call.as_object_mut() {
+                tool_call.insert("index".to_owned(), index.into());
+            }
+        }
+        if !tool_calls.is_empty() {
+            events.push(event(
+                serde_json::json!({ "tool_calls": tool_calls }),
+                serde_json::Value::Null,
+            ));
+        }
+    }
+    events.push(event(
+        serde_json::json!({}),
+        choice
+            .remove("finish_reason")
+            .unwrap_or(serde_json::Value::Null),
+    ));
+    if let Some(usage @ serde_json::Value::Object(_)) =
+        value.get_mut("usage").map(serde_json::Value::take)
+    {
+        events.push(serde_json::json!({
+            "object": "chat.completion.chunk",
+            "choices": [],
+            "usage": usage,
+        }));
+    }
+    events
+}
+
+/// Parses a part of a spoken response, which may hold some of its audio and some of its transcript.
+fn parse_audio(
+    audio: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<crate::Chunk>, &'static str> {
+    use base64::Engine;
+
+    let mut chunks = vec![];
+    if let Some(transcript) = audio.get_mut("transcript").and_then(JsonExt::take_str) {
+        if !transcript.is_empty() {
+            chunks.push(crate::Chunk::Token(transcript));
+        }
+    }
+    if let Some(data) = audio.get("data").and_then(serde_json::Value::as_str) {
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|_| "expected audio data to be base64")?;
+        if !data.is_empty() {
+            chunks.push(crate::Chunk::Audio(data));
+        }
+    }
+    Ok(chunks)
 }
 
 fn parse_tool_call(
@@ -516,6 +1173,11 @@ fn parse_tool_call(
         }
     }
 
+    let index = tool_call
+        .get("index")
+        .and_then(serde_json::Value::as_u64)
+        .map(|index| index as usize);
+
     let id = tool_call
         .get_mut("id")
         .and_then(JsonExt::take_str)
@@ -525,17 +1187,23 @@ fn parse_tool_call(
         return Err("expected tool call to have object function");
     };
 
-    let Some(arguments) = function.get_mut("arguments").and_then(JsonExt::take_str) else {
-        return Err("expected tool call to have arguments");
+    // Arguments should be a string of JSON, but some upstreams of OpenRouter send the JSON itself.
+    let arguments = match function.get_mut("arguments").map(serde_json::Value::take) {
+        Some(serde_json::Value::String(arguments)) => arguments,
+        Some(arguments @ (serde_json::Value::Object(_) | serde_json::Value::Array(_))) => {
+            arguments.to_string()
+        }
+        _ => return Err("expected tool call to have arguments"),
     };
 
     let name = function
         .get_mut("name")
         .and_then(JsonExt::take_str)
         .and_then(|v| (!v.is_empty()).then_some(v));
-    return Ok(crate::ToolCallChunk {
+    Ok(crate::ToolCallChunk {
+        index,
         id,
         name,
         arguments,
-    });
+    })
 }
@@ -0,0 +1,307 @@
+//! Support for OpenAI's [Batch API](https://platform.openai.com/docs/guides/batch), which trades a
+//! turnaround of up to 24 hours for a reduced price. Unlike [`crate::LLM::prompt`], nothing here
+//! streams: requests are uploaded as a file, and results are downloaded once the batch completes.
+
+use hyper::{Method, Request, Version};
+
+use crate::JsonExt;
+
+const API_ROOT: &str = "https://api.openai.com/v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BatchError {
+    #[error("failed to build request to model")]
+    PromptError(#[from] crate::PromptError),
+    #[error("failed to build request to the batch API")]
+    RequestError(#[from] hyper::http::Error),
+    #[error("failed to communicate with the batch API")]
+    ConnectionError(#[from] crate::SseError),
+    #[error("failed to transcode batch request or response")]
+    TranscodingError(#[from] serde_json::Error),
+    #[error("the server responded with unexpected data: {message}")]
+    MalformedResponse {
+        message: &'static str,
+        value: serde_json::Value,
+    },
+    #[error("the batch has no results available yet, it is currently {0:?}")]
+    NotFinished(BatchStatus),
+    #[error("the request failed with status {status}: {body}")]
+    RequestFailed {
+        status: u16,
+        body: serde_json::Value,
+    },
+    #[error("the request could not be processed: {0}")]
+    ItemFailed(serde_json::Value),
+    #[error("the batch did not produce a result for this request")]
+    MissingResult,
+}
+
+/// The identifier of a batch, as returned by [`super::Gpt::create_batch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BatchId(String);
+
+impl BatchId {
+    /// Wraps an existing batch identifier, for example one persisted from a previous run.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for BatchId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The lifecycle stage of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchObject {
+    id: String,
+    status: BatchStatus,
+    output_file_id: Option<String>,
+    error_file_id: Option<String>,
+    #[serde(default)]
+    request_counts: Option<BatchRequestCounts>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchRequestCounts {
+    total: usize,
+}
+
+impl super::Gpt {
+    /// Uploads the given conversations as a single batch, returning the batch's identifier.
+    /// The results of [`Self::fetch_results`] are returned in the same order as `requests`.
+    pub async fn create_batch(
+        &self,
+        requests: &[(Vec<crate::Message>, crate::PromptOptions)],
+    ) -> Result<BatchId, BatchError> {
+        let mut jsonl = String::new();
+        for (i, (chat, options)) in requests.iter().enumerate() {
+            let body = self.request_body(chat, options, false)?;
+            jsonl.push_str(&format!(
+                r#"{{"custom_id":"{i}","method":"POST","url":"/v1/chat/completions","body":{body}}}"#
+            ));
+            jsonl.push('\n');
+        }
+
+        // The boundary only needs to be absent from the file, which is newline-delimited JSON.
+        let boundary = format!(
+            "lmql-batch-{:x}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        );
+        let form = format!(
+            "--{boundary}\r\n\
+            Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+            batch\r\n\
+            --{boundary}\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"batch.jsonl\"\r\n\
+            Content-Type: application/jsonl\r\n\r\n\
+            {jsonl}\r\n\
+            --{boundary}--\r\n"
+        );
+        let mut file = self
+            .batch_request(
+                Method::POST,
+                "/files",
+                &format!("multipart/form-data; boundary={boundary}"),
+                form,
+            )
+            .await?;
+        let Some(file_id) = file.get_mut("id").and_then(JsonExt::take_str) else {
+            return Err(BatchError::MalformedResponse {
+                message: "expected uploaded file to have an id",
+                value: file,
+            });
+        };
+
+        let body = serde_json::json!({
+            "input_file_id": file_id,
+            "endpoint": "/v1/chat/completions",
+            "completion_window": "24h",
+        });
+        let batch = self
+            .batch_request(
+                Method::POST,
+                "/batches",
+                "application/json",
+                serde_json::to_string(&body)?,
+            )
+            .await?;
+        let batch = serde_json::from_value::<BatchObject>(batch)?;
+
+        Ok(BatchId(batch.id))
+    }
+
+    /// Retrieves the current status of a batch.
+    pub async fn poll_batch(&self, id: &BatchId) -> Result<BatchStatus, BatchError> {
+        Ok(self.retrieve_batch(id).await?.status)
+    }
+
+    /// Downloads the results of a finished batch, in the order the requests were given to
+    /// [`Self::create_batch`]. Every request has a result, which is
+    /// [`BatchError::MissingResult`] for those the batch did not produce one for.
+    pub async fn fetch_results(
+        &self,
+        id: &BatchId,
+    ) -> Result<Vec<Result<Vec<crate::Chunk>, BatchError>>, BatchError> {
+        let batch = self.retrieve_batch(id).await?;
+        if batch.output_file_id.is_none() && batch.error_file_id.is_none() {
+            return Err(BatchError::NotFinished(batch.status));
+        }
+
+        let mut results = vec![];
+        let total = batch.request_counts.map_or(0, |counts| counts.total);
+        results.resize_with(total, || Err(BatchError::MissingResult));
+        for file_id in [batch.output_file_id, batch.error_file_id]
+            .into_iter()
+            .flatten()
+        {
+            let request = Request::builder()
+                .uri(format!("{API_ROOT}/files/{file_id}/content"))
+                .header("Authorization", &self.bearer_header)
+                .version(Version::HTTP_2)
+                .method(Method::GET)
+                .body(String::new())?;
+            let content = crate::sse::fetch(&*self.transport, request).await?;
+
+            for line in content.split(|b| *b == b'\n') {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let mut line = serde_json::from_slice::<serde_json::Value>(line)?;
+
+                let Some(index) = line
+                    .get("custom_id")
+                    .and_then(serde_json::Value::as_str)
+                    .and_then(|id| id.parse::<usize>().ok())
+                    .filter(|index| *index < total)
+                else {
+                    tracing::warn!("ignoring batch result with unknown custom_id - {line:?}");
+                    continue;
+                };
+
+                results[index] = parse_result(line.take());
+            }
+        }
+
+        Ok(results)
+    }
+
+    async fn retrieve_batch(&self, id: &BatchId) -> Result<BatchObject, BatchError> {
+        let batch = self
+            .batch_request(
+                Method::GET,
+                &format!("/batches/{id}"),
+                "application/json",
+                String::new(),
+            )
+            .await?;
+        Ok(serde_json::from_value(batch)?)
+    }
+
+    async fn batch_request(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: String,
+    ) -> Result<serde_json::Value, BatchError> {
+        let request = Request::builder()
+            .uri(format!("{API_ROOT}{path}"))
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", content_type)
+            .version(Version::HTTP_2)
+            .method(method)
+            .body(body)?;
+        tracing::debug!(
+            "OpenAI batch request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let response = crate::sse::fetch(&*self.transport, request).await?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+}
+
+/// Converts a single line of a batch output or error file into the chunks of its response.
+fn parse_result(mut line: serde_json::Value) -> Result<Vec<crate::Chunk>, BatchError> {
+    let error = line.get_mut("error").map(serde_json::Value::take);
+    if let Some(error) = error.filter(|error| !error.is_null()) {
+        return Err(BatchError::ItemFailed(error));
+    }
+
+    let Some(response) = line.get_mut("response").map(serde_json::Value::take) else {
+        return Err(BatchError::MalformedResponse {
+            message: "expected batch result to have a response",
+            value: line,
+        });
+    };
+    let Some(status) = response
+        .get("status_code")
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|status| u16::try_from(status).ok())
+    else {
+        return Err(BatchError::MalformedResponse {
+            message: "expected batch result to have a status code",
+            value: response,
+        });
+    };
+    let mut body = response
+        .get("body")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+    if !(200..300).contains(&status) {
+        return Err(BatchError::RequestFailed { status, body });
+    }
+
+    let Some(serde_json::Value::Object(mut message)) = body
+        .pointer_mut("/choices/0/message")
+        .map(serde_json::Value::take)
+    else {
+        return Err(BatchError::MalformedResponse {
+            message: "expected OpenAI chat completion to have a message",
+            value: body,
+        });
+    };
+
+    let mut chunks = vec![];
+    if let Some(serde_json::Value::String(text)) = message.remove("content") {
+        if !text.is_empty() {
+            chunks.push(crate::Chunk::Token(text));
+        }
+    }
+    if let Some(serde_json::Value::Array(tool_calls)) = message.remove("tool_calls") {
+        for mut tool_call in tool_calls {
+            let tool_call = super::parse_tool_call(&mut tool_call).map_err(|message| {
+                BatchError::MalformedResponse {
+                    message,
+                    value: tool_call.clone(),
+                }
+            })?;
+            chunks.push(crate::Chunk::ToolCall(tool_call));
+        }
+    }
+
+    Ok(chunks)
+}
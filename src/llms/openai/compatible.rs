@@ -0,0 +1,184 @@
+//! Support for any server implementing OpenAI's chat completions API, such as a
+//! [LiteLLM](https://docs.litellm.ai/docs/simple_proxy) proxy or a self-hosted model server.
+
+use std::{fmt::Display, sync::Arc};
+
+use hyper::{Method, Request, Version};
+
+use crate::{sse::SseClient, transport::Transport};
+
+/// A model served from an OpenAI-compatible `/chat/completions` endpoint.
+///
+/// The server may be reached over `https`, speaking HTTP/2 where it is offered and HTTP/1.1
+/// otherwise, or over plain `http` with HTTP/1.1, as for a proxy running locally.
+///
+/// ```no_run
+/// use lmql::llms::openai::compatible::OpenAICompatible;
+///
+/// // A LiteLLM proxy, routing to whichever deployment is configured as `claude-3-5-sonnet`,
+/// // with requests tagged for spend tracking.
+/// let llm = OpenAICompatible::litellm("http://localhost:4000", "claude-3-5-sonnet", "sk-1234")
+///     .with_header("x-litellm-tags", "team-a,batch-jobs");
+/// ```
+pub struct OpenAICompatible {
+    base_url: String,
+    model: String,
+    bearer_header: Option<String>,
+    headers: Vec<(String, String)>,
+    coalesce_tokens: bool,
+    stream_usage: bool,
+    transport: Arc<dyn Transport>,
+}
+
+impl OpenAICompatible {
+    /// A model served at `base_url`, the url which `/chat/completions` is relative to,
+    /// e.g. `https://api.example.com/v1`.
+    pub fn new(base_url: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            model: model.into(),
+            bearer_header: None,
+            headers: vec![],
+            coalesce_tokens: false,
+            stream_usage: false,
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// A model routed by a LiteLLM proxy, where `model` is the model name configured in the proxy
+    /// and `api_key` is a LiteLLM virtual or master key. A proxy started with the defaults is at
+    /// `http://localhost:4000`.
+    pub fn litellm(
+        base_url: impl Into<String>,
+        model: impl Into<String>,
+        api_key: impl Display,
+    ) -> Self {
+        Self::new(base_url, model).with_api_key(api_key)
+    }
+
+    /// Sends the key as a bearer token in the `Authorization` header.
+    pub fn with_api_key(mut self, api_key: impl Display) -> Self {
+        self.bearer_header = Some(format!("Bearer {api_key}"));
+        self
+    }
+
+    /// Sends an extra header with every request, e.g. for routing or tagging by a proxy.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Joins the text of events which arrive together into a single [`crate::Chunk::Token`],
+    /// rather than returning a chunk per event. For the fastest servers, which send many tokens
+    /// at once, this saves handling each token separately, at the cost of coarser chunks.
+    pub fn with_coalesced_tokens(mut self) -> Self {
+        self.coalesce_tokens = true;
+        self
+    }
+
+    /// Asks the server to end each response with its [`crate::Chunk::Usage`], through
+    /// `stream_options`. Only for servers which support it, such as vLLM, as others may reject
+    /// the request.
+    pub fn with_stream_usage(mut self) -> Self {
+        self.stream_usage = true;
+        self
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// The model to prompt, taking into account any override.
+    fn model<'a>(
+        &'a self,
+        options: &'a crate::PromptOptions,
+    ) -> Result<&'a str, crate::PromptError> {
+        match &options.model_override {
+            None => Ok(&self.model),
+            Some(crate::ModelOverride::Named(model)) => Ok(model),
+            Some(model_override) => Err(crate::PromptError::IncompatibleModelOverride {
+                provider: "OpenAI-compatible",
+                model_override: model_override.clone(),
+            }),
+        }
+    }
+}
+
+impl crate::LLM for OpenAICompatible {
+    type TokenStream = super::OpenAITokenStream;
+
+    fn provider(&self) -> &'static str {
+        "OpenAI-compatible"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.model.clone().into()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        super::chat_completion_body(
+            chat,
+            options,
+            super::ChatCompletionTarget {
+                provider: "OpenAI-compatible",
+                model: self.model(options)?,
+                system_role: "system",
+                supports_temperature: true,
+                supports_tools: true,
+                max_stopping_sequences: None,
+                max_output_tokens: None,
+                supports_store: false,
+                supports_stream_options: self.stream_usage,
+            },
+            true,
+        )
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<super::OpenAITokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!(
+            "OpenAI-compatible request body: {}",
+            crate::logging::body(&body)
+        );
+
+        let mut request = Request::builder()
+            .uri(format!(
+                "{}/chat/completions",
+                self.base_url.trim_end_matches('/')
+            ))
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST);
+        if let Some(bearer_header) = &self.bearer_header {
+            request = request.header("Authorization", bearer_header);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        let request = request.body(body)?;
+        tracing::debug!(
+            "OpenAI-compatible request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let sse = SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout);
+
+        Ok(super::OpenAITokenStream::new(sse, span)
+            .with_coalesced_tokens(self.coalesce_tokens)
+            .with_seed(options.seed))
+    }
+}
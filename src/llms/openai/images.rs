@@ -0,0 +1,101 @@
+//! Support for OpenAI's [image generation](https://platform.openai.com/docs/guides/image-generation)
+//! endpoint.
+
+use hyper::{Method, Request, Version};
+
+use crate::images::{ImageData, ImageGeneration, ImageModel, ImageOptions};
+use crate::{EndpointError, JsonExt};
+
+const IMAGE_GENERATIONS_URL: &str = "https://api.openai.com/v1/images/generations";
+
+impl ImageGeneration for super::Gpt {
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<Vec<ImageData>, EndpointError> {
+        let ImageOptions {
+            model,
+            n,
+            size,
+            quality,
+            response_format,
+        } = options;
+
+        #[derive(Debug, serde::Serialize)]
+        struct OpenAIImageRequest<'a> {
+            model: ImageModel,
+            prompt: &'a str,
+            n: usize,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            size: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            quality: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            response_format: Option<&'a str>,
+        }
+
+        let body = OpenAIImageRequest {
+            model: *model,
+            prompt,
+            n: *n,
+            size: size.as_deref(),
+            quality: quality.as_deref(),
+            // GPT Image always returns base64, and rejects the parameter.
+            response_format: match (model, response_format) {
+                (ImageModel::GptImage1, _) => None,
+                (_, crate::images::ImageResponseFormat::Url) => Some("url"),
+                (_, crate::images::ImageResponseFormat::Base64) => Some("b64_json"),
+            },
+        };
+        let request = Request::builder()
+            .uri(IMAGE_GENERATIONS_URL)
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(serde_json::to_string(&body)?)?;
+        tracing::debug!(
+            "OpenAI image request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let response = crate::sse::fetch(&*self.transport, request).await?;
+        let mut response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        let Some(serde_json::Value::Array(images)) =
+            response.get_mut("data").map(serde_json::Value::take)
+        else {
+            return Err(EndpointError::MalformedResponse {
+                message: "expected image response to have data",
+                value: response,
+            });
+        };
+        // GPT Image gives the format it was asked for, while DALL·E only returns PNGs.
+        let media_type = match response
+            .get("output_format")
+            .and_then(serde_json::Value::as_str)
+        {
+            Some(format) => format!("image/{format}"),
+            None => "image/png".to_owned(),
+        };
+        images
+            .into_iter()
+            .map(|mut image| {
+                if let Some(data) = image.get_mut("b64_json").and_then(JsonExt::take_str) {
+                    return Ok(ImageData::Base64(crate::Image {
+                        media_type: media_type.clone(),
+                        data,
+                    }));
+                }
+                match image.get_mut("url").and_then(JsonExt::take_str) {
+                    Some(url) => Ok(ImageData::Url(url)),
+                    None => Err(EndpointError::MalformedResponse {
+                        message: "expected image to have a url or base64 data",
+                        value: image,
+                    }),
+                }
+            })
+            .collect()
+    }
+}
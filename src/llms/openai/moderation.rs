@@ -0,0 +1,66 @@
+//! Support for OpenAI's [moderation endpoint](https://platform.openai.com/docs/guides/moderation),
+//! for screening text before it is sent to a model.
+
+use std::collections::BTreeMap;
+
+use hyper::{Method, Request, Version};
+
+const MODERATIONS_URL: &str = "https://api.openai.com/v1/moderations";
+const MODERATION_MODEL: &str = "omni-moderation-latest";
+
+/// How the moderation model classified a piece of text.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct ModerationResult {
+    /// Whether any category was flagged.
+    pub flagged: bool,
+    /// Whether each category, e.g. `harassment` or `self-harm/intent`, was flagged.
+    pub categories: BTreeMap<String, bool>,
+    /// The model's confidence in each category, between 0 and 1.
+    pub category_scores: BTreeMap<String, f64>,
+}
+
+impl ModerationResult {
+    /// The names of the categories which were flagged.
+    pub fn flagged_categories(&self) -> impl Iterator<Item = &str> {
+        self.categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.as_str())
+    }
+}
+
+impl super::Gpt {
+    /// Classifies the text with OpenAI's latest moderation model, independently of this
+    /// instance's chat model.
+    pub async fn moderate(&self, input: &str) -> Result<ModerationResult, crate::EndpointError> {
+        let body = serde_json::json!({
+            "model": MODERATION_MODEL,
+            "input": input,
+        });
+        let request = Request::builder()
+            .uri(MODERATIONS_URL)
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(serde_json::to_string(&body)?)?;
+        tracing::debug!(
+            "OpenAI moderation request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let response = crate::sse::fetch(&*self.transport, request).await?;
+        let mut response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        let Some(result) = response
+            .pointer_mut("/results/0")
+            .map(serde_json::Value::take)
+        else {
+            return Err(crate::EndpointError::MalformedResponse {
+                message: "expected moderation response to have a result",
+                value: response,
+            });
+        };
+        Ok(serde_json::from_value(result)?)
+    }
+}
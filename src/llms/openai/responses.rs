@@ -0,0 +1,664 @@
+//! Support for OpenAI's [Responses API](https://platform.openai.com/docs/api-reference/responses),
+//! the successor to chat completions which OpenAI's newer features, such as reasoning summaries,
+//! are built on.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, OnceLock},
+};
+
+use hyper::{Method, Request, Version};
+
+use super::GptModel;
+use crate::{
+    sse::{SseClient, SseTask},
+    transport::Transport,
+    JsonExt,
+};
+
+/// An OpenAI model prompted through `/v1/responses` rather than `/v1/chat/completions`.
+///
+/// The Responses API does not accept stopping sequences or predicted outputs, so these options
+/// are ignored.
+pub struct GptResponses {
+    model: GptModel,
+    bearer_header: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl GptResponses {
+    /// Sugar for [`Self::new`], but uses the `OPENAI_API_KEY` environment variable for the API key.
+    pub fn new_from_env(model: GptModel) -> Self {
+        Self::new(
+            model,
+            std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY environment variable not set"),
+        )
+    }
+
+    pub fn new(model: GptModel, api_key: String) -> Self {
+        Self {
+            model,
+            bearer_header: format!("Bearer {api_key}"),
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+}
+
+impl crate::LLM for GptResponses {
+    type TokenStream = GptResponsesTokenStream;
+
+    fn provider(&self) -> &'static str {
+        "OpenAI"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        crate::llms::model_name(&self.model).into()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.model.supports_tools()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        let crate::PromptOptions {
+            // Resolved against the model's limit.
+            max_tokens: _,
+            temperature,
+            system_prompt: _,
+            stopping_sequences,
+            tools: _,
+            reasoning,
+            model_override,
+            exclude_reasoning,
+            keepalive_timeout: _,
+            idempotency_key: _,
+            stopping_sequence_overflow: _,
+            prediction,
+            extra_body,
+            parallel_tool_calls,
+            tool_choice,
+            reasoning_summary,
+            audio_output: _,
+            correlation_id: _,
+            store,
+            previous_response_id,
+            prompt_template,
+            response_format,
+            seed,
+        } = options;
+
+        if !stopping_sequences.is_empty() {
+            tracing::warn!(
+                "the OpenAI Responses API does not support stopping sequences, ignoring them"
+            );
+        }
+        if prediction.is_some() {
+            tracing::warn!("the OpenAI Responses API does not support predictions, ignoring it");
+        }
+        if seed.is_some() {
+            tracing::warn!("the OpenAI Responses API does not support seeds, ignoring it");
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        enum ResponsesReasoningEffort {
+            #[serde(rename = "low")]
+            Low,
+            #[serde(rename = "medium")]
+            Medium,
+            #[serde(rename = "high")]
+            High,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        enum ResponsesReasoningSummary {
+            #[serde(rename = "auto")]
+            Auto,
+            #[serde(rename = "concise")]
+            Concise,
+            #[serde(rename = "detailed")]
+            Detailed,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesReasoning {
+            effort: ResponsesReasoningEffort,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            summary: Option<ResponsesReasoningSummary>,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesTool<'a> {
+            r#type: &'a str,
+            name: &'a str,
+            description: &'a str,
+            parameters: Cow<'a, schemars::schema::Schema>,
+            /// Tools are strict unless told otherwise, which needs every property to be required
+            /// and no others allowed, as only [`crate::ToolParameters::strict`] schemas promise.
+            strict: bool,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        #[serde(untagged)]
+        enum ResponsesToolChoice<'a> {
+            Mode(&'static str),
+            Function { r#type: &'static str, name: &'a str },
+        }
+
+        /// Text alone is sent as a string, and text with images as a list of parts.
+        #[derive(Debug, serde::Serialize)]
+        #[serde(untagged)]
+        enum ResponsesContent<'a> {
+            Text(&'a str),
+            Parts(Vec<ResponsesContentPart<'a>>),
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum ResponsesContentPart<'a> {
+            InputText { text: &'a str },
+            InputImage { image_url: String },
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        #[serde(tag = "type")]
+        enum ResponsesInputItem<'a> {
+            #[serde(rename = "message")]
+            Message {
+                role: &'a str,
+                content: ResponsesContent<'a>,
+            },
+            #[serde(rename = "function_call")]
+            FunctionCall {
+                call_id: &'a str,
+                name: &'a str,
+                arguments: &'a str,
+            },
+            #[serde(rename = "function_call_output")]
+            FunctionCallOutput { call_id: &'a str, output: &'a str },
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesRequest<'a> {
+            model: GptModel,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            instructions: Option<&'a str>,
+            max_output_tokens: usize,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
+            stream: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            reasoning: Option<ResponsesReasoning>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            tools: Vec<ResponsesTool<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            parallel_tool_calls: Option<bool>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tool_choice: Option<ResponsesToolChoice<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            store: Option<bool>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            previous_response_id: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            prompt: Option<ResponsesPrompt<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            text: Option<ResponsesText>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            input: Vec<ResponsesInputItem<'a>>,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesTextFormat {
+            r#type: &'static str,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesText {
+            format: ResponsesTextFormat,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ResponsesPrompt<'a> {
+            id: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            version: Option<&'a str>,
+            #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+            variables: &'a std::collections::BTreeMap<String, String>,
+        }
+
+        let model = match model_override {
+            None => self.model,
+            Some(crate::ModelOverride::Gpt(model)) => *model,
+            Some(model_override) => {
+                return Err(crate::PromptError::IncompatibleModelOverride {
+                    provider: "OpenAI",
+                    model_override: model_override.clone(),
+                })
+            }
+        };
+
+        let tools = options.resolve_tools()?;
+        let tools = if !tools.is_empty() && !model.supports_tools() {
+            tracing::warn!(
+                "OpenAI model {} does not support tools, ignoring them",
+                crate::llms::model_name(&model)
+            );
+            &[]
+        } else {
+            tools
+        };
+        let tools: Vec<_> = tools
+            .iter()
+            .map(|tool| ResponsesTool {
+                r#type: "function",
+                name: &tool.name,
+                description: &tool.description,
+                parameters: tool.parameters.resolve_strict_schema(),
+                strict: tool.parameters.is_strict(),
+            })
+            .collect();
+
+        let input = chat
+            .iter()
+            .filter_map(|message| {
+                Some(match message {
+                    crate::Message::User(content) => ResponsesInputItem::Message {
+                        role: "user",
+                        content: ResponsesContent::Text(content),
+                    },
+                    crate::Message::UserParts(parts) => ResponsesInputItem::Message {
+                        role: "user",
+                        content: ResponsesContent::Parts(
+                            parts
+                                .iter()
+                                .map(|part| match part {
+                                    crate::ContentPart::Text(text) => {
+                                        ResponsesContentPart::InputText { text }
+                                    }
+                                    crate::ContentPart::Image(image) => {
+                                        ResponsesContentPart::InputImage {
+                                            image_url: format!(
+                                                "data:{};base64,{}",
+                                                image.media_type, image.data
+                                            ),
+                                        }
+                                    }
+                                })
+                                .collect(),
+                        ),
+                    },
+                    crate::Message::Assistant(content) => ResponsesInputItem::Message {
+                        role: "assistant",
+                        content: ResponsesContent::Text(content),
+                    },
+                    crate::Message::Thinking { .. } => {
+                        tracing::warn!("OpenAI does not support thinking messages, ignoring them");
+                        return None;
+                    }
+                    crate::Message::ToolRequest {
+                        id,
+                        name,
+                        arguments,
+                    } => ResponsesInputItem::FunctionCall {
+                        call_id: id,
+                        name,
+                        arguments: &arguments.serialized,
+                    },
+                    crate::Message::ToolResponse {
+                        content,
+                        id,
+                        images,
+                    } => {
+                        if !images.is_empty() {
+                            tracing::warn!("tool responses cannot include images, ignoring them");
+                        }
+                        ResponsesInputItem::FunctionCallOutput {
+                            call_id: id,
+                            output: content,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let body = ResponsesRequest {
+            model,
+            instructions: options.resolve_system_prompt(),
+            max_output_tokens: options
+                .resolve_max_tokens(Some(model.max_output_tokens()))
+                .unwrap_or(crate::DEFAULT_MAX_TOKENS),
+            temperature: temperature.filter(|_| model.supports_temperature()),
+            stream: true,
+            reasoning: reasoning.map(|effort| ResponsesReasoning {
+                effort: match effort.level() {
+                    crate::ReasoningLevel::Low => ResponsesReasoningEffort::Low,
+                    crate::ReasoningLevel::Medium => ResponsesReasoningEffort::Medium,
+                    crate::ReasoningLevel::High => ResponsesReasoningEffort::High,
+                },
+                // Without a summary, the reasoning is never sent.
+                summary: (!exclude_reasoning).then_some(match reasoning_summary {
+                    None | Some(crate::ReasoningSummary::Auto) => ResponsesReasoningSummary::Auto,
+                    Some(crate::ReasoningSummary::Concise) => ResponsesReasoningSummary::Concise,
+                    Some(crate::ReasoningSummary::Detailed) => ResponsesReasoningSummary::Detailed,
+                }),
+            }),
+            parallel_tool_calls: parallel_tool_calls.filter(|_| !tools.is_empty()),
+            tool_choice: tool_choice
+                .as_ref()
+                .filter(|_| !tools.is_empty())
+                .map(|tool_choice| match tool_choice {
+                    crate::ToolChoice::Auto => ResponsesToolChoice::Mode("auto"),
+                    crate::ToolChoice::Any => ResponsesToolChoice::Mode("required"),
+                    crate::ToolChoice::Tool(name) => ResponsesToolChoice::Function {
+                        r#type: "function",
+                        name,
+                    },
+                    crate::ToolChoice::None => ResponsesToolChoice::Mode("none"),
+                }),
+            tools,
+            store: *store,
+            previous_response_id: previous_response_id.as_deref(),
+            prompt: prompt_template
+                .as_ref()
+                .map(|prompt_template| ResponsesPrompt {
+                    id: &prompt_template.id,
+                    version: prompt_template.version.as_deref(),
+                    variables: &prompt_template.variables,
+                }),
+            text: response_format.map(|response_format| ResponsesText {
+                format: match response_format {
+                    crate::ResponseFormat::JsonObject => ResponsesTextFormat {
+                        r#type: "json_object",
+                    },
+                },
+            }),
+            input,
+        };
+        crate::serialize_request_body(&body, extra_body)
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<GptResponsesTokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!(
+            "OpenAI Responses request body: {}",
+            crate::logging::body(&body)
+        );
+
+        let mut request = Request::builder()
+            .uri("https://api.openai.com/v1/responses")
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST);
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        let request = request.body(body)?;
+        tracing::debug!(
+            "OpenAI Responses request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let mut sse = SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout);
+
+        Ok(GptResponsesTokenStream {
+            metadata: sse.metadata(),
+            task: sse.take_task(),
+            stream: Some(Box::pin(sse)),
+            outstanding: VecDeque::new(),
+            called_tool: false,
+            response_id: None,
+            span,
+        })
+    }
+}
+
+pub struct GptResponsesTokenStream {
+    stream: Option<std::pin::Pin<Box<SseClient>>>,
+    task: Option<SseTask>,
+    outstanding: VecDeque<crate::Chunk>,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    /// A completed response does not say whether it stopped to call a tool, so this is tracked.
+    called_tool: bool,
+    response_id: Option<String>,
+    span: crate::span::PromptSpan,
+}
+
+impl GptResponsesTokenStream {
+    /// The status and headers of the response, or `None` if the server has not yet responded successfully.
+    pub fn response_metadata(&self) -> Option<&crate::ResponseMetadata> {
+        self.metadata.get()
+    }
+
+    /// The id of the response, once the server has created it, which can be given as
+    /// [`crate::PromptOptions::previous_response_id`] to continue the conversation if the
+    /// response is stored.
+    pub fn response_id(&self) -> Option<&str> {
+        self.response_id.as_deref()
+    }
+
+    /// A copy of each event read by the stream from now on, as sent by the server, for inspecting
+    /// fields which aren't otherwise supported. Replaces any previous receiver.
+    pub fn raw_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<crate::RawEvent> {
+        crate::sse::SseClient::raw_events(self.stream.as_deref_mut())
+    }
+
+    /// Stops the response and waits for the task which read it to finish, returning any error
+    /// the task hit that the stream has not already returned. The connection is closed in the
+    /// background, and may still be closing when this returns.
+    pub async fn shutdown(mut self) -> Result<(), crate::TokenError> {
+        crate::sse::SseTask::shutdown_stream(self.task.take(), self.stream.take()).await
+    }
+}
+
+impl futures::Stream for GptResponsesTokenStream {
+    type Item = Result<crate::Chunk, crate::TokenError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let _entered = self.span.span().clone().entered();
+        let poll = self.as_mut().poll_chunk(cx);
+        self.span.record(&poll);
+        poll
+    }
+}
+
+impl GptResponsesTokenStream {
+    fn poll_chunk(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<crate::Chunk, crate::TokenError>>> {
+        loop {
+            if let Some(chunk) = self.outstanding.pop_front() {
+                return std::task::Poll::Ready(Some(Ok(chunk)));
+            }
+
+            let Some(stream) = self.stream.as_mut() else {
+                return std::task::Poll::Ready(None);
+            };
+
+            let message = match futures::Stream::poll_next(stream.as_mut(), cx) {
+                std::task::Poll::Ready(None) => {
+                    self.stream = None;
+                    return std::task::Poll::Ready(None);
+                }
+                std::task::Poll::Ready(Some(message)) => message,
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            };
+
+            let message = match message {
+                Err(error) => {
+                    self.stream = None;
+                    return std::task::Poll::Ready(Some(Err(crate::TokenError::ConnectionLost(
+                        error,
+                    ))));
+                }
+                Ok(message) => message,
+            };
+
+            if let Err(error) = self.process_event(&message.event, message.value) {
+                self.stream = None;
+                return std::task::Poll::Ready(Some(Err(error)));
+            }
+        }
+    }
+
+    /// Queues the chunks described by a single event.
+    fn process_event(
+        &mut self,
+        event: &str,
+        mut value: serde_json::Value,
+    ) -> Result<(), crate::TokenError> {
+        match event {
+            "response.created" => {
+                self.response_id = value
+                    .pointer_mut("/response/id")
+                    .and_then(JsonExt::take_str);
+            }
+            "response.output_text.delta" => {
+                if let Some(text) = value.get_mut("delta").and_then(JsonExt::take_str) {
+                    if !text.is_empty() {
+                        self.outstanding.push_back(crate::Chunk::Token(text));
+                    }
+                }
+            }
+            "response.reasoning.delta"
+            | "response.reasoning_text.delta"
+            | "response.reasoning_summary_text.delta" => {
+                if let Some(text) = value.get_mut("delta").and_then(JsonExt::take_str) {
+                    if !text.is_empty() {
+                        self.outstanding.push_back(crate::Chunk::Thinking(text));
+                    }
+                }
+            }
+            "response.output_item.added" => {
+                let index = output_index(&value);
+                let Some(item) = value.get_mut("item") else {
+                    return Err(crate::TokenError::MalformedResponse {
+                        message: "expected OpenAI output item event to have an item",
+                        value,
+                    });
+                };
+                if item.get("type").and_then(serde_json::Value::as_str) != Some("function_call") {
+                    return Ok(());
+                }
+
+                self.called_tool = true;
+                self.outstanding
+                    .push_back(crate::Chunk::ToolCall(crate::ToolCallChunk {
+                        index,
+                        id: item.get_mut("call_id").and_then(JsonExt::take_str),
+                        name: item.get_mut("name").and_then(JsonExt::take_str),
+                        arguments: item
+                            .get_mut("arguments")
+                            .and_then(JsonExt::take_str)
+                            .unwrap_or_default(),
+                    }));
+            }
+            "response.function_call_arguments.delta" => {
+                let index = output_index(&value);
+                let Some(arguments) = value.get_mut("delta").and_then(JsonExt::take_str) else {
+                    return Err(crate::TokenError::MalformedResponse {
+                        message: "expected OpenAI function call arguments event to have a delta",
+                        value,
+                    });
+                };
+                self.outstanding
+                    .push_back(crate::Chunk::ToolCall(crate::ToolCallChunk {
+                        index,
+                        id: None,
+                        name: None,
+                        arguments,
+                    }));
+            }
+            "response.completed" | "response.incomplete" => {
+                let Some(response) = value.get("response") else {
+                    return Err(crate::TokenError::MalformedResponse {
+                        message: "expected OpenAI response event to have a response",
+                        value,
+                    });
+                };
+
+                let reason = response
+                    .pointer("/incomplete_details/reason")
+                    .and_then(serde_json::Value::as_str);
+                let finish_reason = match reason {
+                    None if self.called_tool => crate::FinishReason::ToolUse,
+                    None => crate::FinishReason::EndTurn,
+                    Some("max_output_tokens") => crate::FinishReason::MaxTokens,
+                    Some("content_filter") => crate::FinishReason::ContentFilter,
+                    Some(other) => crate::FinishReason::Other(other.to_owned()),
+                };
+                self.outstanding
+                    .push_back(crate::Chunk::Finish(finish_reason));
+
+                if let Some(usage) = response.get("usage") {
+                    let tokens = |key| {
+                        usage
+                            .get(key)
+                            .and_then(serde_json::Value::as_u64)
+                            .map(|tokens| tokens as usize)
+                    };
+                    self.outstanding
+                        .push_back(crate::Chunk::Usage(crate::Usage {
+                            input_tokens: tokens("input_tokens"),
+                            output_tokens: tokens("output_tokens"),
+                        }));
+                }
+            }
+            "response.failed" | "error" => {
+                let error = value.pointer("/response/error").unwrap_or(&value);
+                let field = |key| error.get(key).and_then(serde_json::Value::as_str);
+                let message = field("message").unwrap_or("the response failed").to_owned();
+                // Internal errors and rate limits may pass, while others, such as an invalid
+                // prompt, would only fail again.
+                let transient = [field("code"), field("type")]
+                    .into_iter()
+                    .flatten()
+                    .any(|code| {
+                        matches!(
+                            code,
+                            "server_error" | "rate_limit_exceeded" | "rate_limit_error"
+                        )
+                    });
+                return Err(if transient {
+                    crate::TokenError::TransientServerError(message)
+                } else {
+                    crate::TokenError::ServerError(message)
+                });
+            }
+            other if other.starts_with("response.") => {
+                // Lifecycle events, such as items and content parts finishing, repeat what the
+                // deltas have already given.
+            }
+            other => return Err(crate::TokenError::UnknownEventType(other.to_owned())),
+        }
+
+        Ok(())
+    }
+}
+
+/// The position of the output item an event refers to.
+fn output_index(value: &serde_json::Value) -> Option<usize> {
+    value
+        .get("output_index")
+        .and_then(serde_json::Value::as_u64)
+        .map(|index| index as usize)
+}
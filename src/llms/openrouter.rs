@@ -1,12 +1,38 @@
-use std::{borrow::Cow, fmt::Display};
+use std::{borrow::Cow, fmt::Display, sync::Arc};
 
 use hyper::{Method, Request, Version};
 
-use crate::sse::SseClient;
+use super::openai::OpenAIContent;
+use crate::{
+    sse::{Fallback, SseClient},
+    transport::Transport,
+};
+
+const CHAT_COMPLETIONS_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+const GENERATION_URL: &str = "https://openrouter.ai/api/v1/generation";
+
+/// What a generation cost, as accounted by OpenRouter.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct GenerationStats {
+    /// The cost of the generation in US dollars, including OpenRouter's fees.
+    #[serde(rename = "total_cost")]
+    pub cost: f64,
+    /// The tokens of the prompt, as counted by the upstream provider.
+    #[serde(rename = "native_tokens_prompt")]
+    pub input_tokens: Option<usize>,
+    /// The tokens of the response, as counted by the upstream provider.
+    #[serde(rename = "native_tokens_completion")]
+    pub output_tokens: Option<usize>,
+    /// The name of the upstream provider which served the generation, e.g. `"Anthropic"`.
+    #[serde(rename = "provider_name")]
+    pub provider: Option<String>,
+}
 
 pub struct OpenRouter {
     model: String,
     bearer_header: String,
+    transport: Arc<dyn Transport>,
+    allow_non_stream_fallback: bool,
 }
 
 impl OpenRouter {
@@ -23,27 +49,142 @@ impl OpenRouter {
         Self {
             model: model.into(),
             bearer_header: format!("Bearer {api_key}"),
+            transport: crate::transport::default_transport(),
+            allow_non_stream_fallback: false,
+        }
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// If a model refuses to stream, sends the prompt again without streaming, and gives the whole
+    /// response as the stream's chunks. Off by default, as a refusal is otherwise reported as an
+    /// error.
+    pub fn with_non_stream_fallback(mut self, allow_non_stream_fallback: bool) -> Self {
+        self.allow_non_stream_fallback = allow_non_stream_fallback;
+        self
+    }
+
+    /// Looks up what a generation cost, by the id given by
+    /// [`crate::llms::openai::OpenAITokenStream::id`]. OpenRouter takes a moment to account for a
+    /// generation once it ends, so looking it up immediately may fail.
+    ///
+    /// As with the crate's other endpoints which answer in one response, this fails with an
+    /// [`EndpointError`](crate::EndpointError) rather than a [`crate::PromptError`], which can't
+    /// describe a lost connection or an unexpected response.
+    pub async fn generation_stats(
+        &self,
+        id: &str,
+    ) -> Result<GenerationStats, crate::EndpointError> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("id", id)
+            .finish();
+        let request = Request::builder()
+            .uri(format!("{GENERATION_URL}?{query}"))
+            .header("Authorization", &self.bearer_header)
+            .version(Version::HTTP_2)
+            .method(Method::GET)
+            .body(String::new())?;
+        tracing::debug!(
+            "OpenRouter generation request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let response = crate::sse::fetch(&*self.transport, request).await?;
+        let mut response = serde_json::from_slice::<serde_json::Value>(&response)?;
+        match response.get_mut("data").map(serde_json::Value::take) {
+            Some(data) => Ok(serde_json::from_value(data)?),
+            None => Err(crate::EndpointError::MalformedResponse {
+                message: "expected generation response to have data",
+                value: response,
+            }),
         }
     }
+
+    /// Builds the request for a prompt, either streamed or not.
+    fn request(
+        &self,
+        body: String,
+        options: &crate::PromptOptions,
+    ) -> Result<Request<String>, crate::PromptError> {
+        let mut request = Request::builder()
+            .uri(CHAT_COMPLETIONS_URL)
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST);
+        if let Some(idempotency_key) = &options.idempotency_key {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        Ok(request.body(body)?)
+    }
 }
 
-impl crate::LLM for OpenRouter {
-    type TokenStream = super::openai::OpenAITokenStream;
+/// Whether the request failed because the model does not support streaming, as told by the error
+/// naming `stream` as the parameter at fault. OpenRouter passes on the upstream provider's error
+/// as its `raw` metadata, so that is checked too.
+fn refuses_streaming(error: &crate::SseError) -> bool {
+    fn rejects_stream(error: &serde_json::Value) -> bool {
+        let error = error.get("error").unwrap_or(error);
+        if error.get("param").and_then(serde_json::Value::as_str) == Some("stream") {
+            return true;
+        }
+        error
+            .pointer("/metadata/raw")
+            .and_then(serde_json::Value::as_str)
+            .and_then(|raw| serde_json::from_str::<serde_json::Value>(raw).ok())
+            .is_some_and(|raw| rejects_stream(&raw))
+    }
 
-    fn prompt(
+    let crate::SseError::StatusError { body, .. } = error else {
+        return false;
+    };
+    serde_json::from_str::<serde_json::Value>(body).is_ok_and(|body| rejects_stream(&body))
+}
+
+impl OpenRouter {
+    /// Serializes the body of a chat completion request, streamed or not.
+    fn request_body(
         &self,
         chat: &[crate::Message],
         options: &crate::PromptOptions,
-    ) -> Result<super::openai::OpenAITokenStream, crate::PromptError> {
+        stream: bool,
+    ) -> Result<String, crate::PromptError> {
         let crate::PromptOptions {
-            max_tokens,
+            max_tokens: _,
             temperature,
-            system_prompt,
-            stopping_sequences,
-            tools,
+            system_prompt: _,
+            stopping_sequences: _,
+            tools: _,
             reasoning,
+            model_override,
+            exclude_reasoning,
+            keepalive_timeout: _,
+            idempotency_key: _,
+            stopping_sequence_overflow: _,
+            prediction,
+            extra_body,
+            parallel_tool_calls,
+            tool_choice,
+            reasoning_summary: _,
+            audio_output: _,
+            correlation_id: _,
+            store: _,
+            previous_response_id: _,
+            prompt_template,
+            response_format,
+            seed,
         } = options;
 
+        if prompt_template.is_some() {
+            return Err(crate::PromptError::UnsupportedPromptTemplate {
+                provider: "OpenRouter",
+            });
+        }
+
         #[derive(Debug, serde::Serialize)]
         enum OpenRouterReasoningEffort {
             #[serde(rename = "low")]
@@ -58,7 +199,9 @@ impl crate::LLM for OpenRouter {
         struct OpenRouterFunctionDescription<'a> {
             name: &'a str,
             description: &'a str,
-            parameters: &'a schemars::schema::Schema,
+            parameters: Cow<'a, schemars::schema::Schema>,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            strict: bool,
         }
 
         #[derive(Debug, serde::Serialize)]
@@ -69,7 +212,14 @@ impl crate::LLM for OpenRouter {
 
         #[derive(Debug, serde::Serialize)]
         struct OpenRouterReasoning {
-            effort: OpenRouterReasoningEffort,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            enabled: Option<bool>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            effort: Option<OpenRouterReasoningEffort>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_tokens: Option<usize>,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            exclude: bool,
         }
 
         #[derive(Debug, serde::Serialize)]
@@ -85,58 +235,72 @@ impl crate::LLM for OpenRouter {
             function: OpenRouterToolCallFunction<'a>,
         }
 
-        #[derive(Debug, serde::Serialize)]
+        #[derive(Debug, Default, serde::Serialize)]
         struct OpenRouterMessage<'a> {
             role: &'a str,
-            #[serde(skip_serializing_if = "str::is_empty")]
-            content: Cow<'a, str>,
+            #[serde(skip_serializing_if = "OpenAIContent::is_empty")]
+            content: OpenAIContent<'a>,
             #[serde(skip_serializing_if = "str::is_empty")]
             tool_call_id: &'a str,
             #[serde(skip_serializing_if = "Vec::is_empty")]
             tool_calls: Vec<OpenRouterToolCall<'a>>,
         }
 
-        impl Default for OpenRouterMessage<'_> {
-            fn default() -> Self {
-                Self {
-                    role: "",
-                    content: Cow::Borrowed(""),
-                    tool_call_id: "",
-                    tool_calls: vec![],
-                }
-            }
+        #[derive(Debug, serde::Serialize)]
+        struct OpenRouterPrediction<'a> {
+            r#type: &'a str,
+            content: &'a str,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct OpenRouterResponseFormat {
+            r#type: &'static str,
         }
 
         #[derive(Debug, serde::Serialize)]
         struct OpenRouterRequest<'a> {
             model: &'a str,
-            max_tokens: usize,
-            temperature: f32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_tokens: Option<usize>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
             stream: bool,
             #[serde(skip_serializing_if = "<[String]>::is_empty")]
             stop: &'a [String],
             tools: Vec<OpenRouterTool<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            parallel_tool_calls: Option<bool>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tool_choice: Option<super::openai::OpenAIToolChoice<'a>>,
             reasoning: Option<OpenRouterReasoning>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            prediction: Option<OpenRouterPrediction<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            response_format: Option<OpenRouterResponseFormat>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            seed: Option<u64>,
             messages: Vec<OpenRouterMessage<'a>>,
         }
 
-        let tools = tools
+        let tools: Vec<_> = options
+            .resolve_tools()?
             .iter()
             .map(|tool| OpenRouterTool {
                 r#type: "function",
                 function: OpenRouterFunctionDescription {
                     name: &tool.name,
                     description: &tool.description,
-                    parameters: &tool.parameters.inner,
+                    parameters: tool.parameters.resolve_strict_schema(),
+                    strict: tool.parameters.is_strict(),
                 },
             })
             .collect();
 
         let mut messages = vec![];
-        if let Some(system_prompt) = system_prompt {
+        if let Some(system_prompt) = options.resolve_system_prompt() {
             messages.push(OpenRouterMessage {
                 role: "system",
-                content: Cow::Borrowed(system_prompt),
+                content: OpenAIContent::Text(Cow::Borrowed(system_prompt)),
                 ..OpenRouterMessage::default()
             });
         }
@@ -153,18 +317,14 @@ impl crate::LLM for OpenRouter {
             // Try collate
             if let Some(last) = messages.last_mut() {
                 if last.role == role {
-                    if !last.content.is_empty() {
-                        last.content = Cow::Owned(format!("{}\n\n{}", last.content, content));
-                    } else {
-                        last.content = Cow::Borrowed(content);
-                    }
+                    last.content.push_text(content);
                     return None;
                 }
             }
 
             Some(OpenRouterMessage {
                 role,
-                content: Cow::Borrowed(content),
+                content: OpenAIContent::Text(Cow::Borrowed(content)),
                 ..OpenRouterMessage::default()
             })
         }
@@ -177,19 +337,43 @@ impl crate::LLM for OpenRouter {
                     };
                     message
                 }
+                crate::Message::UserParts(parts) => {
+                    // Try collate
+                    if let Some(last) = messages.last_mut() {
+                        if last.role == "user" {
+                            last.content.push_parts(parts);
+                            return;
+                        }
+                    }
+
+                    let mut content = OpenAIContent::default();
+                    content.push_parts(parts);
+                    if content.is_empty() {
+                        return;
+                    }
+                    OpenRouterMessage {
+                        role: "user",
+                        content,
+                        ..OpenRouterMessage::default()
+                    }
+                }
                 crate::Message::Assistant(content) => {
                     let Some(message) = try_append_text(messages, content, "assistant") else {
                         return;
                     };
                     message
                 }
+                crate::Message::Thinking { .. } => {
+                    tracing::warn!("OpenRouter does not support thinking messages, ignoring them");
+                    return;
+                }
                 crate::Message::ToolRequest {
                     id,
                     name,
                     arguments,
                 } => {
                     let tool_request = OpenRouterToolCall {
-                        id: &id,
+                        id,
                         r#type: "function",
                         function: OpenRouterToolCallFunction {
                             name,
@@ -212,12 +396,21 @@ impl crate::LLM for OpenRouter {
                         ..OpenRouterMessage::default()
                     }
                 }
-                crate::Message::ToolResponse { content, id } => OpenRouterMessage {
-                    role: "tool",
-                    content: Cow::Borrowed(content),
-                    tool_call_id: &id,
-                    ..OpenRouterMessage::default()
-                },
+                crate::Message::ToolResponse {
+                    content,
+                    id,
+                    images,
+                } => {
+                    if !images.is_empty() {
+                        tracing::warn!("tool responses cannot include images, ignoring them");
+                    }
+                    OpenRouterMessage {
+                        role: "tool",
+                        content: OpenAIContent::Text(Cow::Borrowed(content)),
+                        tool_call_id: id,
+                        ..OpenRouterMessage::default()
+                    }
+                }
             };
 
             messages.push(new_message);
@@ -228,34 +421,114 @@ impl crate::LLM for OpenRouter {
         }
 
         let body = OpenRouterRequest {
-            model: &self.model,
-            max_tokens: *max_tokens,
+            model: match model_override {
+                None => &self.model,
+                Some(crate::ModelOverride::Named(model)) => model,
+                Some(model_override) => {
+                    return Err(crate::PromptError::IncompatibleModelOverride {
+                        provider: "OpenRouter",
+                        model_override: model_override.clone(),
+                    })
+                }
+            },
+            // Models are named freely, so their limits aren't known.
+            max_tokens: options.resolve_max_tokens(None),
             temperature: *temperature,
-            stop: stopping_sequences.as_slice(),
-            stream: true,
+            // The limit is that of whichever provider OpenRouter routes the model to.
+            stop: options.resolve_stopping_sequences("OpenRouter", None)?,
+            stream,
+            parallel_tool_calls: parallel_tool_calls.filter(|_| !tools.is_empty()),
+            tool_choice: tool_choice
+                .as_ref()
+                .filter(|_| !tools.is_empty())
+                .map(super::openai::OpenAIToolChoice::new),
             tools,
+            // OpenRouter converts a budget to an effort level for models which only take a level.
             reasoning: reasoning.map(|effort| OpenRouterReasoning {
+                enabled: match effort {
+                    crate::ReasoningEffort::Off => Some(false),
+                    _ => None,
+                },
                 effort: match effort {
-                    crate::ReasoningEffort::Low => OpenRouterReasoningEffort::Low,
-                    crate::ReasoningEffort::Medium => OpenRouterReasoningEffort::Medium,
-                    crate::ReasoningEffort::High => OpenRouterReasoningEffort::High,
+                    crate::ReasoningEffort::Off | crate::ReasoningEffort::Budget(_) => None,
+                    effort => Some(match effort.level() {
+                        crate::ReasoningLevel::Low => OpenRouterReasoningEffort::Low,
+                        crate::ReasoningLevel::Medium => OpenRouterReasoningEffort::Medium,
+                        crate::ReasoningLevel::High => OpenRouterReasoningEffort::High,
+                    }),
+                },
+                max_tokens: match effort {
+                    crate::ReasoningEffort::Budget(budget) => Some(budget),
+                    _ => None,
+                },
+                exclude: *exclude_reasoning,
+            }),
+            prediction: prediction.as_deref().map(|content| OpenRouterPrediction {
+                r#type: "content",
+                content,
+            }),
+            response_format: response_format.map(|response_format| match response_format {
+                crate::ResponseFormat::JsonObject => OpenRouterResponseFormat {
+                    r#type: "json_object",
                 },
             }),
+            seed: *seed,
             messages,
         };
-        let body = serde_json::to_string(&body)?;
-        tracing::debug!("OpenRouter request body: {}", body);
+        crate::serialize_request_body(&body, extra_body)
+    }
+}
 
-        let request = Request::builder()
-            .uri("https://openrouter.ai/api/v1/chat/completions")
-            .header("Authorization", &self.bearer_header)
-            .header("content-type", "application/json")
-            .version(Version::HTTP_2)
-            .method(Method::POST)
-            .body(body)?;
-        tracing::debug!("OpenRouter request: {:#?}", request);
-        let sse = SseClient::spawn(request);
+impl crate::LLM for OpenRouter {
+    type TokenStream = super::openai::OpenAITokenStream;
+
+    fn provider(&self) -> &'static str {
+        "OpenRouter"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.model.clone().into()
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        self.request_body(chat, options, true)
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<super::openai::OpenAITokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("OpenRouter request body: {}", crate::logging::body(&body));
+
+        let request = self.request(body, options)?;
+        tracing::debug!(
+            "OpenRouter request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+        let sse = if self.allow_non_stream_fallback {
+            let fallback = Fallback {
+                request: self.request(self.request_body(chat, options, false)?, options)?,
+                applies: refuses_streaming,
+                into_events: super::openai::completion_into_events,
+            };
+            SseClient::spawn_with_fallback(
+                self.transport.clone(),
+                request,
+                fallback,
+                options.keepalive_timeout,
+            )
+        } else {
+            SseClient::spawn(self.transport.clone(), request, options.keepalive_timeout)
+        };
 
-        Ok(super::openai::OpenAITokenStream::new(sse))
+        Ok(super::openai::OpenAITokenStream::new(sse, span).with_seed(options.seed))
     }
 }
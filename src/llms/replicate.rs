@@ -0,0 +1,403 @@
+use std::{borrow::Cow, fmt::Display, future::Future, pin::Pin, sync::Arc};
+
+use hyper::{Method, Request, Version};
+
+use crate::{
+    sse::{SseClient, SseTask},
+    transport::Transport,
+    JsonExt,
+};
+
+/// A language model hosted on [Replicate](https://replicate.com), such as
+/// `meta/meta-llama-3-70b-instruct`. Only models which support streaming can be used.
+///
+/// Replicate models each define their own inputs, so only the common `prompt`, `system_prompt`,
+/// `max_tokens`, `temperature` and `stop_sequences` inputs are sent. Tools are not supported.
+pub struct Replicate {
+    model: String,
+    bearer_header: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl Replicate {
+    /// Sugar for [`Self::new`], but uses the `REPLICATE_API_TOKEN` environment variable for the API key.
+    pub fn new_from_env(model: impl Into<String>) -> Self {
+        Self::new(
+            model,
+            std::env::var("REPLICATE_API_TOKEN")
+                .expect("REPLICATE_API_TOKEN environment variable not set"),
+        )
+    }
+
+    pub fn new(model: impl Into<String>, api_key: impl Display) -> Self {
+        Self {
+            model: model.into(),
+            bearer_header: format!("Bearer {api_key}"),
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+}
+
+impl Replicate {
+    /// The model to prompt, taking into account any override.
+    fn model<'a>(
+        &'a self,
+        options: &'a crate::PromptOptions,
+    ) -> Result<&'a str, crate::PromptError> {
+        match &options.model_override {
+            None => Ok(&self.model),
+            Some(crate::ModelOverride::Named(model)) => Ok(model),
+            Some(model_override) => Err(crate::PromptError::IncompatibleModelOverride {
+                provider: "Replicate",
+                model_override: model_override.clone(),
+            }),
+        }
+    }
+}
+
+impl crate::LLM for Replicate {
+    type TokenStream = ReplicateTokenStream;
+
+    fn provider(&self) -> &'static str {
+        "Replicate"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.model.clone().into()
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        let crate::PromptOptions {
+            max_tokens: _,
+            temperature,
+            system_prompt: _,
+            stopping_sequences: _,
+            tools,
+            reasoning: _,
+            model_override: _,
+            exclude_reasoning: _,
+            keepalive_timeout: _,
+            idempotency_key: _,
+            stopping_sequence_overflow: _,
+            prediction: _,
+            extra_body,
+            parallel_tool_calls: _,
+            tool_choice: _,
+            reasoning_summary: _,
+            audio_output: _,
+            correlation_id: _,
+            store: _,
+            previous_response_id: _,
+            prompt_template,
+            response_format,
+            seed,
+        } = options;
+
+        if prompt_template.is_some() {
+            return Err(crate::PromptError::UnsupportedPromptTemplate {
+                provider: "Replicate",
+            });
+        }
+        if response_format.is_some() {
+            tracing::warn!("Replicate models do not support response formats, ignoring it");
+        }
+        if seed.is_some() {
+            tracing::warn!("Replicate models do not support seeds, ignoring it");
+        }
+
+        if !tools.is_empty() {
+            tracing::warn!("Replicate models do not support tools, ignoring them");
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ReplicateInput<'a> {
+            prompt: Cow<'a, str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            system_prompt: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_tokens: Option<usize>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
+            #[serde(skip_serializing_if = "String::is_empty")]
+            stop_sequences: String,
+        }
+
+        #[derive(Debug, serde::Serialize)]
+        struct ReplicateRequest<'a> {
+            input: ReplicateInput<'a>,
+            stream: bool,
+        }
+
+        // Replicate models take a single prompt, so a longer conversation is given as a transcript.
+        let prompt = match chat {
+            [crate::Message::User(content)] => Cow::Borrowed(content.as_str()),
+            chat => {
+                let mut transcript = String::new();
+                for message in chat {
+                    let (role, content) = match message {
+                        crate::Message::User(content) => ("User", Cow::Borrowed(content.as_str())),
+                        crate::Message::UserParts(parts) => {
+                            if parts.iter().any(|part| part.as_text().is_none()) {
+                                tracing::warn!(
+                                    "Replicate models do not support images, ignoring them"
+                                );
+                            }
+                            ("User", Cow::Owned(crate::joined_text(parts)))
+                        }
+                        crate::Message::Assistant(content) => {
+                            ("Assistant", Cow::Borrowed(content.as_str()))
+                        }
+                        crate::Message::ToolRequest { .. }
+                        | crate::Message::ToolResponse { .. } => {
+                            tracing::warn!(
+                                "Replicate models do not support tools, ignoring tool message"
+                            );
+                            continue;
+                        }
+                        crate::Message::Thinking { .. } => {
+                            tracing::warn!(
+                                "Replicate models do not support thinking messages, ignoring them"
+                            );
+                            continue;
+                        }
+                    };
+                    transcript.push_str(role);
+                    transcript.push_str(": ");
+                    transcript.push_str(&content);
+                    transcript.push_str("\n\n");
+                }
+                transcript.push_str("Assistant:");
+                Cow::Owned(transcript)
+            }
+        };
+
+        let body = ReplicateRequest {
+            input: ReplicateInput {
+                prompt,
+                system_prompt: options.resolve_system_prompt(),
+                max_tokens: options.resolve_max_tokens(None),
+                temperature: *temperature,
+                // Sent as a single string, which Replicate doesn't limit.
+                stop_sequences: options
+                    .resolve_stopping_sequences("Replicate", None)?
+                    .join(","),
+            },
+            stream: true,
+        };
+        crate::serialize_request_body(&body, extra_body)
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<ReplicateTokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("Replicate request body: {}", crate::logging::body(&body));
+
+        let request = Request::builder()
+            .uri(format!(
+                "https://api.replicate.com/v1/models/{}/predictions",
+                self.model(options)?
+            ))
+            .header("Authorization", &self.bearer_header)
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(body)?;
+        tracing::debug!(
+            "Replicate request: {:#?}",
+            crate::logging::LoggedRequest(&request)
+        );
+
+        let bearer_header = self.bearer_header.clone();
+        let keepalive_timeout = options.keepalive_timeout;
+        let transport = self.transport.clone();
+        let start = async move {
+            let response = crate::sse::fetch(&*transport, request).await?;
+            let mut prediction = serde_json::from_slice::<serde_json::Value>(&response)
+                .map_err(crate::SseError::from)?;
+
+            let Some(stream_url) = prediction
+                .pointer_mut("/urls/stream")
+                .and_then(JsonExt::take_str)
+            else {
+                return Err(crate::TokenError::MalformedResponse {
+                    message: "expected Replicate prediction to have a stream url",
+                    value: prediction,
+                });
+            };
+
+            let request = Request::builder()
+                .uri(stream_url)
+                .header("Authorization", bearer_header)
+                .header("accept", "text/event-stream")
+                .header("cache-control", "no-store")
+                .version(Version::HTTP_2)
+                .method(Method::GET)
+                .body(String::new())
+                .map_err(crate::SseError::from)?;
+            tracing::debug!(
+                "Replicate stream request: {:#?}",
+                crate::logging::LoggedRequest(&request)
+            );
+
+            Ok(SseClient::spawn_text(transport, request, keepalive_timeout))
+        };
+
+        Ok(ReplicateTokenStream {
+            state: ReplicateState::Starting(Box::pin(start)),
+            task: None,
+            raw: None,
+            span,
+        })
+    }
+}
+
+type StartPrediction = Pin<Box<dyn Future<Output = Result<SseClient, crate::TokenError>> + Send>>;
+
+enum ReplicateState {
+    /// Creating the prediction, which gives the url to stream from.
+    Starting(StartPrediction),
+    Streaming(Pin<Box<SseClient>>),
+    Finished,
+}
+
+pub struct ReplicateTokenStream {
+    state: ReplicateState,
+    /// The task streaming the prediction, once it has started.
+    task: Option<SseTask>,
+    /// Where to send a copy of each event, once the prediction has started.
+    raw: Option<tokio::sync::mpsc::UnboundedSender<crate::RawEvent>>,
+    span: crate::span::PromptSpan,
+}
+
+impl futures::Stream for ReplicateTokenStream {
+    type Item = Result<crate::Chunk, crate::TokenError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let _entered = self.span.span().clone().entered();
+        let poll = self.as_mut().poll_chunk(cx);
+        self.span.record(&poll);
+        poll
+    }
+}
+
+impl ReplicateTokenStream {
+    /// A copy of each event read by the stream from now on, as sent by the server, for inspecting
+    /// fields which aren't otherwise supported. Replaces any previous receiver.
+    pub fn raw_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<crate::RawEvent> {
+        match &mut self.state {
+            // The events are sent on once the prediction is created and its stream starts.
+            ReplicateState::Starting(_) => {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                self.raw = Some(tx);
+                rx
+            }
+            ReplicateState::Streaming(stream) => {
+                crate::sse::SseClient::raw_events(Some(stream.as_mut().get_mut()))
+            }
+            ReplicateState::Finished => crate::sse::SseClient::raw_events(None),
+        }
+    }
+
+    /// Stops the response and waits for the task which read it to finish, returning any error
+    /// the task hit that the stream has not already returned. The connection is closed in the
+    /// background, and may still be closing when this returns. A prediction which is still
+    /// being created is abandoned.
+    pub async fn shutdown(mut self) -> Result<(), crate::TokenError> {
+        let stream = match std::mem::replace(&mut self.state, ReplicateState::Finished) {
+            ReplicateState::Streaming(stream) => Some(stream),
+            ReplicateState::Starting(_) | ReplicateState::Finished => None,
+        };
+        crate::sse::SseTask::shutdown_stream(self.task.take(), stream).await
+    }
+
+    fn poll_chunk(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<crate::Chunk, crate::TokenError>>> {
+        loop {
+            let stream = match &mut self.state {
+                ReplicateState::Finished => return std::task::Poll::Ready(None),
+                ReplicateState::Starting(start) => match start.as_mut().poll(cx) {
+                    std::task::Poll::Pending => return std::task::Poll::Pending,
+                    std::task::Poll::Ready(Ok(mut sse)) => {
+                        self.task = sse.take_task();
+                        if let Some(raw) = self.raw.take() {
+                            sse.tee_raw_events(raw);
+                        }
+                        self.state = ReplicateState::Streaming(Box::pin(sse));
+                        continue;
+                    }
+                    std::task::Poll::Ready(Err(error)) => {
+                        self.state = ReplicateState::Finished;
+                        return std::task::Poll::Ready(Some(Err(error)));
+                    }
+                },
+                ReplicateState::Streaming(stream) => stream,
+            };
+
+            let message = match futures::Stream::poll_next(stream.as_mut(), cx) {
+                std::task::Poll::Ready(None) => {
+                    self.state = ReplicateState::Finished;
+                    return std::task::Poll::Ready(None);
+                }
+                std::task::Poll::Ready(Some(message)) => message,
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            };
+
+            let mut message = match message {
+                Err(error) => {
+                    self.state = ReplicateState::Finished;
+                    return std::task::Poll::Ready(Some(Err(crate::TokenError::ConnectionLost(
+                        error,
+                    ))));
+                }
+                Ok(message) => message,
+            };
+
+            let data = message.value.take_str().unwrap_or_default();
+            match message.event.as_str() {
+                "output" => {
+                    if data.is_empty() {
+                        continue;
+                    }
+                    return std::task::Poll::Ready(Some(Ok(crate::Chunk::Token(data))));
+                }
+                "error" => {
+                    self.state = ReplicateState::Finished;
+                    return std::task::Poll::Ready(Some(Err(crate::TokenError::ServerError(data))));
+                }
+                "done" => {
+                    self.state = ReplicateState::Finished;
+                    return std::task::Poll::Ready(None);
+                }
+                other => {
+                    return std::task::Poll::Ready(Some(Err(crate::TokenError::UnknownEventType(
+                        other.to_owned(),
+                    ))))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,668 @@
+//! Models hosted on Google Cloud's [Vertex AI](https://cloud.google.com/vertex-ai), which
+//! authorizes requests with Google OAuth2 access tokens rather than an API key.
+//!
+//! Requires the `vertex` feature.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, OnceLock},
+};
+
+use hyper::{Method, Request, Version};
+
+use crate::{
+    sse::{SseClient, SseTask},
+    transport::Transport,
+};
+
+mod auth;
+
+pub use auth::{Credentials, CredentialsError};
+
+/// The most stopping sequences Gemini accepts.
+const MAX_GEMINI_STOPPING_SEQUENCES: usize = 5;
+
+/// A model available through Vertex AI, named as in the Vertex AI model garden.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VertexModel {
+    /// A Gemini model, such as `gemini-2.0-flash`.
+    Gemini(String),
+    /// An Anthropic model, such as `claude-3-5-sonnet-v2@20241022`.
+    Claude(String),
+}
+
+pub struct Vertex {
+    project: String,
+    region: String,
+    model: VertexModel,
+    tokens: Arc<auth::TokenSource>,
+    transport: Arc<dyn Transport>,
+}
+
+impl Vertex {
+    /// Sugar for [`Self::new`], but uses the `GOOGLE_CLOUD_PROJECT` environment variable for the
+    /// project, and the application default credentials.
+    pub fn new_from_env(region: impl Into<String>, model: VertexModel) -> Self {
+        Self::new(
+            std::env::var("GOOGLE_CLOUD_PROJECT")
+                .expect("GOOGLE_CLOUD_PROJECT environment variable not set"),
+            region,
+            model,
+            Credentials::application_default()
+                .expect("application default credentials could not be loaded"),
+        )
+    }
+
+    pub fn new(
+        project: impl Into<String>,
+        region: impl Into<String>,
+        model: VertexModel,
+        credentials: Credentials,
+    ) -> Self {
+        Self {
+            project: project.into(),
+            region: region.into(),
+            model,
+            tokens: Arc::new(auth::TokenSource::new(credentials)),
+            transport: crate::transport::default_transport(),
+        }
+    }
+
+    /// Sends requests over the given transport, rather than over TLS.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// The model to prompt, taking into account any override. Overrides keep the publisher of
+    /// the model the provider was created with.
+    fn model<'a>(
+        &'a self,
+        options: &'a crate::PromptOptions,
+    ) -> Result<&'a str, crate::PromptError> {
+        match &options.model_override {
+            None => match &self.model {
+                VertexModel::Gemini(model) | VertexModel::Claude(model) => Ok(model),
+            },
+            Some(crate::ModelOverride::Named(model)) => Ok(model),
+            Some(model_override) => Err(crate::PromptError::IncompatibleModelOverride {
+                provider: "Vertex AI",
+                model_override: model_override.clone(),
+            }),
+        }
+    }
+
+    /// The streaming endpoint of the given model.
+    fn url(&self, model: &str) -> String {
+        let host = match self.region.as_str() {
+            "global" => "aiplatform.googleapis.com".to_owned(),
+            region => format!("{region}-aiplatform.googleapis.com"),
+        };
+        let (publisher, method) = match self.model {
+            VertexModel::Gemini(_) => ("google", "streamGenerateContent?alt=sse"),
+            VertexModel::Claude(_) => ("anthropic", "streamRawPredict"),
+        };
+        format!(
+            "https://{host}/v1/projects/{}/locations/{}/publishers/{publisher}/models/{model}:{method}",
+            self.project, self.region
+        )
+    }
+}
+
+impl crate::LLM for Vertex {
+    type TokenStream = VertexTokenStream;
+
+    fn provider(&self) -> &'static str {
+        "Vertex AI"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        match &self.model {
+            VertexModel::Gemini(model) | VertexModel::Claude(model) => model.clone().into(),
+        }
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<String, crate::PromptError> {
+        // Vertex AI takes the model in the url, but an incompatible override should still fail.
+        let model = self.model(options)?;
+        match self.model {
+            VertexModel::Gemini(_) => gemini_request_body(chat, options),
+            VertexModel::Claude(_) => super::anthropic::request_body(
+                chat,
+                options,
+                super::anthropic::ClaudeEndpoint::Vertex(model),
+            ),
+        }
+    }
+
+    fn prompt(
+        &self,
+        chat: &[crate::Message],
+        options: &crate::PromptOptions,
+    ) -> Result<VertexTokenStream, crate::PromptError> {
+        let body = self.build_request_body(chat, options)?;
+        let model = self.model(options)?;
+        let span = crate::span::PromptSpan::new(self, options);
+        let _entered = span.span().clone().entered();
+        tracing::debug!("Vertex AI request body: {}", crate::logging::body(&body));
+
+        let request = Request::builder()
+            .uri(self.url(model))
+            .header("content-type", "application/json")
+            .version(Version::HTTP_2)
+            .method(Method::POST);
+
+        // The request can only be sent once there is an access token to send with it.
+        let tokens = self.tokens.clone();
+        let transport = self.transport.clone();
+        let request = async move {
+            let bearer_header = tokens.bearer_header(&*transport).await?;
+            let request = request.header("Authorization", bearer_header).body(body)?;
+            tracing::debug!(
+                "Vertex AI request: {:#?}",
+                crate::logging::LoggedRequest(&request)
+            );
+            Ok(request)
+        };
+        let mut sse =
+            SseClient::spawn_deferred(self.transport.clone(), request, options.keepalive_timeout);
+
+        Ok(match self.model {
+            VertexModel::Gemini(_) => VertexTokenStream::Gemini(GeminiTokenStream {
+                metadata: sse.metadata(),
+                task: sse.take_task(),
+                stream: Some(Box::pin(sse)),
+                outstanding: VecDeque::new(),
+                tool_calls: 0,
+                span,
+            }),
+            VertexModel::Claude(_) => VertexTokenStream::Claude(
+                super::anthropic::ClaudeTokenStream::new(sse, options.exclude_reasoning, span),
+            ),
+        })
+    }
+}
+
+/// Serializes a Gemini `generateContent` request body for the given conversation.
+fn gemini_request_body(
+    chat: &[crate::Message],
+    options: &crate::PromptOptions,
+) -> Result<String, crate::PromptError> {
+    let crate::PromptOptions {
+        max_tokens: _,
+        temperature,
+        system_prompt: _,
+        stopping_sequences: _,
+        tools: _,
+        reasoning,
+        model_override: _,
+        exclude_reasoning,
+        keepalive_timeout: _,
+        idempotency_key: _,
+        stopping_sequence_overflow: _,
+        prediction,
+        extra_body,
+        parallel_tool_calls: _,
+        tool_choice,
+        reasoning_summary: _,
+        audio_output: _,
+        correlation_id: _,
+        store: _,
+        previous_response_id: _,
+        prompt_template,
+        response_format,
+        seed,
+    } = options;
+
+    if prediction.is_some() {
+        tracing::warn!("Gemini does not support predictions, ignoring it");
+    }
+    if prompt_template.is_some() {
+        return Err(crate::PromptError::UnsupportedPromptTemplate { provider: "Gemini" });
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    enum GeminiPart<'a> {
+        Text(&'a str),
+        #[serde(rename_all = "camelCase")]
+        InlineData {
+            mime_type: &'a str,
+            data: &'a str,
+        },
+        FunctionCall {
+            name: &'a str,
+            args: &'a serde_json::Value,
+        },
+        FunctionResponse {
+            name: &'a str,
+            response: serde_json::Value,
+        },
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct GeminiContent<'a> {
+        role: &'a str,
+        parts: Vec<GeminiPart<'a>>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct GeminiSystemInstruction<'a> {
+        parts: Vec<GeminiPart<'a>>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    struct GeminiFunctionDeclaration<'a> {
+        name: &'a str,
+        description: &'a str,
+        parameters: serde_json::Value,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiTool<'a> {
+        function_declarations: Vec<GeminiFunctionDeclaration<'a>>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiFunctionCallingConfig<'a> {
+        mode: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        allowed_function_names: Option<[&'a str; 1]>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiToolConfig<'a> {
+        function_calling_config: GeminiFunctionCallingConfig<'a>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiThinkingConfig {
+        thinking_budget: usize,
+        include_thoughts: bool,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiGenerationConfig<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_output_tokens: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        stop_sequences: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thinking_config: Option<GeminiThinkingConfig>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response_mime_type: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+    }
+
+    #[derive(Debug, serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GeminiRequest<'a> {
+        contents: Vec<GeminiContent<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system_instruction: Option<GeminiSystemInstruction<'a>>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tools: Vec<GeminiTool<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_config: Option<GeminiToolConfig<'a>>,
+        generation_config: GeminiGenerationConfig<'a>,
+    }
+
+    let mut contents: Vec<GeminiContent> = vec![];
+    for message in chat {
+        let (role, parts) = match message {
+            crate::Message::User(content) => ("user", vec![GeminiPart::Text(content)]),
+            crate::Message::UserParts(parts) => (
+                "user",
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        crate::ContentPart::Text(text) => GeminiPart::Text(text),
+                        crate::ContentPart::Image(image) => GeminiPart::InlineData {
+                            mime_type: &image.media_type,
+                            data: &image.data,
+                        },
+                    })
+                    .collect(),
+            ),
+            crate::Message::Assistant(content) => ("model", vec![GeminiPart::Text(content)]),
+            crate::Message::Thinking { .. } => {
+                tracing::warn!("Gemini does not support thinking messages, ignoring them");
+                continue;
+            }
+            crate::Message::ToolRequest {
+                name, arguments, ..
+            } => (
+                "model",
+                vec![GeminiPart::FunctionCall {
+                    name,
+                    args: &arguments.raw,
+                }],
+            ),
+            crate::Message::ToolResponse {
+                content,
+                id,
+                images,
+            } => {
+                if !images.is_empty() {
+                    tracing::warn!("tool responses cannot include images, ignoring them");
+                }
+                // Gemini matches responses to calls by name, so find the call being responded to.
+                let name = chat
+                    .iter()
+                    .find_map(|message| match message {
+                        crate::Message::ToolRequest {
+                            id: request_id,
+                            name,
+                            ..
+                        } if request_id == id => Some(name.as_str()),
+                        _ => None,
+                    })
+                    .unwrap_or(id);
+                (
+                    "user",
+                    vec![GeminiPart::FunctionResponse {
+                        name,
+                        response: serde_json::json!({ "content": content }),
+                    }],
+                )
+            }
+        };
+
+        // Try collate
+        if let Some(last) = contents.last_mut() {
+            if last.role == role {
+                last.parts.extend(parts);
+                continue;
+            }
+        }
+        contents.push(GeminiContent { role, parts });
+    }
+
+    let tools = options.resolve_tools()?;
+    let tools = if tools.is_empty() {
+        vec![]
+    } else {
+        let function_declarations = tools
+            .iter()
+            .map(|tool| {
+                // Gemini rejects schema keywords it does not recognise.
+                let mut parameters = serde_json::to_value(&tool.parameters.inner)?;
+                if let Some(parameters) = parameters.as_object_mut() {
+                    parameters.remove("$schema");
+                }
+                Ok(GeminiFunctionDeclaration {
+                    name: &tool.name,
+                    description: &tool.description,
+                    parameters,
+                })
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        vec![GeminiTool {
+            function_declarations,
+        }]
+    };
+
+    let body = GeminiRequest {
+        contents,
+        system_instruction: options.resolve_system_prompt().map(|system_prompt| {
+            GeminiSystemInstruction {
+                parts: vec![GeminiPart::Text(system_prompt)],
+            }
+        }),
+        tool_config: tool_choice
+            .as_ref()
+            .filter(|_| !tools.is_empty())
+            .map(|tool_choice| GeminiToolConfig {
+                function_calling_config: match tool_choice {
+                    crate::ToolChoice::Auto => GeminiFunctionCallingConfig {
+                        mode: "AUTO",
+                        allowed_function_names: None,
+                    },
+                    crate::ToolChoice::Any => GeminiFunctionCallingConfig {
+                        mode: "ANY",
+                        allowed_function_names: None,
+                    },
+                    crate::ToolChoice::Tool(name) => GeminiFunctionCallingConfig {
+                        mode: "ANY",
+                        allowed_function_names: Some([name]),
+                    },
+                    crate::ToolChoice::None => GeminiFunctionCallingConfig {
+                        mode: "NONE",
+                        allowed_function_names: None,
+                    },
+                },
+            }),
+        tools,
+        generation_config: GeminiGenerationConfig {
+            max_output_tokens: options.resolve_max_tokens(None),
+            temperature: *temperature,
+            stop_sequences: options
+                .resolve_stopping_sequences("Gemini", Some(MAX_GEMINI_STOPPING_SEQUENCES))?,
+            // A budget of zero turns thinking off.
+            thinking_config: reasoning.map(|effort| {
+                let thinking_budget = effort.max_tokens().unwrap_or(0);
+                GeminiThinkingConfig {
+                    thinking_budget,
+                    include_thoughts: thinking_budget > 0 && !exclude_reasoning,
+                }
+            }),
+            response_mime_type: response_format.map(|response_format| match response_format {
+                crate::ResponseFormat::JsonObject => "application/json",
+            }),
+            seed: *seed,
+        },
+    };
+    crate::serialize_request_body(&body, extra_body)
+}
+
+pub enum VertexTokenStream {
+    Gemini(GeminiTokenStream),
+    Claude(super::anthropic::ClaudeTokenStream),
+}
+
+impl VertexTokenStream {
+    /// The status and headers of the response, or `None` if the server has not yet responded successfully.
+    pub fn response_metadata(&self) -> Option<&crate::ResponseMetadata> {
+        match self {
+            Self::Gemini(stream) => stream.metadata.get(),
+            Self::Claude(stream) => stream.response_metadata(),
+        }
+    }
+
+    /// A copy of each event read by the stream from now on, as sent by the server, for inspecting
+    /// fields which aren't otherwise supported. Replaces any previous receiver.
+    pub fn raw_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<crate::RawEvent> {
+        match self {
+            Self::Gemini(stream) => crate::sse::SseClient::raw_events(stream.stream.as_deref_mut()),
+            Self::Claude(stream) => stream.raw_events(),
+        }
+    }
+
+    /// Stops the response and waits for the task which read it to finish, returning any error
+    /// the task hit that the stream has not already returned. The connection is closed in the
+    /// background, and may still be closing when this returns.
+    pub async fn shutdown(self) -> Result<(), crate::TokenError> {
+        match self {
+            Self::Gemini(mut stream) => {
+                crate::sse::SseTask::shutdown_stream(stream.task.take(), stream.stream.take()).await
+            }
+            Self::Claude(stream) => stream.shutdown().await,
+        }
+    }
+}
+
+impl futures::Stream for VertexTokenStream {
+    type Item = Result<crate::Chunk, crate::TokenError>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Self::Gemini(stream) => std::pin::Pin::new(stream).poll_next(cx),
+            Self::Claude(stream) => std::pin::Pin::new(stream).poll_next(cx),
+        }
+    }
+}
+
+pub struct GeminiTokenStream {
+    stream: Option<std::pin::Pin<Box<SseClient>>>,
+    task: Option<SseTask>,
+    outstanding: VecDeque<crate::Chunk>,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    /// Gemini does not identify its function calls, so they are numbered as they arrive.
+    tool_calls: usize,
+    span: crate::span::PromptSpan,
+}
+
+impl futures::Stream for GeminiTokenStream {
+    type Item = Result<crate::Chunk, crate::TokenError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let _entered = self.span.span().clone().entered();
+        let poll = self.as_mut().poll_chunk(cx);
+        self.span.record(&poll);
+        poll
+    }
+}
+
+impl GeminiTokenStream {
+    fn poll_chunk(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<crate::Chunk, crate::TokenError>>> {
+        loop {
+            if let Some(chunk) = self.outstanding.pop_front() {
+                return std::task::Poll::Ready(Some(Ok(chunk)));
+            }
+
+            let Some(stream) = self.stream.as_mut() else {
+                return std::task::Poll::Ready(None);
+            };
+
+            let message = match futures::Stream::poll_next(stream.as_mut(), cx) {
+                std::task::Poll::Ready(None) => {
+                    self.stream = None;
+                    return std::task::Poll::Ready(None);
+                }
+                std::task::Poll::Ready(Some(message)) => message,
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            };
+
+            let message = match message {
+                Err(error) => {
+                    self.stream = None;
+                    return std::task::Poll::Ready(Some(Err(crate::TokenError::ConnectionLost(
+                        error,
+                    ))));
+                }
+                Ok(message) => message,
+            };
+
+            if let Err(error) = self.process_response(message.value) {
+                self.stream = None;
+                return std::task::Poll::Ready(Some(Err(error)));
+            }
+        }
+    }
+
+    /// Queues the chunks of a single streamed `GenerateContentResponse`.
+    fn process_response(&mut self, mut value: serde_json::Value) -> Result<(), crate::TokenError> {
+        let Some(candidate) = value
+            .pointer_mut("/candidates/0")
+            .map(serde_json::Value::take)
+        else {
+            if value.get("usageMetadata").is_some() {
+                return Ok(());
+            }
+            return Err(crate::TokenError::MalformedResponse {
+                message: "expected Gemini response to have a candidate",
+                value,
+            });
+        };
+
+        let parts = candidate
+            .pointer("/content/parts")
+            .and_then(serde_json::Value::as_array);
+        for part in parts.into_iter().flatten() {
+            if let Some(function_call) = part.get("functionCall") {
+                let id = match function_call.get("id").and_then(serde_json::Value::as_str) {
+                    Some(id) => id.to_owned(),
+                    None => format!("call_{}", self.tool_calls),
+                };
+                self.tool_calls += 1;
+                let arguments = function_call
+                    .get("args")
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!({}));
+                self.outstanding
+                    .push_back(crate::Chunk::ToolCall(crate::ToolCallChunk::new(
+                        Some(id),
+                        function_call
+                            .get("name")
+                            .and_then(serde_json::Value::as_str)
+                            .map(str::to_owned),
+                        arguments.to_string(),
+                    )));
+            } else if let Some(text) = part.get("text").and_then(serde_json::Value::as_str) {
+                if text.is_empty() {
+                    continue;
+                }
+                let thought = part.get("thought").and_then(serde_json::Value::as_bool);
+                self.outstanding.push_back(if thought == Some(true) {
+                    crate::Chunk::Thinking(text.to_owned())
+                } else {
+                    crate::Chunk::Token(text.to_owned())
+                });
+            }
+        }
+
+        if let Some(reason) = candidate
+            .get("finishReason")
+            .and_then(serde_json::Value::as_str)
+        {
+            let finish_reason = match reason {
+                "STOP" if self.tool_calls > 0 => crate::FinishReason::ToolUse,
+                "STOP" => crate::FinishReason::EndTurn,
+                "MAX_TOKENS" => crate::FinishReason::MaxTokens,
+                "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => {
+                    crate::FinishReason::ContentFilter
+                }
+                other => crate::FinishReason::Other(other.to_owned()),
+            };
+            self.outstanding
+                .push_back(crate::Chunk::Finish(finish_reason));
+
+            if let Some(usage) = value.get("usageMetadata") {
+                let tokens = |key| {
+                    usage
+                        .get(key)
+                        .and_then(serde_json::Value::as_u64)
+                        .map(|tokens| tokens as usize)
+                };
+                self.outstanding
+                    .push_back(crate::Chunk::Usage(crate::Usage {
+                        input_tokens: tokens("promptTokenCount"),
+                        output_tokens: tokens("candidatesTokenCount"),
+                    }));
+            }
+        }
+
+        Ok(())
+    }
+}
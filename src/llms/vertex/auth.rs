@@ -0,0 +1,225 @@
+//! Google OAuth2 access tokens, which Vertex AI takes in place of an API key.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{Method, Request, Version};
+
+use crate::{sse::Error, transport::Transport};
+
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// How long before a token expires that it is replaced, so that it does not expire mid-request.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum CredentialsError {
+    #[error("failed to read credentials file")]
+    IoError(#[from] std::io::Error),
+    #[error("failed to parse credentials file")]
+    JsonError(#[from] serde_json::Error),
+    #[error("no application default credentials were found, set GOOGLE_APPLICATION_CREDENTIALS or run `gcloud auth application-default login`")]
+    NotFound,
+}
+
+/// Google credentials, in the format of an application default credentials file.
+#[derive(Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credentials {
+    /// A service account key, as downloaded from the Google Cloud console.
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        #[serde(default = "default_token_uri")]
+        token_uri: String,
+    },
+    /// A user's credentials, as created by `gcloud auth application-default login`.
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+}
+
+fn default_token_uri() -> String {
+    TOKEN_URI.to_owned()
+}
+
+impl Credentials {
+    pub fn from_json(json: &str) -> Result<Self, CredentialsError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Loads the application default credentials, from the file named by the
+    /// `GOOGLE_APPLICATION_CREDENTIALS` environment variable or else from gcloud's default location.
+    pub fn application_default() -> Result<Self, CredentialsError> {
+        let path = match std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            Some(path) => std::path::PathBuf::from(path),
+            None => {
+                let config = if cfg!(windows) {
+                    std::env::var_os("APPDATA").map(std::path::PathBuf::from)
+                } else {
+                    std::env::var_os("HOME")
+                        .map(|home| std::path::PathBuf::from(home).join(".config"))
+                };
+                let Some(config) = config else {
+                    return Err(CredentialsError::NotFound);
+                };
+                let path = config
+                    .join("gcloud")
+                    .join("application_default_credentials.json");
+                if !path.exists() {
+                    return Err(CredentialsError::NotFound);
+                }
+                path
+            }
+        };
+
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+struct AccessToken {
+    bearer_header: String,
+    expires: Instant,
+}
+
+/// Exchanges credentials for access tokens, reusing each token until it is about to expire.
+pub(super) struct TokenSource {
+    credentials: Credentials,
+    token: tokio::sync::Mutex<Option<AccessToken>>,
+}
+
+impl TokenSource {
+    pub(super) fn new(credentials: Credentials) -> Self {
+        Self {
+            credentials,
+            token: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// The `Authorization` header to send, fetching a new token if needed.
+    pub(super) async fn bearer_header(&self, transport: &dyn Transport) -> Result<String, Error> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref() {
+            if token.expires > Instant::now() + EXPIRY_MARGIN {
+                return Ok(token.bearer_header.clone());
+            }
+        }
+
+        let new_token = self.fetch_token(transport).await?;
+        let bearer_header = new_token.bearer_header.clone();
+        *token = Some(new_token);
+        Ok(bearer_header)
+    }
+
+    async fn fetch_token(&self, transport: &dyn Transport) -> Result<AccessToken, Error> {
+        let (token_uri, form) = match &self.credentials {
+            Credentials::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+            } => {
+                let assertion = service_account_assertion(client_email, private_key, token_uri)?;
+                (
+                    token_uri.as_str(),
+                    form_encode(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &assertion),
+                    ]),
+                )
+            }
+            Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => (
+                TOKEN_URI,
+                form_encode(&[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", client_id),
+                    ("client_secret", client_secret),
+                    ("refresh_token", refresh_token),
+                ]),
+            ),
+        };
+
+        let request = Request::builder()
+            .uri(token_uri)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .version(Version::HTTP_2)
+            .method(Method::POST)
+            .body(form)?;
+        let response = crate::sse::fetch(transport, request).await?;
+
+        #[derive(serde::Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            expires_in: u64,
+        }
+        let response = serde_json::from_slice::<TokenResponse>(&response)?;
+
+        Ok(AccessToken {
+            bearer_header: format!("Bearer {}", response.access_token),
+            expires: Instant::now() + Duration::from_secs(response.expires_in),
+        })
+    }
+}
+
+/// A signed JWT asserting the service account's identity, to exchange for an access token.
+fn service_account_assertion(
+    client_email: &str,
+    private_key: &str,
+    token_uri: &str,
+) -> Result<String, Error> {
+    #[derive(serde::Serialize)]
+    struct Claims<'a> {
+        iss: &'a str,
+        scope: &'a str,
+        aud: &'a str,
+        iat: u64,
+        exp: u64,
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let claims = Claims {
+        iss: client_email,
+        scope: SCOPE,
+        aud: token_uri,
+        iat: now,
+        exp: now + 3600,
+    };
+
+    let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())
+        .map_err(|error| Error::AuthorizationError(error.to_string()))?;
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+        &claims,
+        &key,
+    )
+    .map_err(|error| Error::AuthorizationError(error.to_string()))
+}
+
+/// Encodes the fields as an `application/x-www-form-urlencoded` body.
+fn form_encode(fields: &[(&str, &str)]) -> String {
+    let mut form = String::new();
+    for (key, value) in fields {
+        if !form.is_empty() {
+            form.push('&');
+        }
+        form.push_str(key);
+        form.push('=');
+        for byte in value.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    form.push(byte as char)
+                }
+                byte => form.push_str(&format!("%{byte:02X}")),
+            }
+        }
+    }
+    form
+}
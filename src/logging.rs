@@ -0,0 +1,105 @@
+//! Controls over what is written to the debug logs. Providers log each request body at the debug
+//! level, which includes the prompt, and the response as it arrives, so a redactor can be set to
+//! remove anything sensitive from the logged copy, or bodies can be left out of the logs
+//! altogether. Credentials in the headers are never logged. The request itself is always sent
+//! unchanged.
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use hyper::{HeaderMap, Request};
+
+type Redactor = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+static REDACTOR: RwLock<Option<Redactor>> = RwLock::new(None);
+static LOG_BODIES: AtomicBool = AtomicBool::new(true);
+
+/// The headers which carry credentials, whose values are replaced in the logs.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "x-api-key",
+    "x-goog-api-key",
+    "x-amz-security-token",
+];
+
+/// Whether request and response bodies are written to the debug logs, for the whole process. They
+/// are by default. If not, requests are still logged with their method, uri and headers, but
+/// without their body, and the text of responses is not logged.
+pub fn set_log_bodies(log_bodies: bool) {
+    LOG_BODIES.store(log_bodies, Ordering::Relaxed);
+}
+
+/// Whether bodies are written to the debug logs, as set by [`set_log_bodies`].
+pub fn log_bodies() -> bool {
+    LOG_BODIES.load(Ordering::Relaxed)
+}
+
+/// Passes every request body, and each piece of a response, through the function before it is
+/// logged, for the whole process. Responses are logged a piece at a time as they arrive, so text
+/// split between two pieces is only seen by the redactor in halves.
+///
+/// ```
+/// // Hides anything that looks like an email address.
+/// lmql::logging::set_redactor(|body| {
+///     body.split(' ')
+///         .map(|word| if word.contains('@') { "[email]" } else { word })
+///         .collect::<Vec<_>>()
+///         .join(" ")
+/// });
+/// ```
+pub fn set_redactor(redact: impl Fn(&str) -> String + Send + Sync + 'static) {
+    *REDACTOR.write().unwrap() = Some(Arc::new(redact));
+}
+
+/// Logs bodies as they are sent and received again, undoing [`set_redactor`].
+pub fn clear_redactor() {
+    *REDACTOR.write().unwrap() = None;
+}
+
+/// The body as it should be logged.
+pub(crate) fn body(body: &str) -> Cow<'_, str> {
+    if !log_bodies() {
+        return Cow::Borrowed("[omitted]");
+    }
+    // The lock isn't held while redacting, in case the redactor itself logs.
+    let redactor = REDACTOR.read().unwrap().clone();
+    match redactor {
+        Some(redact) => Cow::Owned(redact(body)),
+        None => Cow::Borrowed(body),
+    }
+}
+
+/// Formats headers as their [`std::fmt::Debug`] implementation does, but with credentials
+/// redacted.
+struct LoggedHeaders<'a>(&'a HeaderMap);
+
+impl std::fmt::Debug for LoggedHeaders<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.0 {
+            if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                map.entry(name, &"[redacted]");
+            } else {
+                map.entry(name, value);
+            }
+        }
+        map.finish()
+    }
+}
+
+/// Formats a request as its [`std::fmt::Debug`] implementation does, but with its body as it
+/// should be logged and with credentials in its headers redacted.
+pub(crate) struct LoggedRequest<'a>(pub(crate) &'a Request<String>);
+
+impl std::fmt::Debug for LoggedRequest<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Request")
+            .field("method", self.0.method())
+            .field("uri", self.0.uri())
+            .field("version", &self.0.version())
+            .field("headers", &LoggedHeaders(self.0.headers()))
+            .field("body", &body(self.0.body()))
+            .finish()
+    }
+}
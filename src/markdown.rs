@@ -0,0 +1,188 @@
+//! Tracking whether a response's text is inside a fenced code block as it streams, for consumers
+//! which render code differently from prose.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::Poll;
+
+use crate::{Chunk, TokenError};
+
+/// Where in a markdown document some text is.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MarkdownState {
+    #[default]
+    Text,
+    /// Inside a fenced code block, including the fences themselves.
+    CodeBlock {
+        /// The language given after the opening fence, e.g. `python`, if any.
+        language: Option<String>,
+    },
+}
+
+/// An opening code fence, which is closed by at least as many of the same character.
+#[derive(Debug)]
+struct Fence {
+    char: char,
+    len: usize,
+}
+
+/// Parses a line as a code fence, returning the fence and what follows it.
+fn parse_fence(line: &str) -> Option<(Fence, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = &line[indent..];
+    let char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.len() - line.trim_start_matches(char).len();
+    (len >= 3).then(|| (Fence { char, len }, line[len..].trim()))
+}
+
+/// Whether the start of a line could still turn out to be a code fence.
+fn may_be_fence(start: &str) -> bool {
+    let rest = start.trim_start_matches(' ');
+    if start.len() - rest.len() > 3 {
+        return false;
+    }
+    match rest.chars().next() {
+        None => true,
+        Some(char @ ('`' | '~')) => {
+            let len = rest.len() - rest.trim_start_matches(char).len();
+            // Once three fence characters have arrived, the rest of the line is its info string.
+            len >= 3 || len == rest.len()
+        }
+        Some(_) => false,
+    }
+}
+
+/// The stream returned by [`crate::TokenStreamExt::with_markdown_state`].
+pub(crate) struct WithMarkdownState<S> {
+    stream: Option<Pin<Box<S>>>,
+    state: MarkdownState,
+    /// The fence of the code block being read, if any.
+    fence: Option<Fence>,
+    /// The start of the current line, held back while it could be a fence.
+    line: String,
+    /// Whether the current line has been ruled out as a fence and so is passed straight through.
+    mid_line: bool,
+    /// Items to return before reading any more of the stream.
+    pending: VecDeque<Result<(Chunk, MarkdownState), TokenError>>,
+}
+
+impl<S> WithMarkdownState<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream: Some(Box::pin(stream)),
+            state: MarkdownState::Text,
+            fence: None,
+            line: String::new(),
+            mid_line: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queues text in the current state, joining it to the text queued before it in that state.
+    fn emit(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Some(Ok((Chunk::Token(last), state))) = self.pending.back_mut() {
+            if *state == self.state {
+                last.push_str(text);
+                return;
+            }
+        }
+        self.pending
+            .push_back(Ok((Chunk::Token(text.to_owned()), self.state.clone())));
+    }
+
+    /// Decides whether the held back line is a fence, and queues it in the state it belongs to.
+    fn end_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        match (&self.fence, parse_fence(&line)) {
+            (None, Some((fence, info))) => {
+                let language = info.split_whitespace().next().map(str::to_owned);
+                self.state = MarkdownState::CodeBlock { language };
+                self.fence = Some(fence);
+                self.emit(&line);
+            }
+            (Some(open), Some((close, ""))) if close.char == open.char && close.len >= open.len => {
+                self.emit(&line);
+                self.state = MarkdownState::Text;
+                self.fence = None;
+            }
+            _ => self.emit(&line),
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let (part, newline) = match rest.find('\n') {
+                Some(end) => (&rest[..=end], true),
+                None => (rest, false),
+            };
+            rest = &rest[part.len()..];
+
+            if self.mid_line {
+                self.emit(part);
+            } else {
+                self.line.push_str(part);
+                if newline {
+                    self.end_line();
+                } else if !may_be_fence(&self.line) {
+                    let line = std::mem::take(&mut self.line);
+                    self.emit(&line);
+                    self.mid_line = true;
+                }
+            }
+            if newline {
+                self.mid_line = false;
+            }
+        }
+    }
+}
+
+impl<S> futures::Stream for WithMarkdownState<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    type Item = Result<(Chunk, MarkdownState), TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(item) = this.pending.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    this.stream = None;
+                    this.end_line();
+                }
+                Poll::Ready(Some(Ok(Chunk::Token(text)))) => this.push_text(&text),
+                // Other chunks end any held back line, so that the text stays in order.
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if !this.line.is_empty() {
+                        this.end_line();
+                        this.mid_line = true;
+                    }
+                    this.pending.push_back(Ok((chunk, this.state.clone())));
+                }
+                Poll::Ready(Some(Err(error))) => {
+                    this.end_line();
+                    this.pending.push_back(Err(error));
+                }
+            }
+        }
+    }
+}
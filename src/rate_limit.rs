@@ -0,0 +1,277 @@
+//! Client-side rate limiting, to stay under a provider's limits rather than backing off once
+//! throttled.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{Chunk, Message, PromptError, PromptOptions, TokenError, LLM};
+
+/// A token bucket, which refills continuously up to its capacity.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    per_second: f64,
+    /// May be negative, when capacity has been reserved ahead of time.
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn per_minute(limit: u32) -> Self {
+        let capacity = f64::from(limit.max(1));
+        Self {
+            capacity,
+            per_second: capacity / 60.0,
+            available: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes `cost` from the bucket, returning how long to wait until it would have been available.
+    /// Reserving ahead means that waiters are served in the order that they arrive.
+    fn reserve(&mut self, cost: f64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.per_second;
+        self.available = (self.available + refill).min(self.capacity);
+        self.updated = now;
+
+        // A cost larger than the whole bucket waits for a full bucket, rather than forever.
+        self.available -= cost.min(self.capacity);
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / self.per_second)
+        }
+    }
+
+    /// Gives back a `cost` which was reserved but never used.
+    fn refund(&mut self, cost: f64) {
+        self.available = (self.available + cost.min(self.capacity)).min(self.capacity);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// The capacity reserved for a prompt which hasn't been sent yet, which is given back if the
+/// prompt is dropped before it is sent.
+struct Reservation {
+    /// `None` once the prompt has been sent.
+    limiter: Option<Arc<Mutex<Limiter>>>,
+    tokens: f64,
+}
+
+impl Reservation {
+    /// Keeps the capacity, as the prompt has been sent.
+    fn spend(&mut self) {
+        self.limiter = None;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(limiter) = self.limiter.take() else {
+            return;
+        };
+        let mut limiter = limiter.lock().unwrap();
+        if let Some(bucket) = &mut limiter.requests {
+            bucket.refund(1.0);
+        }
+        if let Some(bucket) = &mut limiter.tokens {
+            bucket.refund(self.tokens);
+        }
+    }
+}
+
+/// Wraps an [`LLM`] so that each prompt waits until it is within the configured requests and
+/// tokens per minute before being sent. Clones share the same limits, so that every task using a
+/// clone of one provider is limited together.
+///
+/// ```no_run
+/// use lmql::rate_limit::RateLimited;
+///
+/// let llm = RateLimited::new(lmql::llms::openai::Gpt::new_from_env(
+///     lmql::llms::openai::GptModel::Gpt4oMini,
+/// ))
+/// .with_requests_per_minute(500)
+/// .with_tokens_per_minute(200_000);
+/// ```
+pub struct RateLimited<L> {
+    llm: Arc<L>,
+    limiter: Arc<Mutex<Limiter>>,
+}
+
+impl<L> Clone for RateLimited<L> {
+    fn clone(&self) -> Self {
+        Self {
+            llm: self.llm.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<L: LLM> RateLimited<L> {
+    /// Wraps the LLM without any limits, which are then added with the `with_` methods.
+    pub fn new(llm: L) -> Self {
+        Self {
+            llm: Arc::new(llm),
+            limiter: Arc::default(),
+        }
+    }
+
+    /// Limits how many prompts are sent per minute, allowing bursts of up to a minute's worth.
+    pub fn with_requests_per_minute(self, limit: u32) -> Self {
+        self.limiter.lock().unwrap().requests = Some(Bucket::per_minute(limit));
+        self
+    }
+
+    /// Limits how many tokens are used per minute, allowing bursts of up to a minute's worth. Each
+    /// prompt is counted as its [estimated](crate::history::estimate_tokens) size plus its
+    /// [`PromptOptions::max_tokens`], as the length of the response is not known in advance. The
+    /// model's limit isn't known here, so prompts using [`crate::MODEL_MAX_TOKENS`] are counted
+    /// by their messages alone.
+    pub fn with_tokens_per_minute(self, limit: u32) -> Self {
+        self.limiter.lock().unwrap().tokens = Some(Bucket::per_minute(limit));
+        self
+    }
+
+    /// The wrapped LLM.
+    pub fn inner(&self) -> &L {
+        &self.llm
+    }
+
+    /// Reserves capacity for the prompt, returning how long to wait before sending it.
+    fn reserve(&self, messages: &[Message], options: &PromptOptions) -> (Duration, Reservation) {
+        let max_tokens = options.resolve_max_tokens(None).unwrap_or(0);
+        let cost = crate::history::estimate_tokens(messages).saturating_add(max_tokens) as f64;
+
+        let mut limiter = self.limiter.lock().unwrap();
+        let requests = match &mut limiter.requests {
+            Some(bucket) => bucket.reserve(1.0),
+            None => Duration::ZERO,
+        };
+        let tokens = match &mut limiter.tokens {
+            Some(bucket) => bucket.reserve(cost),
+            None => Duration::ZERO,
+        };
+        let reservation = Reservation {
+            limiter: Some(self.limiter.clone()),
+            tokens: cost,
+        };
+        (requests.max(tokens), reservation)
+    }
+}
+
+impl<L> LLM for RateLimited<L>
+where
+    L: LLM + Send + Sync + 'static,
+{
+    type TokenStream = RateLimitedStream<L>;
+
+    fn prompt(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<RateLimitedStream<L>, PromptError> {
+        // Anything wrong with the request is reported now, rather than after waiting.
+        self.llm.build_request_body(messages, options)?;
+
+        let (delay, reservation) = self.reserve(messages, options);
+        if !delay.is_zero() {
+            tracing::debug!("delaying prompt by {delay:?} to stay within the rate limit");
+        }
+
+        Ok(RateLimitedStream {
+            state: State::Waiting {
+                sleep: Box::pin(tokio::time::sleep(delay)),
+                llm: self.llm.clone(),
+                messages: messages.to_vec(),
+                options: Box::new(options.clone()),
+                reservation,
+            },
+        })
+    }
+
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        self.llm.build_request_body(messages, options)
+    }
+
+    fn provider(&self) -> &'static str {
+        self.llm.provider()
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.llm.model_name()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.llm.supports_tools()
+    }
+}
+
+enum State<L: LLM> {
+    Waiting {
+        sleep: Pin<Box<tokio::time::Sleep>>,
+        llm: Arc<L>,
+        messages: Vec<Message>,
+        options: Box<PromptOptions>,
+        reservation: Reservation,
+    },
+    Streaming(Pin<Box<L::TokenStream>>),
+    Done,
+}
+
+/// The stream returned by [`RateLimited`], which sends the prompt once the limits allow it. If
+/// the stream is dropped before then, its share of the limits is given back to later prompts.
+pub struct RateLimitedStream<L: LLM> {
+    state: State<L>,
+}
+
+impl<L: LLM> futures::Stream for RateLimitedStream<L> {
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            match &mut self.state {
+                State::Waiting {
+                    sleep,
+                    llm,
+                    messages,
+                    options,
+                    reservation,
+                } => {
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    match llm.prompt(messages, options) {
+                        Ok(stream) => {
+                            reservation.spend();
+                            self.state = State::Streaming(Box::pin(stream));
+                        }
+                        Err(error) => {
+                            self.state = State::Done;
+                            return Poll::Ready(Some(Err(TokenError::DeferredPrompt(error))));
+                        }
+                    }
+                }
+                State::Streaming(stream) => return stream.as_mut().poll_next(cx),
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Reading the text of a response as bytes, for libraries which consume an [`AsyncRead`].
+
+use std::pin::Pin;
+use std::task::Poll;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{Chunk, TokenError};
+
+/// The reader returned by [`crate::TokenStreamExt::into_async_read`].
+pub(crate) struct TextReader<S> {
+    stream: Option<Pin<Box<S>>>,
+    /// The text which has arrived but not yet been read.
+    text: Vec<u8>,
+    /// How much of the text has been read.
+    read: usize,
+}
+
+impl<S> TextReader<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream: Some(Box::pin(stream)),
+            text: vec![],
+            read: 0,
+        }
+    }
+}
+
+impl<S> AsyncRead for TextReader<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        while this.read == this.text.len() {
+            // Once the stream has ended, reads return nothing, which marks the end of the text.
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+            match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => this.stream = None,
+                Poll::Ready(Some(Ok(Chunk::Token(text)))) => {
+                    this.text = text.into_bytes();
+                    this.read = 0;
+                }
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(error))) => {
+                    this.stream = None;
+                    return Poll::Ready(Err(std::io::Error::other(error)));
+                }
+            }
+        }
+
+        let len = buf.remaining().min(this.text.len() - this.read);
+        buf.put_slice(&this.text[this.read..this.read + len]);
+        this.read += len;
+        Poll::Ready(Ok(()))
+    }
+}
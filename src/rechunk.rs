@@ -0,0 +1,97 @@
+//! Evening out how text arrives, for typewriter effects which should look the same however the
+//! provider chunks its response.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::{Chunk, TokenError};
+
+/// The stream returned by [`crate::TokenStreamExt::rechunk`].
+pub(crate) struct Rechunk<S> {
+    stream: Option<Pin<Box<S>>>,
+    max_chars: usize,
+    min_delay: Duration,
+    /// Chunks to return before reading any more of the stream.
+    pending: VecDeque<Chunk>,
+    /// The wait before the next text can be returned, if the last chunk returned was text.
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> Rechunk<S> {
+    pub(crate) fn new(stream: S, max_chars: usize, min_delay: Duration) -> Self {
+        Self {
+            stream: Some(Box::pin(stream)),
+            max_chars: max_chars.max(1),
+            min_delay,
+            pending: VecDeque::new(),
+            sleep: None,
+        }
+    }
+
+    /// Queues text in pieces of at most `max_chars` characters.
+    fn push_text(&mut self, text: &str) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let end = rest
+                .char_indices()
+                .nth(self.max_chars)
+                .map_or(rest.len(), |(end, _)| end);
+            self.pending.push_back(Chunk::Token(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+    }
+}
+
+impl<S> futures::Stream for Rechunk<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunk) = this.pending.front() {
+                let is_text = matches!(chunk, Chunk::Token(_));
+                // Only text is paced, so other chunks aren't held back any longer than the text
+                // before them.
+                if is_text {
+                    if let Some(sleep) = this.sleep.as_mut() {
+                        if sleep.as_mut().poll(cx).is_pending() {
+                            return Poll::Pending;
+                        }
+                        this.sleep = None;
+                    }
+                    if !this.min_delay.is_zero() {
+                        this.sleep = Some(Box::pin(tokio::time::sleep(this.min_delay)));
+                    }
+                }
+                return Poll::Ready(this.pending.pop_front().map(Ok));
+            }
+
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    this.stream = None;
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Err(error))) => {
+                    this.stream = None;
+                    return Poll::Ready(Some(Err(error)));
+                }
+                Poll::Ready(Some(Ok(Chunk::Token(text)))) => this.push_text(&text),
+                Poll::Ready(Some(Ok(chunk))) => this.pending.push_back(chunk),
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! Ordering documents by their relevance to a query, for providers which support it.
+
+/// A provider which can rank documents by their relevance to a query.
+pub trait Reranker {
+    /// Scores the documents against the query, returning `(index, relevance)` pairs with the most
+    /// relevant document first. Only the `top_n` most relevant are returned, if given.
+    fn rerank(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<usize>,
+    ) -> impl std::future::Future<Output = Result<Vec<(usize, f32)>, crate::EndpointError>> + Send;
+}
@@ -0,0 +1,352 @@
+//! Retrying responses which end without any content, as some backends send under load, and
+//! prompts which fail before their response starts.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::{Chunk, Error, Message, PromptError, PromptOptions, TokenError, LLM};
+
+/// Wraps an [`LLM`] so that a response which ends successfully without any text, tool calls or
+/// audio is retried, up to the given number of times. After the last retry the empty response is
+/// returned as it is.
+///
+/// Until content arrives, the other chunks of the response, such as its thinking, are held back,
+/// so that those of a retried response are never seen. Retries are sent without the options'
+/// [idempotency key](PromptOptions::idempotency_key), as with it the server would return the same
+/// empty response again.
+///
+/// ```no_run
+/// use lmql::retry::RetryOnEmpty;
+///
+/// let llm = RetryOnEmpty::new(
+///     lmql::llms::openrouter::OpenRouter::new_from_env("meta-llama/llama-3.3-70b-instruct"),
+///     2,
+/// );
+/// ```
+pub struct RetryOnEmpty<L> {
+    llm: Arc<L>,
+    max_retries: usize,
+}
+
+impl<L> Clone for RetryOnEmpty<L> {
+    fn clone(&self) -> Self {
+        Self {
+            llm: self.llm.clone(),
+            max_retries: self.max_retries,
+        }
+    }
+}
+
+impl<L: LLM> RetryOnEmpty<L> {
+    pub fn new(llm: L, max_retries: usize) -> Self {
+        Self {
+            llm: Arc::new(llm),
+            max_retries,
+        }
+    }
+
+    /// The wrapped LLM.
+    pub fn inner(&self) -> &L {
+        &self.llm
+    }
+}
+
+impl<L> LLM for RetryOnEmpty<L>
+where
+    L: LLM + Send + Sync + 'static,
+{
+    type TokenStream = RetryOnEmptyStream<L>;
+
+    fn prompt(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<RetryOnEmptyStream<L>, PromptError> {
+        let stream = self.llm.prompt(messages, options)?;
+
+        Ok(RetryOnEmptyStream {
+            stream: Some(Box::pin(stream)),
+            llm: self.llm.clone(),
+            messages: messages.to_vec(),
+            options: Box::new(options.clone()),
+            retries_left: self.max_retries,
+            content_arrived: false,
+            held: VecDeque::new(),
+        })
+    }
+
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        self.llm.build_request_body(messages, options)
+    }
+
+    fn provider(&self) -> &'static str {
+        self.llm.provider()
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        self.llm.model_name()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.llm.supports_tools()
+    }
+}
+
+/// The stream returned by [`RetryOnEmpty`].
+pub struct RetryOnEmptyStream<L: LLM> {
+    stream: Option<Pin<Box<L::TokenStream>>>,
+    llm: Arc<L>,
+    messages: Vec<Message>,
+    options: Box<PromptOptions>,
+    retries_left: usize,
+    /// Whether the current response has any content, after which nothing more is held back.
+    content_arrived: bool,
+    /// The items held back until content arrives or the response ends.
+    held: VecDeque<Result<Chunk, TokenError>>,
+}
+
+impl<L: LLM> futures::Stream for RetryOnEmptyStream<L> {
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.content_arrived || this.stream.is_none() {
+                if let Some(item) = this.held.pop_front() {
+                    return Poll::Ready(Some(item));
+                }
+            }
+
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(chunk))) if this.content_arrived => {
+                    return Poll::Ready(Some(Ok(chunk)))
+                }
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.content_arrived = matches!(
+                        chunk,
+                        Chunk::Token(_) | Chunk::ToolCall(_) | Chunk::Audio(_)
+                    );
+                    this.held.push_back(Ok(chunk));
+                }
+                // Only empty responses are retried, so errors are returned as they are.
+                Poll::Ready(Some(Err(error))) => {
+                    this.stream = None;
+                    this.held.push_back(Err(error));
+                }
+                Poll::Ready(None) if !this.content_arrived && this.retries_left > 0 => {
+                    this.retries_left -= 1;
+                    tracing::warn!(
+                        "the response ended without any content, retrying ({} retries left)",
+                        this.retries_left
+                    );
+                    this.held.clear();
+                    this.options.idempotency_key = None;
+                    match this.llm.prompt(&this.messages, &this.options) {
+                        Ok(stream) => this.stream = Some(Box::pin(stream)),
+                        Err(error) => {
+                            this.stream = None;
+                            this.held.push_back(Err(TokenError::DeferredPrompt(error)));
+                        }
+                    }
+                }
+                Poll::Ready(None) => this.stream = None,
+            }
+        }
+    }
+}
+
+type RetryPredicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// When and how often [`retry_prompt`] tries again.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_retries: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    should_retry: RetryPredicate,
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Retries up to three times, backing off from half a second, on the errors which may pass:
+    /// lost connections, timeouts, server error statuses, the 408 and 429 statuses for timeouts
+    /// and rate limits, and [transient](TokenError::TransientServerError) errors reported
+    /// partway through a response. Other errors, such as an invalid request or a malformed
+    /// response, would only fail again.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            should_retry: Arc::new(is_transient),
+        }
+    }
+}
+
+/// Whether the error may pass if the prompt is tried again.
+fn is_transient(error: &Error) -> bool {
+    use crate::SseError;
+
+    match error {
+        Error::Token(TokenError::ConnectionLost(error)) => match error {
+            SseError::IoError(_) | SseError::KeepaliveTimeout(_) => true,
+            // Parse and user errors are mistakes in the request or response, rather than in the
+            // connection.
+            SseError::HyperError(error) => !error.is_parse() && !error.is_user(),
+            SseError::StatusError { status, .. } => {
+                status.is_server_error() || matches!(status.as_u16(), 408 | 429)
+            }
+            _ => false,
+        },
+        Error::Token(TokenError::Timeout(_) | TokenError::TransientServerError(_)) => true,
+        _ => false,
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Waits `initial` before the first retry, doubling the wait for each retry after it up to
+    /// `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Retries only the errors for which the function returns `true`, in place of the default.
+    pub fn retry_if(
+        mut self,
+        should_retry: impl Fn(&Error) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.should_retry = Arc::new(should_retry);
+        self
+    }
+
+    /// The wait before the given retry, counting from zero.
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = 2u32.saturating_pow(retry.try_into().unwrap_or(u32::MAX));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Runs `prompt` to start a response, running it again as the policy allows if it fails before
+/// the first chunk of its response arrives, e.g. because the server is overloaded. Once a chunk
+/// has arrived the response is returned, with that chunk first, and any later error is left to
+/// the caller, as part of the response has already been read.
+///
+/// `prompt` is given the options to send. If they have no
+/// [idempotency key](PromptOptions::idempotency_key), one is generated, and every attempt sends
+/// the same key, so that a request which reached the server before failing isn't processed (and
+/// billed) twice.
+///
+/// ```no_run
+/// # async fn run(llm: impl lmql::LLM) -> Result<(), lmql::Error> {
+/// use lmql::retry::{retry_prompt, RetryPolicy};
+/// use lmql::{Message, PromptOptions, TokenStreamExt};
+///
+/// let chat = [Message::User("Hello!".into())];
+/// let policy = RetryPolicy::default().with_max_retries(5);
+/// let stream = retry_prompt(&policy, &PromptOptions::default(), |options| {
+///     let (llm, chat) = (&llm, &chat);
+///     async move { llm.prompt(chat, &options) }
+/// })
+/// .await?;
+/// let chunks = stream.all_tokens().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn retry_prompt<F, Fut, S>(
+    policy: &RetryPolicy,
+    options: &PromptOptions,
+    mut prompt: F,
+) -> Result<impl futures::Stream<Item = Result<Chunk, TokenError>>, Error>
+where
+    F: FnMut(PromptOptions) -> Fut,
+    Fut: Future<Output = Result<S, PromptError>>,
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    use futures::StreamExt;
+
+    let mut options = options.clone();
+    if options.idempotency_key.is_none() {
+        options.idempotency_key = Some(generate_idempotency_key());
+    }
+
+    let mut retry = 0;
+    loop {
+        let error = match prompt(options.clone()).await {
+            Ok(stream) => {
+                let mut stream = Box::pin(stream);
+                match stream.next().await {
+                    Some(Err(error)) => Error::Token(error),
+                    first => return Ok(futures::stream::iter(first).chain(stream)),
+                }
+            }
+            Err(error) => Error::Prompt(error),
+        };
+
+        if retry >= policy.max_retries || !(policy.should_retry)(&error) {
+            return Err(error);
+        }
+        let backoff = policy.backoff(retry);
+        retry += 1;
+        tracing::warn!(
+            "the prompt failed before its response started, retrying in {backoff:?} ({} retries left): {error}",
+            policy.max_retries - retry
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// A key which is unique to this prompt, for the `Idempotency-Key` header.
+fn generate_idempotency_key() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Each `RandomState` is randomly seeded, and the count and time make each key distinct even
+    // where the seeds are not.
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let half = || {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(count);
+        hasher.write_u128(time);
+        hasher.finish()
+    };
+    format!("lmql-{:016x}{:016x}", half(), half())
+}
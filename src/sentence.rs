@@ -0,0 +1,168 @@
+//! Splitting a response's text into sentences as it streams, for consumers such as text-to-speech
+//! which need whole sentences at a time.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::Poll;
+
+use crate::{Chunk, TokenError};
+
+/// Words which end with a full stop without ending the sentence.
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "st", "jr", "sr", "vs", "e.g", "i.e",
+];
+
+/// The stream returned by [`crate::TokenStreamExt::by_sentence`].
+pub(crate) struct BySentence<S> {
+    stream: Option<Pin<Box<S>>>,
+    keep_other_chunks: bool,
+    /// Text received since the end of the last sentence.
+    buffer: String,
+    /// How much of the buffer is known not to end a sentence, so that each chunk only scans the
+    /// text after it.
+    scanned: usize,
+    /// Items to return before reading any more of the stream.
+    pending: VecDeque<Result<Chunk, TokenError>>,
+}
+
+impl<S> BySentence<S> {
+    pub(crate) fn new(stream: S, keep_other_chunks: bool) -> Self {
+        Self {
+            stream: Some(Box::pin(stream)),
+            keep_other_chunks,
+            buffer: String::new(),
+            scanned: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queues whatever text is buffered, even though it is not a complete sentence.
+    fn flush(&mut self) {
+        let text = std::mem::take(&mut self.buffer);
+        self.scanned = 0;
+        let text = text.trim_end();
+        if !text.is_empty() {
+            self.pending.push_back(Ok(Chunk::Token(text.to_owned())));
+        }
+    }
+}
+
+impl<S> futures::Stream for BySentence<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    type Item = Result<Chunk, TokenError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(item) = this.pending.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            if let Some(end) = sentence_end(&this.buffer, &mut this.scanned) {
+                let rest = this.buffer[end..].trim_start().to_owned();
+                let mut sentence = std::mem::replace(&mut this.buffer, rest);
+                sentence.truncate(end);
+                this.scanned = 0;
+                return Poll::Ready(Some(Ok(Chunk::Token(sentence))));
+            }
+
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    this.stream = None;
+                    this.flush();
+                }
+                Poll::Ready(Some(Ok(Chunk::Token(text)))) => {
+                    // Sentences never start with whitespace.
+                    let text = if this.buffer.is_empty() {
+                        text.trim_start()
+                    } else {
+                        &text
+                    };
+                    this.buffer.push_str(text);
+                }
+                Poll::Ready(Some(Ok(chunk))) => {
+                    // Other chunks are kept in order, so a partial sentence comes out before them.
+                    if this.keep_other_chunks {
+                        this.flush();
+                        this.pending.push_back(Ok(chunk));
+                    }
+                }
+                Poll::Ready(Some(Err(error))) => {
+                    this.flush();
+                    this.pending.push_back(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// The byte index just after the first complete sentence in the text, if it has one. A sentence
+/// ends with `.`, `!` or `?`, and any closing quotes or brackets, followed by whitespace.
+///
+/// The text before `scanned` is skipped. If no sentence ends, `scanned` is moved up to where the
+/// next scan should start: the end of the text, or the last `.`, `!` or `?` if more text is needed
+/// to tell whether it ends a sentence.
+fn sentence_end(text: &str, scanned: &mut usize) -> Option<usize> {
+    let from = *scanned;
+    let mut chars = text[from..]
+        .char_indices()
+        .map(|(i, c)| (from + i, c))
+        .peekable();
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, c)) = chars.peek() {
+            if !matches!(c, '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’') {
+                break;
+            }
+            end = j + c.len_utf8();
+            chars.next();
+        }
+
+        // Without the following whitespace the sentence may still continue, e.g. `3.5`.
+        match chars.peek() {
+            None => {
+                *scanned = i;
+                return None;
+            }
+            Some((_, c)) if !c.is_whitespace() => continue,
+            Some(_) => {}
+        }
+        if c == '.' && is_abbreviation(&text[..i]) {
+            continue;
+        }
+        return Some(end);
+    }
+    *scanned = text.len();
+    None
+}
+
+/// Whether the last word of the text is an abbreviation or an initial, which a full stop after
+/// does not end the sentence.
+fn is_abbreviation(text: &str) -> bool {
+    let word = text
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches(|c: char| !c.is_alphanumeric());
+
+    let mut chars = word.chars();
+    let is_initial = chars.next().is_some_and(char::is_uppercase) && chars.next().is_none();
+    is_initial
+        || ABBREVIATIONS
+            .iter()
+            .any(|abbreviation| abbreviation.eq_ignore_ascii_case(word))
+}
@@ -0,0 +1,350 @@
+//! Authenticating requests by signing them, for endpoints which need more than a static header.
+//! Any provider sending over a [`SignedTransport`] has each request signed just before it is
+//! sent, so the authentication is independent of the API the request is for.
+
+use hyper::Request;
+
+use crate::transport::{Connect, ConnectScheme, Transport};
+
+/// Why a request could not be signed.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to sign request")]
+pub struct SigningError(#[source] pub Box<dyn std::error::Error + Send + Sync>);
+
+/// Signs outgoing requests, e.g. by adding an `Authorization` header computed from the request.
+pub trait RequestSigner: Send + Sync {
+    /// Signs the request, which is complete apart from the signature and is sent as it is left.
+    fn sign(&self, request: &mut Request<String>) -> Result<(), SigningError>;
+}
+
+/// Sends requests over another transport, signing each request first.
+///
+/// ```no_run
+/// # #[cfg(feature = "aws-sigv4")]
+/// # {
+/// use lmql::llms::anthropic::{Claude, ClaudeModel};
+/// use lmql::signing::{AwsSigV4Signer, SignedTransport};
+/// use lmql::transport::TlsTransport;
+///
+/// let signer = AwsSigV4Signer::new("AKIDEXAMPLE", "secret", "us-east-1", "execute-api");
+/// let llm = Claude::new(ClaudeModel::Claude_3_5_Haiku_20241022, "key".to_owned())
+///     .with_transport(SignedTransport::new(TlsTransport, signer));
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SignedTransport<T, S> {
+    transport: T,
+    signer: S,
+}
+
+impl<T: Transport, S: RequestSigner> SignedTransport<T, S> {
+    pub fn new(transport: T, signer: S) -> Self {
+        Self { transport, signer }
+    }
+}
+
+impl<T: Transport, S: RequestSigner> Transport for SignedTransport<T, S> {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> Connect<'a> {
+        self.transport.connect(host, port)
+    }
+
+    fn connect_scheme<'a>(
+        &'a self,
+        scheme: &'a str,
+        host: &'a str,
+        port: u16,
+    ) -> ConnectScheme<'a> {
+        self.transport.connect_scheme(scheme, host, port)
+    }
+
+    fn sign(&self, request: &mut Request<String>) -> Result<(), SigningError> {
+        self.transport.sign(request)?;
+        self.signer.sign(request)
+    }
+}
+
+#[cfg(feature = "aws-sigv4")]
+pub use aws::AwsSigV4Signer;
+
+#[cfg(feature = "aws-sigv4")]
+mod aws {
+    use std::fmt::Write;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use hmac::{Hmac, Mac};
+    use hyper::header::HeaderValue;
+    use hyper::Request;
+    use sha2::{Digest, Sha256};
+
+    use super::{RequestSigner, SigningError};
+
+    const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+    /// Signs requests with [AWS Signature Version 4](https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_aws-signing.html),
+    /// as required by AWS services and by gateways which use AWS credentials.
+    ///
+    /// The `host` is signed as the authority of the request's uri, which HTTP/2 sends in place of
+    /// a `host` header, along with every header already on the request apart from
+    /// `authorization`, which is replaced by the signature.
+    ///
+    /// ```no_run
+    /// use lmql::llms::openai::compatible::OpenAICompatible;
+    /// use lmql::signing::{AwsSigV4Signer, SignedTransport};
+    /// use lmql::transport::TlsTransport;
+    ///
+    /// let signer = AwsSigV4Signer::new("AKIDEXAMPLE", "secret", "us-east-1", "execute-api");
+    /// let llm = OpenAICompatible::new("https://gateway.example.com/v1", "my-model")
+    ///     .with_transport(SignedTransport::new(TlsTransport, signer));
+    /// ```
+    #[derive(Clone)]
+    pub struct AwsSigV4Signer {
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: Option<String>,
+        region: String,
+        service: String,
+    }
+
+    impl std::fmt::Debug for AwsSigV4Signer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("AwsSigV4Signer")
+                .field("access_key_id", &self.access_key_id)
+                .field("region", &self.region)
+                .field("service", &self.service)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl AwsSigV4Signer {
+        /// Signs with long-term credentials, for the service's signing name in the region, e.g.
+        /// `bedrock` or `execute-api` in `us-east-1`.
+        pub fn new(
+            access_key_id: impl Into<String>,
+            secret_access_key: impl Into<String>,
+            region: impl Into<String>,
+            service: impl Into<String>,
+        ) -> Self {
+            Self {
+                access_key_id: access_key_id.into(),
+                secret_access_key: secret_access_key.into(),
+                session_token: None,
+                region: region.into(),
+                service: service.into(),
+            }
+        }
+
+        /// Sends the session token of temporary credentials with each request, as
+        /// `x-amz-security-token`.
+        pub fn with_session_token(mut self, session_token: impl Into<String>) -> Self {
+            self.session_token = Some(session_token.into());
+            self
+        }
+
+        /// Signs the request as if it were sent at the given time.
+        pub fn sign_at(
+            &self,
+            request: &mut Request<String>,
+            time: SystemTime,
+        ) -> Result<(), SigningError> {
+            let amz_date = amz_date(time);
+            let date = &amz_date[..8];
+
+            let headers = request.headers_mut();
+            headers.insert("x-amz-date", header_value(&amz_date)?);
+            if let Some(session_token) = &self.session_token {
+                headers.insert("x-amz-security-token", header_value(session_token)?);
+            }
+
+            let host = request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str().to_owned())
+                .unwrap_or_default();
+            let mut signed = vec![("host".to_owned(), host)];
+            // As with the AWS SDKs, the header the signature is sent in isn't signed itself.
+            for name in request
+                .headers()
+                .keys()
+                .filter(|name| *name != hyper::header::AUTHORIZATION)
+            {
+                let values = request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|value| {
+                        let value = String::from_utf8_lossy(value.as_bytes());
+                        value.split_whitespace().collect::<Vec<_>>().join(" ")
+                    })
+                    .collect::<Vec<_>>();
+                signed.push((name.as_str().to_owned(), values.join(",")));
+            }
+            signed.sort();
+            let signed_headers = signed
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(";");
+
+            let mut canonical_request = format!(
+                "{}\n{}\n{}\n",
+                request.method(),
+                canonical_path(request.uri().path()),
+                canonical_query(request.uri().query().unwrap_or_default()),
+            );
+            for (name, value) in &signed {
+                writeln!(canonical_request, "{name}:{value}").unwrap();
+            }
+            write!(
+                canonical_request,
+                "\n{signed_headers}\n{}",
+                hex(&Sha256::digest(request.body().as_bytes()))
+            )
+            .unwrap();
+
+            let scope = format!("{date}/{}/{}/aws4_request", self.region, self.service);
+            let string_to_sign = format!(
+                "{ALGORITHM}\n{amz_date}\n{scope}\n{}",
+                hex(&Sha256::digest(canonical_request.as_bytes()))
+            );
+
+            let key = format!("AWS4{}", self.secret_access_key);
+            let key = hmac(key.as_bytes(), date);
+            let key = hmac(&key, &self.region);
+            let key = hmac(&key, &self.service);
+            let key = hmac(&key, "aws4_request");
+            let signature = hex(&hmac(&key, &string_to_sign));
+
+            let authorization = format!(
+                "{ALGORITHM} Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.access_key_id
+            );
+            request
+                .headers_mut()
+                .insert("authorization", header_value(&authorization)?);
+            Ok(())
+        }
+    }
+
+    impl RequestSigner for AwsSigV4Signer {
+        fn sign(&self, request: &mut Request<String>) -> Result<(), SigningError> {
+            self.sign_at(request, SystemTime::now())
+        }
+    }
+
+    fn header_value(value: &str) -> Result<HeaderValue, SigningError> {
+        HeaderValue::from_str(value).map_err(|error| SigningError(Box::new(error)))
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().fold(String::new(), |mut hex, byte| {
+            write!(hex, "{byte:02x}").unwrap();
+            hex
+        })
+    }
+
+    /// The time as `YYYYMMDDTHHMMSSZ`, in UTC.
+    fn amz_date(time: SystemTime) -> String {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+        // Converts days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`.
+        let days = days as i64 + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        format!(
+            "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+
+    /// Percent encodes everything but unreserved characters, and `/` if it is not a separator.
+    fn uri_encode(text: &str, encode_slash: bool) -> String {
+        let mut encoded = String::new();
+        for byte in text.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    encoded.push(byte as char)
+                }
+                b'/' if !encode_slash => encoded.push('/'),
+                _ => write!(encoded, "%{byte:02X}").unwrap(),
+            }
+        }
+        encoded
+    }
+
+    fn uri_decode(text: &str) -> String {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = (bytes[i] == b'%')
+                .then(|| text.get(i + 1..i + 3))
+                .flatten()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    /// Outside of S3, the path is signed encoded a second time, so an escape such as `%3A` in
+    /// the path as sent is signed as `%253A`.
+    fn canonical_path(path: &str) -> String {
+        match path {
+            "" => "/".to_owned(),
+            path => uri_encode(path, false),
+        }
+    }
+
+    /// The query's parameters, each encoded exactly once, sorted by name and then value.
+    fn canonical_query(query: &str) -> String {
+        let mut parameters = query
+            .split('&')
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| {
+                let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+                (
+                    uri_encode(&uri_decode(name), true),
+                    uri_encode(&uri_decode(value), true),
+                )
+            })
+            .collect::<Vec<_>>();
+        parameters.sort();
+        parameters
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
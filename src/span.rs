@@ -0,0 +1,80 @@
+//! Each prompt is traced with a span covering it from being sent until its response completes,
+//! so that subscribers such as OpenTelemetry exporters can follow a prompt through its lifecycle.
+//! The span is entered wherever the crate does work for the prompt, including in the task reading
+//! its response, so every event logged for the prompt is within it.
+
+use std::task::Poll;
+
+use crate::{Chunk, TokenError};
+
+/// The `prompt` span of a single prompt, held by its token stream.
+pub(crate) struct PromptSpan {
+    span: tracing::Span,
+    /// When the prompt was sent, until its duration is recorded.
+    start: Option<std::time::Instant>,
+}
+
+impl PromptSpan {
+    /// Starts the span for a prompt to the given model, or to the model the options override it with.
+    pub(crate) fn new(llm: &impl crate::LLM, options: &crate::PromptOptions) -> Self {
+        let provider = llm.provider();
+        let model = options.resolve_model_name(llm);
+
+        Self {
+            span: tracing::info_span!(
+                "prompt",
+                provider,
+                model,
+                correlation_id = options.correlation_id.as_deref(),
+                input_tokens = tracing::field::Empty,
+                output_tokens = tracing::field::Empty,
+                finish_reason = tracing::field::Empty,
+                error = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+            ),
+            start: Some(std::time::Instant::now()),
+        }
+    }
+
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Records anything the token stream has learned about the response.
+    pub(crate) fn record(&mut self, poll: &Poll<Option<Result<Chunk, TokenError>>>) {
+        match poll {
+            Poll::Ready(Some(Ok(Chunk::Finish(reason)))) => {
+                self.span
+                    .record("finish_reason", tracing::field::debug(reason));
+            }
+            Poll::Ready(Some(Ok(Chunk::Usage(usage)))) => {
+                if let Some(input_tokens) = usage.input_tokens {
+                    self.span.record("input_tokens", input_tokens);
+                }
+                if let Some(output_tokens) = usage.output_tokens {
+                    self.span.record("output_tokens", output_tokens);
+                }
+            }
+            Poll::Ready(Some(Err(error))) => {
+                self.span.record("error", tracing::field::display(error));
+                self.finish();
+            }
+            Poll::Ready(None) => self.finish(),
+            Poll::Ready(Some(Ok(_))) | Poll::Pending => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(start) = self.start.take() {
+            self.span
+                .record("duration_ms", start.elapsed().as_millis() as u64);
+        }
+    }
+}
+
+impl Drop for PromptSpan {
+    fn drop(&mut self) {
+        // A stream dropped before it completes still took this long.
+        self.finish();
+    }
+}
@@ -0,0 +1,131 @@
+//! Demultiplexing a response into its reasoning and its answer, so that each can be read by a
+//! separate consumer.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Wake, Waker};
+
+use crate::{Chunk, TokenError};
+
+const THINKING: usize = 0;
+const ANSWER: usize = 1;
+
+/// Wakes every half which is waiting on the shared stream. The stream only remembers the last
+/// waker it was polled with, so polling it with either half's waker alone could starve the other.
+#[derive(Default)]
+struct SplitWaker {
+    wakers: Mutex<[Option<Waker>; 2]>,
+}
+
+impl Wake for SplitWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared<S> {
+    stream: Option<Pin<Box<S>>>,
+    /// Items read from the stream but not yet taken by each half.
+    queues: [VecDeque<Result<String, TokenError>>; 2],
+    /// Whether each half has been dropped, so that nothing more need be queued for it.
+    dropped: [bool; 2],
+}
+
+/// One of the streams returned by [`crate::TokenStreamExt::split_thinking`].
+pub(crate) struct SplitHalf<S> {
+    shared: Arc<Mutex<Shared<S>>>,
+    waker: Arc<SplitWaker>,
+    half: usize,
+}
+
+pub(crate) fn split<S>(stream: S) -> (SplitHalf<S>, SplitHalf<S>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        stream: Some(Box::pin(stream)),
+        queues: Default::default(),
+        dropped: [false; 2],
+    }));
+    let waker = Arc::new(SplitWaker::default());
+    let half = |half| SplitHalf {
+        shared: shared.clone(),
+        waker: waker.clone(),
+        half,
+    };
+    (half(THINKING), half(ANSWER))
+}
+
+impl<S> futures::Stream for SplitHalf<S>
+where
+    S: futures::Stream<Item = Result<Chunk, TokenError>>,
+{
+    type Item = Result<String, TokenError>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock().unwrap();
+        let shared = &mut *shared;
+        loop {
+            if let Some(item) = shared.queues[self.half].pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            let Some(stream) = shared.stream.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            self.waker.wakers.lock().unwrap()[self.half] = Some(cx.waker().clone());
+            let waker = Waker::from(self.waker.clone());
+            let item = match stream
+                .as_mut()
+                .poll_next(&mut std::task::Context::from_waker(&waker))
+            {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    shared.stream = None;
+                    self.waker.wake_by_ref();
+                    continue;
+                }
+                Poll::Ready(Some(item)) => item,
+            };
+
+            let (half, item) = match item {
+                Ok(Chunk::Thinking(thinking)) => (THINKING, Ok(thinking)),
+                Ok(Chunk::Token(token)) => (ANSWER, Ok(token)),
+                Ok(_) => continue,
+                // Errors aren't cloneable, so they are given to the answer, and the thinking ends.
+                Err(error) => {
+                    shared.stream = None;
+                    (ANSWER, Err(error))
+                }
+            };
+            if !shared.dropped[half] {
+                shared.queues[half].push_back(item);
+            }
+            // The other half may be waiting for this item, or for the end of the stream.
+            if half != self.half || shared.stream.is_none() {
+                self.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+impl<S> Drop for SplitHalf<S> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.dropped[self.half] = true;
+        shared.queues[self.half].clear();
+        // Once neither half is read, the response is cancelled.
+        if shared.dropped.iter().all(|dropped| *dropped) {
+            shared.stream = None;
+        }
+    }
+}
@@ -1,21 +1,18 @@
 //! LLM streaming uses SSE (Server-Sent Events) to stream responses from the server to the client.
 //! This module provides a client for SSE built on top of Hyper.
 
-use std::io::{BufRead, Read};
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
 
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
-use rustls_pki_types::ServerName;
 use tokio::select;
-use tokio::{
-    net::TcpStream,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-};
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
-use tokio_rustls::TlsConnector;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::Instrument;
+
+use crate::transport::{HttpVersion, Transport};
 
 const TIMEOUT_MS: u64 = 10000;
 
@@ -23,20 +20,185 @@ const TIMEOUT_MS: u64 = 10000;
 pub enum Error {
     #[error("Io error")]
     IoError(#[from] std::io::Error),
+    /// The TLS handshake failed, e.g. because the server's certificate has expired or is signed
+    /// by an untrusted authority, as with a TLS-intercepting proxy.
+    #[error("TLS error: {0}")]
+    TlsError(String),
     #[error("Hyper error")]
     HyperError(#[from] hyper::Error),
     #[error("Http error")]
     HttpError(#[from] hyper::http::Error),
     #[error("Json error")]
     JsonError(#[from] serde_json::Error),
+    #[error("no data received from the server within the keepalive timeout of {0:?}")]
+    KeepaliveTimeout(std::time::Duration),
+    #[error("failed to authorize the request: {0}")]
+    AuthorizationError(String),
+    #[error("failed to sign the request")]
+    SigningError(#[from] crate::signing::SigningError),
+    #[error("the response has an unsupported content encoding `{0}`")]
+    UnsupportedContentEncoding(String),
+    #[error("request failed with status: {status} - `{body}`")]
+    StatusError {
+        status: hyper::StatusCode,
+        /// What the failure was, as far as it can be told from the response.
+        kind: ProviderErrorKind,
+        body: String,
+    },
+}
+
+/// Why a provider rejected a request, for the failures which callers may be able to recover from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProviderErrorKind {
+    /// The prompt and the tokens requested for the response don't fit in the model's context
+    /// window. The numbers are given where the provider's message states them.
+    ContextLengthExceeded {
+        max_context: Option<usize>,
+        requested: Option<usize>,
+    },
+    Other,
+}
+
+impl ProviderErrorKind {
+    /// Classifies a failure from the body of the response.
+    fn classify(body: &str) -> Self {
+        let error = serde_json::from_str::<serde_json::Value>(body).ok();
+        let error = error.as_ref().map(|value| {
+            // OpenAI-style errors are nested under `error`, and some gateways send a list of them.
+            let error = value.get("error").unwrap_or(value);
+            error.get(0).unwrap_or(error).clone()
+        });
+        let code = error
+            .as_ref()
+            .and_then(|error| error.get("code"))
+            .and_then(serde_json::Value::as_str);
+        let message = error
+            .as_ref()
+            .and_then(|error| error.get("message"))
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or(body)
+            .to_lowercase();
+
+        let exceeded = code == Some("context_length_exceeded")
+            || [
+                "maximum context length",
+                "prompt is too long",
+                "exceed context limit",
+                "exceeds the maximum number of tokens",
+            ]
+            .iter()
+            .any(|phrase| message.contains(phrase));
+        if !exceeded {
+            return Self::Other;
+        }
+
+        let numbers = |text: &str| {
+            text.split(|c: char| !c.is_ascii_digit())
+                .filter_map(|number| number.parse::<usize>().ok())
+                .collect::<Vec<_>>()
+        };
+        let (max_context, requested) =
+            if let Some((requested, max_context)) = message.split_once('>') {
+                // Anthropic, e.g. `prompt is too long: 210000 tokens > 200000 maximum`, or
+                // `input length and max_tokens exceed context limit: 190000 + 20000 > 200000`.
+                let requested = numbers(requested);
+                (
+                    numbers(max_context).first().copied(),
+                    (!requested.is_empty()).then(|| requested.iter().sum()),
+                )
+            } else if message.contains("maximum context length") {
+                // OpenAI, e.g. `This model's maximum context length is 128000 tokens. However, your
+                // messages resulted in 130000 tokens.`
+                let numbers = numbers(&message);
+                (numbers.first().copied(), numbers.get(1).copied())
+            } else {
+                // Gemini, e.g. `The input token count (1200000) exceeds the maximum number of tokens
+                // allowed (1048576).`
+                let numbers = numbers(&message);
+                (numbers.get(1).copied(), numbers.first().copied())
+            };
+        Self::ContextLengthExceeded {
+            max_context,
+            requested,
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 pub(crate) struct SseClient {
-    _join_handle: tokio::task::JoinHandle<()>,
+    task: Option<SseTask>,
     shutdown: Option<tokio::sync::oneshot::Sender<()>>,
-    rx: UnboundedReceiver<Result<SseValue>>,
+    rx: UnboundedReceiver<Result<Vec<SseValue>>>,
+    /// The rest of the events of the last batch received, to return before receiving another.
+    buffered: std::collections::VecDeque<SseValue>,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    /// Where to send a copy of each event, if anywhere.
+    raw: Option<UnboundedSender<crate::RawEvent>>,
+}
+
+/// The background task of an [`SseClient`], which may outlive the client so that it can be awaited.
+pub(crate) struct SseTask(tokio::task::JoinHandle<Result<()>>);
+
+impl SseTask {
+    /// Closes the client, if it has not already been dropped, and waits for the task to finish.
+    /// Returns the first error that the task hit which the client has not yet received. The
+    /// connection is closed by its own task, which may still be closing it after this returns.
+    pub(crate) async fn shutdown(
+        self,
+        client: Option<std::pin::Pin<Box<SseClient>>>,
+    ) -> Result<()> {
+        let mut unread = Ok(());
+        if let Some(mut client) = client {
+            if let Some(shutdown) = client.shutdown.take() {
+                shutdown.send(()).ok();
+            }
+            // Anything the task sends from now on is given back to us when it finishes.
+            client.rx.close();
+            while let Ok(value) = client.rx.try_recv() {
+                if let (Ok(()), Err(error)) = (&unread, value) {
+                    unread = Err(error);
+                }
+            }
+        }
+
+        let finished = match self.0.await {
+            Ok(finished) => finished,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(_) => Ok(()),
+        };
+        unread.and(finished)
+    }
+
+    /// Shuts down the task of a token stream, as with [`Self::shutdown`], unless the stream has
+    /// already taken it.
+    pub(crate) async fn shutdown_stream(
+        task: Option<Self>,
+        client: Option<std::pin::Pin<Box<SseClient>>>,
+    ) -> std::result::Result<(), crate::TokenError> {
+        match task {
+            Some(task) => Ok(task.shutdown(client).await?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How the client should treat the connection and the events it receives.
+#[derive(Debug, Clone, Copy)]
+struct SseConfig {
+    keepalive_timeout: Option<std::time::Duration>,
+    /// Whether event data is plain text, rather than JSON. Text data is given as a JSON string.
+    text_data: bool,
+}
+
+/// A request to send instead if the server refuses to stream, along with how to present its
+/// complete response as events.
+pub(crate) struct Fallback {
+    pub(crate) request: Request<String>,
+    /// Whether the error from the streaming request means that the server refuses to stream.
+    pub(crate) applies: fn(&Error) -> bool,
+    /// Splits the complete response into the data of the events it would have been streamed as.
+    pub(crate) into_events: fn(serde_json::Value) -> Vec<serde_json::Value>,
 }
 
 #[derive(Debug)]
@@ -45,188 +207,509 @@ pub(crate) struct SseValue {
     pub(crate) value: serde_json::Value,
 }
 
+/// Undoes the `Content-Encoding` of a response body as its frames arrive.
+enum Decoder {
+    Identity,
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    #[cfg(feature = "compression")]
+    Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    fn new(res: &Response<Incoming>) -> Result<Self> {
+        let Some(encoding) = res.headers().get(hyper::header::CONTENT_ENCODING) else {
+            return Ok(Self::Identity);
+        };
+        let encoding = String::from_utf8_lossy(encoding.as_bytes());
+        match encoding.trim() {
+            "identity" => Ok(Self::Identity),
+            #[cfg(feature = "compression")]
+            "gzip" | "x-gzip" => Ok(Self::Gzip(flate2::write::GzDecoder::new(vec![]))),
+            #[cfg(feature = "compression")]
+            "deflate" => Ok(Self::Deflate(flate2::write::ZlibDecoder::new(vec![]))),
+            encoding => Err(Error::UnsupportedContentEncoding(encoding.to_owned())),
+        }
+    }
+
+    /// Decodes the next frame of the body, giving as much of the decoded body as is available.
+    fn decode<'a>(&'a mut self, chunk: &'a [u8]) -> Result<std::borrow::Cow<'a, [u8]>> {
+        #[cfg(feature = "compression")]
+        use std::io::Write;
+
+        match self {
+            Self::Identity => Ok(std::borrow::Cow::Borrowed(chunk)),
+            #[cfg(feature = "compression")]
+            Self::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.flush()?;
+                Ok(std::borrow::Cow::Owned(std::mem::take(decoder.get_mut())))
+            }
+            #[cfg(feature = "compression")]
+            Self::Deflate(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.flush()?;
+                Ok(std::borrow::Cow::Owned(std::mem::take(decoder.get_mut())))
+            }
+        }
+    }
+}
+
 async fn receive_events(
     mut res: Response<Incoming>,
-    tx: UnboundedSender<Result<SseValue>>,
+    tx: UnboundedSender<Result<Vec<SseValue>>>,
+    config: SseConfig,
 ) -> Result<()> {
     let mut accumulation = Vec::new();
+    let mut decoder = Decoder::new(&res)?;
+
+    loop {
+        // Any frame, including pings and comments, counts as a keepalive.
+        let next = match config.keepalive_timeout {
+            Some(keepalive_timeout) => {
+                match tokio::time::timeout(keepalive_timeout, res.frame()).await {
+                    Ok(next) => next,
+                    Err(_) => return Err(Error::KeepaliveTimeout(keepalive_timeout)),
+                }
+            }
+            None => res.frame().await,
+        };
+        let Some(next) = next else {
+            break;
+        };
 
-    while let Some(next) = res.frame().await {
         let frame = next?;
         if let Some(chunk) = frame.data_ref() {
-            let mut chunk = &**chunk;
-            tracing::debug!("Received chunk: `{}`", String::from_utf8_lossy(chunk));
-
-            // We split on double newlines, respecting the accumulation buffer.
-            let mut i = 0;
-            while !chunk.is_empty() && i < chunk.len() - 1 {
-                if chunk[i] == b'\n' && chunk[i + 1] == b'\n' {
-                    let (message_end, tail) = chunk.split_at(i);
-                    chunk = &tail[2..];
-                    i = 0;
-
-                    let mut message = std::io::Read::chain(
-                        std::io::Cursor::new(&accumulation),
-                        std::io::Cursor::new(message_end),
-                    );
-
-                    let mut staging = String::new();
-                    let mut data = None;
-                    let mut event = String::new();
-                    loop {
-                        let mut header = [0u8; 4];
-                        if message.read_exact(&mut header).is_err() {
-                            break;
-                        }
-
-                        match &header {
-                            b"data" => {
-                                // Last 2 bytes
-                                let mut header_colon = [0u8; 2];
-                                message.read_exact(&mut header_colon)?;
-                                assert_eq!(&header_colon, b": ");
-
-                                let mut data_line = String::new();
-                                message.read_line(&mut data_line)?;
-                                if data_line.ends_with('\n') {
-                                    data_line.pop(); // Remove the trailing newline.
-                                }
-                                data = Some(data_line);
-                            }
-                            b"even" => {
-                                // Last 3 bytes
-                                let mut header_colon = [0u8; 3];
-                                message.read_exact(&mut header_colon)?;
-                                assert_eq!(&header_colon, b"t: ");
-
-                                message.read_line(&mut event)?;
-                                if event.ends_with('\n') {
-                                    event.pop(); // Remove the trailing newline.
-                                }
-                            }
-                            _ => {
-                                message.read_line(&mut staging)?;
-                            }
-                        }
-                    }
-
-                    let Some(data) = data.take() else {
-                        continue;
-                    };
-
-                    let value = serde_json::from_str(&data)?;
-                    if let Err(_) = tx.send(Ok(SseValue { event, value })) {
-                        tracing::error!("stream disconnected prematurely");
-                        return Ok(());
-                    }
+            let chunk = decoder.decode(chunk)?;
+            if crate::logging::log_bodies() {
+                tracing::debug!(
+                    "Received chunk: `{}`",
+                    crate::logging::body(&String::from_utf8_lossy(&chunk))
+                );
+            } else {
+                tracing::debug!("Received chunk of {} bytes", chunk.len());
+            }
 
-                    accumulation.clear();
-                } else {
-                    i += 1;
-                }
+            // The events of each frame are sent together, so that a burst costs a single send.
+            let mut batch = vec![];
+            let parsed = parse_events(&chunk, &mut accumulation, config, &mut batch);
+            if !batch.is_empty() && tx.send(Ok(batch)).is_err() {
+                tracing::error!("stream disconnected prematurely");
+                return Ok(());
             }
-            accumulation.extend_from_slice(chunk);
+            parsed?;
         }
     }
 
     Ok(())
 }
 
-async fn run_client(
-    request: Request<String>,
-    tx: UnboundedSender<Result<SseValue>>,
-    shutdown_signal: tokio::sync::oneshot::Receiver<()>,
+/// Parses the events completed by a frame of the body into the batch, keeping any incomplete event
+/// in the accumulation buffer until the next frame.
+fn parse_events(
+    mut chunk: &[u8],
+    accumulation: &mut Vec<u8>,
+    config: SseConfig,
+    batch: &mut Vec<SseValue>,
 ) -> Result<()> {
-    let url = request.uri();
-
-    let host = url.host().expect("Url should have a host");
-    let port = url.port_u16().unwrap_or(443);
+    // We split on double newlines, respecting the accumulation buffer.
+    let mut i = 0;
+    while !chunk.is_empty() && i < chunk.len() - 1 {
+        if chunk[i] == b'\n' && chunk[i + 1] == b'\n' {
+            let (message_end, tail) = chunk.split_at(i);
+            chunk = &tail[2..];
+            i = 0;
+
+            accumulation.extend_from_slice(message_end);
+            let message = std::mem::take(accumulation);
+
+            let (event, data) = parse_fields(&message)?;
+            let Some(data) = data else {
+                continue;
+            };
+
+            let value = if config.text_data {
+                serde_json::Value::String(data)
+            } else {
+                serde_json::from_str(&data)?
+            };
+            batch.push(SseValue { event, value });
+        } else {
+            i += 1;
+        }
+    }
+    accumulation.extend_from_slice(chunk);
+    Ok(())
+}
 
-    let mut root_cert_store = RootCertStore::empty();
-    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+/// Reads the event type and data of a message, if it has any data. A message may spread its data
+/// over several `data` lines, which are joined with newlines, and the value of each field may or
+/// may not follow a space.
+fn parse_fields(message: &[u8]) -> Result<(String, Option<String>)> {
+    let message = std::str::from_utf8(message)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+    let mut event = String::new();
+    let mut data: Option<String> = None;
+    for line in message.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        // Lines starting with a colon are comments, such as keepalives.
+        if line.is_empty() || line.starts_with(':') {
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_owned()),
+            },
+            "event" => event = value.to_owned(),
+            _ => {}
+        }
+    }
+    Ok((event, data))
+}
 
-    let mut config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    config.alpn_protocols = vec![b"h2".to_vec()];
-    let connector = TlsConnector::from(Arc::new(config));
+/// Opens a connection to the request's host, sends the request and waits for the response head,
+/// failing if the server does not respond successfully.
+async fn send(
+    transport: &dyn Transport,
+    mut request: Request<String>,
+) -> Result<Response<Incoming>> {
+    transport.sign(&mut request)?;
+    let url = request.uri();
 
-    let tls_domain = ServerName::try_from(host.to_string())
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname"))?;
+    let host = url.host().expect("Url should have a host");
+    let scheme = url.scheme_str().unwrap_or("https");
+    let port = url
+        .port_u16()
+        .unwrap_or(if scheme == "http" { 80 } else { 443 });
 
-    let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
-    let stream = connector.connect(tls_domain, stream).await?;
+    let (stream, version) = transport
+        .connect_scheme(scheme, host, port)
+        .await
+        .map_err(connect_error)?;
 
-    let executor = hyper_util::rt::tokio::TokioExecutor::new();
     let io = TokioIo::new(stream);
-    let (mut sender, connection) = hyper::client::conn::http2::handshake(executor, io).await?;
-
-    tokio::task::spawn(async move {
-        if let Err(e) = connection.await {
-            tracing::error!("connection error: {}", e);
-        }
-        tracing::debug!("connection closed");
-    });
-
-    let work = sender.send_request(request);
-    let mut res =
-        match tokio::time::timeout(std::time::Duration::from_millis(TIMEOUT_MS), work).await {
-            Ok(result) => result?,
-            Err(_) => {
-                return Err(tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "Timeout").into())
+    let work: std::pin::Pin<Box<dyn Future<Output = hyper::Result<Response<Incoming>>> + Send>> =
+        match version {
+            HttpVersion::Http2 => {
+                let executor = hyper_util::rt::tokio::TokioExecutor::new();
+                let (mut sender, connection) =
+                    hyper::client::conn::http2::handshake(executor, io).await?;
+                spawn_connection(connection);
+                Box::pin(sender.send_request(request))
+            }
+            HttpVersion::Http1 => {
+                let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+                spawn_connection(connection);
+                Box::pin(sender.send_request(into_http1(request)?))
             }
         };
+    let res = match tokio::time::timeout(std::time::Duration::from_millis(TIMEOUT_MS), work).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "Timeout").into())
+        }
+    };
 
     let status = res.status();
     if !status.is_success() {
         // Collect bad body
-        let mut bytes = vec![];
-        while let Some(Ok(next)) = res.frame().await {
-            let frame = next;
-            if let Some(chunk) = frame.data_ref() {
-                let chunk = &**chunk;
-                bytes.extend_from_slice(chunk);
+        let bytes = collect_body(res).await?;
+        let body = String::from_utf8_lossy(&bytes).into_owned();
+
+        return Err(Error::StatusError {
+            status,
+            kind: ProviderErrorKind::classify(&body),
+            body,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Drives the connection in the background until it closes.
+fn spawn_connection(connection: impl Future<Output = hyper::Result<()>> + Send + 'static) {
+    tokio::task::spawn(
+        async move {
+            if let Err(e) = connection.await {
+                tracing::error!("connection error: {}", e);
             }
+            tracing::debug!("connection closed");
         }
-        let body = String::from_utf8_lossy(&bytes);
+        .instrument(tracing::Span::current()),
+    );
+}
 
-        return Err(tokio::io::Error::new(
-            tokio::io::ErrorKind::Other,
-            format!("request failed with status: {status} - `{body}`"),
-        )
-        .into());
+/// Rewrites a request for HTTP/1.1, which gives the host in the `Host` header and only the path in
+/// the request line, where HTTP/2 gives the whole url.
+fn into_http1(mut request: Request<String>) -> Result<Request<String>> {
+    *request.version_mut() = hyper::Version::HTTP_11;
+    if let Some(authority) = request.uri().authority() {
+        let host = hyper::header::HeaderValue::from_str(authority.as_str())
+            .map_err(hyper::http::Error::from)?;
+        request
+            .headers_mut()
+            .entry(hyper::header::HOST)
+            .or_insert(host);
     }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .parse()
+        .map_err(hyper::http::Error::from)?;
+    *request.uri_mut() = path;
+    Ok(request)
+}
 
-    tracing::debug!("sse opened successfully");
+/// Tells TLS failures, which the transport reports as IO errors, apart from other IO errors.
+fn connect_error(error: std::io::Error) -> Error {
+    match error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<tokio_rustls::rustls::Error>())
+    {
+        Some(tls_error) => Error::TlsError(tls_error.to_string()),
+        None => Error::IoError(error),
+    }
+}
+
+/// Reads and decodes the remainder of a response body, stopping early if the connection errors.
+async fn collect_body(mut res: Response<Incoming>) -> Result<Vec<u8>> {
+    let mut decoder = Decoder::new(&res)?;
+    let mut bytes = vec![];
+    while let Some(frame) = res.frame().await {
+        let frame = frame?;
+        if let Some(chunk) = frame.data_ref() {
+            bytes.extend_from_slice(&decoder.decode(chunk)?);
+        }
+    }
+    Ok(bytes)
+}
 
+/// Sends a one-off, non-streaming request and returns the full response body.
+pub(crate) async fn fetch(transport: &dyn Transport, request: Request<String>) -> Result<Vec<u8>> {
+    let res = send(transport, request).await?;
+    collect_body(res).await
+}
+
+async fn run_client(
+    transport: Arc<dyn Transport>,
+    request: impl Future<Output = Result<Request<String>>>,
+    tx: UnboundedSender<Result<Vec<SseValue>>>,
+    shutdown_signal: tokio::sync::oneshot::Receiver<()>,
+    config: SseConfig,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    fallback: Option<Fallback>,
+) -> Result<()> {
     select! {
-        _ = receive_events(res, tx) => {
+        result = stream_response(transport, request, tx, config, metadata, fallback) => {
             // Connection was probably closed
+            result?;
         }
         _ = shutdown_signal => {
-            // Received a shutdown signal
+            // Dropping the request or response cancels just its HTTP/2 stream, with
+            // RST_STREAM(CANCEL), whether it is still being sent or already streaming.
+            // Connections aren't shared, so the connection task then has no streams left and
+            // closes the connection with GOAWAY, rather than it being torn down mid-stream.
+            // HTTP/1.1 has no way to cancel a response, so there the connection is closed.
         }
     };
     Ok(())
 }
 
+/// Sends the request, falling back to a complete response if the server refuses to stream, and
+/// passes on the events of the response until it ends.
+async fn stream_response(
+    transport: Arc<dyn Transport>,
+    request: impl Future<Output = Result<Request<String>>>,
+    tx: UnboundedSender<Result<Vec<SseValue>>>,
+    config: SseConfig,
+    metadata: Arc<OnceLock<crate::ResponseMetadata>>,
+    fallback: Option<Fallback>,
+) -> Result<()> {
+    let res = match send(&*transport, request.await?).await {
+        Ok(res) => res,
+        Err(error) => match fallback {
+            Some(fallback) if (fallback.applies)(&error) => {
+                tracing::warn!("the server refused to stream, retrying without streaming: {error}");
+                let body = fetch(&*transport, fallback.request).await?;
+                let events = (fallback.into_events)(serde_json::from_slice(&body)?)
+                    .into_iter()
+                    .map(|value| SseValue {
+                        event: String::new(),
+                        value,
+                    })
+                    .collect();
+                tx.send(Ok(events)).ok();
+                return Ok(());
+            }
+            _ => return Err(error),
+        },
+    };
+    let status = res.status();
+
+    tracing::debug!("sse opened successfully");
+    let _ = metadata.set(crate::ResponseMetadata {
+        status,
+        headers: res.headers().clone(),
+    });
+
+    receive_events(res, tx, config).await
+}
+
 impl SseClient {
-    pub(crate) fn spawn(request: Request<String>) -> Self {
+    pub(crate) fn spawn(
+        transport: Arc<dyn Transport>,
+        request: Request<String>,
+        keepalive_timeout: Option<std::time::Duration>,
+    ) -> Self {
+        Self::spawn_with_config(
+            transport,
+            std::future::ready(Ok(request)),
+            SseConfig {
+                keepalive_timeout,
+                text_data: false,
+            },
+            None,
+        )
+    }
+
+    /// As with [`Self::spawn`], but the request is only sent once it has been built, for example
+    /// after fetching an access token. Failing to build the request ends the stream with the error.
+    #[cfg_attr(not(feature = "vertex"), allow(dead_code))]
+    pub(crate) fn spawn_deferred(
+        transport: Arc<dyn Transport>,
+        request: impl Future<Output = Result<Request<String>>> + Send + 'static,
+        keepalive_timeout: Option<std::time::Duration>,
+    ) -> Self {
+        Self::spawn_with_config(
+            transport,
+            request,
+            SseConfig {
+                keepalive_timeout,
+                text_data: false,
+            },
+            None,
+        )
+    }
+
+    /// As with [`Self::spawn`], but if the request fails because the server refuses to stream, the
+    /// fallback request is sent instead.
+    pub(crate) fn spawn_with_fallback(
+        transport: Arc<dyn Transport>,
+        request: Request<String>,
+        fallback: Fallback,
+        keepalive_timeout: Option<std::time::Duration>,
+    ) -> Self {
+        Self::spawn_with_config(
+            transport,
+            std::future::ready(Ok(request)),
+            SseConfig {
+                keepalive_timeout,
+                text_data: false,
+            },
+            Some(fallback),
+        )
+    }
+
+    /// As with [`Self::spawn`], but for servers whose event data is plain text rather than JSON.
+    pub(crate) fn spawn_text(
+        transport: Arc<dyn Transport>,
+        request: Request<String>,
+        keepalive_timeout: Option<std::time::Duration>,
+    ) -> Self {
+        Self::spawn_with_config(
+            transport,
+            std::future::ready(Ok(request)),
+            SseConfig {
+                keepalive_timeout,
+                text_data: true,
+            },
+            None,
+        )
+    }
+
+    fn spawn_with_config(
+        transport: Arc<dyn Transport>,
+        request: impl Future<Output = Result<Request<String>>> + Send + 'static,
+        config: SseConfig,
+        fallback: Option<Fallback>,
+    ) -> Self {
         let (tx, rx) = unbounded_channel();
         let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
-
-        let join_handle = tokio::spawn(async move {
-            let tx_clone = tx.clone();
-            if let Err(e) = run_client(request, tx_clone, shutdown_signal).await {
-                let _ = tx.send(Err(e));
+        let metadata = Arc::new(OnceLock::new());
+
+        let client_metadata = metadata.clone();
+        // The connection is traced as part of whichever prompt opened it.
+        let join_handle = tokio::spawn(
+            async move {
+                let tx_clone = tx.clone();
+                if let Err(e) = run_client(
+                    transport,
+                    request,
+                    tx_clone,
+                    shutdown_signal,
+                    config,
+                    client_metadata,
+                    fallback,
+                )
+                .await
+                {
+                    // If the client has closed, the error is kept for whoever awaits the task.
+                    if let Err(tokio::sync::mpsc::error::SendError(Err(e))) = tx.send(Err(e)) {
+                        return Err(e);
+                    }
+                }
+                Ok(())
             }
-        });
+            .instrument(tracing::Span::current()),
+        );
 
         Self {
-            _join_handle: join_handle,
+            task: Some(SseTask(join_handle)),
             rx,
+            buffered: std::collections::VecDeque::new(),
             shutdown: Some(shutdown),
+            metadata,
+            raw: None,
+        }
+    }
+
+    /// Takes the background task, so that it can be awaited after the client is dropped. The task
+    /// is still told to shut down when the client is dropped.
+    pub(crate) fn take_task(&mut self) -> Option<SseTask> {
+        self.task.take()
+    }
+
+    /// A handle to the response metadata, which is filled in once the server responds successfully.
+    pub(crate) fn metadata(&self) -> Arc<OnceLock<crate::ResponseMetadata>> {
+        self.metadata.clone()
+    }
+
+    /// Whether more events have already been received, so that the next poll is ready at once.
+    pub(crate) fn has_buffered(&self) -> bool {
+        !self.buffered.is_empty()
+    }
+
+    /// Sends a copy of each event read from now on to the channel, replacing any previous channel.
+    pub(crate) fn tee_raw_events(&mut self, raw: UnboundedSender<crate::RawEvent>) {
+        self.raw = Some(raw);
+    }
+
+    /// A copy of each event the client reads from now on, replacing any previous receiver. Without
+    /// a client, as once a token stream has finished with it, nothing is ever received.
+    pub(crate) fn raw_events(client: Option<&mut Self>) -> UnboundedReceiver<crate::RawEvent> {
+        let (tx, rx) = unbounded_channel();
+        if let Some(client) = client {
+            client.tee_raw_events(tx);
         }
+        rx
     }
 }
 
@@ -237,16 +720,45 @@ impl futures::Stream for SseClient {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.rx.poll_recv(cx)
+        let poll = match self.buffered.pop_front() {
+            Some(value) => std::task::Poll::Ready(Some(Ok(value))),
+            None => match self.rx.poll_recv(cx) {
+                std::task::Poll::Ready(Some(Ok(batch))) => {
+                    self.buffered.extend(batch);
+                    match self.buffered.pop_front() {
+                        Some(value) => std::task::Poll::Ready(Some(Ok(value))),
+                        // Batches are never empty, but an empty one would end nothing.
+                        None => {
+                            cx.waker().wake_by_ref();
+                            std::task::Poll::Pending
+                        }
+                    }
+                }
+                std::task::Poll::Ready(Some(Err(error))) => {
+                    std::task::Poll::Ready(Some(Err(error)))
+                }
+                std::task::Poll::Ready(None) => std::task::Poll::Ready(None),
+                std::task::Poll::Pending => std::task::Poll::Pending,
+            },
+        };
+        if let (std::task::Poll::Ready(Some(Ok(value))), Some(raw)) = (&poll, &self.raw) {
+            let event = crate::RawEvent {
+                event: value.event.clone(),
+                value: value.value.clone(),
+            };
+            if raw.send(event).is_err() {
+                self.raw = None;
+            }
+        }
+        poll
     }
 }
 
 impl Drop for SseClient {
     fn drop(&mut self) {
+        // The task may finish at any moment, in which case there's nothing left to tell.
         if let Some(shutdown) = self.shutdown.take() {
-            if !shutdown.is_closed() {
-                shutdown.send(()).ok();
-            }
+            shutdown.send(()).ok();
         }
     }
 }
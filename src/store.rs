@@ -0,0 +1,65 @@
+//! Persisting conversations between turns, so that a chat can be picked up again later, e.g. by
+//! [`crate::LLMExt::chat_with_store`].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use crate::Message;
+
+/// Somewhere conversations are kept, by id, such as a database. [`Message`] can be serialized, so
+/// a backend can store each conversation as JSON.
+pub trait ConversationStore {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The messages of the conversation, or `None` if it has never been saved.
+    fn load(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<Vec<Message>>, Self::Error>> + Send;
+
+    /// Replaces the messages of the conversation.
+    fn save(
+        &self,
+        id: &str,
+        messages: &[Message],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Keeps conversations in memory for as long as the store lives. Clones share the same
+/// conversations.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore {
+    conversations: Arc<Mutex<HashMap<String, Vec<Message>>>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ConversationStore for InMemoryStore {
+    type Error = std::convert::Infallible;
+
+    async fn load(&self, id: &str) -> Result<Option<Vec<Message>>, Self::Error> {
+        Ok(self.conversations.lock().unwrap().get(id).cloned())
+    }
+
+    async fn save(&self, id: &str, messages: &[Message]) -> Result<(), Self::Error> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .insert(id.to_owned(), messages.to_vec());
+        Ok(())
+    }
+}
+
+/// The error returned by [`crate::LLMExt::chat_with_store`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChatError<E> {
+    #[error("failed to prompt the model")]
+    Model(#[from] crate::Error),
+    #[error("failed to load or save the conversation")]
+    Store(#[source] E),
+}
@@ -0,0 +1,206 @@
+//! Summarizing documents longer than a model's context window, by summarizing each part of the
+//! document and then combining the summaries.
+
+use futures::{StreamExt, TryStreamExt};
+
+use crate::history::{estimate_text_tokens, split_to_budget};
+use crate::{Error, LLMExt, PromptOptions, LLM};
+
+/// How [`map_reduce`] prompts the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapReduceOptions {
+    /// The options for every prompt, both of the parts and of the summaries.
+    pub prompt_options: PromptOptions,
+    /// The most prompts to have in flight at once.
+    pub concurrency: usize,
+    /// The most [estimated](crate::history::estimate_tokens) tokens to send in a single prompt,
+    /// including its instruction. Longer parts are split before they are summarized, and if the
+    /// summaries together are longer, they are combined in groups until they fit. Summaries too
+    /// long to be combined with another are first summarized again on their own. If `None`,
+    /// everything is sent as given.
+    pub max_chunk_tokens: Option<usize>,
+}
+
+impl Default for MapReduceOptions {
+    fn default() -> Self {
+        Self {
+            prompt_options: PromptOptions::default(),
+            concurrency: 4,
+            max_chunk_tokens: None,
+        }
+    }
+}
+
+impl MapReduceOptions {
+    pub fn set_prompt_options(&mut self, prompt_options: PromptOptions) -> &mut Self {
+        self.prompt_options = prompt_options;
+        self
+    }
+    pub fn set_concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency;
+        self
+    }
+    pub fn set_max_chunk_tokens(&mut self, max_chunk_tokens: usize) -> &mut Self {
+        self.max_chunk_tokens = Some(max_chunk_tokens);
+        self
+    }
+}
+
+/// Prompts the model with each chunk after the `map_prompt`, up to
+/// [`MapReduceOptions::concurrency`] at a time, then with the responses after the
+/// `reduce_prompt`, returning the final response. Without any chunks, the model isn't prompted
+/// and the summary is empty.
+///
+/// Fails with [`Error::SummaryOverBudget`] if the summaries can't be made to fit within
+/// [`MapReduceOptions::max_chunk_tokens`], as when the budget barely covers the instructions.
+///
+/// ```no_run
+/// # async fn run(llm: impl lmql::LLM, document: &str) -> Result<(), lmql::Error> {
+/// use lmql::history::split_to_budget;
+/// use lmql::summarize::{map_reduce, MapReduceOptions};
+///
+/// let mut options = MapReduceOptions::default();
+/// options.set_max_chunk_tokens(50_000);
+/// let summary = map_reduce(
+///     &llm,
+///     split_to_budget(document, 50_000),
+///     "Summarize this part of a report:",
+///     "Combine these summaries of the parts of a report into one summary:",
+///     &options,
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn map_reduce<L: LLM>(
+    llm: &L,
+    chunks: Vec<String>,
+    map_prompt: &str,
+    reduce_prompt: &str,
+    options: &MapReduceOptions,
+) -> Result<String, Error> {
+    // Along with the budget, the budgets for the text sent after each instruction.
+    let budgets = match options.max_chunk_tokens {
+        Some(budget) => Some((
+            budget,
+            text_budget(budget, map_prompt)?,
+            text_budget(budget, reduce_prompt)?,
+        )),
+        None => None,
+    };
+    let chunks = match budgets {
+        Some((_, map_budget, _)) => chunks
+            .iter()
+            .flat_map(|chunk| split_to_budget(chunk, map_budget))
+            .collect(),
+        None => chunks,
+    };
+    if chunks.is_empty() {
+        return Ok(String::new());
+    }
+    let mut summaries = prompt_all(llm, map_prompt, chunks, options).await?;
+
+    if let Some((budget, map_budget, reduce_budget)) = budgets {
+        // The fewest tokens the summaries have been summarized again to, which must keep falling
+        // for the summaries to ever fit.
+        let mut shortest = usize::MAX;
+        while estimate_text_tokens(&join(&summaries)) > reduce_budget {
+            let groups = group_to_budget(summaries, reduce_budget);
+            // Once every summary needs a group of its own, grouping no longer shrinks anything, so
+            // those too long to share a group are summarized again.
+            if groups.iter().all(|group| group.len() == 1) {
+                let before = groups.into_iter().flatten().collect::<Vec<_>>();
+                shortest = shortest.min(estimate_text_tokens(&join(&before)));
+                summaries = vec![];
+                for summary in before {
+                    if estimate_text_tokens(&summary) * 2 < reduce_budget {
+                        summaries.push(summary);
+                        continue;
+                    }
+                    let parts = split_to_budget(&summary, map_budget);
+                    summaries.extend(prompt_all(llm, map_prompt, parts, options).await?);
+                }
+                let tokens = estimate_text_tokens(&join(&summaries));
+                if tokens >= shortest {
+                    return Err(Error::SummaryOverBudget { budget });
+                }
+                shortest = tokens;
+                continue;
+            }
+            summaries = futures::stream::iter(groups)
+                .map(|mut group| async move {
+                    match group.len() {
+                        1 => Ok(group.remove(0)),
+                        _ => prompt(llm, reduce_prompt, join(&group), options).await,
+                    }
+                })
+                .buffered(options.concurrency.max(1))
+                .try_collect()
+                .await?;
+        }
+    }
+
+    prompt(llm, reduce_prompt, join(&summaries), options).await
+}
+
+/// Prompts the model with each text after the instruction, returning the responses in order.
+async fn prompt_all<L: LLM>(
+    llm: &L,
+    instruction: &str,
+    texts: Vec<String>,
+    options: &MapReduceOptions,
+) -> Result<Vec<String>, Error> {
+    futures::stream::iter(texts)
+        .map(|text| prompt(llm, instruction, text, options))
+        .buffered(options.concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+async fn prompt<L: LLM>(
+    llm: &L,
+    instruction: &str,
+    text: String,
+    options: &MapReduceOptions,
+) -> Result<String, Error> {
+    let (response, _) = llm
+        .chat(
+            vec![],
+            format!("{instruction}{SEPARATOR}{text}"),
+            &options.prompt_options,
+        )
+        .await?;
+    Ok(response)
+}
+
+/// What summaries are joined with before they are reduced, and what separates the text from its
+/// instruction.
+const SEPARATOR: &str = "\n\n";
+
+/// The tokens left for text in a prompt of at most `budget` tokens after the instruction.
+fn text_budget(budget: usize, instruction: &str) -> Result<usize, Error> {
+    budget
+        .checked_sub(estimate_text_tokens(&format!("{instruction}{SEPARATOR}")))
+        .filter(|text_budget| *text_budget > 0)
+        .ok_or(Error::SummaryOverBudget { budget })
+}
+
+fn join(summaries: &[String]) -> String {
+    summaries.join(SEPARATOR)
+}
+
+/// Groups consecutive summaries so that each group's text fits within the budget, where possible.
+fn group_to_budget(summaries: Vec<String>, budget: usize) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = vec![];
+    for summary in summaries {
+        match groups.last_mut() {
+            Some(group)
+                if estimate_text_tokens(&join(group)) + estimate_text_tokens(&summary) < budget =>
+            {
+                group.push(summary)
+            }
+            _ => groups.push(vec![summary]),
+        }
+    }
+    groups
+}
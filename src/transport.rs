@@ -0,0 +1,129 @@
+//! The connections which requests are sent over. By default each request opens a TLS connection
+//! over TCP, or a plain TCP connection for `http` urls, but a provider can be given another
+//! [`Transport`], for example to serve canned responses in tests or to route requests through a
+//! sandbox.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::Request;
+use rustls_pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::signing::SigningError;
+
+/// A bidirectional byte stream to a server.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// The version of HTTP spoken over a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http1,
+    Http2,
+}
+
+/// The result of [`Transport::connect`].
+pub type Connect<'a> =
+    Pin<Box<dyn Future<Output = std::io::Result<Box<dyn Connection>>> + Send + 'a>>;
+
+/// The result of [`Transport::connect_scheme`].
+pub type ConnectScheme<'a> =
+    Pin<Box<dyn Future<Output = std::io::Result<(Box<dyn Connection>, HttpVersion)>> + Send + 'a>>;
+
+/// Opens connections to servers.
+pub trait Transport: Send + Sync {
+    /// Opens a connection to the host, over which HTTP/2 will be spoken. Any encryption is the
+    /// responsibility of the transport.
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> Connect<'a>;
+
+    /// Opens a connection for a url with the given scheme, such as `https`, returning the version
+    /// of HTTP to speak over it. By default, connects with [`Self::connect`] and speaks HTTP/2
+    /// whatever the scheme.
+    fn connect_scheme<'a>(
+        &'a self,
+        scheme: &'a str,
+        host: &'a str,
+        port: u16,
+    ) -> ConnectScheme<'a> {
+        let _ = scheme;
+        Box::pin(async move { Ok((self.connect(host, port).await?, HttpVersion::Http2)) })
+    }
+
+    /// Signs a request just before it is sent over a connection from this transport, as
+    /// [`crate::signing::SignedTransport`] does. By default, requests are sent as they are.
+    fn sign(&self, request: &mut Request<String>) -> Result<(), SigningError> {
+        let _ = request;
+        Ok(())
+    }
+}
+
+/// Connects over TCP, verifying the server against the Mozilla root certificates. HTTP/2 is spoken
+/// where the server offers it, and HTTP/1.1 otherwise. `http` urls are connected to without
+/// encryption, speaking HTTP/1.1, as for a proxy running locally.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlsTransport;
+
+impl TlsTransport {
+    /// Connects with TLS, offering the given protocols, in order of preference.
+    async fn connect_tls(
+        host: &str,
+        port: u16,
+        alpn_protocols: &[&[u8]],
+    ) -> std::io::Result<(Box<dyn Connection>, HttpVersion)> {
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let tls_domain = ServerName::try_from(host.to_string()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname")
+        })?;
+
+        let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        let stream = connector.connect(tls_domain, stream).await?;
+        // Servers which don't negotiate a protocol are assumed to only speak HTTP/1.1.
+        let version = match stream.get_ref().1.alpn_protocol() {
+            Some(b"h2") => HttpVersion::Http2,
+            _ => HttpVersion::Http1,
+        };
+        Ok((Box::new(stream) as Box<dyn Connection>, version))
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> Connect<'a> {
+        Box::pin(async move { Ok(Self::connect_tls(host, port, &[b"h2"]).await?.0) })
+    }
+
+    fn connect_scheme<'a>(
+        &'a self,
+        scheme: &'a str,
+        host: &'a str,
+        port: u16,
+    ) -> ConnectScheme<'a> {
+        Box::pin(async move {
+            if scheme == "http" {
+                let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+                return Ok((Box::new(stream) as Box<dyn Connection>, HttpVersion::Http1));
+            }
+            Self::connect_tls(host, port, &[b"h2", b"http/1.1"]).await
+        })
+    }
+}
+
+/// The transport used by providers unless they are given another.
+pub(crate) fn default_transport() -> Arc<dyn Transport> {
+    Arc::new(TlsTransport)
+}
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lmql::cache::Cached;
+use lmql::llms::echo::Echo;
+use lmql::{Message, PromptOptions, TokenStreamExt, Tool, LLM};
+
+/// Builds OpenAI's requests, but answers by echoing, counting how many prompts are sent.
+#[derive(Default)]
+struct Counting {
+    prompts: AtomicUsize,
+    /// Builds bodies which leave out the model, as providers which send it in the url do.
+    model_in_url: bool,
+}
+
+impl LLM for Counting {
+    type TokenStream = lmql::llms::echo::EchoTokenStream;
+
+    fn prompt(
+        &self,
+        chat: &[Message],
+        options: &PromptOptions,
+    ) -> Result<Self::TokenStream, lmql::PromptError> {
+        self.prompts.fetch_add(1, Ordering::SeqCst);
+        Echo::new().prompt(chat, options)
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, lmql::PromptError> {
+        if self.model_in_url {
+            return Echo::new().build_request_body(chat, options);
+        }
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .build_request_body(chat, options)
+    }
+
+    fn provider(&self) -> &'static str {
+        "Counting"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "counting".into()
+    }
+}
+
+async fn text(llm: &Cached<Counting>, chat: &[Message], options: &PromptOptions) -> String {
+    llm.prompt(chat, options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|chunk| match chunk {
+            lmql::Chunk::Token(text) => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+fn tool(name: &str) -> Tool {
+    Tool {
+        name: name.to_owned(),
+        description: format!("Calls {name}"),
+        parameters: lmql::ToolParameters::new::<()>(),
+    }
+}
+
+#[tokio::test]
+async fn identical_requests_are_replayed() {
+    let llm = Cached::new(Counting::default());
+    let chat = [Message::User("Hello!".into())];
+    let options = PromptOptions::default();
+
+    assert_eq!(text(&llm, &chat, &options).await, "Hello!");
+    assert_eq!(text(&llm, &chat, &options).await, "Hello!");
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 1);
+
+    // Anything which is sent misses the cache.
+    let mut seeded = options.clone();
+    seeded.set_seed(7);
+    text(&llm, &chat, &seeded).await;
+    let mut warmer = options.clone();
+    warmer.set_temperature(0.5);
+    text(&llm, &chat, &warmer).await;
+    text(&llm, &[Message::User("Hi!".into())], &options).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 4);
+
+    // But options which aren't sent don't.
+    let mut patient = options.clone();
+    patient.set_keepalive_timeout(std::time::Duration::from_secs(60));
+    text(&llm, &chat, &patient).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn model_override_misses_the_cache() {
+    let llm = Cached::new(Counting {
+        model_in_url: true,
+        ..Default::default()
+    });
+    let chat = [Message::User("Hello!".into())];
+    let options = PromptOptions::default();
+    let mut overridden = options.clone();
+    overridden.set_model_override(lmql::ModelOverride::Named("other".to_owned()));
+
+    text(&llm, &chat, &options).await;
+    text(&llm, &chat, &overridden).await;
+    text(&llm, &chat, &overridden).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn tool_order_is_ignored() {
+    let llm = Cached::new(Counting::default());
+    let chat = [Message::User("Hello!".into())];
+    let options = PromptOptions {
+        tools: vec![tool("a"), tool("b")],
+        ..Default::default()
+    };
+    let reordered = PromptOptions {
+        tools: vec![tool("b"), tool("a")],
+        ..Default::default()
+    };
+
+    text(&llm, &chat, &options).await;
+    text(&llm, &chat, &reordered).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn force_refresh_bypasses_and_replaces() {
+    let llm = Cached::new(Counting::default());
+    let chat = [Message::User("Hello!".into())];
+    let options = PromptOptions::default();
+
+    text(&llm, &chat, &options).await;
+    llm.force_refresh(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    text(&llm, &chat, &options).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 2);
+
+    llm.clear();
+    text(&llm, &chat, &options).await;
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 3);
+}
@@ -1,9 +1,11 @@
+#![allow(dead_code)]
+
 use lmql::{PromptOptions, TokenStreamExt};
 
 #[macro_export]
 macro_rules! tests_with_llm {
     ($llm:expr $(=> skip $($test_name:ident),* $(,)?)?) => {
-        use crate::common::*;
+        use $crate::common::*;
 
         $($(
             async fn $test_name(_llm: impl lmql::LLM) {}
@@ -34,6 +36,12 @@ pub fn setup() {
         .try_init();
 }
 
+/// Drops the chunks which describe the response rather than contributing to it.
+pub fn content(mut chunks: Vec<lmql::Chunk>) -> Vec<lmql::Chunk> {
+    chunks.retain(|chunk| !matches!(chunk, lmql::Chunk::Finish(_) | lmql::Chunk::Usage(_)));
+    chunks
+}
+
 pub async fn stream(llm: impl lmql::LLM) {
     let stream = llm
         .prompt(
@@ -41,7 +49,7 @@ pub async fn stream(llm: impl lmql::LLM) {
             &PromptOptions::default(),
         )
         .unwrap();
-    let response = stream.all_tokens().await.unwrap();
+    let response = content(stream.all_tokens().await.unwrap());
     assert_eq!(response.len(), 1, "{response:?}");
     assert!(matches!(&response[0], lmql::Chunk::Token(text) if text.len() > 1));
 }
@@ -54,14 +62,14 @@ pub async fn reasoning(llm: impl lmql::LLM) {
             )],
             &PromptOptions {
                 reasoning: Some(lmql::ReasoningEffort::Low),
-                temperature: 0.0,
+                temperature: Some(0.0),
                 ..Default::default()
             },
         )
         .unwrap();
-    let mut response = stream.all_tokens().await.unwrap();
+    let mut response = content(stream.all_tokens().await.unwrap());
 
-    assert!(response.len() >= 1 && response.len() <= 2, "{response:?}");
+    assert!(!response.is_empty() && response.len() <= 2, "{response:?}");
 
     if response.len() > 1 {
         let reasoning = response.remove(0);
@@ -93,27 +101,40 @@ pub async fn tool(llm: impl lmql::LLM) {
                     parameters: lmql::ToolParameters::new::<StockPrice>(),
                 }],
                 max_tokens: 4000,
-                temperature: 0.12,
+                temperature: Some(0.12),
                 system_prompt: Some("You are an assistant with little to no knowledge about the world. but you are very good at using tools to answer questions.".to_owned()),
                 stopping_sequences: vec![
                     "pear".to_owned(),
                     "banana".to_owned(),
                 ],
-                reasoning: None
+                reasoning: None,
+                model_override: None,
+                exclude_reasoning: false,
+                keepalive_timeout: None,
+                idempotency_key: None,
+                stopping_sequence_overflow: lmql::StoppingSequenceOverflow::Error,
+                prediction: None,
+                extra_body: serde_json::Map::new(),
+                parallel_tool_calls: None,
+                tool_choice: None,
+                reasoning_summary: Some(lmql::ReasoningSummary::Concise),
+                audio_output: None,
+                correlation_id: None,
+                store: None,
+                previous_response_id: None,
+                prompt_template: None,
+                response_format: None,
+                seed: None,
             };
 
     let mut chat = vec![lmql::Message::User(
         "What is the current price of AAPL?".into(),
     )];
     let stream = llm.prompt(&chat, &options).unwrap();
-    let mut response = stream.all_tokens().await.unwrap();
+    let mut response = content(stream.all_tokens().await.unwrap());
     assert!(response.len() <= 2, "{response:?}");
 
-    chat.extend(
-        response
-            .iter()
-            .filter_map(|response| response.clone().try_into_message()),
-    );
+    chat.extend(lmql::Message::from_chunks(response.clone(), false));
 
     if response.len() > 1 {
         let text = response.remove(0);
@@ -124,6 +145,7 @@ pub async fn tool(llm: impl lmql::LLM) {
         id,
         name,
         arguments,
+        ..
     }) = &response[0]
     else {
         panic!("Expected a tool call, got {response:?}");
@@ -139,10 +161,11 @@ pub async fn tool(llm: impl lmql::LLM) {
         id: id
             .clone()
             .expect("tool usages must include an ID in at least one chunk"),
+        images: vec![],
     });
 
     let stream = llm.prompt(&chat, &options).unwrap();
-    let response = stream.all_tokens().await.unwrap();
+    let response = content(stream.all_tokens().await.unwrap());
     assert_eq!(response.len(), 1, "{response:?}");
     assert!(matches!(&response[0], lmql::Chunk::Token(response) if response.len() >= 7));
 }
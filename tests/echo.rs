@@ -0,0 +1,456 @@
+use lmql::llms::echo::Echo;
+use lmql::{Chunk, FinishReason, Message, PromptOptions, TokenStreamExt, LLM};
+
+async fn chunks(llm: &Echo, chat: &[Message], options: &PromptOptions) -> Vec<Chunk> {
+    use futures::StreamExt;
+    llm.prompt(chat, options)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn echoes_last_user_message() {
+    let llm = Echo::new().with_chunk_size(5);
+    let chat = [
+        Message::User("Not this one.".into()),
+        Message::Assistant("Okay.".into()),
+        Message::User("Hello, world!".into()),
+    ];
+
+    let chunks = chunks(&llm, &chat, &PromptOptions::default()).await;
+
+    let tokens = chunks
+        .iter()
+        .filter_map(|chunk| match chunk {
+            Chunk::Token(token) => Some(token.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tokens, ["Hello", ", wor", "ld!"]);
+    assert!(matches!(chunks[3], Chunk::Finish(FinishReason::EndTurn)));
+}
+
+#[tokio::test]
+async fn honours_stopping_sequences_and_max_tokens() {
+    let llm = Echo::new().reversed();
+    let chat = [Message::User("abc def ghi".into())];
+
+    let mut options = PromptOptions::default();
+    options.set_stopping_sequences(vec![" ".to_owned()]);
+    let response = llm
+        .prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(matches!(
+        response.as_slice(),
+        [Chunk::Token(text), Chunk::Finish(FinishReason::StopSequence(Some(sequence))), Chunk::Usage(_)]
+            if text == "ihg" && sequence == " "
+    ));
+
+    let mut options = PromptOptions::default();
+    options.set_max_tokens(2);
+    let response = llm
+        .prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(matches!(
+        response.as_slice(),
+        [Chunk::Token(text), Chunk::Finish(FinishReason::MaxTokens), Chunk::Usage(_)]
+            if text == "ihg fed "
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn delays_each_chunk() {
+    let llm = Echo::new()
+        .with_chunk_size(1)
+        .with_delay(std::time::Duration::from_millis(100));
+
+    let start = tokio::time::Instant::now();
+    let chunks = chunks(
+        &llm,
+        &[Message::User("Hi!".into())],
+        &PromptOptions::default(),
+    )
+    .await;
+
+    assert_eq!(chunks.len(), 5);
+    assert_eq!(start.elapsed(), std::time::Duration::from_millis(300));
+}
+
+#[tokio::test(start_paused = true)]
+async fn deadline_ends_slow_response() {
+    let llm = Echo::new()
+        .with_chunk_size(1)
+        .with_delay(std::time::Duration::from_millis(100));
+    let deadline = std::time::Duration::from_millis(250);
+
+    let start = tokio::time::Instant::now();
+    let partial = llm
+        .prompt(&[Message::User("Hello!".into())], &PromptOptions::default())
+        .unwrap()
+        .deadline(deadline)
+        .all_tokens_lossy()
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(partial.error, lmql::TokenError::Timeout(timeout) if timeout == deadline),
+        "{partial:?}"
+    );
+    assert!(
+        matches!(partial.partial.as_slice(), [Chunk::Token(text)] if text == "He"),
+        "{partial:?}"
+    );
+    assert_eq!(start.elapsed(), deadline);
+
+    // A response which finishes in time is unaffected.
+    let chunks = llm
+        .prompt(&[Message::User("Hi".into())], &PromptOptions::default())
+        .unwrap()
+        .deadline(deadline)
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(
+        matches!(chunks.first(), Some(Chunk::Token(text)) if text == "Hi"),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn prompt_stream_outlives_messages() {
+    /// Builds the messages itself, which couldn't be returned alongside a borrowing stream.
+    fn greet(llm: &Echo, name: &str) -> lmql::llms::echo::EchoTokenStream {
+        let messages = vec![Message::User(format!("Hello, {name}!"))];
+        llm.prompt(&messages, &PromptOptions::default()).unwrap()
+    }
+
+    let chunks = greet(&Echo::new().with_chunk_size(100), "Ada")
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(
+        matches!(chunks.first(), Some(Chunk::Token(text)) if text == "Hello, Ada!"),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn chat_grows_history() {
+    use lmql::LLMExt;
+
+    let llm = Echo::new().reversed();
+    let (text, history) = llm
+        .chat(vec![], "Hi", &PromptOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(text, "iH");
+
+    let (text, history) = llm
+        .chat(history, "Bye", &PromptOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(text, "eyB");
+    assert_eq!(
+        history,
+        vec![
+            Message::User("Hi".into()),
+            Message::Assistant("iH".into()),
+            Message::User("Bye".into()),
+            Message::Assistant("eyB".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn chat_with_store_persists_turns() {
+    use lmql::store::{ConversationStore, InMemoryStore};
+    use lmql::LLMExt;
+
+    let llm = Echo::new().reversed();
+    let store = InMemoryStore::new();
+    let options = PromptOptions::default();
+
+    assert_eq!(
+        llm.chat_with_store(&store, "a", "Hi", &options)
+            .await
+            .unwrap(),
+        "iH"
+    );
+    assert_eq!(
+        llm.chat_with_store(&store, "a", "Bye", &options)
+            .await
+            .unwrap(),
+        "eyB"
+    );
+    llm.chat_with_store(&store, "b", "Yo", &options)
+        .await
+        .unwrap();
+
+    let history = store.load("a").await.unwrap().unwrap();
+    assert_eq!(history.len(), 4);
+    assert_eq!(store.load("b").await.unwrap().unwrap().len(), 2);
+    assert!(store.load("c").await.unwrap().is_none());
+
+    // Conversations survive a round trip through JSON, as a durable store would keep them.
+    let mut history = history;
+    history.push(Message::ToolRequest {
+        id: "call_1".to_owned(),
+        name: "plot".to_owned(),
+        arguments: lmql::SerializedJson::try_new(serde_json::json!({"title": "Sales"})).unwrap(),
+    });
+    let json = serde_json::to_string(&history).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Vec<Message>>(&json).unwrap(),
+        history
+    );
+}
+
+#[tokio::test]
+async fn ext_futures_can_be_spawned() {
+    use lmql::store::InMemoryStore;
+    use lmql::LLMExt;
+
+    /// Runs each future on another task, which only compiles if every future is `Send`.
+    async fn spawn_all<L: LLM + Send + Sync + 'static>(llm: std::sync::Arc<L>) {
+        let options = PromptOptions::default();
+        let store = std::sync::Arc::new(InMemoryStore::new());
+        let tasks = [
+            tokio::spawn({
+                let llm = llm.clone();
+                async move { llm.prompt_str("Hi").await.map(drop) }
+            }),
+            tokio::spawn({
+                let (llm, options) = (llm.clone(), options.clone());
+                async move { llm.chat(vec![], "Hi", &options).await.map(drop) }
+            }),
+            tokio::spawn({
+                let (llm, options) = (llm.clone(), options.clone());
+                async move {
+                    let chat = [Message::User("Hi".into())];
+                    llm.continue_until_complete(&chat, &options, 2)
+                        .await
+                        .map(drop)
+                }
+            }),
+            tokio::spawn({
+                let (llm, options) = (llm.clone(), options.clone());
+                async move {
+                    let chat = [Message::User(r#"{"x":1}"#.into())];
+                    llm.prompt_structured::<serde_json::Value>(&chat, &options)
+                        .await
+                        .map(drop)
+                }
+            }),
+        ];
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        tokio::spawn(async move { llm.chat_with_store(&*store, "a", "Hi", &options).await })
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    spawn_all(std::sync::Arc::new(Echo::new())).await;
+}
+
+/// Echoes as [`Echo`] does, recording the options of the last prompt.
+struct Recording {
+    supports_tools: bool,
+    options: std::sync::Mutex<Option<PromptOptions>>,
+}
+
+impl LLM for Recording {
+    type TokenStream = lmql::llms::echo::EchoTokenStream;
+
+    fn prompt(
+        &self,
+        chat: &[Message],
+        options: &PromptOptions,
+    ) -> Result<Self::TokenStream, lmql::PromptError> {
+        *self.options.lock().unwrap() = Some(options.clone());
+        Echo::new().prompt(chat, options)
+    }
+
+    fn build_request_body(
+        &self,
+        chat: &[Message],
+        options: &PromptOptions,
+    ) -> Result<String, lmql::PromptError> {
+        Echo::new().build_request_body(chat, options)
+    }
+
+    fn provider(&self) -> &'static str {
+        "Recording"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "recording".into()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.supports_tools
+    }
+}
+
+#[tokio::test]
+async fn prompt_structured_picks_mechanism() {
+    use lmql::LLMExt;
+
+    #[derive(Debug, PartialEq, serde::Deserialize, lmql::JsonSchema)]
+    struct Weather {
+        city: String,
+    }
+
+    let chat = [Message::User(r#"{"city":"Paris"}"#.into())];
+    let mut options = PromptOptions::default();
+    options.set_system_prompt("Be brief.".to_owned());
+
+    // Models with tools are made to call the tool.
+    let llm = Recording {
+        supports_tools: true,
+        options: Default::default(),
+    };
+    let structured = llm
+        .prompt_structured::<Weather>(&chat, &options)
+        .await
+        .unwrap();
+    assert_eq!(structured.value.city, "Paris");
+    let sent = llm.options.lock().unwrap().take().unwrap();
+    assert_eq!(
+        sent.tool_choice,
+        Some(lmql::ToolChoice::Tool("respond".to_owned()))
+    );
+    assert_eq!(sent.tools.len(), 1);
+    assert!(sent.tools[0].parameters.is_strict());
+    assert_eq!(sent.response_format, None);
+
+    // Others are asked for JSON matching the schema.
+    let llm = Recording {
+        supports_tools: false,
+        options: Default::default(),
+    };
+    let structured = llm
+        .prompt_structured::<Weather>(&chat, &options)
+        .await
+        .unwrap();
+    assert_eq!(structured.value.city, "Paris");
+    let sent = llm.options.lock().unwrap().take().unwrap();
+    assert!(sent.tools.is_empty());
+    assert_eq!(sent.response_format, Some(lmql::ResponseFormat::JsonObject));
+    let system_prompt = sent.system_prompt.unwrap();
+    assert!(
+        system_prompt.starts_with("Be brief.\n\n"),
+        "{system_prompt}"
+    );
+    assert!(system_prompt.contains(r#""city""#), "{system_prompt}");
+}
+
+#[tokio::test]
+async fn prompt_structured_skips_brackets_in_prose() {
+    use lmql::LLMExt;
+
+    #[derive(Debug, PartialEq, serde::Deserialize, lmql::JsonSchema)]
+    struct Weather {
+        city: String,
+    }
+
+    let llm = Recording {
+        supports_tools: false,
+        options: Default::default(),
+    };
+    let prompt = |text: &str| {
+        let chat = [Message::User(text.into())];
+        let llm = &llm;
+        async move {
+            llm.prompt_structured::<Weather>(&chat, &PromptOptions::default())
+                .await
+        }
+    };
+
+    // Neither a bracket which starts no value nor a value of another shape is taken.
+    let structured = prompt(r#"Cities [see above] ranked [1, 2]: {"city":"Paris"} done"#)
+        .await
+        .unwrap();
+    assert_eq!(structured.value.city, "Paris");
+    assert_eq!(structured.raw_json, r#"{"city":"Paris"}"#);
+    assert_eq!(
+        structured.preface.as_deref(),
+        Some("Cities [see above] ranked [1, 2]:")
+    );
+
+    // Without a matching value, the first well-formed one is reported.
+    let error = prompt(r#"See [this] and {"town":"Paris"}"#)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&error, lmql::Error::MalformedStructuredOutput { raw_json, .. } if raw_json == r#"{"town":"Paris"}"#),
+        "{error:?}"
+    );
+    let error = prompt("See [this] and {that}").await.unwrap_err();
+    assert!(
+        matches!(error, lmql::Error::MissingStructuredOutput),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn prompt_boxed_erases_the_stream() {
+    use futures::stream::BoxStream;
+
+    fn prompt(
+        reversed: bool,
+        chat: &[Message],
+    ) -> Result<BoxStream<'static, Result<Chunk, lmql::TokenError>>, lmql::PromptError> {
+        let options = PromptOptions::default();
+        if reversed {
+            lmql::rate_limit::RateLimited::new(Echo::new().reversed()).prompt_boxed(chat, &options)
+        } else {
+            Echo::new().prompt_boxed(chat, &options)
+        }
+    }
+
+    let chat = [Message::User("abc".into())];
+    for (reversed, expected) in [(false, "abc"), (true, "cba")] {
+        let chunks = prompt(reversed, &chat).unwrap().all_tokens().await.unwrap();
+        assert!(
+            matches!(&chunks[0], Chunk::Token(text) if text == expected),
+            "{chunks:?}"
+        );
+    }
+}
+
+#[test]
+fn llm_defaults_for_custom_models() {
+    /// Implements only what every model must.
+    struct Minimal;
+
+    impl LLM for Minimal {
+        type TokenStream = lmql::llms::echo::EchoTokenStream;
+
+        fn prompt(
+            &self,
+            chat: &[Message],
+            options: &PromptOptions,
+        ) -> Result<Self::TokenStream, lmql::PromptError> {
+            Echo::new().prompt(chat, options)
+        }
+    }
+
+    let chat = [Message::User("Hi".into())];
+    let options = PromptOptions::default();
+    assert!(Minimal.provider().ends_with("Minimal"));
+    assert_eq!(Minimal.model_name(), "unknown");
+    assert!(matches!(
+        Minimal.build_request_body(&chat, &options),
+        Err(lmql::PromptError::UnsupportedRequestBody { provider }) if provider.ends_with("Minimal")
+    ));
+}
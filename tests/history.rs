@@ -0,0 +1,74 @@
+use lmql::{
+    history::{estimate_tokens, split_to_budget, truncate_to_budget},
+    Message, SerializedJson,
+};
+
+fn conversation() -> Vec<Message> {
+    vec![
+        Message::User("What is the weather in Paris?".repeat(10)),
+        Message::ToolRequest {
+            id: "call_1".to_owned(),
+            name: "get_weather".to_owned(),
+            arguments: SerializedJson::try_new(serde_json::json!({ "city": "Paris" })).unwrap(),
+        },
+        Message::ToolResponse {
+            content: "Sunny, 24C".repeat(10),
+            id: "call_1".to_owned(),
+            images: vec![],
+        },
+        Message::Assistant("It is sunny in Paris.".repeat(10)),
+        Message::User("And in London?".to_owned()),
+        Message::Assistant("I don't know.".to_owned()),
+    ]
+}
+
+#[test]
+fn truncation_keeps_whole_turns() {
+    let mut chat = conversation();
+    let latest_turn = estimate_tokens(&chat[4..]);
+    assert!(estimate_tokens(&chat) > latest_turn + 10);
+
+    // Too small for the first turn, so its tool call is dropped along with it.
+    let removed = truncate_to_budget(&mut chat, latest_turn + 10);
+    assert_eq!(removed, 4);
+    assert_eq!(chat, conversation()[4..]);
+}
+
+#[test]
+fn truncation_within_budget_is_noop() {
+    let mut chat = conversation();
+    let budget = estimate_tokens(&chat);
+    let removed = truncate_to_budget(&mut chat, budget);
+    assert_eq!(removed, 0);
+    assert_eq!(chat, conversation());
+}
+
+#[test]
+fn truncation_keeps_latest_turn_over_budget() {
+    let mut chat = conversation();
+    let removed = truncate_to_budget(&mut chat, 0);
+    assert_eq!(removed, 4);
+    assert_eq!(chat.len(), 2);
+}
+
+#[test]
+fn splitting_prefers_paragraph_breaks() {
+    let text = "First paragraph here.\n\nSecond one, which is longer.";
+
+    let pieces = split_to_budget(text, 10);
+
+    assert_eq!(
+        pieces,
+        ["First paragraph here.", "Second one, which is longer."]
+    );
+}
+
+#[test]
+fn splitting_falls_back_to_spaces_and_hard_cuts() {
+    let pieces = split_to_budget("one two three four", 2);
+    assert_eq!(pieces, ["one two", "three", "four"]);
+
+    let pieces = split_to_budget(&"x".repeat(10), 1);
+    assert_eq!(pieces, ["xxxx", "xxxx", "xx"]);
+    assert_eq!(split_to_budget("", 1), Vec::<String>::new());
+}
@@ -0,0 +1,104 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use lmql::{Message, PromptOptions, LLM};
+
+/// Held by each test, as the logging settings are shared by the whole process.
+static SETTINGS: Mutex<()> = Mutex::new(());
+
+/// Collects everything logged while the closure runs.
+fn logs(run: impl FnOnce()) -> String {
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, run);
+
+    let logs = buffer.0.lock().unwrap();
+    String::from_utf8_lossy(&logs).into_owned()
+}
+
+#[tokio::test]
+async fn request_bodies_are_redacted_in_logs() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let chat = [Message::User("My email is jo@example.com".into())];
+
+    let _settings = SETTINGS.lock().unwrap();
+    lmql::logging::set_redactor(|body| body.replace("jo@example.com", "[email]"));
+    let redacted = logs(|| {
+        gpt.prompt(&chat, &PromptOptions::default()).unwrap();
+    });
+    lmql::logging::clear_redactor();
+
+    assert!(redacted.contains("OpenAI request body"), "{redacted}");
+    assert!(redacted.contains("[email]"), "{redacted}");
+    assert!(!redacted.contains("jo@example.com"), "{redacted}");
+
+    // The request itself is unchanged.
+    let body = gpt
+        .build_request_body(&chat, &PromptOptions::default())
+        .unwrap();
+    assert!(body.contains("jo@example.com"));
+}
+
+#[tokio::test]
+async fn credentials_are_redacted_in_logs() {
+    let gpt = lmql::llms::openai::Gpt::new(
+        lmql::llms::openai::GptModel::Gpt4oMini,
+        "sk-secret-key".to_owned(),
+    );
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "sk-ant-secret-key".to_owned(),
+    );
+    let chat = [Message::User("Hi!".into())];
+
+    let _settings = SETTINGS.lock().unwrap();
+    let logged = logs(|| {
+        gpt.prompt(&chat, &PromptOptions::default()).unwrap();
+        claude.prompt(&chat, &PromptOptions::default()).unwrap();
+    });
+
+    assert!(!logged.contains("secret-key"), "{logged}");
+    assert!(
+        logged.contains("\"authorization\": \"[redacted]\""),
+        "{logged}"
+    );
+    assert!(logged.contains("\"x-api-key\": \"[redacted]\""), "{logged}");
+    // Other headers are still logged.
+    assert!(logged.contains("application/json"), "{logged}");
+}
+
+#[tokio::test]
+async fn bodies_can_be_left_out_of_logs() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let chat = [Message::User("My email is jo@example.com".into())];
+
+    let _settings = SETTINGS.lock().unwrap();
+    lmql::logging::set_log_bodies(false);
+    let logged = logs(|| {
+        gpt.prompt(&chat, &PromptOptions::default()).unwrap();
+    });
+    lmql::logging::set_log_bodies(true);
+
+    assert!(!logged.contains("jo@example.com"), "{logged}");
+    // The rest of the request is still logged.
+    assert!(logged.contains("api.openai.com"), "{logged}");
+}
@@ -17,3 +17,11 @@ mod o3 {
         )
     }
 }
+
+mod responses {
+    super::tests_with_llm! {
+        lmql::llms::openai::responses::GptResponses::new_from_env(
+            lmql::llms::openai::GptModel::o3Mini,
+        )
+    }
+}
@@ -0,0 +1,25 @@
+use lmql::{PromptOptions, PromptOptionsOverrides};
+
+#[test]
+fn overrides_only_replace_set_fields() {
+    let mut base = PromptOptions::default();
+    base.set_system_prompt("Be brief.".to_owned())
+        .set_temperature(0.2);
+    base.extra_body
+        .insert("store".to_owned(), serde_json::json!(true));
+
+    let mut overrides = PromptOptionsOverrides {
+        max_tokens: Some(100),
+        temperature: Some(1.0),
+        ..Default::default()
+    };
+    overrides
+        .extra_body
+        .insert("user".to_owned(), serde_json::json!("me"));
+
+    let options = base.with_overrides(overrides);
+    assert_eq!(options.max_tokens, 100);
+    assert_eq!(options.temperature, Some(1.0));
+    assert_eq!(options.system_prompt.as_deref(), Some("Be brief."));
+    assert_eq!(options.extra_body.len(), 2);
+}
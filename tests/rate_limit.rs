@@ -0,0 +1,135 @@
+use lmql::rate_limit::RateLimited;
+use lmql::{Chunk, Message, PromptError, PromptOptions, TokenError, TokenStreamExt, LLM};
+
+/// Responds to every prompt with the same text, without sending anything.
+struct Echo;
+
+impl LLM for Echo {
+    type TokenStream = futures::stream::Iter<std::vec::IntoIter<Result<Chunk, TokenError>>>;
+
+    fn prompt(
+        &self,
+        _messages: &[Message],
+        _options: &PromptOptions,
+    ) -> Result<Self::TokenStream, PromptError> {
+        Ok(futures::stream::iter(vec![Ok(Chunk::Token(
+            "Hello".to_owned(),
+        ))]))
+    }
+
+    fn build_request_body(
+        &self,
+        _messages: &[Message],
+        _options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        Ok(String::new())
+    }
+
+    fn provider(&self) -> &'static str {
+        "Echo"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "echo".into()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_per_minute_shared_between_clones() {
+    let llm = RateLimited::new(Echo).with_requests_per_minute(2);
+    let clone = llm.clone();
+    let chat = [Message::User("Hi!".into())];
+    let options = PromptOptions::default();
+
+    let start = tokio::time::Instant::now();
+    for llm in [&llm, &clone] {
+        llm.prompt(&chat, &options)
+            .unwrap()
+            .all_tokens()
+            .await
+            .unwrap();
+    }
+    assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+
+    // The bucket refills at two requests a minute, so the third waits half a minute.
+    let chunks = clone
+        .prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(matches!(chunks.as_slice(), [Chunk::Token(text)] if text == "Hello"));
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!((elapsed - 30.0).abs() < 0.01, "{elapsed}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn tokens_per_minute_counts_max_tokens() {
+    let llm = RateLimited::new(Echo).with_tokens_per_minute(1000);
+    let chat = [Message::User("Hi!".into())];
+    let mut options = PromptOptions::default();
+    options.set_max_tokens(500);
+
+    let start = tokio::time::Instant::now();
+    llm.prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+
+    // The first prompt also used a few tokens of its messages, so the second must wait for them.
+    llm.prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert!(start.elapsed() > std::time::Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn tokens_per_minute_ignores_model_max_tokens() {
+    let llm = RateLimited::new(Echo).with_tokens_per_minute(1000);
+    let chat = [Message::User("Hi!".into())];
+    let mut options = PromptOptions::default();
+    options.set_max_tokens(lmql::MODEL_MAX_TOKENS);
+
+    // The model's limit isn't known, so only the messages are counted, rather than a whole
+    // minute's worth for each prompt.
+    let start = tokio::time::Instant::now();
+    for _ in 0..2 {
+        llm.prompt(&chat, &options)
+            .unwrap()
+            .all_tokens()
+            .await
+            .unwrap();
+    }
+    assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropped_prompts_give_back_their_reservation() {
+    let llm = RateLimited::new(Echo).with_requests_per_minute(1);
+    let chat = [Message::User("Hi!".into())];
+    let options = PromptOptions::default();
+
+    // A prompt which is never sent doesn't count.
+    drop(llm.prompt(&chat, &options).unwrap());
+    let start = tokio::time::Instant::now();
+    llm.prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+
+    // Nor does one which is dropped while waiting, so the next waits only for the one sent.
+    drop(llm.prompt(&chat, &options).unwrap());
+    llm.prompt(&chat, &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!((elapsed - 60.0).abs() < 0.01, "{elapsed}");
+}
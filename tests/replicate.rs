@@ -0,0 +1,8 @@
+mod common;
+
+mod llama3 {
+    super::tests_with_llm! {
+        lmql::llms::replicate::Replicate::new_from_env("meta/meta-llama-3-8b-instruct")
+            => skip tool, reasoning
+    }
+}
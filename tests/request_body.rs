@@ -0,0 +1,1196 @@
+use lmql::{Message, PromptOptions, LLM};
+
+fn body(llm: &impl LLM, chat: &[Message], options: &PromptOptions) -> serde_json::Value {
+    let body = llm.build_request_body(chat, options).unwrap();
+    serde_json::from_str(&body).unwrap()
+}
+
+#[test]
+fn claude_system_prompt() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let body = body(
+        &claude,
+        &[Message::User("Hello!".into())],
+        PromptOptions::default().set_system_prompt("Be brief.".to_owned()),
+    );
+
+    assert_eq!(body["model"], "claude-3-5-haiku-20241022");
+    assert_eq!(body["system"], "Be brief.");
+    assert_eq!(body["stream"], true);
+    assert_eq!(body["messages"][0]["role"], "user");
+    assert_eq!(body["messages"][0]["content"][0]["text"], "Hello!");
+}
+
+#[test]
+fn multimodal_user_message() {
+    let image = lmql::Image::from_bytes(b"png", "image/png");
+    let chat = [lmql::UserMessage::new()
+        .text("Describe these")
+        .image(image.clone())
+        .image(image)
+        .text("Briefly.")
+        .build()];
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let claude_body = body(&claude, &chat, &PromptOptions::default());
+    let content = &claude_body["messages"][0]["content"];
+    assert_eq!(content[0]["text"], "Describe these");
+    assert_eq!(content[1]["type"], "image");
+    assert_eq!(content[1]["source"]["media_type"], "image/png");
+    assert_eq!(content[2]["type"], "image");
+    assert_eq!(content[3]["text"], "Briefly.");
+    assert_eq!(claude_body["messages"].as_array().unwrap().len(), 1);
+}
+
+fn multimodal_chat() -> [Message; 2] {
+    let image = lmql::Image::from_bytes(b"png", "image/png");
+    [
+        Message::User("Here are some charts.".into()),
+        lmql::UserMessage::new()
+            .text("Describe this")
+            .image(image)
+            .text("Briefly.")
+            .build(),
+    ]
+}
+
+#[test]
+fn gpt_multimodal_user_message() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let body = body(&gpt, &multimodal_chat(), &PromptOptions::default());
+
+    // Consecutive user messages are still collated into one.
+    assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+    let content = &body["messages"][0]["content"];
+    assert_eq!(content[0]["type"], "text");
+    assert_eq!(content[0]["text"], "Here are some charts.\n\nDescribe this");
+    assert_eq!(content[1]["type"], "image_url");
+    assert_eq!(content[1]["image_url"]["url"], "data:image/png;base64,cG5n");
+    assert_eq!(content[2]["text"], "Briefly.");
+}
+
+#[test]
+fn openrouter_multimodal_user_message() {
+    let openrouter = lmql::llms::openrouter::OpenRouter::new("openai/gpt-4o", "key");
+    let body = body(&openrouter, &multimodal_chat(), &PromptOptions::default());
+
+    assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+    let content = &body["messages"][0]["content"];
+    assert_eq!(content[0]["text"], "Here are some charts.\n\nDescribe this");
+    assert_eq!(content[1]["type"], "image_url");
+    assert_eq!(content[1]["image_url"]["url"], "data:image/png;base64,cG5n");
+    assert_eq!(content[2]["text"], "Briefly.");
+}
+
+#[test]
+fn gpt_responses_multimodal_user_message() {
+    let gpt = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4oMini,
+        "key".to_owned(),
+    );
+    let body = body(&gpt, &multimodal_chat(), &PromptOptions::default());
+
+    assert_eq!(body["input"][0]["content"], "Here are some charts.");
+    let content = &body["input"][1]["content"];
+    assert_eq!(content[0]["type"], "input_text");
+    assert_eq!(content[0]["text"], "Describe this");
+    assert_eq!(content[1]["type"], "input_image");
+    assert_eq!(content[1]["image_url"], "data:image/png;base64,cG5n");
+    assert_eq!(content[2]["text"], "Briefly.");
+}
+
+#[test]
+fn blank_system_prompt() {
+    let chat = [Message::User("Hello!".into())];
+    let mut options = PromptOptions::default();
+    options.set_system_prompt("  \n".to_owned());
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    assert!(body(&claude, &chat, &options).get("system").is_none());
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let gpt_body = body(&gpt, &chat, &options);
+    assert_eq!(gpt_body["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(gpt_body["messages"][0]["role"], "user");
+}
+
+#[test]
+fn gpt_collates_messages() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let body = body(
+        &gpt,
+        &[
+            Message::User("Hello!".into()),
+            Message::User("How are you?".into()),
+        ],
+        &PromptOptions::default(),
+    );
+
+    assert_eq!(body["model"], "gpt-4o-mini");
+    assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(body["messages"][0]["content"], "Hello!\n\nHow are you?");
+}
+
+#[test]
+fn gpt_too_many_stopping_sequences() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let mut options = PromptOptions::default();
+    options.set_stopping_sequences((0..5).map(|i| i.to_string()).collect());
+
+    let error = gpt
+        .build_request_body(&[Message::User("Hello!".into())], &options)
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        lmql::PromptError::TooManyStoppingSequences {
+            limit: 4,
+            count: 5,
+            ..
+        }
+    ));
+
+    options.set_stopping_sequence_overflow(lmql::StoppingSequenceOverflow::Truncate);
+    let body = body(&gpt, &[Message::User("Hello!".into())], &options);
+    assert_eq!(body["stop"].as_array().unwrap().len(), 4);
+}
+
+#[test]
+fn claude_tool_result_image() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let body = body(
+        &claude,
+        &[
+            Message::User("Plot it.".into()),
+            Message::ToolRequest {
+                id: "tool_1".to_owned(),
+                name: "plot".to_owned(),
+                arguments: lmql::SerializedJson::try_new(serde_json::json!({})).unwrap(),
+            },
+            Message::ToolResponse {
+                content: "Here is the chart.".to_owned(),
+                id: "tool_1".to_owned(),
+                images: vec![lmql::Image {
+                    media_type: "image/png".to_owned(),
+                    data: "iVBORw0KGgo=".to_owned(),
+                }],
+            },
+        ],
+        &PromptOptions::default(),
+    );
+
+    let result = &body["messages"][2]["content"][0];
+    assert_eq!(result["type"], "tool_result");
+    assert_eq!(result["content"][0]["text"], "Here is the chart.");
+    assert_eq!(result["content"][1]["type"], "image");
+    assert_eq!(result["content"][1]["source"]["media_type"], "image/png");
+}
+
+#[test]
+fn image_from_path() {
+    let dir = std::env::temp_dir().join(format!("lmql-image-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // The contents decide the media type over a misleading extension.
+    let png = dir.join("chart.jpg");
+    std::fs::write(&png, b"\x89PNG\r\n\x1a\n").unwrap();
+    let image = lmql::Image::from_path(&png).unwrap();
+    assert_eq!(image.media_type, "image/png");
+    assert_eq!(image.data, "iVBORw0KGgo=");
+
+    let webp = dir.join("photo.WEBP");
+    std::fs::write(&webp, b"not quite").unwrap();
+    assert_eq!(
+        lmql::Image::from_path(&webp).unwrap().media_type,
+        "image/webp"
+    );
+
+    let text = dir.join("notes.txt");
+    std::fs::write(&text, b"hello").unwrap();
+    let error = lmql::Image::from_path(&text).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn model_override() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let chat = [Message::User("Hello!".into())];
+
+    let mut options = PromptOptions::default();
+    options.set_model_override(lmql::ModelOverride::Gpt(
+        lmql::llms::openai::GptModel::Gpt4o,
+    ));
+    assert_eq!(body(&gpt, &chat, &options)["model"], "gpt-4o");
+
+    options.set_model_override(lmql::ModelOverride::Named("gpt-4o".to_owned()));
+    assert!(matches!(
+        gpt.build_request_body(&chat, &options),
+        Err(lmql::PromptError::IncompatibleModelOverride { .. })
+    ));
+}
+
+#[test]
+fn extra_body() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let chat = [Message::User("Hello!".into())];
+
+    let mut options = PromptOptions::default();
+    options
+        .extra_body
+        .insert("store".to_owned(), serde_json::json!(true));
+    assert_eq!(body(&gpt, &chat, &options)["store"], true);
+
+    options
+        .extra_body
+        .insert("model".to_owned(), serde_json::json!("gpt-4o"));
+    assert!(matches!(
+        gpt.build_request_body(&chat, &options),
+        Err(lmql::PromptError::ConflictingExtraBodyField(field)) if field == "model"
+    ));
+}
+
+#[test]
+fn gpt_responses_tools_are_not_strict() {
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Plot {
+        title: Option<String>,
+    }
+    let gpt = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4o,
+        "key".to_owned(),
+    );
+    let options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "plot".to_owned(),
+            description: "Plots a chart.".to_owned(),
+            parameters: lmql::ToolParameters::new::<Plot>(),
+        }],
+        ..Default::default()
+    };
+    let body = body(&gpt, &[Message::User("Plot it.".into())], &options);
+
+    assert_eq!(body["tools"][0]["type"], "function");
+    assert_eq!(body["tools"][0]["name"], "plot");
+    assert_eq!(body["tools"][0]["strict"], false);
+}
+
+#[test]
+fn strict_tools() {
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Plot {
+        title: Option<String>,
+        points: Vec<Point>,
+        #[serde(default)]
+        kind: String,
+    }
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    let options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "plot".to_owned(),
+            description: "Plots a chart.".to_owned(),
+            parameters: lmql::ToolParameters::new_with_settings::<Plot>(
+                schemars::gen::SchemaSettings::draft07().with(|settings| {
+                    settings.inline_subschemas = true;
+                    settings.meta_schema = None;
+                }),
+            )
+            .strict(),
+        }],
+        ..Default::default()
+    };
+    let chat = [Message::User("Plot it.".into())];
+    let expected = serde_json::json!({
+        "title": "Plot",
+        "type": "object",
+        "required": ["kind", "points", "title"],
+        "additionalProperties": false,
+        "properties": {
+            "kind": {"default": "", "type": ["string", "null"]},
+            "points": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["x", "y"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "x": {"type": "number", "format": "double"},
+                        "y": {"type": "number", "format": "double"},
+                    },
+                },
+            },
+            "title": {"type": ["string", "null"]},
+        },
+    });
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let function = &body(&gpt, &chat, &options)["tools"][0]["function"];
+    assert_eq!(function["strict"], true);
+    assert_eq!(function["parameters"], expected);
+
+    let openrouter = lmql::llms::openrouter::OpenRouter::new("openai/gpt-4o", "key");
+    let function = &body(&openrouter, &chat, &options)["tools"][0]["function"];
+    assert_eq!(function["strict"], true);
+    assert_eq!(function["parameters"], expected);
+
+    let responses = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4o,
+        "key".to_owned(),
+    );
+    let tool = &body(&responses, &chat, &options)["tools"][0];
+    assert_eq!(tool["strict"], true);
+    assert_eq!(tool["parameters"], expected);
+
+    // Providers without strict schemas are given the schema as it is.
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let tool = &body(&claude, &chat, &options)["tools"][0];
+    assert_eq!(
+        tool["input_schema"]["required"],
+        serde_json::json!(["points"])
+    );
+
+    // Tools aren't strict unless asked to be.
+    let options = PromptOptions {
+        tools: vec![lmql::Tool {
+            parameters: lmql::ToolParameters::new::<Point>(),
+            ..options.tools[0].clone()
+        }],
+        ..Default::default()
+    };
+    let function = &body(&gpt, &chat, &options)["tools"][0]["function"];
+    assert!(function.get("strict").is_none());
+    assert!(function["parameters"].get("additionalProperties").is_none());
+}
+
+#[test]
+fn gpt_responses_input() {
+    let gpt = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::o3Mini,
+        "key".to_owned(),
+    );
+    let options = PromptOptions {
+        system_prompt: Some("Be brief.".to_owned()),
+        reasoning: Some(lmql::ReasoningEffort::Low),
+        reasoning_summary: Some(lmql::ReasoningSummary::Detailed),
+        ..Default::default()
+    };
+    let body = body(
+        &gpt,
+        &[
+            Message::User("Plot it.".into()),
+            Message::ToolRequest {
+                id: "call_1".to_owned(),
+                name: "plot".to_owned(),
+                arguments: lmql::SerializedJson::try_new(serde_json::json!({})).unwrap(),
+            },
+            Message::ToolResponse {
+                content: "Done.".to_owned(),
+                id: "call_1".to_owned(),
+                images: vec![],
+            },
+        ],
+        &options,
+    );
+
+    assert_eq!(body["model"], "o3-mini");
+    assert_eq!(body["instructions"], "Be brief.");
+    assert_eq!(body["reasoning"]["effort"], "low");
+    assert_eq!(body["reasoning"]["summary"], "detailed");
+    assert!(body.get("temperature").is_none());
+    assert_eq!(body["input"][0]["role"], "user");
+    assert_eq!(body["input"][1]["type"], "function_call");
+    assert_eq!(body["input"][1]["arguments"], "{}");
+    assert_eq!(body["input"][2]["type"], "function_call_output");
+    assert_eq!(body["input"][2]["call_id"], "call_1");
+}
+
+#[cfg(feature = "vertex")]
+#[test]
+fn vertex_claude_model_max_tokens() {
+    let credentials = lmql::llms::vertex::Credentials::from_json(
+        r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"token"}"#,
+    )
+    .unwrap();
+    let claude = |model: &str| {
+        lmql::llms::vertex::Vertex::new(
+            "project",
+            "us-east5",
+            lmql::llms::vertex::VertexModel::Claude(model.to_owned()),
+            credentials.clone(),
+        )
+    };
+    let chat = [Message::User("Write an essay.".into())];
+    let mut options = PromptOptions::default();
+    options.set_max_tokens(lmql::MODEL_MAX_TOKENS);
+
+    assert_eq!(
+        body(&claude("claude-3-5-sonnet-v2@20241022"), &chat, &options)["max_tokens"],
+        8192
+    );
+    assert_eq!(
+        body(&claude("claude-sonnet-4@20250514"), &chat, &options)["max_tokens"],
+        64_000
+    );
+    // Models which aren't known fall back to the default.
+    assert_eq!(
+        body(&claude("claude-next@20990101"), &chat, &options)["max_tokens"],
+        lmql::DEFAULT_MAX_TOKENS
+    );
+}
+
+#[cfg(feature = "vertex")]
+#[test]
+fn vertex_gemini_tool_response() {
+    let credentials = lmql::llms::vertex::Credentials::from_json(
+        r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"token"}"#,
+    )
+    .unwrap();
+    let gemini = lmql::llms::vertex::Vertex::new(
+        "project",
+        "us-central1",
+        lmql::llms::vertex::VertexModel::Gemini("gemini-2.0-flash".to_owned()),
+        credentials,
+    );
+    let body = body(
+        &gemini,
+        &[
+            Message::User("Plot it.".into()),
+            Message::ToolRequest {
+                id: "call_0".to_owned(),
+                name: "plot".to_owned(),
+                arguments: lmql::SerializedJson::try_new(serde_json::json!({})).unwrap(),
+            },
+            Message::ToolResponse {
+                content: "Done.".to_owned(),
+                id: "call_0".to_owned(),
+                images: vec![],
+            },
+        ],
+        &PromptOptions::default(),
+    );
+
+    assert_eq!(body["contents"][0]["role"], "user");
+    assert_eq!(body["contents"][1]["role"], "model");
+    assert_eq!(
+        body["contents"][1]["parts"][0]["functionCall"]["name"],
+        "plot"
+    );
+    let response = &body["contents"][2]["parts"][0]["functionResponse"];
+    assert_eq!(response["name"], "plot");
+    assert_eq!(response["response"]["content"], "Done.");
+}
+
+#[test]
+fn claude_zero_temperature() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let chat = [Message::User("Hello!".into())];
+
+    let mut options = PromptOptions::default();
+    options.set_temperature(0.0);
+    let raw = claude.build_request_body(&chat, &options).unwrap();
+    assert!(raw.contains(r#""temperature":0.0"#), "{raw}");
+
+    // Without a temperature, the API's default is used.
+    options.temperature = None;
+    assert!(body(&claude, &chat, &options).get("temperature").is_none());
+    options.set_temperature(1.0);
+    assert_eq!(body(&claude, &chat, &options)["temperature"], 1.0);
+
+    // Reasoning requires the default temperature.
+    options.set_temperature(0.0);
+    options.reasoning = Some(lmql::ReasoningEffort::Low);
+    assert!(body(&claude, &chat, &options).get("temperature").is_none());
+}
+
+#[test]
+fn temperature_defaults_to_the_models() {
+    let chat = [Message::User("Hello!".into())];
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let openrouter =
+        lmql::llms::openrouter::OpenRouter::new("meta-llama/llama-3.1-70b-instruct", "key");
+    let replicate = lmql::llms::replicate::Replicate::new("meta/meta-llama-3-70b-instruct", "key");
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let responses = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4oMini,
+        "key".to_owned(),
+    );
+    let compatible = lmql::llms::openai::compatible::OpenAICompatible::new(
+        "https://api.example.com/v1",
+        "some-model",
+    );
+    let huggingface = lmql::llms::huggingface::HuggingFace::new("Qwen/Qwen2.5-72B-Instruct", "key");
+
+    let options = PromptOptions::default();
+    assert!(body(&gpt, &chat, &options).get("temperature").is_none());
+    assert!(body(&openrouter, &chat, &options)
+        .get("temperature")
+        .is_none());
+    assert!(body(&replicate, &chat, &options)["input"]
+        .get("temperature")
+        .is_none());
+    assert!(body(&claude, &chat, &options).get("temperature").is_none());
+    assert!(body(&responses, &chat, &options)
+        .get("temperature")
+        .is_none());
+    assert!(body(&compatible, &chat, &options)
+        .get("temperature")
+        .is_none());
+    assert!(body(&huggingface, &chat, &options)
+        .get("temperature")
+        .is_none());
+
+    let mut options = PromptOptions::default();
+    options.set_temperature(1.0);
+    assert_eq!(body(&gpt, &chat, &options)["temperature"], 1.0);
+    assert_eq!(body(&openrouter, &chat, &options)["temperature"], 1.0);
+    assert_eq!(
+        body(&replicate, &chat, &options)["input"]["temperature"],
+        1.0
+    );
+    assert_eq!(body(&claude, &chat, &options)["temperature"], 1.0);
+    assert_eq!(body(&responses, &chat, &options)["temperature"], 1.0);
+    assert_eq!(body(&compatible, &chat, &options)["temperature"], 1.0);
+    assert_eq!(body(&huggingface, &chat, &options)["temperature"], 1.0);
+}
+
+#[cfg(feature = "vertex")]
+#[test]
+fn vertex_temperature_defaults_to_the_models() {
+    let credentials = lmql::llms::vertex::Credentials::from_json(
+        r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"token"}"#,
+    )
+    .unwrap();
+    let vertex =
+        |model| lmql::llms::vertex::Vertex::new("project", "us-east5", model, credentials.clone());
+    let gemini = vertex(lmql::llms::vertex::VertexModel::Gemini(
+        "gemini-2.0-flash".to_owned(),
+    ));
+    let claude = vertex(lmql::llms::vertex::VertexModel::Claude(
+        "claude-3-5-sonnet-v2@20241022".to_owned(),
+    ));
+    let chat = [Message::User("Hello!".into())];
+
+    let options = PromptOptions::default();
+    assert!(body(&gemini, &chat, &options)["generationConfig"]
+        .get("temperature")
+        .is_none());
+    assert!(body(&claude, &chat, &options).get("temperature").is_none());
+
+    let mut options = PromptOptions::default();
+    options.set_temperature(1.0);
+    assert_eq!(
+        body(&gemini, &chat, &options)["generationConfig"]["temperature"],
+        1.0
+    );
+    assert_eq!(body(&claude, &chat, &options)["temperature"], 1.0);
+}
+
+#[test]
+fn openai_compatible_named_model() {
+    let litellm = lmql::llms::openai::compatible::OpenAICompatible::litellm(
+        "https://litellm.example.com",
+        "claude-3-5-sonnet",
+        "sk-1234",
+    )
+    .with_header("x-litellm-tags", "team-a");
+    let mut options = PromptOptions::default();
+    options
+        .set_system_prompt("Be brief.".to_owned())
+        .set_stopping_sequences((0..5).map(|i| i.to_string()).collect());
+    let body = body(&litellm, &[Message::User("Hello!".into())], &options);
+
+    assert_eq!(body["model"], "claude-3-5-sonnet");
+    assert_eq!(body["messages"][0]["role"], "system");
+    assert_eq!(body["stop"].as_array().unwrap().len(), 5);
+}
+
+#[test]
+fn huggingface_limits_stopping_sequences() {
+    let llm = lmql::llms::huggingface::HuggingFace::new("tgi", "hf_1234")
+        .with_base_url("https://abc123.us-east-1.aws.endpoints.huggingface.cloud/v1");
+    let mut options = PromptOptions::default();
+    options.set_stopping_sequences((0..4).map(|i| i.to_string()).collect());
+    let body = body(&llm, &[Message::User("Hello!".into())], &options);
+
+    assert_eq!(body["model"], "tgi");
+    assert_eq!(body["stream"], true);
+    assert_eq!(body["stop"].as_array().unwrap().len(), 4);
+
+    options.set_stopping_sequences((0..5).map(|i| i.to_string()).collect());
+    let error = llm
+        .build_request_body(&[Message::User("Hello!".into())], &options)
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        lmql::PromptError::TooManyStoppingSequences {
+            provider: "HuggingFace",
+            limit: 4,
+            count: 5,
+        }
+    ));
+}
+
+#[test]
+fn gpt_audio_output() {
+    let gpt = lmql::llms::openai::Gpt::new(
+        lmql::llms::openai::GptModel::Gpt4oAudioPreview,
+        "key".to_owned(),
+    );
+    let mut options = PromptOptions::default();
+    options.set_audio_output(lmql::AudioOutput {
+        voice: "alloy".to_owned(),
+        format: lmql::AudioFormat::Pcm16,
+    });
+    let body = body(&gpt, &[Message::User("Hello!".into())], &options);
+
+    assert_eq!(body["modalities"], serde_json::json!(["text", "audio"]));
+    assert_eq!(
+        body["audio"],
+        serde_json::json!({ "voice": "alloy", "format": "pcm16" })
+    );
+}
+
+#[test]
+fn parallel_tool_calls() {
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Plot {
+        title: String,
+    }
+    let mut options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "plot".to_owned(),
+            description: "Plots a chart.".to_owned(),
+            parameters: lmql::ToolParameters::new::<Plot>(),
+        }],
+        ..Default::default()
+    };
+    options.set_parallel_tool_calls(false);
+    let chat = [Message::User("Plot it.".into())];
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    assert_eq!(body(&gpt, &chat, &options)["parallel_tool_calls"], false);
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let tool_choice = &body(&claude, &chat, &options)["tool_choice"];
+    assert_eq!(tool_choice["type"], "auto");
+    assert_eq!(tool_choice["disable_parallel_tool_use"], true);
+
+    options.tools.clear();
+    assert!(body(&gpt, &chat, &options)
+        .get("parallel_tool_calls")
+        .is_none());
+}
+
+#[test]
+fn tool_choice() {
+    #[derive(schemars::JsonSchema, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct Search {
+        query: String,
+    }
+    let mut options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "search".to_owned(),
+            description: "Searches the web.".to_owned(),
+            parameters: lmql::ToolParameters::new::<Search>(),
+        }],
+        ..Default::default()
+    };
+    let chat = [Message::User("Look it up.".into())];
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+
+    options.set_tool_choice(lmql::ToolChoice::Any);
+    assert_eq!(
+        body(&claude, &chat, &options)["tool_choice"],
+        serde_json::json!({"type": "any"})
+    );
+    assert_eq!(body(&gpt, &chat, &options)["tool_choice"], "required");
+
+    options
+        .set_tool_choice(lmql::ToolChoice::Tool("search".to_owned()))
+        .set_parallel_tool_calls(false);
+    assert_eq!(
+        body(&claude, &chat, &options)["tool_choice"],
+        serde_json::json!({"type": "tool", "name": "search", "disable_parallel_tool_use": true})
+    );
+    assert_eq!(
+        body(&gpt, &chat, &options)["tool_choice"],
+        serde_json::json!({"type": "function", "function": {"name": "search"}})
+    );
+
+    options.set_tool_choice(lmql::ToolChoice::None);
+    assert_eq!(
+        body(&claude, &chat, &options)["tool_choice"],
+        serde_json::json!({"type": "none"})
+    );
+}
+
+#[test]
+fn model_max_tokens() {
+    let chat = [Message::User("Write an essay.".into())];
+    let mut options = PromptOptions::default();
+    options.set_max_tokens(lmql::MODEL_MAX_TOKENS);
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    assert_eq!(body(&claude, &chat, &options)["max_tokens"], 8192);
+    let gpt = lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::o3Mini, "key".to_owned());
+    assert_eq!(
+        body(&gpt, &chat, &options)["max_completion_tokens"],
+        100_000
+    );
+    let openrouter =
+        lmql::llms::openrouter::OpenRouter::new("meta-llama/llama-3.1-70b-instruct", "key");
+    assert!(body(&openrouter, &chat, &options)
+        .get("max_tokens")
+        .is_none());
+
+    // Any other value is lowered to the model's limit where it is known.
+    options.set_max_tokens(10_000);
+    assert_eq!(body(&claude, &chat, &options)["max_tokens"], 8192);
+    assert_eq!(body(&gpt, &chat, &options)["max_completion_tokens"], 10_000);
+    assert_eq!(body(&openrouter, &chat, &options)["max_tokens"], 10_000);
+    options.set_max_tokens(200_000);
+    assert_eq!(
+        body(&gpt, &chat, &options)["max_completion_tokens"],
+        100_000
+    );
+    assert_eq!(body(&openrouter, &chat, &options)["max_tokens"], 200_000);
+}
+
+#[test]
+fn stopping_sequence_fields() {
+    let chat = [Message::User("Count to ten.".into())];
+    let mut options = PromptOptions::default();
+    options.set_stopping_sequences(vec!["five".to_owned(), "six".to_owned()]);
+    let stop = serde_json::json!(["five", "six"]);
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    assert_eq!(body(&gpt, &chat, &options)["stop"], stop);
+    let openrouter =
+        lmql::llms::openrouter::OpenRouter::new("meta-llama/llama-3.1-70b-instruct", "key");
+    assert_eq!(body(&openrouter, &chat, &options)["stop"], stop);
+    let huggingface = lmql::llms::huggingface::HuggingFace::new("Qwen/Qwen2.5-72B-Instruct", "key");
+    assert_eq!(body(&huggingface, &chat, &options)["stop"], stop);
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    assert_eq!(body(&claude, &chat, &options)["stop_sequences"], stop);
+    let replicate = lmql::llms::replicate::Replicate::new("meta/meta-llama-3-70b-instruct", "key");
+    assert_eq!(
+        body(&replicate, &chat, &options)["input"]["stop_sequences"],
+        "five,six"
+    );
+
+    // Providers without a known limit are sent every stopping sequence.
+    options.set_stopping_sequences((0..10).map(|i| i.to_string()).collect());
+    assert_eq!(
+        body(&openrouter, &chat, &options)["stop"]
+            .as_array()
+            .unwrap()
+            .len(),
+        10
+    );
+    assert_eq!(
+        body(&claude, &chat, &options)["stop_sequences"]
+            .as_array()
+            .unwrap()
+            .len(),
+        10
+    );
+    assert_eq!(
+        body(&replicate, &chat, &options)["input"]["stop_sequences"],
+        "0,1,2,3,4,5,6,7,8,9"
+    );
+
+    options.stopping_sequences.clear();
+    for body in [body(&gpt, &chat, &options), body(&claude, &chat, &options)] {
+        assert!(body.get("stop").is_none() && body.get("stop_sequences").is_none());
+    }
+}
+
+#[test]
+fn provider_and_model_name() {
+    use lmql::llms::openai::OpenAITokenStream;
+
+    let llms: [Box<dyn LLM<TokenStream = OpenAITokenStream>>; 2] = [
+        Box::new(lmql::llms::openai::Gpt::new(
+            lmql::llms::openai::GptModel::Gpt4oMini,
+            "key".to_owned(),
+        )),
+        Box::new(lmql::llms::openrouter::OpenRouter::new(
+            "meta-llama/llama-3.1-70b-instruct",
+            "key",
+        )),
+    ];
+    let names = llms
+        .iter()
+        .map(|llm| (llm.provider(), llm.model_name()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        names,
+        [
+            ("OpenAI", "gpt-4o-mini".into()),
+            ("OpenRouter", "meta-llama/llama-3.1-70b-instruct".into()),
+        ]
+    );
+}
+
+#[test]
+fn reasoning_budget_and_off() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_7_Sonnet_20250219,
+        "key".to_owned(),
+    );
+    let gpt = lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::o3Mini, "key".to_owned());
+    let chat = [Message::User("Think.".into())];
+    let with_reasoning = |reasoning| PromptOptions {
+        reasoning: Some(reasoning),
+        ..Default::default()
+    };
+
+    let budget = with_reasoning(lmql::ReasoningEffort::Budget(3000));
+    assert_eq!(
+        body(&claude, &chat, &budget)["thinking"]["budget_tokens"],
+        3000
+    );
+    assert_eq!(body(&gpt, &chat, &budget)["reasoning_effort"], "high");
+
+    let off = with_reasoning(lmql::ReasoningEffort::Off);
+    assert!(body(&claude, &chat, &off).get("thinking").is_none());
+    assert_eq!(body(&gpt, &chat, &off)["reasoning_effort"], "low");
+}
+
+#[test]
+fn openrouter_reasoning_off() {
+    let llm = lmql::llms::openrouter::OpenRouter::new("anthropic/claude-3.7-sonnet", "key");
+    let chat = [Message::User("Think.".into())];
+    let options = PromptOptions {
+        reasoning: Some(lmql::ReasoningEffort::Off),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        body(&llm, &chat, &options)["reasoning"],
+        serde_json::json!({ "enabled": false })
+    );
+}
+
+#[test]
+fn claude_reasoning_budget_is_validated() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_7_Sonnet_20250219,
+        "key".to_owned(),
+    );
+    let chat = [Message::User("Think.".into())];
+    let with_budget = |budget| PromptOptions {
+        reasoning: Some(lmql::ReasoningEffort::Budget(budget)),
+        max_tokens: 4096,
+        ..Default::default()
+    };
+
+    for budget in [512, 4096, 8000] {
+        assert!(
+            matches!(
+                claude.build_request_body(&chat, &with_budget(budget)),
+                Err(lmql::PromptError::InvalidReasoningBudget { .. })
+            ),
+            "{budget}"
+        );
+    }
+    assert_eq!(
+        body(&claude, &chat, &with_budget(1024))["thinking"]["budget_tokens"],
+        1024
+    );
+}
+
+#[test]
+fn unsupported_tools_are_dropped() {
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Plot {
+        title: String,
+    }
+    let mut options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "plot".to_owned(),
+            description: "Plots a chart.".to_owned(),
+            parameters: lmql::ToolParameters::new::<Plot>(),
+        }],
+        ..Default::default()
+    };
+    options.set_parallel_tool_calls(true);
+    let chat = [Message::User("Plot it.".into())];
+
+    let o1_mini =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::o1Mini, "key".to_owned());
+    let body = body(&o1_mini, &chat, &options);
+    assert!(body.get("tools").is_none());
+    assert!(body.get("parallel_tool_calls").is_none());
+}
+
+#[derive(lmql::JsonSchema)]
+#[allow(dead_code)]
+struct Location {
+    city: String,
+}
+
+#[derive(lmql::JsonSchema)]
+#[allow(dead_code)]
+struct Trip {
+    from: Location,
+    to: Location,
+}
+
+#[test]
+fn tool_parameters_with_settings() {
+    let trip_tool = |parameters| lmql::Tool {
+        name: "plan_trip".to_owned(),
+        description: "Plans a trip.".to_owned(),
+        parameters,
+    };
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let chat = [Message::User("Hello!".into())];
+
+    let mut options = PromptOptions {
+        tools: vec![trip_tool(lmql::ToolParameters::new_with_settings::<Trip>(
+            schemars::gen::SchemaSettings::draft07().with(|settings| {
+                settings.inline_subschemas = true;
+                settings.meta_schema = None;
+            }),
+        ))],
+        ..PromptOptions::default()
+    };
+    let parameters = &body(&gpt, &chat, &options)["tools"][0]["function"]["parameters"];
+    assert!(parameters.get("$schema").is_none());
+    assert!(parameters.get("definitions").is_none());
+    assert_eq!(
+        parameters["properties"]["to"]["properties"]["city"]["type"],
+        "string"
+    );
+
+    options.tools = vec![trip_tool(lmql::ToolParameters::new_with_settings::<Trip>(
+        schemars::gen::SchemaSettings::draft2019_09()
+            .with(|settings| settings.definitions_path = "#/$defs/".to_owned()),
+    ))];
+    let parameters = &body(&gpt, &chat, &options)["tools"][0]["function"]["parameters"];
+    assert_eq!(parameters["properties"]["to"]["$ref"], "#/$defs/Location");
+    assert_eq!(
+        parameters["$defs"]["Location"]["properties"]["city"]["type"],
+        "string"
+    );
+}
+
+#[test]
+fn stored_responses() {
+    let options = PromptOptions {
+        store: Some(true),
+        previous_response_id: Some("resp_1".to_owned()),
+        ..Default::default()
+    };
+    let chat = [Message::User("And then?".into())];
+
+    let gpt = lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4o, "key".to_owned());
+    assert_eq!(body(&gpt, &chat, &options)["store"], true);
+
+    let responses = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4o,
+        "key".to_owned(),
+    );
+    let responses_body = body(&responses, &chat, &options);
+    assert_eq!(responses_body["store"], true);
+    assert_eq!(responses_body["previous_response_id"], "resp_1");
+
+    let compatible =
+        lmql::llms::openai::compatible::OpenAICompatible::new("http://localhost:8000", "llama");
+    assert!(body(&compatible, &chat, &options).get("store").is_none());
+    assert!(body(&gpt, &chat, &PromptOptions::default())
+        .get("store")
+        .is_none());
+}
+
+#[test]
+fn responses_prompt_template() {
+    let mut options = PromptOptions::default();
+    options.set_prompt_template(
+        lmql::PromptTemplate::new("pmpt_123")
+            .with_version("2")
+            .with_variable("city", "Paris"),
+    );
+    let responses = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4o,
+        "key".to_owned(),
+    );
+
+    // The stored prompt may stand in for the messages entirely.
+    let body = body(&responses, &[], &options);
+    assert_eq!(
+        body["prompt"],
+        serde_json::json!({"id": "pmpt_123", "version": "2", "variables": {"city": "Paris"}})
+    );
+    assert!(body.get("input").is_none(), "{body}");
+}
+
+#[test]
+fn prompt_template_is_rejected_elsewhere() {
+    let mut options = PromptOptions::default();
+    options.set_prompt_template(lmql::PromptTemplate::new("pmpt_123"));
+    let chat = [Message::User("Hi!".into())];
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    let openrouter =
+        lmql::llms::openrouter::OpenRouter::new("meta-llama/llama-3.1-70b-instruct", "key");
+    let replicate = lmql::llms::replicate::Replicate::new("meta/meta-llama-3-70b-instruct", "key");
+    let results = [
+        claude.build_request_body(&chat, &options),
+        gpt.build_request_body(&chat, &options),
+        openrouter.build_request_body(&chat, &options),
+        replicate.build_request_body(&chat, &options),
+    ];
+    for result in results {
+        let error = result.unwrap_err();
+        assert!(
+            matches!(error, lmql::PromptError::UnsupportedPromptTemplate { .. }),
+            "{error:?}"
+        );
+        assert!(error
+            .to_string()
+            .contains("prompt templates are only supported by the Responses API"));
+    }
+}
+
+#[test]
+fn duplicate_tool_names() {
+    #[derive(lmql::JsonSchema)]
+    #[allow(dead_code)]
+    struct Plot {
+        title: String,
+    }
+    let tool = |description: &str| lmql::Tool {
+        name: "plot".to_owned(),
+        description: description.to_owned(),
+        parameters: lmql::ToolParameters::new::<Plot>(),
+    };
+
+    // Equal tools hash alike, so duplicates can be removed with a set.
+    let tools = [tool("Plots a chart."), tool("Plots a chart.")]
+        .into_iter()
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(tools.len(), 1);
+    let strict = lmql::Tool {
+        parameters: lmql::ToolParameters::new::<Plot>().strict(),
+        ..tool("Plots a chart.")
+    };
+    assert_eq!(
+        tools
+            .into_iter()
+            .chain([strict])
+            .collect::<std::collections::HashSet<_>>()
+            .len(),
+        2
+    );
+
+    let options = PromptOptions {
+        tools: vec![tool("Plots a chart."), tool("Plots a graph.")],
+        ..Default::default()
+    };
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    );
+    let error = claude
+        .build_request_body(&[Message::User("Plot it.".into())], &options)
+        .unwrap_err();
+    assert!(
+        matches!(&error, lmql::PromptError::DuplicateToolName(name) if name == "plot"),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn json_response_format() {
+    let chat = [Message::User("Reply in JSON.".into())];
+    let mut options = PromptOptions::default();
+    options.set_response_format(lmql::ResponseFormat::JsonObject);
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned());
+    assert_eq!(
+        body(&gpt, &chat, &options)["response_format"],
+        serde_json::json!({"type": "json_object"})
+    );
+
+    let responses = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4o,
+        "key".to_owned(),
+    );
+    assert_eq!(
+        body(&responses, &chat, &options)["text"]["format"]["type"],
+        "json_object"
+    );
+
+    let openrouter = lmql::llms::openrouter::OpenRouter::new("meta-llama/llama-3-8b", "key");
+    assert_eq!(
+        body(&openrouter, &chat, &options)["response_format"],
+        serde_json::json!({"type": "json_object"})
+    );
+
+    let gpt_body = body(&gpt, &chat, &PromptOptions::default());
+    assert!(gpt_body.get("response_format").is_none());
+}
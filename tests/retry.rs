@@ -0,0 +1,295 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lmql::retry::RetryOnEmpty;
+use lmql::{
+    Chunk, FinishReason, Message, PromptError, PromptOptions, TokenError, TokenStreamExt, LLM,
+};
+
+/// Responds with nothing but a finish reason until the given number of prompts have been sent.
+struct Flaky {
+    empty_responses: usize,
+    prompts: AtomicUsize,
+    /// The idempotency key of each prompt.
+    idempotency_keys: std::sync::Mutex<Vec<Option<String>>>,
+}
+
+impl Flaky {
+    fn new(empty_responses: usize) -> Self {
+        Self {
+            empty_responses,
+            prompts: AtomicUsize::new(0),
+            idempotency_keys: Default::default(),
+        }
+    }
+}
+
+impl LLM for Flaky {
+    type TokenStream = futures::stream::Iter<std::vec::IntoIter<Result<Chunk, TokenError>>>;
+
+    fn prompt(
+        &self,
+        _messages: &[Message],
+        options: &PromptOptions,
+    ) -> Result<Self::TokenStream, PromptError> {
+        self.idempotency_keys
+            .lock()
+            .unwrap()
+            .push(options.idempotency_key.clone());
+        let prompt = self.prompts.fetch_add(1, Ordering::SeqCst);
+        let mut chunks = vec![Ok(Chunk::Thinking(format!("Attempt {prompt}.")))];
+        if prompt >= self.empty_responses {
+            chunks.push(Ok(Chunk::Token("Hello".to_owned())));
+        }
+        chunks.push(Ok(Chunk::Finish(FinishReason::EndTurn)));
+        Ok(futures::stream::iter(chunks))
+    }
+
+    fn build_request_body(
+        &self,
+        _messages: &[Message],
+        _options: &PromptOptions,
+    ) -> Result<String, PromptError> {
+        Ok(String::new())
+    }
+
+    fn provider(&self) -> &'static str {
+        "Flaky"
+    }
+
+    fn model_name(&self) -> std::borrow::Cow<'static, str> {
+        "flaky".into()
+    }
+}
+
+#[tokio::test]
+async fn retries_empty_responses() {
+    let chat = [Message::User("Hi!".into())];
+
+    let llm = RetryOnEmpty::new(Flaky::new(2), 2);
+    let chunks = llm
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    // Only the thinking of the response which is kept is seen.
+    let [Chunk::Thinking(thinking), Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] =
+        chunks.as_slice()
+    else {
+        panic!("Expected the third response, got {chunks:?}");
+    };
+    assert_eq!(thinking, "Attempt 2.");
+    assert_eq!(text, "Hello");
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 3);
+
+    // Once out of retries, the empty response is returned.
+    let llm = RetryOnEmpty::new(Flaky::new(2), 1);
+    let chunks = llm
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    let [Chunk::Thinking(thinking), Chunk::Finish(FinishReason::EndTurn)] = chunks.as_slice()
+    else {
+        panic!("Expected the second, empty response, got {chunks:?}");
+    };
+    assert_eq!(thinking, "Attempt 1.");
+    assert_eq!(llm.inner().prompts.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn empty_responses_are_retried_without_their_idempotency_key() {
+    let llm = RetryOnEmpty::new(Flaky::new(1), 1);
+    let mut options = PromptOptions::default();
+    options.set_idempotency_key("key".to_owned());
+    llm.prompt(&[Message::User("Hi!".into())], &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert_eq!(
+        *llm.inner().idempotency_keys.lock().unwrap(),
+        [Some("key".to_owned()), None]
+    );
+}
+
+/// The error of a request which failed with the given status.
+fn status_error(status: hyper::StatusCode) -> TokenError {
+    TokenError::ConnectionLost(lmql::SseError::StatusError {
+        status,
+        kind: lmql::ProviderErrorKind::Other,
+        body: String::new(),
+    })
+}
+
+/// The error of a server which is too busy to respond.
+fn overloaded() -> TokenError {
+    status_error(hyper::StatusCode::SERVICE_UNAVAILABLE)
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_prompt_until_the_response_starts() {
+    use lmql::retry::{retry_prompt, RetryPolicy};
+
+    let attempts = AtomicUsize::new(0);
+    let prompt = |_| async {
+        let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+        let chunks = match attempt {
+            0 => vec![Err(overloaded())],
+            _ => vec![
+                Ok(Chunk::Token("Hello".to_owned())),
+                Err(TokenError::ServerError("lost".to_owned())),
+            ],
+        };
+        Ok::<_, PromptError>(futures::stream::iter(chunks))
+    };
+
+    // Only the failure before the first chunk is retried.
+    let chunks = retry_prompt(&RetryPolicy::default(), &PromptOptions::default(), prompt)
+        .await
+        .unwrap()
+        .all_tokens_lossy()
+        .await
+        .unwrap_err();
+    assert!(matches!(chunks.partial.as_slice(), [Chunk::Token(text)] if text == "Hello"));
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    // A policy can refuse to retry.
+    attempts.store(0, Ordering::SeqCst);
+    let policy = RetryPolicy::default().retry_if(|_| false);
+    let error = retry_prompt(&policy, &PromptOptions::default(), prompt)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error,
+        lmql::Error::Token(TokenError::ConnectionLost(
+            lmql::SseError::StatusError { .. }
+        ))
+    ));
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    // And gives up after its last retry.
+    attempts.store(0, Ordering::SeqCst);
+    let failing = |_| async {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Ok::<_, PromptError>(futures::stream::iter(vec![Err(overloaded())]))
+    };
+    let policy = RetryPolicy::default().with_max_retries(2);
+    assert!(retry_prompt(&policy, &PromptOptions::default(), failing)
+        .await
+        .is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_prompt_reuses_one_idempotency_key() {
+    use lmql::retry::{retry_prompt, RetryPolicy};
+
+    let keys = std::sync::Mutex::new(vec![]);
+    let prompt = |options: PromptOptions| {
+        let keys = &keys;
+        async move {
+            keys.lock().unwrap().push(options.idempotency_key.unwrap());
+            Ok::<_, PromptError>(futures::stream::iter(vec![Err(overloaded())]))
+        }
+    };
+    let policy = RetryPolicy::default().with_max_retries(2);
+
+    // A key is generated for each prompt, and sent with each of its attempts.
+    assert!(retry_prompt(&policy, &PromptOptions::default(), prompt)
+        .await
+        .is_err());
+    assert!(retry_prompt(&policy, &PromptOptions::default(), prompt)
+        .await
+        .is_err());
+    let generated = std::mem::take(&mut *keys.lock().unwrap());
+    assert_eq!(generated.len(), 6);
+    assert!(generated[..3].iter().all(|key| *key == generated[0]));
+    assert!(generated[3..].iter().all(|key| *key == generated[3]));
+    assert_ne!(generated[0], generated[3]);
+
+    // A key which is already set is kept.
+    let mut options = PromptOptions::default();
+    options.set_idempotency_key("key".to_owned());
+    assert!(retry_prompt(&policy, &options, prompt).await.is_err());
+    assert_eq!(*keys.lock().unwrap(), ["key", "key", "key"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_prompt_only_retries_transient_errors() {
+    use lmql::retry::{retry_prompt, RetryPolicy};
+
+    let attempts = AtomicUsize::new(0);
+    let retries = |error: fn() -> TokenError| {
+        attempts.store(0, Ordering::SeqCst);
+        let attempts = &attempts;
+        async move {
+            let prompt = |_| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, PromptError>(futures::stream::iter(vec![Err(error())]))
+            };
+            assert!(
+                retry_prompt(&RetryPolicy::default(), &PromptOptions::default(), prompt)
+                    .await
+                    .is_err()
+            );
+            attempts.load(Ordering::SeqCst) - 1
+        }
+    };
+
+    // A malformed response would only be malformed again.
+    assert_eq!(
+        retries(|| TokenError::MalformedResponse {
+            message: "expected choices",
+            value: serde_json::json!({}),
+        })
+        .await,
+        0
+    );
+    assert_eq!(
+        retries(|| TokenError::ConnectionLost(lmql::SseError::JsonError(
+            serde_json::from_str::<serde_json::Value>("{").unwrap_err()
+        )))
+        .await,
+        0
+    );
+    assert_eq!(
+        retries(|| TokenError::ServerError("invalid request".to_owned())).await,
+        0
+    );
+    assert_eq!(
+        retries(|| status_error(hyper::StatusCode::BAD_REQUEST)).await,
+        0
+    );
+
+    // Lost connections, timeouts, server errors and rate limits may pass.
+    assert_eq!(
+        retries(|| TokenError::ConnectionLost(lmql::SseError::IoError(
+            std::io::ErrorKind::ConnectionReset.into()
+        )))
+        .await,
+        3
+    );
+    assert_eq!(
+        retries(|| TokenError::Timeout(std::time::Duration::from_secs(1))).await,
+        3
+    );
+    assert_eq!(
+        retries(|| status_error(hyper::StatusCode::BAD_GATEWAY)).await,
+        3
+    );
+    assert_eq!(
+        retries(|| status_error(hyper::StatusCode::REQUEST_TIMEOUT)).await,
+        3
+    );
+    assert_eq!(
+        retries(|| status_error(hyper::StatusCode::TOO_MANY_REQUESTS)).await,
+        3
+    );
+    assert_eq!(
+        retries(|| TokenError::TransientServerError("overloaded".to_owned())).await,
+        3
+    );
+}
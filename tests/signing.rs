@@ -0,0 +1,107 @@
+/// Examples from the AWS Signature Version 4 test suite.
+#[cfg(feature = "aws-sigv4")]
+mod aws {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use lmql::signing::AwsSigV4Signer;
+
+    /// 2015-08-30T12:36:00Z
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1440938160)
+    }
+
+    fn signer() -> AwsSigV4Signer {
+        AwsSigV4Signer::new(
+            "AKIDEXAMPLE",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "service",
+        )
+    }
+
+    fn authorization(mut request: hyper::Request<String>) -> String {
+        signer().sign_at(&mut request, time()).unwrap();
+        assert_eq!(request.headers()["x-amz-date"], "20150830T123600Z");
+        request.headers()["authorization"]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn get_vanilla() {
+        let request = hyper::Request::get("https://example.amazonaws.com/")
+            .body(String::new())
+            .unwrap();
+
+        assert_eq!(
+            authorization(request),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn get_vanilla_query_order_key_case() {
+        let request =
+            hyper::Request::get("https://example.amazonaws.com/?Param2=value2&Param1=value1")
+                .body(String::new())
+                .unwrap();
+
+        assert_eq!(
+            authorization(request),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn post_x_www_form_urlencoded() {
+        let request = hyper::Request::post("https://example.amazonaws.com/")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("Param1=value1".to_owned())
+            .unwrap();
+
+        assert_eq!(
+            authorization(request),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a"
+        );
+    }
+
+    #[test]
+    fn existing_authorization_is_replaced_unsigned() {
+        let request = hyper::Request::get("https://example.amazonaws.com/")
+            .header("authorization", "Bearer key")
+            .body(String::new())
+            .unwrap();
+
+        // Signed as if the header were never there, as the signature replaces it.
+        assert_eq!(
+            authorization(request),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn session_token_is_sent_and_signed() {
+        let mut request = hyper::Request::get("https://example.amazonaws.com/")
+            .body(String::new())
+            .unwrap();
+        signer()
+            .with_session_token("token")
+            .sign_at(&mut request, time())
+            .unwrap();
+
+        assert_eq!(request.headers()["x-amz-security-token"], "token");
+        assert!(request.headers()["authorization"]
+            .to_str()
+            .unwrap()
+            .contains("SignedHeaders=host;x-amz-date;x-amz-security-token,"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+
+use lmql::llms::echo::Echo;
+use lmql::summarize::{map_reduce, MapReduceOptions};
+
+/// Summarizes each part as its first word, or the first part of a combined summary, and combines
+/// summaries by joining them with `+` in brackets.
+fn summarize(prompt: &str) -> String {
+    match prompt.split_once("\n\n") {
+        Some(("MAP", text)) => text
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('(')
+            .split(['+', ')'])
+            .next()
+            .unwrap_or_default()
+            .to_owned(),
+        Some(("REDUCE", text)) => {
+            format!(
+                "({})",
+                text.split_whitespace().collect::<Vec<_>>().join("+")
+            )
+        }
+        _ => panic!("unexpected prompt {prompt:?}"),
+    }
+}
+
+fn llm() -> Echo {
+    Echo::new().with_transform(summarize)
+}
+
+#[tokio::test]
+async fn map_reduce_keeps_order() {
+    let chunks = [
+        "alpha one",
+        "beta two",
+        "gamma three",
+        "delta four",
+        "epsilon",
+    ]
+    .map(str::to_owned)
+    .to_vec();
+    let mut options = MapReduceOptions::default();
+    options.set_concurrency(2);
+
+    let summary = map_reduce(&llm(), chunks, "MAP", "REDUCE", &options)
+        .await
+        .unwrap();
+
+    assert_eq!(summary, "(alpha+beta+gamma+delta+epsilon)");
+}
+
+#[tokio::test]
+async fn map_reduce_splits_and_combines_within_budget() {
+    let prompts = Arc::new(Mutex::new(vec![]));
+    let llm = Echo::new().with_transform({
+        let prompts = Arc::clone(&prompts);
+        move |prompt| {
+            prompts.lock().unwrap().push(prompt.to_owned());
+            summarize(prompt)
+        }
+    });
+    // Split into pairs of words, whose summaries only fit two at a time.
+    let document = "aaaaaaaa bbbbbbbb cccccccc dddddddd eeeeeeee ffffffff".to_owned();
+    let mut options = MapReduceOptions::default();
+    options.set_max_chunk_tokens(7);
+
+    let summary = map_reduce(&llm, vec![document], "MAP", "REDUCE", &options)
+        .await
+        .unwrap();
+
+    // The first two summaries are combined, and the combination is too long to be combined with
+    // the last, so is summarized again.
+    assert_eq!(summary, "(aaaaaaaa+eeeeeeee)");
+    for prompt in prompts.lock().unwrap().iter() {
+        assert!(prompt.chars().count().div_ceil(4) <= 7, "{prompt:?}");
+    }
+}
+
+#[tokio::test]
+async fn map_reduce_fails_when_summaries_cannot_fit() {
+    // The budget doesn't leave room for any text after the instruction.
+    let mut options = MapReduceOptions::default();
+    options.set_max_chunk_tokens(2);
+    let result = map_reduce(&llm(), vec!["a".to_owned()], "MAP", "REDUCE", &options).await;
+    assert!(
+        matches!(result, Err(lmql::Error::SummaryOverBudget { budget: 2 })),
+        "{result:?}"
+    );
+
+    // Summaries which are never any shorter can't be combined.
+    let llm = Echo::new().with_transform(|prompt| prompt.split_once("\n\n").unwrap().1.to_owned());
+    let chunks = ["aaaaaaaaaaaa bb", "cccccccccccc dd"]
+        .map(str::to_owned)
+        .to_vec();
+    options.set_max_chunk_tokens(6);
+    let result = map_reduce(&llm, chunks, "MAP", "REDUCE", &options).await;
+    assert!(
+        matches!(result, Err(lmql::Error::SummaryOverBudget { budget: 6 })),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn map_reduce_of_nothing_is_empty() {
+    let llm = Echo::new().with_transform(|prompt| panic!("unexpected prompt {prompt:?}"));
+
+    let summary = map_reduce(&llm, vec![], "MAP", "REDUCE", &MapReduceOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(summary, "");
+}
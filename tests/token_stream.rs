@@ -0,0 +1,587 @@
+use lmql::{Chunk, TokenStreamExt, ToolCallChunk};
+
+fn tool_call(index: Option<usize>, id: Option<&str>, name: Option<&str>, arguments: &str) -> Chunk {
+    Chunk::ToolCall(ToolCallChunk {
+        index,
+        ..ToolCallChunk::new(id.map(str::to_owned), name.map(str::to_owned), arguments)
+    })
+}
+
+async fn all_tokens(chunks: Vec<Chunk>) -> Vec<Chunk> {
+    futures::stream::iter(chunks.into_iter().map(Ok))
+        .all_tokens()
+        .await
+        .unwrap()
+}
+
+/// The id, name and parsed arguments of each chunk, which must all be tool calls.
+fn tool_calls(chunks: &[Chunk]) -> Vec<(&str, &str, serde_json::Value)> {
+    chunks
+        .iter()
+        .map(|chunk| {
+            let Chunk::ToolCall(call) = chunk else {
+                panic!("Expected only tool calls, got {chunks:?}");
+            };
+            let arguments = serde_json::from_str::<serde_json::Value>(&call.arguments).unwrap();
+            (
+                call.id.as_deref().unwrap(),
+                call.name.as_deref().unwrap(),
+                arguments,
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn interleaved_tool_calls() {
+    let chunks = all_tokens(vec![
+        tool_call(Some(0), Some("call_a"), Some("get_stock_price"), ""),
+        tool_call(Some(1), Some("call_b"), Some("get_stock_price"), ""),
+        tool_call(Some(0), None, None, r#"{"ticker""#),
+        tool_call(Some(2), Some("call_c"), Some("get_weather"), r#"{"city":"#),
+        tool_call(Some(1), None, None, r#"{"ticker":"MSFT"}"#),
+        tool_call(Some(0), None, None, r#":"AAPL"}"#),
+        tool_call(Some(2), None, None, r#""London"}"#),
+    ])
+    .await;
+
+    assert_eq!(
+        tool_calls(&chunks),
+        vec![
+            (
+                "call_a",
+                "get_stock_price",
+                serde_json::json!({"ticker": "AAPL"})
+            ),
+            (
+                "call_b",
+                "get_stock_price",
+                serde_json::json!({"ticker": "MSFT"})
+            ),
+            (
+                "call_c",
+                "get_weather",
+                serde_json::json!({"city": "London"})
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn distinct_unindexed_tool_calls() {
+    let chunks = all_tokens(vec![
+        tool_call(
+            None,
+            Some("call_a"),
+            Some("get_stock_price"),
+            r#"{"ticker":"#,
+        ),
+        tool_call(None, None, None, r#""AAPL"}"#),
+        tool_call(
+            None,
+            Some("call_b"),
+            Some("get_stock_price"),
+            r#"{"ticker":"MSFT"}"#,
+        ),
+    ])
+    .await;
+
+    assert_eq!(
+        tool_calls(&chunks),
+        vec![
+            (
+                "call_a",
+                "get_stock_price",
+                serde_json::json!({"ticker": "AAPL"})
+            ),
+            (
+                "call_b",
+                "get_stock_price",
+                serde_json::json!({"ticker": "MSFT"})
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn tool_calls_keeps_complete_calls() {
+    let calls = futures::stream::iter(
+        vec![
+            Chunk::Token("Checking.".to_owned()),
+            tool_call(Some(0), Some("call_a"), Some("get_stock_price"), ""),
+            tool_call(Some(1), Some("call_b"), None, r#"{"ticker":"MSFT"}"#),
+            tool_call(Some(0), None, None, r#"{"ticker":"AAPL"}"#),
+            tool_call(Some(2), Some("call_c"), Some("get_weather"), r#"{"city":"#),
+        ]
+        .into_iter()
+        .map(Ok),
+    )
+    .tool_calls()
+    .await
+    .unwrap();
+
+    // The call without a name and the call with truncated arguments are dropped.
+    assert_eq!(
+        calls,
+        vec![(
+            "get_stock_price".to_owned(),
+            serde_json::json!({"ticker": "AAPL"})
+        )]
+    );
+}
+
+#[tokio::test]
+async fn audio_joined_across_transcript() {
+    let chunks = all_tokens(vec![
+        Chunk::Token("Hello".to_owned()),
+        Chunk::Audio(vec![1, 2]),
+        Chunk::Token(", world!".to_owned()),
+        Chunk::Audio(vec![3, 4]),
+    ])
+    .await;
+
+    let Chunk::Audio(audio) = &chunks[1] else {
+        panic!("Expected audio, got {chunks:?}");
+    };
+    assert_eq!(audio, &[1, 2, 3, 4]);
+    assert_eq!(chunks.len(), 3, "{chunks:?}");
+}
+
+#[test]
+fn tool_call_completeness() {
+    assert!(
+        ToolCallChunk::complete("call_a", "get_stock_price", r#"{"ticker":"AAPL"}"#).is_complete()
+    );
+    assert!(!ToolCallChunk::complete("call_a", "get_stock_price", r#"{"ticker":"#).is_complete());
+    assert!(!ToolCallChunk::new(None, Some("get_stock_price".to_owned()), "{}").is_complete());
+}
+
+#[tokio::test]
+async fn broadcast_to_every_receiver() {
+    let stream = futures::stream::iter(
+        vec![
+            Chunk::Token("Hello".to_owned()),
+            Chunk::Token(", world!".to_owned()),
+        ]
+        .into_iter()
+        .map(Ok),
+    );
+    let (drive, mut first) = stream.into_broadcast(8);
+    let mut second = first.resubscribe();
+    drive.await;
+
+    for receiver in [&mut first, &mut second] {
+        let mut text = String::new();
+        while let Ok(chunk) = receiver.recv().await {
+            let Ok(Chunk::Token(token)) = chunk else {
+                panic!("Expected only tokens, got {chunk:?}");
+            };
+            text.push_str(&token);
+        }
+        assert_eq!(text, "Hello, world!");
+    }
+}
+
+#[tokio::test]
+async fn broadcast_reports_lagging_receivers() {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let stream =
+        futures::stream::iter(["a", "b", "c"].map(|token| Ok(Chunk::Token(token.to_owned()))));
+    let (drive, mut receiver) = stream.into_broadcast(2);
+    drive.await;
+
+    assert!(matches!(receiver.recv().await, Err(RecvError::Lagged(1))));
+    for expected in ["b", "c"] {
+        let Ok(Ok(Chunk::Token(token))) = receiver.recv().await else {
+            panic!("Expected a token");
+        };
+        assert_eq!(token, expected);
+    }
+    assert!(matches!(receiver.recv().await, Err(RecvError::Closed)));
+}
+
+#[test]
+fn tool_call_into_message() {
+    let message = Chunk::ToolCall(ToolCallChunk::complete(
+        "call_a",
+        "get_stock_price",
+        r#"{"ticker":"AAPL"}"#,
+    ))
+    .try_into_message();
+
+    assert_eq!(
+        message,
+        Some(lmql::Message::ToolRequest {
+            id: "call_a".to_owned(),
+            name: "get_stock_price".to_owned(),
+            arguments: lmql::SerializedJson::try_new(serde_json::json!({"ticker": "AAPL"}))
+                .unwrap(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn split_into_sentences() {
+    use futures::StreamExt;
+
+    let stream = futures::stream::iter(
+        [
+            Chunk::Token("Hello Dr. Smith".to_owned()),
+            Chunk::Thinking("greeting".to_owned()),
+            Chunk::Token(", it costs 3.5 dollars! Is".to_owned()),
+            Chunk::Token(" that \"fine?\" It is".to_owned()),
+        ]
+        .into_iter()
+        .map(Ok),
+    );
+    let sentences = stream
+        .by_sentence(false)
+        .map(|chunk| {
+            let Ok(Chunk::Token(sentence)) = chunk else {
+                panic!("Expected only tokens, got {chunk:?}");
+            };
+            sentence
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(
+        sentences,
+        [
+            "Hello Dr. Smith, it costs 3.5 dollars!",
+            "Is that \"fine?\"",
+            "It is",
+        ]
+    );
+
+    // Chunks may end partway through the end of a sentence, or an abbreviation.
+    let stream = futures::stream::iter(
+        [
+            "Hello Dr",
+            ".",
+            " Smith, it costs 3",
+            ".",
+            "5 dollars",
+            "!",
+            " Is that \"fine?",
+            "\"",
+            " It is",
+        ]
+        .map(|text| Ok(Chunk::Token(text.to_owned()))),
+    );
+    let sentences = stream
+        .by_sentence(false)
+        .map(|chunk| match chunk {
+            Ok(Chunk::Token(sentence)) => sentence,
+            chunk => panic!("Expected only tokens, got {chunk:?}"),
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        sentences,
+        [
+            "Hello Dr. Smith, it costs 3.5 dollars!",
+            "Is that \"fine?\"",
+            "It is",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn take_chars_and_tokens() {
+    let chunks = || {
+        futures::stream::iter(
+            [
+                Chunk::Token("Hello".to_owned()),
+                Chunk::Thinking("greeting".to_owned()),
+                Chunk::Token(", wörld!".to_owned()),
+                Chunk::Token(" Goodbye.".to_owned()),
+            ]
+            .into_iter()
+            .map(Ok),
+        )
+    };
+
+    assert_eq!(chunks().take_chars(9).await.unwrap(), "Hello, wö");
+    assert_eq!(
+        chunks().take_chars(100).await.unwrap(),
+        "Hello, wörld! Goodbye."
+    );
+    assert_eq!(chunks().take_tokens(2).await.unwrap(), "Hello, wörld!");
+
+    // Nothing after the limit is read, so a later failure is never seen.
+    let failing = futures::stream::iter([
+        Ok(Chunk::Token("Hello".to_owned())),
+        Err(lmql::TokenError::ServerError("overloaded".to_owned())),
+    ]);
+    assert_eq!(failing.take_tokens(1).await.unwrap(), "Hello");
+}
+
+#[tokio::test]
+async fn next_token_skips_other_chunks() {
+    let mut stream = futures::stream::iter(
+        [
+            Chunk::Thinking("greeting".to_owned()),
+            Chunk::Token("Hello".to_owned()),
+            Chunk::Finish(lmql::FinishReason::EndTurn),
+        ]
+        .into_iter()
+        .map(Ok),
+    );
+
+    assert_eq!(stream.next_token().await.unwrap().unwrap(), "Hello");
+    assert!(stream.next_token().await.is_none());
+    assert!(stream.next_chunk().await.is_none());
+}
+
+#[tokio::test]
+async fn split_thinking_from_answer() {
+    use futures::StreamExt;
+
+    // Read from separate tasks, with the chunks arriving one at a time.
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let (thinking, answer) = rx.split_thinking();
+    let thinking = tokio::spawn(thinking.map(Result::unwrap).collect::<Vec<_>>());
+    let answer = tokio::spawn(answer.map(Result::unwrap).collect::<Vec<_>>());
+    for chunk in [
+        Chunk::Thinking("Hmm".to_owned()),
+        Chunk::Token("Yes".to_owned()),
+        Chunk::Thinking(", sure".to_owned()),
+        Chunk::Finish(lmql::FinishReason::EndTurn),
+        Chunk::Token(".".to_owned()),
+    ] {
+        tx.unbounded_send(Ok(chunk)).unwrap();
+        tokio::task::yield_now().await;
+    }
+    drop(tx);
+    assert_eq!(thinking.await.unwrap(), ["Hmm", ", sure"]);
+    assert_eq!(answer.await.unwrap(), ["Yes", "."]);
+
+    // Reading all of one buffers the other, and an error ends both.
+    let (thinking, answer) = futures::stream::iter([
+        Ok(Chunk::Thinking("Hmm".to_owned())),
+        Ok(Chunk::Token("Yes".to_owned())),
+        Err(lmql::TokenError::UnknownEventType("oops".to_owned())),
+        Ok(Chunk::Thinking("unreachable".to_owned())),
+    ])
+    .split_thinking();
+    let answer = answer.collect::<Vec<_>>().await;
+    assert_eq!(answer.len(), 2);
+    assert_eq!(answer[0].as_ref().unwrap(), "Yes");
+    assert!(answer[1].is_err());
+    let thinking = thinking.map(Result::unwrap).collect::<Vec<_>>().await;
+    assert_eq!(thinking, ["Hmm"]);
+}
+
+#[test]
+fn messages_from_chunks() {
+    let chunks = vec![
+        Chunk::Thinking("Look it ".to_owned()),
+        Chunk::Thinking("up.".to_owned()),
+        Chunk::Token("Checking ".to_owned()),
+        Chunk::Token("now.".to_owned()),
+        tool_call(
+            Some(0),
+            Some("call_a"),
+            Some("get_stock_price"),
+            r#"{"ticker""#,
+        ),
+        tool_call(Some(0), None, None, r#":"AAPL"}"#),
+        tool_call(
+            Some(1),
+            Some("call_b"),
+            Some("get_stock_price"),
+            r#"{"tick"#,
+        ),
+        Chunk::Finish(lmql::FinishReason::ToolUse),
+    ];
+    let request = lmql::Message::ToolRequest {
+        id: "call_a".to_owned(),
+        name: "get_stock_price".to_owned(),
+        arguments: lmql::SerializedJson::try_new(serde_json::json!({"ticker": "AAPL"})).unwrap(),
+    };
+
+    assert_eq!(
+        lmql::Message::from_chunks(chunks.clone(), false),
+        [
+            lmql::Message::Assistant("Checking now.".to_owned()),
+            request.clone(),
+        ]
+    );
+    assert_eq!(
+        lmql::Message::from_chunks(chunks, true),
+        [
+            lmql::Message::Assistant("<thinking>Look it up.</thinking>Checking now.".to_owned()),
+            request,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn markdown_code_fences_across_chunks() {
+    use futures::StreamExt;
+    use lmql::MarkdownState;
+
+    let chunks = [
+        "Here:\n`",
+        "``pyt",
+        "hon\nprint(1)\n",
+        "  ``",
+        "`\nDone ``inline``.",
+    ];
+    let stream = futures::stream::iter(chunks.map(|text| Ok(Chunk::Token(text.to_owned()))));
+    let mut parts: Vec<(String, MarkdownState)> = vec![];
+    for item in stream.with_markdown_state().collect::<Vec<_>>().await {
+        let (Chunk::Token(text), state) = item.unwrap() else {
+            panic!("expected only text");
+        };
+        match parts.last_mut() {
+            Some((last, last_state)) if *last_state == state => last.push_str(&text),
+            _ => parts.push((text, state)),
+        }
+    }
+
+    let python = MarkdownState::CodeBlock {
+        language: Some("python".to_owned()),
+    };
+    assert_eq!(
+        parts,
+        [
+            ("Here:\n".to_owned(), MarkdownState::Text),
+            ("```python\nprint(1)\n  ```\n".to_owned(), python),
+            ("Done ``inline``.".to_owned(), MarkdownState::Text),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn fold_until_keyword() {
+    use std::ops::ControlFlow;
+
+    let tokens = || {
+        futures::stream::iter(
+            ["The ", "answer ", "is ", "DONE", " and more"]
+                .map(|text| Ok(Chunk::Token(text.to_owned()))),
+        )
+    };
+    let until_done = |mut text: String, chunk: Chunk| {
+        if let Chunk::Token(token) = chunk {
+            text.push_str(&token);
+        }
+        match text.find("DONE") {
+            Some(end) => ControlFlow::Break(text[..end].to_owned()),
+            None => ControlFlow::Continue(text),
+        }
+    };
+
+    let result = tokens()
+        .try_fold_until(String::new(), until_done)
+        .await
+        .unwrap();
+    assert_eq!(result, ControlFlow::Break("The answer is ".to_owned()));
+
+    let result = tokens()
+        .try_fold_until(0, |count, _| ControlFlow::<(), _>::Continue(count + 1))
+        .await
+        .unwrap();
+    assert_eq!(result, ControlFlow::Continue(5));
+}
+
+#[tokio::test]
+async fn forward_to_sink() {
+    use futures::StreamExt;
+
+    let (mut tx, rx) = futures::channel::mpsc::unbounded();
+    futures::stream::iter(["Hello", " world"].map(|text| Ok(Chunk::Token(text.to_owned()))))
+        .forward_to(&mut tx)
+        .await
+        .unwrap();
+    // The sink is left open for more.
+    tx.unbounded_send(Chunk::Token("!".to_owned())).unwrap();
+    drop(tx);
+    let received = rx.collect::<Vec<_>>().await;
+    assert_eq!(received.len(), 3, "{received:?}");
+
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let result = futures::stream::iter([
+        Ok(Chunk::Token("Hello".to_owned())),
+        Err(lmql::TokenError::ServerError("overloaded".to_owned())),
+    ])
+    .forward_to(tx)
+    .await;
+    assert!(
+        matches!(result, Err(lmql::ForwardError::Stream(_))),
+        "{result:?}"
+    );
+    assert_eq!(rx.collect::<Vec<_>>().await.len(), 1);
+
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    drop(rx);
+    let result = futures::stream::iter([Ok(Chunk::Token("Hello".to_owned()))])
+        .forward_to(tx)
+        .await;
+    assert!(
+        matches!(result, Err(lmql::ForwardError::Sink(_))),
+        "{result:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn rechunk_paces_text() {
+    use futures::StreamExt;
+
+    let start = tokio::time::Instant::now();
+    let chunks = futures::stream::iter([
+        Ok(Chunk::Token("Héllo world".to_owned())),
+        Ok(Chunk::Finish(lmql::FinishReason::EndTurn)),
+    ])
+    .rechunk(4, std::time::Duration::from_millis(10))
+    .map(Result::unwrap)
+    .collect::<Vec<_>>()
+    .await;
+
+    let [Chunk::Token(a), Chunk::Token(b), Chunk::Token(c), Chunk::Finish(_)] = chunks.as_slice()
+    else {
+        panic!("Expected three pieces of text, got {chunks:?}");
+    };
+    assert_eq!([a, b, c], ["Héll", "o wo", "rld"]);
+    assert_eq!(start.elapsed(), std::time::Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn read_text_as_bytes() {
+    use tokio::io::AsyncReadExt;
+
+    let mut text = String::new();
+    futures::stream::iter([
+        Ok(Chunk::Thinking("Hmm.".to_owned())),
+        Ok(Chunk::Token("Héllo".to_owned())),
+        Ok(Chunk::Finish(lmql::FinishReason::EndTurn)),
+        Ok(Chunk::Token(", world!".to_owned())),
+    ])
+    .into_async_read()
+    .read_to_string(&mut text)
+    .await
+    .unwrap();
+    assert_eq!(text, "Héllo, world!");
+
+    // Small reads split chunks, and errors end the text.
+    let mut reader = std::pin::pin!(futures::stream::iter([
+        Ok(Chunk::Token("Hello".to_owned())),
+        Err(lmql::TokenError::ServerError("overloaded".to_owned())),
+    ])
+    .into_async_read());
+    let mut buf = [0; 3];
+    assert_eq!(reader.read(&mut buf).await.unwrap(), 3);
+    assert_eq!(&buf, b"Hel");
+    assert_eq!(reader.read(&mut buf).await.unwrap(), 2);
+    let error = reader.read(&mut buf).await.unwrap_err();
+    assert!(
+        matches!(
+            error.get_ref().and_then(|error| error.downcast_ref()),
+            Some(lmql::TokenError::ServerError(_))
+        ),
+        "{error:?}"
+    );
+}
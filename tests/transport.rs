@@ -0,0 +1,1982 @@
+use lmql::transport::{Connect, Connection, Transport};
+use lmql::{Chunk, FinishReason, LLMExt, Message, PromptOptions, TokenStreamExt, LLM};
+
+type CannedResponse = hyper::Response<http_body_util::Full<hyper::body::Bytes>>;
+
+/// Serves the same response to every request, over an in-memory connection, showing each request
+/// to `on_request` first.
+fn serve(
+    response: CannedResponse,
+    on_request: impl Fn(&hyper::Request<hyper::body::Incoming>) + Send + 'static,
+) -> Connect<'static> {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let service = hyper::service::service_fn(move |request| {
+            on_request(&request);
+            let response = response.clone();
+            async move { Ok::<_, std::convert::Infallible>(response) }
+        });
+        hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+            .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+            .await
+            .ok();
+    });
+    Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+}
+
+/// A successful response with the given body.
+fn canned(body: &'static str) -> CannedResponse {
+    hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from_static(
+        body.as_bytes(),
+    )))
+}
+
+/// Serves the same canned SSE body in response to every request.
+struct CannedTransport {
+    body: &'static str,
+}
+
+impl Transport for CannedTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        serve(canned(self.body), |_| {})
+    }
+}
+
+/// As with [`CannedTransport`], but gzips the body, as some proxies do.
+#[cfg(feature = "compression")]
+struct GzipTransport {
+    body: &'static str,
+}
+
+#[cfg(feature = "compression")]
+impl Transport for GzipTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(self.body.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+
+        serve(
+            hyper::Response::builder()
+                .header("content-encoding", "gzip")
+                .body(http_body_util::Full::new(body.into()))
+                .unwrap(),
+            |_| {},
+        )
+    }
+}
+
+/// Fails every request with the given status and body.
+struct FailingTransport {
+    status: u16,
+    body: &'static str,
+}
+
+impl Transport for FailingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        serve(
+            hyper::Response::builder()
+                .status(self.status)
+                .body(http_body_util::Full::new(hyper::body::Bytes::from_static(
+                    self.body.as_bytes(),
+                )))
+                .unwrap(),
+            |_| {},
+        )
+    }
+}
+
+#[tokio::test]
+async fn canned_openai_response() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":", world!"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let chunks = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello, world!"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn gzipped_openai_response() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(GzipTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let chunks = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn multi_line_data() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    ": keepalive\n\n",
+                    r#"data: {"object":"chat.completion.chunk","#,
+                    "\n",
+                    r#"data:"choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                    "\r\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let chunks = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn null_content_deltas() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"role":"assistant","content":null,"refusal":null},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":null},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":null},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let chunks = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn canned_anthropic_inline_tool_input() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: concat!(
+            "event: message_start\n",
+            r#"data: {"type":"message_start","message":{"usage":{"input_tokens":10}}}"#,
+            "\n\n",
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{"city":"Paris"}}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            "\n\n",
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ),
+    });
+
+    let chunks = claude
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    let [Chunk::ToolCall(call)] = chunks.as_slice() else {
+        panic!("Expected a single tool call, got {chunks:?}");
+    };
+    assert!(call.is_complete(), "{call:?}");
+    assert_eq!(call.arguments, r#"{"city":"Paris"}"#);
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, lmql::JsonSchema)]
+struct Weather {
+    city: String,
+    celsius: i32,
+}
+
+#[tokio::test]
+async fn canned_structured_tool_call() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Looking that up."},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"respond","arguments":"{\"city\":\"Paris\","}}]},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"celsius\":21}"}}]},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let structured = gpt
+        .prompt_structured::<Weather>(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        structured.value,
+        Weather {
+            city: "Paris".to_owned(),
+            celsius: 21
+        }
+    );
+    assert_eq!(structured.raw_json, r#"{"city":"Paris","celsius":21}"#);
+    assert_eq!(structured.preface.as_deref(), Some("Looking that up."));
+}
+
+#[tokio::test]
+async fn structured_tool_name_is_reserved() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(FailingTransport {
+                status: 500,
+                body: "",
+            });
+    let options = PromptOptions {
+        tools: vec![lmql::Tool {
+            name: "respond".to_owned(),
+            description: "Sends a reply.".to_owned(),
+            parameters: lmql::ToolParameters::new::<Weather>(),
+        }],
+        ..Default::default()
+    };
+
+    let error = gpt
+        .prompt_structured::<Weather>(&[Message::User("Hi!".into())], &options)
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(
+            &error,
+            lmql::Error::Prompt(lmql::PromptError::ReservedToolName(name)) if name == "respond"
+        ),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn canned_structured_text() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Here you go:\n```json\n{\"city\": \"Oslo\", "},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"\"celsius\": -3}\n```"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let structured = gpt
+        .prompt_structured::<Weather>(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(structured.value.celsius, -3);
+    assert_eq!(structured.raw_json, r#"{"city": "Oslo", "celsius": -3}"#);
+    assert_eq!(structured.preface.as_deref(), Some("Here you go:"));
+}
+
+#[tokio::test]
+async fn canned_moderation() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: r#"{"id":"modr-1","model":"omni-moderation-latest","results":[{"flagged":true,"categories":{"harassment":true,"violence":false},"category_scores":{"harassment":0.91,"violence":0.02}}]}"#,
+            });
+
+    let result = gpt.moderate("You are terrible.").await.unwrap();
+
+    assert!(result.flagged);
+    assert_eq!(
+        result.flagged_categories().collect::<Vec<_>>(),
+        ["harassment"]
+    );
+    assert_eq!(result.category_scores["violence"], 0.02);
+}
+
+#[tokio::test]
+async fn canned_claude_token_count() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: r#"{"input_tokens":14}"#,
+    });
+
+    let count = claude
+        .count_tokens(
+            &[lmql::Message::User("Hello, Claude".into())],
+            &lmql::PromptOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(count, 14);
+}
+
+/// Sends the body, then fails the response before it ends.
+struct TruncatingTransport {
+    body: &'static str,
+}
+
+impl Transport for TruncatingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let body = self.body;
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |_request| async move {
+                use futures::StreamExt;
+
+                let data =
+                    hyper::body::Frame::data(hyper::body::Bytes::from_static(body.as_bytes()));
+                // The failure comes after the body has been read.
+                let failure = async {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+                };
+                let frames =
+                    futures::stream::iter([Ok(data)]).chain(futures::stream::once(failure));
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                    http_body_util::StreamBody::new(frames),
+                ))
+            });
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+                .await
+                .ok();
+        });
+        Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+    }
+}
+
+#[tokio::test]
+async fn truncated_bodies_fail() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(TruncatingTransport {
+        body: r#"{"input_tokens":14}"#,
+    });
+
+    // The body looks complete, but the response never ended.
+    let result = claude
+        .count_tokens(
+            &[lmql::Message::User("Hello, Claude".into())],
+            &lmql::PromptOptions::default(),
+        )
+        .await;
+
+    assert!(result.is_err(), "{result:?}");
+}
+
+/// Serves the body of the first route whose path the request's path ends with.
+struct RoutingTransport {
+    routes: &'static [(&'static str, &'static str)],
+}
+
+impl Transport for RoutingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let routes = self.routes;
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: hyper::Request<_>| {
+                let body = routes
+                    .iter()
+                    .find(|(path, _)| request.uri().path().ends_with(path))
+                    .map_or("", |(_, body)| body);
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                        http_body_util::Full::new(hyper::body::Bytes::from_static(body.as_bytes())),
+                    ))
+                }
+            });
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+                .await
+                .ok();
+        });
+        Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+    }
+}
+
+#[tokio::test]
+async fn batch_results_cover_every_request() {
+    use lmql::llms::openai::batch::{BatchError, BatchId};
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(RoutingTransport {
+                routes: &[
+                    (
+                        "/batches/batch_1",
+                        r#"{"id":"batch_1","status":"completed","output_file_id":"file_1","error_file_id":null,"request_counts":{"total":3,"completed":1,"failed":0}}"#,
+                    ),
+                    (
+                        "/files/file_1/content",
+                        concat!(
+                            r#"{"custom_id":"0","response":{"status_code":200,"body":{"choices":[{"message":{"content":"Hello"}}]}},"error":null}"#,
+                            "\n",
+                            r#"{"custom_id":"1","response":{"status_code":70000,"body":{}},"error":null}"#,
+                            "\n",
+                            r#"{"custom_id":"18446744073709551615","response":{"status_code":200,"body":{}},"error":null}"#,
+                            "\n",
+                        ),
+                    ),
+                ],
+            });
+
+    let results = gpt.fetch_results(&BatchId::new("batch_1")).await.unwrap();
+
+    // The requests after the last result are missing too, rather than left out, and results for
+    // requests which were never made are ignored.
+    let [Ok(first), Err(BatchError::MalformedResponse { .. }), Err(BatchError::MissingResult)] =
+        results.as_slice()
+    else {
+        panic!("Expected one result, one malformed and one missing, got {results:?}");
+    };
+    assert!(matches!(first.as_slice(), [Chunk::Token(text), ..] if text == "Hello"));
+}
+
+#[tokio::test]
+async fn canned_cohere_rerank() {
+    use lmql::rerank::Reranker;
+
+    let cohere = lmql::llms::cohere::Cohere::new("key").with_transport(CannedTransport {
+        body: r#"{"id":"rr-1","results":[{"index":2,"relevance_score":0.98},{"index":0,"relevance_score":0.41}],"meta":{"api_version":{"version":"2"}}}"#,
+    });
+
+    let documents = [
+        "Paris is in France.",
+        "Berlin is in Germany.",
+        "The capital of France is Paris.",
+    ]
+    .map(str::to_owned);
+    let ranked = cohere
+        .rerank("What is the capital of France?", &documents, Some(2))
+        .await
+        .unwrap();
+
+    assert_eq!(ranked, [(2, 0.98), (0, 0.41)]);
+}
+
+#[tokio::test]
+async fn canned_image_generation() {
+    use lmql::images::{ImageData, ImageGeneration, ImageOptions};
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: r#"{"created":1713833628,"data":[{"url":"https://example.com/moon.png","revised_prompt":"The moon."},{"b64_json":"iVBORw0KGgo="}]}"#,
+            });
+
+    let images = gpt
+        .generate_image("The moon.", ImageOptions::default().set_n(2))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        images,
+        [
+            ImageData::Url("https://example.com/moon.png".to_owned()),
+            ImageData::Base64(lmql::Image {
+                media_type: "image/png".to_owned(),
+                data: "iVBORw0KGgo=".to_owned(),
+            }),
+        ]
+    );
+
+    // GPT Image returns the format it was asked for.
+    let gpt = lmql::llms::openai::Gpt::new(
+        lmql::llms::openai::GptModel::Gpt4oMini,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: r#"{"created":1713833628,"data":[{"b64_json":"/9j/4AAQ"}],"output_format":"jpeg"}"#,
+    });
+    let mut options = ImageOptions::default();
+    options.set_model(lmql::images::ImageModel::GptImage1);
+    let images = gpt.generate_image("The moon.", &options).await.unwrap();
+    assert_eq!(
+        images,
+        [ImageData::Base64(lmql::Image {
+            media_type: "image/jpeg".to_owned(),
+            data: "/9j/4AAQ".to_owned(),
+        })]
+    );
+}
+
+#[tokio::test]
+async fn canned_openrouter_object_arguments() {
+    let llm = lmql::llms::openrouter::OpenRouter::new("qwen/qwen-turbo", "key").with_transport(
+        CannedTransport {
+            body: concat!(
+                r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"get_weather","arguments":{"city":"Paris"}}}]},"finish_reason":null}]}"#,
+                "\n\n",
+                r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+                "\n\n",
+            ),
+        },
+    );
+
+    let calls = llm
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .tool_calls()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        calls,
+        [(
+            "get_weather".to_owned(),
+            serde_json::json!({"city": "Paris"})
+        )]
+    );
+}
+
+#[tokio::test]
+async fn canned_openrouter_generation_stats() {
+    use futures::StreamExt;
+
+    let llm = lmql::llms::openrouter::OpenRouter::new("anthropic/claude-3.5-haiku", "key")
+        .with_transport(CannedTransport {
+            body: concat!(
+                r#"data: {"id":"gen-123","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#,
+                "\n\n",
+                r#"data: {"id":"gen-123","object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                "\n\n",
+            ),
+        });
+    let mut stream = llm
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    assert_eq!(stream.id(), None);
+    while stream.next().await.is_some() {}
+    assert_eq!(stream.id(), Some("gen-123"));
+
+    let llm = lmql::llms::openrouter::OpenRouter::new("anthropic/claude-3.5-haiku", "key")
+        .with_transport(CannedTransport {
+            body: r#"{"data":{"id":"gen-123","total_cost":0.00042,"tokens_prompt":10,"tokens_completion":2,"native_tokens_prompt":12,"native_tokens_completion":3,"provider_name":"Anthropic"}}"#,
+        });
+    let stats = llm.generation_stats("gen-123").await.unwrap();
+    assert_eq!(
+        stats,
+        lmql::llms::openrouter::GenerationStats {
+            cost: 0.00042,
+            input_tokens: Some(12),
+            output_tokens: Some(3),
+            provider: Some("Anthropic".to_owned()),
+        }
+    );
+}
+
+/// As with [`CannedTransport`], but keeps the uri of each request.
+struct UriRecordingTransport {
+    body: &'static str,
+    uris: std::sync::Arc<std::sync::Mutex<Vec<hyper::Uri>>>,
+}
+
+impl Transport for UriRecordingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let uris = self.uris.clone();
+        serve(canned(self.body), move |request| {
+            uris.lock().unwrap().push(request.uri().clone());
+        })
+    }
+}
+
+#[tokio::test]
+async fn openrouter_generation_id_is_encoded() {
+    let uris = std::sync::Arc::default();
+    let llm = lmql::llms::openrouter::OpenRouter::new("anthropic/claude-3.5-haiku", "key")
+        .with_transport(UriRecordingTransport {
+            uris: std::sync::Arc::clone(&uris),
+            body: r#"{"data":{"id":"gen-1&x=y #z","total_cost":0.0}}"#,
+        });
+
+    llm.generation_stats("gen-1&x=y #z").await.unwrap();
+
+    let uris = uris.lock().unwrap();
+    assert_eq!(uris[0].path(), "/api/v1/generation");
+    assert_eq!(uris[0].query(), Some("id=gen-1%26x%3Dy+%23z"));
+}
+
+/// Refuses streamed requests with the given error, as some OpenRouter models do, answering others
+/// with a complete chat completion.
+struct NonStreamingTransport {
+    refusal: &'static str,
+}
+
+impl Transport for NonStreamingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let refusal = self.refusal;
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(
+                move |request: hyper::Request<hyper::body::Incoming>| async move {
+                    use http_body_util::BodyExt;
+                    let body = request.into_body().collect().await?.to_bytes();
+                    let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+
+                    let response = if body["stream"] == true {
+                        hyper::Response::builder().status(400).body(refusal)
+                    } else {
+                        hyper::Response::builder().body(
+                            r#"{"object":"chat.completion","choices":[{"index":0,"message":{"role":"assistant","content":"Let me check.","tool_calls":[{"id":"call_a","type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"Paris\"}"}}]},"finish_reason":"tool_calls"}],"usage":{"prompt_tokens":12,"completion_tokens":20,"total_tokens":32}}"#,
+                        )
+                    };
+                    Ok::<_, hyper::Error>(
+                        response
+                            .unwrap()
+                            .map(|body| http_body_util::Full::new(hyper::body::Bytes::from(body))),
+                    )
+                },
+            );
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+                .await
+                .ok();
+        });
+        Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+    }
+}
+
+#[tokio::test]
+async fn openrouter_non_stream_fallback() {
+    let chat = [Message::User("Hi!".into())];
+    // OpenRouter passes on the upstream refusal, which names the parameter at fault.
+    let refusal = r#"{"error":{"code":400,"message":"Provider returned error","metadata":{"raw":"{\"error\":{\"message\":\"Unsupported value: 'stream' does not support true with this model.\",\"type\":\"invalid_request_error\",\"param\":\"stream\",\"code\":\"unsupported_value\"}}","provider_name":"OpenAI"}}}"#;
+    let llm = |refusal| {
+        lmql::llms::openrouter::OpenRouter::new("some/model", "key")
+            .with_transport(NonStreamingTransport { refusal })
+    };
+
+    let error = llm(refusal)
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, lmql::TokenError::ConnectionLost(_)),
+        "{error:?}"
+    );
+
+    let chunks = llm(refusal)
+        .with_non_stream_fallback(true)
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    let [Chunk::Token(text), Chunk::ToolCall(call), Chunk::Finish(FinishReason::ToolUse), Chunk::Usage(usage)] =
+        chunks.as_slice()
+    else {
+        panic!("Expected text, a tool call and the usage, got {chunks:?}");
+    };
+    assert_eq!(text, "Let me check.");
+    assert!(call.is_complete(), "{call:?}");
+    assert_eq!(call.arguments, r#"{"city":"Paris"}"#);
+    assert_eq!(usage.input_tokens, Some(12));
+    assert_eq!(usage.output_tokens, Some(20));
+
+    // Other errors which happen to mention streaming aren't mistaken for a refusal.
+    let error = llm(r#"{"error":{"code":400,"message":"Streaming is not supported for free users of this key"}}"#)
+        .with_non_stream_fallback(true)
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, lmql::TokenError::ConnectionLost(_)),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn canned_raw_events() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello","annotations":[{"type":"url_citation"}]},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let mut stream = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    let mut raw_events = stream.raw_events();
+    let chunks = stream.all_tokens().await.unwrap();
+    assert_eq!(chunks.len(), 2, "{chunks:?}");
+
+    let first = raw_events.recv().await.unwrap();
+    assert_eq!(first.event, "");
+    assert_eq!(
+        first.value["choices"][0]["delta"]["annotations"][0]["type"],
+        "url_citation"
+    );
+    assert!(raw_events.recv().await.is_some());
+    assert!(raw_events.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn canned_audio_deltas() {
+    use futures::StreamExt;
+
+    let gpt = lmql::llms::openai::Gpt::new(
+        lmql::llms::openai::GptModel::Gpt4oMiniAudioPreview,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: concat!(
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"role":"assistant","audio":{"id":"audio_1","transcript":"Hel"}},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"audio":{"data":"AAEC"}},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"audio":{"transcript":"lo","data":"AwQ="}},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"audio":{"expires_at":1729018505}},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+            "\n\n",
+        ),
+    });
+    let chat = [Message::User("Say hello.".into())];
+
+    let chunks = gpt
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [
+                Chunk::Token(first),
+                Chunk::Audio(first_audio),
+                Chunk::Token(second),
+                Chunk::Audio(second_audio),
+                Chunk::Finish(FinishReason::EndTurn),
+            ] if first == "Hel"
+                && first_audio == &[0, 1, 2]
+                && second == "lo"
+                && second_audio == &[3, 4]
+        ),
+        "{chunks:?}"
+    );
+
+    // The audio is one recording, however the transcript arrives between it.
+    let chunks = gpt
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    let audio = chunks
+        .iter()
+        .filter_map(|chunk| match chunk {
+            Chunk::Audio(audio) => Some(audio.as_slice()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(audio, [[0, 1, 2, 3, 4]]);
+}
+
+#[tokio::test]
+async fn canned_anthropic_raw_events() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: concat!(
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":"Hello","citations":[]}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            "\n\n",
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ),
+    });
+
+    let mut stream = claude
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    let mut raw_events = stream.raw_events();
+    stream.all_tokens().await.unwrap();
+
+    let first = raw_events.recv().await.unwrap();
+    assert_eq!(first.event, "content_block_start");
+    assert!(first.value["content_block"]["citations"].is_array());
+    assert_eq!(raw_events.recv().await.unwrap().event, "content_block_stop");
+    assert_eq!(raw_events.recv().await.unwrap().event, "message_stop");
+    assert!(raw_events.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn canned_responses_id() {
+    let gpt = lmql::llms::openai::responses::GptResponses::new(
+        lmql::llms::openai::GptModel::Gpt4oMini,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: concat!(
+            "event: response.created\n",
+            r#"data: {"type":"response.created","response":{"id":"resp_1","status":"in_progress"}}"#,
+            "\n\n",
+            "event: response.output_text.delta\n",
+            r#"data: {"type":"response.output_text.delta","delta":"Hello"}"#,
+            "\n\n",
+        ),
+    });
+
+    let mut stream = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    assert_eq!(stream.response_id(), None);
+    assert_eq!(stream.next_token().await.unwrap().unwrap(), "Hello");
+    assert_eq!(stream.response_id(), Some("resp_1"));
+}
+
+/// As with [`CannedTransport`], but keeps the headers of each request.
+struct HeaderRecordingTransport {
+    body: &'static str,
+    headers: std::sync::Arc<std::sync::Mutex<Vec<hyper::HeaderMap>>>,
+}
+
+impl Transport for HeaderRecordingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let headers = self.headers.clone();
+        serve(canned(self.body), move |request| {
+            headers.lock().unwrap().push(request.headers().clone());
+        })
+    }
+}
+
+#[tokio::test]
+async fn canned_anthropic_interleaved_thinking() {
+    let headers = std::sync::Arc::default();
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_Sonnet_4_20250514,
+        "key".to_owned(),
+    )
+    .with_interleaved_thinking()
+    .with_transport(HeaderRecordingTransport {
+        headers: std::sync::Arc::clone(&headers),
+        body: concat!(
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            "\n\n",
+            "event: content_block_delta\n",
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Check the weather."}}"#,
+            "\n\n",
+            "event: content_block_delta\n",
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"sig"}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            "\n\n",
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{"city":"Paris"}}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":1}"#,
+            "\n\n",
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":2,"content_block":{"type":"thinking","thinking":""}}"#,
+            "\n\n",
+            "event: content_block_delta\n",
+            r#"data: {"type":"content_block_delta","index":2,"delta":{"type":"thinking_delta","thinking":"Now the time."}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":2}"#,
+            "\n\n",
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":3,"content_block":{"type":"tool_use","id":"toolu_2","name":"get_time","input":{"city":"Paris"}}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":3}"#,
+            "\n\n",
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ),
+    });
+
+    let options = PromptOptions {
+        reasoning: Some(lmql::ReasoningEffort::Budget(2048)),
+        ..Default::default()
+    };
+    let chunks = claude
+        .prompt(&[Message::User("Hi!".into())], &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    let [Chunk::Thinking(first), Chunk::ThinkingSignature(signature), Chunk::ToolCall(weather), Chunk::Thinking(second), Chunk::ToolCall(time)] =
+        chunks.as_slice()
+    else {
+        panic!("Expected thinking between tool calls, got {chunks:?}");
+    };
+    assert_eq!(first, "Check the weather.");
+    assert_eq!(signature, "sig");
+    assert_eq!(weather.name.as_deref(), Some("get_weather"));
+    assert_eq!(second, "Now the time.");
+    assert_eq!(time.name.as_deref(), Some("get_time"));
+
+    let headers = headers.lock().unwrap();
+    assert_eq!(
+        headers[0]
+            .get("anthropic-beta")
+            .and_then(|value| value.to_str().ok()),
+        Some("interleaved-thinking-2025-05-14")
+    );
+    drop(headers);
+
+    // The signed thinking is sent back with the tool call which follows it, and the unsigned
+    // thinking is dropped.
+    let mut chat = vec![Message::User("Hi!".into())];
+    chat.extend(Message::from_chunks(chunks, false));
+    assert_eq!(
+        chat[1],
+        Message::Thinking {
+            thinking: "Check the weather.".to_owned(),
+            signature: "sig".to_owned(),
+        }
+    );
+    let body: serde_json::Value =
+        serde_json::from_str(&claude.build_request_body(&chat, &options).unwrap()).unwrap();
+    assert_eq!(
+        body["messages"][1]["content"][0],
+        serde_json::json!({
+            "type": "thinking",
+            "thinking": "Check the weather.",
+            "signature": "sig",
+        })
+    );
+    assert_eq!(body["messages"][1]["content"][1]["type"], "tool_use");
+    assert_eq!(body["messages"][1]["content"].as_array().unwrap().len(), 3);
+}
+
+/// Signs requests with a fixed header, or fails to if `refuse` is set.
+struct HeaderSigner {
+    refuse: bool,
+}
+
+impl lmql::signing::RequestSigner for HeaderSigner {
+    fn sign(
+        &self,
+        request: &mut hyper::Request<String>,
+    ) -> Result<(), lmql::signing::SigningError> {
+        if self.refuse {
+            return Err(lmql::signing::SigningError("credentials expired".into()));
+        }
+        request.headers_mut().insert(
+            "x-signature",
+            hyper::header::HeaderValue::from_static("signed"),
+        );
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn any_provider_can_sign_requests() {
+    let headers = std::sync::Arc::default();
+    let transport = |refuse| {
+        lmql::signing::SignedTransport::new(
+            HeaderRecordingTransport {
+                headers: std::sync::Arc::clone(&headers),
+                body: concat!(
+                    "event: message_stop\n",
+                    r#"data: {"type":"message_stop"}"#,
+                    "\n\n",
+                ),
+            },
+            HeaderSigner { refuse },
+        )
+    };
+    let claude = |refuse| {
+        lmql::llms::anthropic::Claude::new(
+            lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+            "key".to_owned(),
+        )
+        .with_transport(transport(refuse))
+    };
+    let chat = [Message::User("Hi!".into())];
+
+    claude(false)
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+    assert_eq!(headers.lock().unwrap()[0]["x-signature"], "signed");
+
+    let error = claude(true)
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            error,
+            lmql::TokenError::ConnectionLost(lmql::SseError::SigningError(_))
+        ),
+        "{error:?}"
+    );
+    // The request was never sent.
+    assert_eq!(headers.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn interleaved_thinking_needs_claude_4() {
+    let headers = std::sync::Arc::default();
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_7_Sonnet_20250219,
+        "key".to_owned(),
+    )
+    .with_interleaved_thinking()
+    .with_transport(HeaderRecordingTransport {
+        headers: std::sync::Arc::clone(&headers),
+        body: concat!(
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ),
+    });
+
+    let options = PromptOptions {
+        reasoning: Some(lmql::ReasoningEffort::Budget(2048)),
+        ..Default::default()
+    };
+    claude
+        .prompt(&[Message::User("Hi!".into())], &options)
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(headers.lock().unwrap()[0].get("anthropic-beta").is_none());
+}
+
+/// Fails every handshake as an untrusted certificate would, as behind a TLS-intercepting proxy.
+struct UntrustedTransport;
+
+impl Transport for UntrustedTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        use tokio_rustls::rustls;
+
+        Box::pin(async move {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+            ))
+        })
+    }
+}
+
+#[tokio::test]
+async fn tls_failures_are_distinct() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(UntrustedTransport);
+
+    let error = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            &error,
+            lmql::TokenError::ConnectionLost(lmql::SseError::TlsError(message))
+                if message.contains("UnknownIssuer")
+        ),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn coalesced_tokens() {
+    use futures::StreamExt;
+
+    const BODY: &str = concat!(
+        r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+        "\n\n",
+        r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":", "},"finish_reason":null}]}"#,
+        "\n\n",
+        r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"world!"},"finish_reason":null}]}"#,
+        "\n\n",
+        r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+        "\n\n",
+    );
+    let llm = || {
+        lmql::llms::openai::compatible::OpenAICompatible::new(
+            "https://api.groq.com/openai/v1",
+            "llama-3.3-70b-versatile",
+        )
+        .with_transport(CannedTransport { body: BODY })
+    };
+    let chat = [Message::User("Hi!".into())];
+
+    let chunks = llm()
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(chunks.len(), 4, "{chunks:?}");
+
+    // The whole body arrives in one frame, so its text becomes one chunk.
+    let chunks = llm()
+        .with_coalesced_tokens()
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello, world!"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn coalesced_tokens_before_an_error() {
+    use futures::StreamExt;
+
+    let llm = lmql::llms::openai::compatible::OpenAICompatible::new(
+        "https://api.groq.com/openai/v1",
+        "llama-3.3-70b-versatile",
+    )
+    .with_coalesced_tokens()
+    .with_transport(CannedTransport {
+        body: concat!(
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":", world!"},"finish_reason":null}]}"#,
+            "\n\n",
+            r#"data: {"error":{"message":"The server is overloaded."}}"#,
+            "\n\n",
+        ),
+    });
+
+    let chunks = llm
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .collect::<Vec<_>>()
+        .await;
+
+    // The text which arrived before the error is returned first.
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Ok(Chunk::Token(text)), Err(_)] if text == "Hello, world!"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn canned_responses_errors_are_classified() {
+    use futures::StreamExt;
+
+    async fn error(body: &'static str) -> lmql::TokenError {
+        let llm = lmql::llms::openai::responses::GptResponses::new(
+            lmql::llms::openai::GptModel::o3Mini,
+            "key".to_owned(),
+        )
+        .with_transport(CannedTransport { body });
+        let chunks = llm
+            .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        match chunks.into_iter().last() {
+            Some(Err(error)) => error,
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    assert!(matches!(
+        error(concat!(
+            "event: response.failed\n",
+            r#"data: {"type":"response.failed","response":{"error":{"code":"server_error","message":"The server had an error."}}}"#,
+            "\n\n",
+        ))
+        .await,
+        lmql::TokenError::TransientServerError(message) if message == "The server had an error."
+    ));
+    assert!(matches!(
+        error(concat!(
+            "event: error\n",
+            r#"data: {"type":"error","code":"rate_limit_exceeded","message":"Slow down."}"#,
+            "\n\n",
+        ))
+        .await,
+        lmql::TokenError::TransientServerError(_)
+    ));
+    assert!(matches!(
+        error(concat!(
+            "event: response.failed\n",
+            r#"data: {"type":"response.failed","response":{"error":{"code":"invalid_prompt","message":"Invalid prompt."}}}"#,
+            "\n\n",
+        ))
+        .await,
+        lmql::TokenError::ServerError(_)
+    ));
+}
+
+/// Thinking, then text alongside two tool calls whose blocks are open at once.
+const INTERLEAVED_ANTHROPIC_BLOCKS: &str = concat!(
+    "event: content_block_start\n",
+    r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Two lookups."}}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"sig"}}"#,
+    "\n\n",
+    "event: content_block_stop\n",
+    r#"data: {"type":"content_block_stop","index":0}"#,
+    "\n\n",
+    "event: content_block_start\n",
+    r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"text","text":""}}"#,
+    "\n\n",
+    "event: content_block_start\n",
+    r#"data: {"type":"content_block_start","index":2,"content_block":{"type":"tool_use","id":"toolu_a","name":"get_weather","input":{}}}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"Checking."}}"#,
+    "\n\n",
+    "event: content_block_start\n",
+    r#"data: {"type":"content_block_start","index":3,"content_block":{"type":"tool_use","id":"toolu_b","name":"get_time","input":{}}}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":"{\"city\":"}}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":3,"delta":{"type":"input_json_delta","partial_json":"{\"zone\":\"UTC\"}"}}"#,
+    "\n\n",
+    "event: content_block_stop\n",
+    r#"data: {"type":"content_block_stop","index":1}"#,
+    "\n\n",
+    "event: content_block_delta\n",
+    r#"data: {"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":"\"Paris\"}"}}"#,
+    "\n\n",
+    "event: content_block_stop\n",
+    r#"data: {"type":"content_block_stop","index":3}"#,
+    "\n\n",
+    "event: content_block_stop\n",
+    r#"data: {"type":"content_block_stop","index":2}"#,
+    "\n\n",
+    "event: message_stop\n",
+    r#"data: {"type":"message_stop"}"#,
+    "\n\n",
+);
+
+#[tokio::test]
+async fn canned_anthropic_interleaved_blocks() {
+    use futures::StreamExt;
+
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: INTERLEAVED_ANTHROPIC_BLOCKS,
+    });
+    let chat = [Message::User("Weather and time?".into())];
+
+    let chunks = claude
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert!(
+        matches!(
+            &chunks[..3],
+            [Chunk::Thinking(thinking), Chunk::ThinkingSignature(signature), Chunk::ToolCall(_)]
+                if thinking == "Two lookups." && signature == "sig"
+        ),
+        "{chunks:?}"
+    );
+    assert!(
+        matches!(&chunks[3], Chunk::Token(text) if text == "Checking."),
+        "{chunks:?}"
+    );
+    // Each piece of a tool call is tagged with the block it arrived in.
+    let tool_chunks = chunks
+        .iter()
+        .filter_map(|chunk| match chunk {
+            Chunk::ToolCall(call) => Some((call.index, call.name.as_deref(), &*call.arguments)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        tool_chunks,
+        [
+            (Some(2), Some("get_weather"), ""),
+            (Some(3), Some("get_time"), ""),
+            (Some(2), None, r#"{"city":"#),
+            (Some(3), None, r#"{"zone":"UTC"}"#),
+            (Some(2), None, r#""Paris"}"#),
+        ]
+    );
+
+    let calls = claude
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .tool_calls()
+        .await
+        .unwrap();
+    assert_eq!(
+        calls,
+        [
+            (
+                "get_weather".to_owned(),
+                serde_json::json!({"city": "Paris"})
+            ),
+            ("get_time".to_owned(), serde_json::json!({"zone": "UTC"})),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn canned_anthropic_stop_sequence() {
+    let claude = lmql::llms::anthropic::Claude::new(
+        lmql::llms::anthropic::ClaudeModel::Claude_3_5_Haiku_20241022,
+        "key".to_owned(),
+    )
+    .with_transport(CannedTransport {
+        body: concat!(
+            "event: content_block_start\n",
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":"Routing to "}}"#,
+            "\n\n",
+            "event: content_block_stop\n",
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            "\n\n",
+            "event: message_delta\n",
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"stop_sequence","stop_sequence":"[SEARCH]"},"usage":{"output_tokens":4}}"#,
+            "\n\n",
+            "event: message_stop\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ),
+    });
+
+    let chunks = claude
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(_), Chunk::Finish(FinishReason::StopSequence(Some(sequence))), Chunk::Usage(_)]
+                if sequence == "[SEARCH]"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[tokio::test]
+async fn context_length_exceeded() {
+    use lmql::{ProviderErrorKind, SseError, TokenError};
+
+    async fn kind(body: &'static str) -> ProviderErrorKind {
+        let gpt =
+            lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+                .with_transport(FailingTransport { status: 400, body });
+        let error = gpt
+            .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+            .unwrap()
+            .all_tokens()
+            .await
+            .unwrap_err();
+        match error {
+            TokenError::ConnectionLost(SseError::StatusError { status, kind, .. }) => {
+                assert_eq!(status, 400);
+                kind
+            }
+            error => panic!("{error:?}"),
+        }
+    }
+
+    assert_eq!(
+        kind(r#"{"error":{"message":"This model's maximum context length is 128000 tokens. However, your messages resulted in 130512 tokens. Please reduce the length of the messages.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#).await,
+        ProviderErrorKind::ContextLengthExceeded {
+            max_context: Some(128000),
+            requested: Some(130512),
+        }
+    );
+    assert_eq!(
+        kind(r#"{"type":"error","error":{"type":"invalid_request_error","message":"input length and `max_tokens` exceed context limit: 195000 + 8192 > 200000, decrease input length or `max_tokens` and try again"}}"#).await,
+        ProviderErrorKind::ContextLengthExceeded {
+            max_context: Some(200000),
+            requested: Some(203192),
+        }
+    );
+    assert_eq!(
+        kind(r#"{"error":{"code":"context_length_exceeded","message":"Too long."}}"#).await,
+        ProviderErrorKind::ContextLengthExceeded {
+            max_context: None,
+            requested: None,
+        }
+    );
+    assert_eq!(
+        kind(r#"{"error":{"message":"Invalid model.","code":"model_not_found"}}"#).await,
+        ProviderErrorKind::Other
+    );
+}
+
+/// As with [`CannedTransport`], but names the request in the response headers.
+struct RequestIdTransport {
+    body: &'static str,
+}
+
+impl Transport for RequestIdTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let mut response = canned(self.body);
+        response.headers_mut().insert(
+            "x-request-id",
+            hyper::header::HeaderValue::from_static("req_123"),
+        );
+        serve(response, |_| {})
+    }
+}
+
+#[tokio::test]
+async fn prompt_with_start() {
+    use futures::StreamExt;
+
+    let llm = lmql::llms::openrouter::OpenRouter::new("some/model", "key").with_transport(
+        RequestIdTransport {
+            body: concat!(
+                r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":"stop"}]}"#,
+                "\n\n",
+            ),
+        },
+    );
+    let options = PromptOptions {
+        model_override: Some(lmql::ModelOverride::Named("other/model".to_owned())),
+        correlation_id: Some("job-7".to_owned()),
+        ..Default::default()
+    };
+
+    let (start, mut stream) = llm
+        .prompt_with_start(&[Message::User("Hi!".into())], &options)
+        .unwrap();
+    assert_eq!(start.provider, "OpenRouter");
+    assert_eq!(start.model, "other/model");
+    assert_eq!(start.correlation_id.as_deref(), Some("job-7"));
+
+    // The request id arrives with the headers, before the first chunk.
+    assert!(stream.next().await.is_some());
+    assert_eq!(
+        stream
+            .response_metadata()
+            .and_then(lmql::ResponseMetadata::request_id),
+        Some("req_123")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn dropping_streams_races_their_tasks() {
+    use futures::StreamExt;
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+    let chat = [Message::User("Hi!".into())];
+
+    // Streams are dropped before, during and after their tasks finish.
+    for i in 0..300 {
+        let mut stream = gpt.prompt(&chat, &PromptOptions::default()).unwrap();
+        match i % 3 {
+            0 => drop(stream),
+            1 => {
+                stream.next().await.unwrap().unwrap();
+                drop(stream);
+            }
+            _ => {
+                stream.next().await.unwrap().unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        }
+    }
+}
+
+/// Never finishes connecting, as a server which never answers.
+struct HangingTransport;
+
+impl Transport for HangingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        Box::pin(std::future::pending())
+    }
+}
+
+#[tokio::test]
+async fn shutdown_before_response() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(HangingTransport);
+
+    let stream = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.shutdown())
+        .await
+        .expect("shutdown should not wait for the request to be sent")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn canned_reproducibility() {
+    use futures::StreamExt;
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","system_fingerprint":"fp_44709d6fcb","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","system_fingerprint":"fp_44709d6fcb","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+    let chat = [Message::User("Hi!".into())];
+    let mut options = PromptOptions::default();
+    options.set_seed(42);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(
+            &gpt.build_request_body(&chat, &options).unwrap()
+        )
+        .unwrap()["seed"],
+        42
+    );
+
+    let mut runs = vec![];
+    for _ in 0..2 {
+        let mut stream = gpt.prompt(&chat, &options).unwrap();
+        while stream.next().await.is_some() {}
+        runs.push(stream.reproducibility());
+    }
+    assert_eq!(runs[0], runs[1]);
+    assert_eq!(
+        runs[0],
+        lmql::Reproducibility {
+            seed: Some(42),
+            system_fingerprint: Some("fp_44709d6fcb".to_owned()),
+        }
+    );
+}
+
+#[tokio::test]
+async fn plain_http1_server() {
+    // A LiteLLM proxy started with the defaults only speaks HTTP/1.1, without TLS.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (requests, mut received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = hyper::service::service_fn(move |request: hyper::Request<_>| {
+            requests
+                .send((
+                    request.version(),
+                    request.uri().to_string(),
+                    request.headers().get("host").cloned(),
+                ))
+                .unwrap();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                    http_body_util::Full::new(hyper::body::Bytes::from_static(
+                        concat!(
+                            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                            "\n\n",
+                            r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+                            "\n\n",
+                        )
+                        .as_bytes(),
+                    )),
+                ))
+            }
+        });
+        hyper::server::conn::http1::Builder::new()
+            .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+            .await
+            .ok();
+    });
+
+    let llm = lmql::llms::openai::compatible::OpenAICompatible::litellm(
+        format!("http://127.0.0.1:{port}"),
+        "gpt-4o",
+        "sk-1234",
+    );
+    let chunks = llm
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [Chunk::Token(text), Chunk::Finish(FinishReason::EndTurn)] if text == "Hello"
+        ),
+        "{chunks:?}"
+    );
+    let (version, uri, host) = received.recv().await.unwrap();
+    assert_eq!(version, hyper::Version::HTTP_11);
+    assert_eq!(uri, "/chat/completions");
+    assert_eq!(host.unwrap(), format!("127.0.0.1:{port}").as_str());
+}
+
+#[tokio::test]
+async fn canned_openai_usage() {
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}],"usage":null}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":null}"#,
+                    "\n\n",
+                    r#"data: {"object":"chat.completion.chunk","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":1,"total_tokens":10}}"#,
+                    "\n\n",
+                ),
+            });
+    let chat = [Message::User("Hi!".into())];
+    let body: serde_json::Value = serde_json::from_str(
+        &gpt.build_request_body(&chat, &PromptOptions::default())
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(body["stream_options"]["include_usage"], true);
+
+    // Other servers may reject the option, so it is only sent when asked for.
+    let compatible = lmql::llms::openai::compatible::OpenAICompatible::new(
+        "http://localhost:8000/v1",
+        "meta-llama/Llama-3.1-8B-Instruct",
+    );
+    let body = |llm: &lmql::llms::openai::compatible::OpenAICompatible| {
+        serde_json::from_str::<serde_json::Value>(
+            &llm.build_request_body(&chat, &PromptOptions::default())
+                .unwrap(),
+        )
+        .unwrap()
+    };
+    assert!(body(&compatible).get("stream_options").is_none());
+    assert_eq!(
+        body(&compatible.with_stream_usage())["stream_options"]["include_usage"],
+        true
+    );
+
+    let chunks = gpt
+        .prompt(&chat, &PromptOptions::default())
+        .unwrap()
+        .all_tokens()
+        .await
+        .unwrap();
+
+    assert!(
+        matches!(
+            chunks.as_slice(),
+            [
+                Chunk::Token(text),
+                Chunk::Finish(FinishReason::EndTurn),
+                Chunk::Usage(lmql::Usage {
+                    input_tokens: Some(9),
+                    output_tokens: Some(1),
+                }),
+            ] if text == "Hello"
+        ),
+        "{chunks:?}"
+    );
+}
+
+#[test]
+fn response_bodies_are_redacted_in_logs() {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(CannedTransport {
+                body: concat!(
+                    r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Mail jo@example.com"},"finish_reason":"stop"}]}"#,
+                    "\n\n",
+                ),
+            });
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .finish();
+    lmql::logging::set_redactor(|body| body.replace("jo@example.com", "[email]"));
+    tracing::subscriber::with_default(subscriber, || {
+        // The connection's task runs on this thread, so that its logs are collected too.
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                gpt.prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+                    .unwrap()
+                    .all_tokens()
+                    .await
+                    .unwrap();
+            });
+    });
+    lmql::logging::clear_redactor();
+
+    let logged = String::from_utf8_lossy(&buffer.0.lock().unwrap()).into_owned();
+    assert!(logged.contains("Received chunk"), "{logged}");
+    assert!(logged.contains("received token in all_tokens"), "{logged}");
+    assert!(logged.contains("[email]"), "{logged}");
+    assert!(!logged.contains("jo@example.com"), "{logged}");
+}
+
+/// Streams one event and then holds the response open, recording the frames the client sends.
+struct FrameRecordingTransport {
+    received: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    ended: tokio::sync::mpsc::UnboundedSender<()>,
+}
+
+/// Copies everything read from the connection.
+struct RecordingIo {
+    inner: tokio::io::DuplexStream,
+    received: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl tokio::io::AsyncRead for RecordingIo {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = std::pin::Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.received
+            .lock()
+            .unwrap()
+            .extend_from_slice(&buf.filled()[before..]);
+        poll
+    }
+}
+
+impl tokio::io::AsyncWrite for RecordingIo {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Transport for FrameRecordingTransport {
+    fn connect<'a>(&'a self, _host: &'a str, _port: u16) -> Connect<'a> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = RecordingIo {
+            inner: server,
+            received: self.received.clone(),
+        };
+        let ended = self.ended.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |_request| async move {
+                use futures::StreamExt;
+
+                let event = hyper::body::Frame::data(hyper::body::Bytes::from_static(
+                    concat!(
+                        r#"data: {"object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+                        "\n\n",
+                    )
+                    .as_bytes(),
+                ));
+                let frames = futures::stream::iter([Ok::<_, std::convert::Infallible>(event)])
+                    .chain(futures::stream::pending());
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                    http_body_util::StreamBody::new(frames),
+                ))
+            });
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(hyper_util::rt::TokioIo::new(server), service)
+                .await
+                .ok();
+            ended.send(()).ok();
+        });
+        Box::pin(async move { Ok(Box::new(client) as Box<dyn Connection>) })
+    }
+}
+
+#[tokio::test]
+async fn dropping_a_stream_cancels_it_cleanly() {
+    let received = std::sync::Arc::default();
+    let (ended, mut end) = tokio::sync::mpsc::unbounded_channel();
+    let gpt =
+        lmql::llms::openai::Gpt::new(lmql::llms::openai::GptModel::Gpt4oMini, "key".to_owned())
+            .with_transport(FrameRecordingTransport {
+                received: std::sync::Arc::clone(&received),
+                ended,
+            });
+
+    let mut stream = gpt
+        .prompt(&[Message::User("Hi!".into())], &PromptOptions::default())
+        .unwrap();
+    assert_eq!(stream.next_token().await.unwrap().unwrap(), "Hello");
+    drop(stream);
+    tokio::time::timeout(std::time::Duration::from_secs(1), end.recv())
+        .await
+        .unwrap();
+
+    // The frame types sent after the connection preface, along with their payloads.
+    let received = received.lock().unwrap();
+    let mut frames = vec![];
+    let mut rest = &received[24..];
+    while rest.len() >= 9 {
+        let length = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        frames.push((rest[3], rest[9..9 + length].to_vec()));
+        rest = &rest[9 + length..];
+    }
+
+    // The stream is reset with CANCEL, then the connection is closed with GOAWAY(NO_ERROR).
+    const RST_STREAM: u8 = 0x3;
+    const GOAWAY: u8 = 0x7;
+    let [.., (reset, reset_payload), (goaway, goaway_payload)] = &frames[..] else {
+        panic!("too few frames: {frames:?}");
+    };
+    assert_eq!(
+        (*reset, &reset_payload[..]),
+        (RST_STREAM, &[0, 0, 0, 0x8][..])
+    );
+    assert_eq!(*goaway, GOAWAY);
+    assert_eq!(goaway_payload[4..8], [0, 0, 0, 0]);
+}
@@ -0,0 +1,25 @@
+#![cfg(feature = "vertex")]
+
+mod common;
+
+mod gemini {
+    super::tests_with_llm! {
+        lmql::llms::vertex::Vertex::new_from_env(
+            "us-central1",
+            lmql::llms::vertex::VertexModel::Gemini("gemini-2.0-flash".to_owned()),
+        )
+
+        => skip reasoning
+    }
+}
+
+mod claude {
+    super::tests_with_llm! {
+        lmql::llms::vertex::Vertex::new_from_env(
+            "us-east5",
+            lmql::llms::vertex::VertexModel::Claude("claude-3-5-haiku@20241022".to_owned()),
+        )
+
+        => skip reasoning
+    }
+}